@@ -1,10 +1,14 @@
 use crate::device::Device;
 use crate::device::{self, DeviceId};
+use crate::stats::LifetimeStats;
 use embassy_sync::channel::Channel;
 use embassy_sync::channel::TrySendError;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
 use embassy_time::{Duration, with_timeout};
 use embedded_services::GlobalRawMutex;
+use embedded_services::comms::EndpointID;
+use embedded_services::power::policy::BatteryPowerState;
 use embedded_services::{IntrusiveList, debug, error, info, intrusive_list, trace, warn};
 
 use core::ops::DerefMut;
@@ -110,6 +114,11 @@ pub struct Context {
     battery_response: Channel<GlobalRawMutex, BatteryResponse, 1>,
     no_op_retry_count: AtomicUsize,
     config: Config,
+    lifetime_stats: Mutex<GlobalRawMutex, LifetimeStats>,
+    lifetime_stats_update: Signal<GlobalRawMutex, LifetimeStats>,
+    lifetime_stats_request: Channel<GlobalRawMutex, EndpointID, 1>,
+    power_state: Mutex<GlobalRawMutex, Option<BatteryPowerState>>,
+    power_state_update: Signal<GlobalRawMutex, BatteryPowerState>,
 }
 
 pub struct Config {
@@ -136,6 +145,11 @@ impl Context {
             battery_response: Channel::new(),
             no_op_retry_count: AtomicUsize::new(0),
             config: Default::default(),
+            lifetime_stats: Mutex::new(LifetimeStats::new()),
+            lifetime_stats_update: Signal::new(),
+            lifetime_stats_request: Channel::new(),
+            power_state: Mutex::new(None),
+            power_state_update: Signal::new(),
         }
     }
 
@@ -147,6 +161,11 @@ impl Context {
             battery_response: Channel::new(),
             no_op_retry_count: AtomicUsize::new(0),
             config,
+            lifetime_stats: Mutex::new(LifetimeStats::new()),
+            lifetime_stats_update: Signal::new(),
+            lifetime_stats_request: Channel::new(),
+            power_state: Mutex::new(None),
+            power_state_update: Signal::new(),
         }
     }
 
@@ -261,19 +280,19 @@ impl Context {
         match *state {
             State::NotPresent => {
                 info!("Initializing fuel gauge with ID {:?}", event.device_id);
-                if self
-                    .execute_device_command(event.device_id, device::Command::Ping)
-                    .await
-                    .is_err()
-                {
+                if !matches!(
+                    self.execute_device_command(event.device_id, device::Command::Ping)
+                        .await,
+                    Ok(Ok(_))
+                ) {
                     error!("Error pinging fuel gauge with ID {:?}", event.device_id);
                     return Err(StateMachineError::DeviceError);
                 }
-                if self
-                    .execute_device_command(event.device_id, device::Command::Initialize)
-                    .await
-                    .is_err()
-                {
+                if !matches!(
+                    self.execute_device_command(event.device_id, device::Command::Initialize)
+                        .await,
+                    Ok(Ok(_))
+                ) {
                     error!("Error initializing fuel gauge with ID {:?}", event.device_id);
                     return Err(StateMachineError::DeviceError);
                 }
@@ -283,7 +302,7 @@ impl Context {
             }
             State::Present(substate) => match substate {
                 PresentSubstate::NotOperational => {
-                    self.set_state_machine_retry_count(self.get_state_machine_max_retries() + 1);
+                    self.set_state_machine_retry_count(self.get_state_machine_retry_count() + 1);
                     match self
                         .execute_device_command(event.device_id, device::Command::Ping)
                         .await
@@ -305,6 +324,7 @@ impl Context {
                             // transition to the NotPresent state.
                             if self.get_state_machine_retry_count() > self.get_state_machine_max_retries() {
                                 *state = State::NotPresent;
+                                self.set_state_machine_retry_count(0);
                                 return Err(StateMachineError::NoOpRecoveryFailed);
                             }
                             Err(StateMachineError::DeviceTimeout)
@@ -319,6 +339,7 @@ impl Context {
                             // transition to the NotPresent state.
                             if self.get_state_machine_retry_count() > self.get_state_machine_max_retries() {
                                 *state = State::NotPresent;
+                                self.set_state_machine_retry_count(0);
                                 return Err(StateMachineError::NoOpRecoveryFailed);
                             }
                             Err(StateMachineError::DeviceTimeout)
@@ -329,11 +350,11 @@ impl Context {
                     OperationalSubstate::Init => {
                         // Collect static data
                         trace!("Collecting fuel gauge static cache with ID {:?}", event.device_id);
-                        if self
-                            .execute_device_command(event.device_id, device::Command::UpdateStaticCache)
-                            .await
-                            .is_err()
-                        {
+                        if !matches!(
+                            self.execute_device_command(event.device_id, device::Command::UpdateStaticCache)
+                                .await,
+                            Ok(Ok(_))
+                        ) {
                             error!("Error updating fuel gauge static cache with ID {:?}", event.device_id);
                             return Err(StateMachineError::DeviceError);
                         }
@@ -343,17 +364,22 @@ impl Context {
                     OperationalSubstate::Polling => {
                         // Collect dynamic data
                         trace!("Collecting fuel gauge dynamic cache with ID {:?}", event.device_id);
-                        if self
-                            .execute_device_command(event.device_id, device::Command::UpdateDynamicCache)
-                            .await
-                            .is_err()
-                        {
+                        if !matches!(
+                            self.execute_device_command(event.device_id, device::Command::UpdateDynamicCache)
+                                .await,
+                            Ok(Ok(_))
+                        ) {
                             error!(
                                 "Error initializing fuel gauge dynamic cache with ID {:?}",
                                 event.device_id
                             );
                             return Err(StateMachineError::DeviceError);
                         }
+                        if let Some(device) = self.get_fuel_gauge(event.device_id) {
+                            let data = device.get_dynamic_battery_cache().await;
+                            self.update_lifetime_stats(&data).await;
+                            self.update_power_state(&data).await;
+                        }
                         Ok(InnerStateMachineResponse::Complete)
                     }
                 },
@@ -410,6 +436,65 @@ impl Context {
         *self.state.lock().await
     }
 
+    /// Get battery lifetime statistics.
+    pub async fn get_lifetime_stats(&self) -> LifetimeStats {
+        *self.lifetime_stats.lock().await
+    }
+
+    /// Restore battery lifetime statistics, merging them with anything sampled since boot.
+    pub async fn restore_lifetime_stats(&self, restored: LifetimeStats) {
+        let mut stats = self.lifetime_stats.lock().await;
+        let sampled = *stats;
+
+        *stats = restored;
+        stats.merge(&sampled);
+        if *stats != restored {
+            self.lifetime_stats_update.signal(*stats);
+        }
+    }
+
+    /// Wait for battery lifetime statistics to change.
+    pub async fn wait_lifetime_stats_update(&self) -> LifetimeStats {
+        self.lifetime_stats_update.wait().await
+    }
+
+    /// Queue a request for the battery lifetime statistics, to be answered to the given endpoint.
+    pub fn request_lifetime_stats(&self, from: EndpointID) -> Result<(), TrySendError<EndpointID>> {
+        self.lifetime_stats_request.try_send(from)
+    }
+
+    /// Wait for a battery lifetime statistics request.
+    pub async fn wait_lifetime_stats_request(&self) -> EndpointID {
+        self.lifetime_stats_request.receive().await
+    }
+
+    /// Wait for the battery state of charge or discharge limit to change.
+    pub async fn wait_power_state_update(&self) -> BatteryPowerState {
+        self.power_state_update.wait().await
+    }
+
+    async fn update_power_state(&self, data: &device::DynamicBatteryMsgs) {
+        let new_state = BatteryPowerState {
+            state_of_charge_pct: data.relative_soc_pct.min(100) as u8,
+            max_discharge_mw: data.max_power_mw,
+        };
+
+        let mut state = self.power_state.lock().await;
+        if *state != Some(new_state) {
+            trace!("Battery power state updated: {:?}", new_state);
+            *state = Some(new_state);
+            self.power_state_update.signal(new_state);
+        }
+    }
+
+    async fn update_lifetime_stats(&self, data: &device::DynamicBatteryMsgs) {
+        let mut stats = self.lifetime_stats.lock().await;
+        if stats.update(data) {
+            trace!("Battery lifetime stats updated");
+            self.lifetime_stats_update.signal(*stats);
+        }
+    }
+
     async fn execute_device_command(
         &self,
         id: DeviceId,
@@ -429,6 +514,7 @@ impl Context {
             Ok(res) => Ok(res),
             Err(_) => {
                 error!("Device timed out when executing command {:?}", command);
+                device.command_timed_out();
                 Err(ContextError::Timeout)
             }
         }
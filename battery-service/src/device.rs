@@ -117,6 +117,7 @@ pub struct Device {
     dynamic_battery_cache: Mutex<GlobalRawMutex, DynamicBatteryMsgs>,
     static_battery_cache: Mutex<GlobalRawMutex, StaticBatteryMsgs>,
     timeout: SyncCell<Duration>,
+    late_responses: SyncCell<usize>,
 }
 
 impl Device {
@@ -129,6 +130,7 @@ impl Device {
             dynamic_battery_cache: Mutex::default(),
             static_battery_cache: Mutex::default(),
             timeout: SyncCell::new(Duration::from_secs(60)),
+            late_responses: SyncCell::new(0),
         }
     }
 
@@ -150,9 +152,19 @@ impl Device {
     /// Send a command and wait for a response from the device.
     pub async fn execute_command(&self, cmd: Command) -> Response {
         self.send_command(cmd).await;
+        // Responses to commands that timed out arrive first, they aren't the response to this command.
+        while self.late_responses.get() > 0 {
+            let _ = self.wait_response().await;
+            self.late_responses.set(self.late_responses.get() - 1);
+        }
         self.wait_response().await
     }
 
+    /// Mark the last command as timed out, its response is dropped once the device sends it.
+    pub fn command_timed_out(&self) {
+        self.late_responses.set(self.late_responses.get() + 1);
+    }
+
     /// Receive a command.
     pub async fn receive_command(&self) -> Command {
         self.command.receive().await
@@ -3,6 +3,7 @@
 use core::{any::Any, convert::Infallible};
 
 use context::BatteryEvent;
+use embassy_futures::select::{Either3, select3};
 use embassy_sync::once_lock::OnceLock;
 use embedded_services::{
     comms::{self, EndpointID},
@@ -12,6 +13,7 @@ use embedded_services::{
 pub mod context;
 pub mod controller;
 pub mod device;
+pub mod stats;
 pub mod wrapper;
 
 /// Standard Battery Service.
@@ -38,9 +40,28 @@ impl Service {
     }
 
     /// Main battery service processing function.
+    ///
+    /// Changes to the battery state of charge or discharge limit are published to the power policy.
     pub async fn process(&self) {
-        let event = self.context.wait_event().await;
-        self.context.process(event).await;
+        match select3(
+            self.context.wait_event(),
+            self.context.wait_lifetime_stats_request(),
+            self.context.wait_power_state_update(),
+        )
+        .await
+        {
+            Either3::First(event) => self.context.process(event).await,
+            Either3::Second(requester) => {
+                let stats = self.context.get_lifetime_stats().await;
+                let _ = self.endpoint.send(requester, &stats).await;
+            }
+            Either3::Third(power_state) => {
+                let _ = self
+                    .endpoint
+                    .send(EndpointID::Internal(comms::Internal::Power), &power_state)
+                    .await;
+            }
+        }
     }
 }
 
@@ -56,6 +77,10 @@ impl comms::MailboxDelegate for Service {
             self.context.send_event_no_wait(*event).map_err(|e| match e {
                 embassy_sync::channel::TrySendError::Full(_) => comms::MailboxDelegateError::BufferFull,
             })?
+        } else if message.data.is_a::<stats::LifetimeStatsRequest>() {
+            self.context.request_lifetime_stats(message.from).map_err(|e| match e {
+                embassy_sync::channel::TrySendError::Full(_) => comms::MailboxDelegateError::BufferFull,
+            })?
         }
 
         Ok(())
@@ -111,6 +136,27 @@ pub async fn get_state() -> context::State {
     service.context.get_state().await
 }
 
+/// Get the battery lifetime statistics.
+pub async fn get_lifetime_stats() -> stats::LifetimeStats {
+    let service = SERVICE.get().await;
+
+    service.context.get_lifetime_stats().await
+}
+
+/// Restore battery lifetime statistics, typically loaded from flash by a [`stats::Recorder`].
+pub async fn restore_lifetime_stats(restored: stats::LifetimeStats) {
+    let service = SERVICE.get().await;
+
+    service.context.restore_lifetime_stats(restored).await
+}
+
+/// Wait for the battery lifetime statistics to change.
+pub async fn wait_lifetime_stats_update() -> stats::LifetimeStats {
+    let service = SERVICE.get().await;
+
+    service.context.wait_lifetime_stats_update().await
+}
+
 /// Battery service task.
 #[embassy_executor::task]
 pub async fn task() {
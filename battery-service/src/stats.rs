@@ -0,0 +1,333 @@
+//! Battery lifetime statistics.
+//!
+//! The battery service aggregates [`LifetimeStats`] in RAM from every dynamic data poll. A [`Recorder`] persists them
+//! to a flash partition (typically a `partition_manager::Partition` mapped read/write) as a
+//! [`RecordLog`](embedded_services::record_log::RecordLog) of complete snapshots.
+//!
+//! The current statistics can be read over comms by sending a [`LifetimeStatsRequest`] to the battery service, which
+//! answers the requester with a [`LifetimeStats`] message.
+use embedded_services::record_log::{self, Record, RecordLog};
+use embedded_services::{error, info};
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::device::DynamicBatteryMsgs;
+
+/// Size of a single record in flash, in bytes.
+pub const RECORD_SIZE: usize = 32;
+
+/// Record magic value, distinguishes lifetime statistics records from other records.
+const RECORD_MAGIC: u16 = 0xBA75;
+
+/// Relative state of charge at or below which the battery is considered deeply discharged.
+const DEEP_DISCHARGE_ENTER_PCT: u16 = 5;
+
+/// Relative state of charge the battery must recover to before another deep discharge event is counted.
+const DEEP_DISCHARGE_EXIT_PCT: u16 = 10;
+
+/// Sentinel for a cycle count that has not been sampled yet.
+const CYCLE_COUNT_UNKNOWN: u16 = u16::MAX;
+
+const FLAG_IN_DEEP_DISCHARGE: u8 = 1 << 0;
+
+/// Request the battery lifetime statistics over comms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LifetimeStatsRequest;
+
+/// Battery lifetime statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LifetimeStats {
+    /// Minimum battery temperature in dK, `u16::MAX` if never sampled.
+    pub min_temp_dk: u16,
+
+    /// Maximum battery temperature in dK.
+    pub max_temp_dk: u16,
+
+    /// Maximum charge current in mA.
+    pub max_charge_current_ma: u16,
+
+    /// Maximum discharge current in mA.
+    pub max_discharge_current_ma: u16,
+
+    /// Number of times the battery was deeply discharged.
+    pub deep_discharge_events: u16,
+
+    /// Cycles accumulated since the statistics were first recorded.
+    pub cycle_count_delta: u32,
+
+    /// Last cycle count reported by the fuel gauge, `u16::MAX` if never sampled.
+    pub last_cycle_count: u16,
+
+    /// True while the battery is deeply discharged, so that each event is only counted once.
+    pub in_deep_discharge: bool,
+}
+
+impl Default for LifetimeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LifetimeStats {
+    /// Create empty statistics.
+    pub const fn new() -> Self {
+        Self {
+            min_temp_dk: u16::MAX,
+            max_temp_dk: 0,
+            max_charge_current_ma: 0,
+            max_discharge_current_ma: 0,
+            deep_discharge_events: 0,
+            cycle_count_delta: 0,
+            last_cycle_count: CYCLE_COUNT_UNKNOWN,
+            in_deep_discharge: false,
+        }
+    }
+
+    /// Update statistics with a new dynamic data sample.
+    ///
+    /// Returns true if the statistics changed and should be persisted.
+    pub fn update(&mut self, data: &DynamicBatteryMsgs) -> bool {
+        let previous = *self;
+
+        self.min_temp_dk = self.min_temp_dk.min(data.battery_temp_dk);
+        self.max_temp_dk = self.max_temp_dk.max(data.battery_temp_dk);
+
+        if data.current_ma > 0 {
+            self.max_charge_current_ma = self.max_charge_current_ma.max(data.current_ma.unsigned_abs());
+        } else {
+            self.max_discharge_current_ma = self.max_discharge_current_ma.max(data.current_ma.unsigned_abs());
+        }
+
+        // A cycle count going backwards means the pack or gauge was swapped, rebase without accumulating.
+        if self.last_cycle_count != CYCLE_COUNT_UNKNOWN && data.cycle_count >= self.last_cycle_count {
+            self.cycle_count_delta = self
+                .cycle_count_delta
+                .saturating_add((data.cycle_count - self.last_cycle_count) as u32);
+        }
+        self.last_cycle_count = data.cycle_count;
+
+        if !self.in_deep_discharge && data.relative_soc_pct <= DEEP_DISCHARGE_ENTER_PCT {
+            self.deep_discharge_events = self.deep_discharge_events.saturating_add(1);
+            self.in_deep_discharge = true;
+        } else if self.in_deep_discharge && data.relative_soc_pct >= DEEP_DISCHARGE_EXIT_PCT {
+            self.in_deep_discharge = false;
+        }
+
+        *self != previous
+    }
+
+    /// Merge more recent statistics, e.g. sampled since boot into statistics loaded from flash.
+    pub fn merge(&mut self, other: &LifetimeStats) {
+        self.min_temp_dk = self.min_temp_dk.min(other.min_temp_dk);
+        self.max_temp_dk = self.max_temp_dk.max(other.max_temp_dk);
+        self.max_charge_current_ma = self.max_charge_current_ma.max(other.max_charge_current_ma);
+        self.max_discharge_current_ma = self.max_discharge_current_ma.max(other.max_discharge_current_ma);
+        self.deep_discharge_events = self.deep_discharge_events.saturating_add(other.deep_discharge_events);
+        self.cycle_count_delta = self.cycle_count_delta.saturating_add(other.cycle_count_delta);
+        if other.last_cycle_count != CYCLE_COUNT_UNKNOWN {
+            self.last_cycle_count = other.last_cycle_count;
+            self.in_deep_discharge = other.in_deep_discharge;
+        }
+    }
+}
+
+impl Record for LifetimeStats {
+    fn encode(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&self.min_temp_dk.to_le_bytes());
+        data[2..4].copy_from_slice(&self.max_temp_dk.to_le_bytes());
+        data[4..6].copy_from_slice(&self.max_charge_current_ma.to_le_bytes());
+        data[6..8].copy_from_slice(&self.max_discharge_current_ma.to_le_bytes());
+        data[8..10].copy_from_slice(&self.deep_discharge_events.to_le_bytes());
+        data[10..14].copy_from_slice(&self.cycle_count_delta.to_le_bytes());
+        data[14..16].copy_from_slice(&self.last_cycle_count.to_le_bytes());
+        data[16] = if self.in_deep_discharge {
+            FLAG_IN_DEEP_DISCHARGE
+        } else {
+            0
+        };
+        // Remaining bytes are reserved and left erased
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+        Some(Self {
+            min_temp_dk: u16_at(0),
+            max_temp_dk: u16_at(2),
+            max_charge_current_ma: u16_at(4),
+            max_discharge_current_ma: u16_at(6),
+            deep_discharge_events: u16_at(8),
+            cycle_count_delta: u32_at(10),
+            last_cycle_count: u16_at(14),
+            in_deep_discharge: data[16] & FLAG_IN_DEEP_DISCHARGE != 0,
+        })
+    }
+}
+
+/// Recorder errors.
+pub use record_log::Error;
+
+/// Persists lifetime statistics to a flash partition.
+pub struct Recorder<F: NorFlash> {
+    log: RecordLog<F, RECORD_SIZE>,
+}
+
+impl<F: NorFlash> Recorder<F> {
+    /// Create a new recorder.
+    ///
+    /// The partition must span at least two erase sectors so that wrapping the log never erases the latest record, and
+    /// [`RECORD_SIZE`] must be a multiple of the flash read and write sizes.
+    pub fn new(flash: F) -> Result<Self, Error<F::Error>> {
+        Ok(Self {
+            log: RecordLog::new(flash, RECORD_MAGIC)?,
+        })
+    }
+
+    /// Scan the partition for the latest record and position the log after it.
+    pub async fn load(&mut self) -> Result<Option<LifetimeStats>, Error<F::Error>> {
+        self.log.load().await
+    }
+
+    /// Append a record to the log.
+    pub async fn append(&mut self, stats: &LifetimeStats) -> Result<(), Error<F::Error>> {
+        self.log.append(stats).await
+    }
+
+    /// Restore persisted statistics into the battery service, then persist every update.
+    ///
+    /// Only call this fn ONCE, it will infinitely loop processing updates.
+    pub async fn process(&mut self) {
+        match self.load().await {
+            Ok(Some(stats)) => {
+                info!("Restored battery lifetime stats");
+                crate::restore_lifetime_stats(stats).await;
+            }
+            Ok(None) => info!("No battery lifetime stats recorded"),
+            Err(_) => error!("Failed to load battery lifetime stats"),
+        }
+
+        loop {
+            let stats = crate::wait_lifetime_stats_update().await;
+            if self.append(&stats).await.is_err() {
+                error!("Failed to persist battery lifetime stats");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embedded_services::testing::RamFlash;
+
+    const ERASE_SIZE: usize = 128;
+    const SECTORS: usize = 2;
+
+    type MockFlash = RamFlash<{ ERASE_SIZE * SECTORS }, ERASE_SIZE>;
+
+    fn sample(temp_dk: u16, current_ma: i16, cycle_count: u16, relative_soc_pct: u16) -> DynamicBatteryMsgs {
+        DynamicBatteryMsgs {
+            battery_temp_dk: temp_dk,
+            current_ma,
+            cycle_count,
+            relative_soc_pct,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let mut stats = LifetimeStats::new();
+
+        assert!(stats.update(&sample(2980, -1000, 10, 50)));
+        assert!(stats.update(&sample(3100, 2000, 12, 4)));
+        // Still deeply discharged, not a new event
+        assert!(!stats.update(&sample(3000, 0, 12, 3)));
+        assert!(stats.update(&sample(3000, 0, 12, 20)));
+        // Pack swapped, cycle count rebased
+        assert!(stats.update(&sample(3000, 0, 1, 2)));
+
+        assert_eq!(stats.min_temp_dk, 2980);
+        assert_eq!(stats.max_temp_dk, 3100);
+        assert_eq!(stats.max_charge_current_ma, 2000);
+        assert_eq!(stats.max_discharge_current_ma, 1000);
+        assert_eq!(stats.deep_discharge_events, 2);
+        assert_eq!(stats.cycle_count_delta, 2);
+        assert_eq!(stats.last_cycle_count, 1);
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let mut stats = LifetimeStats::new();
+        stats.update(&sample(2950, -3000, 100, 1));
+
+        let mut data = [0xFF; RECORD_SIZE - record_log::OVERHEAD];
+        stats.encode(&mut data);
+        assert_eq!(LifetimeStats::decode(&data), Some(stats));
+    }
+
+    #[test]
+    fn test_recorder_wraps_and_reloads() {
+        let mut flash = MockFlash::new();
+        let total_slots = ERASE_SIZE * SECTORS / RECORD_SIZE;
+        let mut stats = LifetimeStats::new();
+
+        {
+            let mut recorder = Recorder::new(&mut flash).unwrap();
+            assert_eq!(block_on(recorder.load()).unwrap(), None);
+
+            // Wrap around the log a couple of times
+            for cycle in 0..(3 * total_slots as u16) {
+                stats.update(&sample(2980, 0, cycle, 50));
+                block_on(recorder.append(&stats)).unwrap();
+            }
+        }
+
+        // Every sector is erased the same number of times
+        assert_eq!(flash.erase_counts(), [3, 3]);
+
+        let mut recorder = Recorder::new(&mut flash).unwrap();
+        assert_eq!(block_on(recorder.load()).unwrap(), Some(stats));
+
+        // Appending after a reload continues the sequence
+        stats.update(&sample(2900, 0, 1000, 50));
+        block_on(recorder.append(&stats)).unwrap();
+        assert_eq!(block_on(recorder.load()).unwrap(), Some(stats));
+    }
+
+    #[test]
+    fn test_recorder_skips_torn_record() {
+        let mut flash = MockFlash::new();
+        let stats = LifetimeStats::new();
+
+        {
+            let mut recorder = Recorder::new(&mut flash).unwrap();
+            block_on(recorder.load()).unwrap();
+            block_on(recorder.append(&stats)).unwrap();
+        }
+
+        // Simulate a power loss while writing the second record
+        flash.data[RECORD_SIZE] = 0x00;
+
+        {
+            let mut recorder = Recorder::new(&mut flash).unwrap();
+            assert_eq!(block_on(recorder.load()).unwrap(), Some(stats));
+            block_on(recorder.append(&stats)).unwrap();
+            assert_eq!(block_on(recorder.load()).unwrap(), Some(stats));
+        }
+
+        // The torn slot was skipped, the record went to the start of the next sector
+        assert_eq!(flash.data[RECORD_SIZE], 0x00);
+        assert_eq!(flash.data[ERASE_SIZE..ERASE_SIZE + 2], RECORD_MAGIC.to_le_bytes());
+    }
+
+    #[test]
+    fn test_invalid_geometry() {
+        // A single sector can't hold a record log
+        let flash = RamFlash::<ERASE_SIZE, ERASE_SIZE>::new();
+        assert!(matches!(Recorder::new(flash), Err(Error::InvalidGeometry)));
+    }
+}
@@ -1,5 +1,6 @@
 //! Module that can buffer CFU content
 //! This allows prompt responses to content requests even if the component is busy
+//! See [`crate::persistent_buffer`] for a variant that spools content to flash and survives resets
 
 use core::future::pending;
 
@@ -95,16 +96,6 @@ impl<'a> Buffer<'a> {
         }
     }
 
-    /// Create a new invalid FW version response
-    fn create_invalid_fw_version_response(&self) -> InternalResponseData {
-        let dev_inf = FwVerComponentInfo::new(FwVersion::new(0xffffffff), self.cfu_device.component_id());
-        let comp_info: [FwVerComponentInfo; MAX_CMPT_COUNT] = [dev_inf; MAX_CMPT_COUNT];
-        InternalResponseData::FwVersionResponse(GetFwVersionResponse {
-            header: GetFwVersionResponseHeader::new(1, GetFwVerRespHeaderByte3::NoSpecialFlags),
-            component_info: comp_info,
-        })
-    }
-
     /// Create a content rejection response
     fn create_content_rejection(sequence: u16) -> InternalResponseData {
         InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
@@ -123,7 +114,7 @@ impl<'a> Buffer<'a> {
             InternalResponseData::FwVersionResponse(response)
         } else {
             error!("Failed to get FW version for device {}", self.buffered_id);
-            self.create_invalid_fw_version_response()
+            InternalResponseData::invalid_fw_version(self.cfu_device.component_id())
         }
     }
 
@@ -0,0 +1,868 @@
+//! A/B firmware slots with automatic rollback
+//!
+//! [`DualBank`] manages two firmware slots and a small state partition, typically all `partition_manager::Partition`s
+//! mapped read/write. Updates are staged into the inactive slot and verified before the boot slot is switched. The
+//! switch is a single record write to the state partition, so a power loss leaves either the old or the new state.
+//!
+//! A freshly switched slot boots on trial. [`DualBank::boot`] counts trial boots and switches back to the previous
+//! slot once [`Config::max_trial_boots`] is exceeded without a call to [`DualBank::confirm`].
+//!
+//! Staged images must end with a little-endian CRC-32 (ISO-HDLC) of all preceding image bytes.
+//!
+//! The boot state is kept in a [`RecordLog`] on the state partition.
+//!
+//! [`DualBankComponent`] exposes a dual bank manager to the host as a CFU component.
+use embassy_sync::mutex::Mutex;
+use embedded_cfu_protocol::protocol_definitions::*;
+use embedded_services::cfu::{
+    self,
+    component::{CfuDevice, InternalResponseData, RequestData},
+};
+use embedded_services::record_log::{self, Record, RecordLog};
+use embedded_services::{GlobalRawMutex, error, info, intrusive_list, trace, warn};
+use embedded_storage_async::nor_flash::NorFlash;
+use platform_service::embedded_crc::EmbeddedCrc;
+
+/// Size of a single state record in flash
+pub const RECORD_SIZE: usize = 16;
+
+/// Record magic value, distinguishes boot state records from other records
+const RECORD_MAGIC: u16 = 0xAB5E;
+
+/// Size of the trailing image CRC
+const IMAGE_CRC_SIZE: usize = 4;
+
+/// Size of the staging write buffer, must be a multiple of the slot flash write size
+const WRITE_BUFFER_SIZE: usize = 32;
+
+/// Bytes read at a time while verifying a slot
+const READ_CHUNK_SIZE: usize = 64;
+
+const FLAG_TRIAL: u8 = 1 << 0;
+
+/// Firmware slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slot {
+    /// Slot A
+    A,
+    /// Slot B
+    B,
+}
+
+impl Slot {
+    /// The other slot
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// Persisted boot state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BootState {
+    /// Slot to boot
+    pub active: Slot,
+    /// The active slot hasn't been confirmed healthy yet
+    pub trial: bool,
+    /// Number of times the active slot booted on trial
+    pub trial_boots: u8,
+}
+
+impl Default for BootState {
+    fn default() -> Self {
+        Self {
+            active: Slot::A,
+            trial: false,
+            trial_boots: 0,
+        }
+    }
+}
+
+impl Record for BootState {
+    fn encode(&self, data: &mut [u8]) {
+        data[0] = match self.active {
+            Slot::A => 0,
+            Slot::B => 1,
+        };
+        data[1] = if self.trial { FLAG_TRIAL } else { 0 };
+        data[2] = self.trial_boots;
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let active = match data[0] {
+            0 => Slot::A,
+            1 => Slot::B,
+            _ => return None,
+        };
+        Some(Self {
+            active,
+            trial: data[1] & FLAG_TRIAL != 0,
+            trial_boots: data[2],
+        })
+    }
+}
+
+/// Dual bank errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Partition sizes or flash alignment aren't supported
+    InvalidGeometry,
+    /// Underlying flash returned an error
+    Flash(E),
+    /// CRC calculation failed
+    Crc,
+    /// No update is being staged
+    NotStaging,
+    /// Writes must be sequential, starting from offset 0
+    NonSequentialWrite,
+    /// Image doesn't fit in the slot
+    ImageTooLarge,
+    /// Staged image failed verification
+    VerifyFailed,
+    /// The active slot is on trial, the inactive slot holds the last known good image
+    TrialInProgress,
+    /// The inactive slot doesn't hold a known good image
+    NoRollbackImage,
+}
+
+impl<E> From<record_log::Error<E>> for Error<E> {
+    fn from(e: record_log::Error<E>) -> Self {
+        match e {
+            record_log::Error::InvalidGeometry => Error::InvalidGeometry,
+            record_log::Error::Flash(e) => Error::Flash(e),
+        }
+    }
+}
+
+/// Dual bank configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Number of boots a new image gets to confirm itself before rolling back
+    pub max_trial_boots: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { max_trial_boots: 3 }
+    }
+}
+
+/// Staging progress
+#[derive(Debug, Default, Clone, Copy)]
+struct Staging {
+    /// Bytes received
+    received: u32,
+    /// Bytes written to flash
+    flushed: u32,
+    /// Received bytes not yet written to flash
+    buffer: [u8; WRITE_BUFFER_SIZE],
+}
+
+/// A/B firmware slot manager
+pub struct DualBank<F: NorFlash, S: NorFlash<Error = F::Error>> {
+    slot_a: F,
+    slot_b: F,
+    state_log: RecordLog<S, RECORD_SIZE>,
+    config: Config,
+    state: BootState,
+    staging: Option<Staging>,
+}
+
+impl<F: NorFlash, S: NorFlash<Error = F::Error>> DualBank<F, S> {
+    /// Create a new dual bank manager
+    ///
+    /// Both slots must be the same size. The state partition must span at least two erase sectors and
+    /// [`RECORD_SIZE`] must be a multiple of its read and write sizes.
+    pub fn new(slot_a: F, slot_b: F, state_flash: S, config: Config) -> Result<Self, Error<F::Error>> {
+        if slot_a.capacity() != slot_b.capacity()
+            || WRITE_BUFFER_SIZE % F::WRITE_SIZE != 0
+            || READ_CHUNK_SIZE % F::READ_SIZE != 0
+            || slot_a.capacity() % F::ERASE_SIZE != 0
+        {
+            return Err(Error::InvalidGeometry);
+        }
+
+        Ok(Self {
+            slot_a,
+            slot_b,
+            state_log: RecordLog::new(state_flash, RECORD_MAGIC)?,
+            config,
+            state: BootState::default(),
+            staging: None,
+        })
+    }
+
+    /// Current boot state
+    pub fn state(&self) -> BootState {
+        self.state
+    }
+
+    /// Slot currently booted
+    pub fn active_slot(&self) -> Slot {
+        self.state.active
+    }
+
+    /// Slot updates are staged into
+    pub fn inactive_slot(&self) -> Slot {
+        self.state.active.other()
+    }
+
+    /// Load the boot state and account for this boot
+    ///
+    /// Call once per boot, before anything else. Returns the slot that should run, rolling back if the active slot
+    /// ran out of trial boots.
+    pub async fn boot(&mut self) -> Result<Slot, Error<F::Error>> {
+        self.load().await?;
+
+        if self.state.trial {
+            if self.state.trial_boots >= self.config.max_trial_boots {
+                warn!(
+                    "Slot {:?} not confirmed after {} boots, rolling back",
+                    self.state.active, self.state.trial_boots
+                );
+                self.rollback().await?;
+            } else {
+                let mut state = self.state;
+                state.trial_boots += 1;
+                trace!("Slot {:?} trial boot {}", state.active, state.trial_boots);
+                self.persist(state).await?;
+            }
+        }
+
+        Ok(self.state.active)
+    }
+
+    /// Mark the active slot healthy, ending its trial
+    pub async fn confirm(&mut self) -> Result<(), Error<F::Error>> {
+        if !self.state.trial {
+            return Ok(());
+        }
+
+        info!("Slot {:?} confirmed", self.state.active);
+        self.persist(BootState {
+            active: self.state.active,
+            trial: false,
+            trial_boots: 0,
+        })
+        .await
+    }
+
+    /// Switch back to the previous slot
+    ///
+    /// Only possible while the active slot is on trial, otherwise the inactive slot may hold a partial image.
+    pub async fn rollback(&mut self) -> Result<(), Error<F::Error>> {
+        if !self.state.trial {
+            return Err(Error::NoRollbackImage);
+        }
+
+        info!("Rolling back to slot {:?}", self.state.active.other());
+        self.persist(BootState {
+            active: self.state.active.other(),
+            trial: false,
+            trial_boots: 0,
+        })
+        .await
+    }
+
+    /// Start staging an update, erasing the inactive slot
+    pub async fn begin_update(&mut self) -> Result<(), Error<F::Error>> {
+        if self.state.trial {
+            error!(
+                "Slot {:?} on trial, refusing to overwrite the previous image",
+                self.state.active
+            );
+            return Err(Error::TrialInProgress);
+        }
+
+        info!("Staging update into slot {:?}", self.inactive_slot());
+        self.staging = None;
+        let slot = self.inactive_flash();
+        let capacity = slot.capacity() as u32;
+        slot.erase(0, capacity).await.map_err(Error::Flash)?;
+        self.staging = Some(Staging::default());
+        Ok(())
+    }
+
+    /// Write the next bytes of the update
+    pub async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error<F::Error>> {
+        let mut staging = self.staging.ok_or(Error::NotStaging)?;
+        if offset != staging.received {
+            error!(
+                "Non-sequential write at {:#x}, expected {:#x}",
+                offset, staging.received
+            );
+            return Err(Error::NonSequentialWrite);
+        }
+        if offset as usize + data.len() > self.slot_a.capacity() {
+            return Err(Error::ImageTooLarge);
+        }
+
+        for byte in data {
+            let index = (staging.received - staging.flushed) as usize;
+            staging.buffer[index] = *byte;
+            staging.received += 1;
+            if index + 1 == WRITE_BUFFER_SIZE {
+                let flushed = staging.flushed;
+                self.inactive_flash()
+                    .write(flushed, &staging.buffer)
+                    .await
+                    .map_err(Error::Flash)?;
+                staging.flushed += WRITE_BUFFER_SIZE as u32;
+            }
+        }
+
+        self.staging = Some(staging);
+        Ok(())
+    }
+
+    /// Flush and verify the staged update, then switch to it on trial
+    ///
+    /// Returns the slot that will run on next boot.
+    pub async fn finish_update(&mut self) -> Result<Slot, Error<F::Error>> {
+        let mut staging = self.staging.take().ok_or(Error::NotStaging)?;
+
+        // Pad the tail to the write size
+        let buffered = (staging.received - staging.flushed) as usize;
+        if buffered > 0 {
+            let len = buffered.div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE;
+            staging.buffer[buffered..len].fill(0xFF);
+            let flushed = staging.flushed;
+            self.inactive_flash()
+                .write(flushed, &staging.buffer[..len])
+                .await
+                .map_err(Error::Flash)?;
+        }
+
+        self.verify_inactive(staging.received as usize).await?;
+
+        let slot = self.inactive_slot();
+        info!("Switching to slot {:?}", slot);
+        self.persist(BootState {
+            active: slot,
+            trial: true,
+            trial_boots: 0,
+        })
+        .await?;
+        Ok(slot)
+    }
+
+    /// Stage a CFU content block, starting a new update on the first block
+    pub async fn process_content(&mut self, content: &FwUpdateContentCommand) -> CfuUpdateContentResponseStatus {
+        if content.header.flags & FW_UPDATE_FLAG_FIRST_BLOCK != 0 && self.begin_update().await.is_err() {
+            return CfuUpdateContentResponseStatus::ErrorPrepare;
+        }
+
+        let len = (content.header.data_length as usize).min(content.data.len());
+        match self.write(content.header.firmware_address, &content.data[..len]).await {
+            Ok(()) => CfuUpdateContentResponseStatus::Success,
+            Err(Error::NotStaging | Error::NonSequentialWrite | Error::ImageTooLarge) => {
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            }
+            Err(_) => CfuUpdateContentResponseStatus::ErrorWrite,
+        }
+    }
+
+    fn inactive_flash(&mut self) -> &mut F {
+        match self.state.active {
+            Slot::A => &mut self.slot_b,
+            Slot::B => &mut self.slot_a,
+        }
+    }
+
+    /// Check the trailing CRC of the image staged in the inactive slot
+    async fn verify_inactive(&mut self, len: usize) -> Result<(), Error<F::Error>> {
+        if len <= IMAGE_CRC_SIZE {
+            error!("Staged image too short");
+            return Err(Error::VerifyFailed);
+        }
+
+        let body_len = len - IMAGE_CRC_SIZE;
+        let mut crc = EmbeddedCrc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let mut trailer = [0u8; IMAGE_CRC_SIZE];
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            let count = (len - offset).min(READ_CHUNK_SIZE);
+            // Reads are padded to the read size, the slot is erased past the image so this stays in bounds
+            let read_len = count.div_ceil(F::READ_SIZE) * F::READ_SIZE;
+            self.inactive_flash()
+                .read(offset as u32, &mut chunk[..read_len])
+                .await
+                .map_err(Error::Flash)?;
+
+            let body = body_len.saturating_sub(offset).min(count);
+            if body > 0 {
+                crc.calculate(&chunk[..body]).await.map_err(|e| {
+                    error!("CRC calculation failed: {:?}", e);
+                    Error::Crc
+                })?;
+            }
+            for (i, byte) in chunk[body..count].iter().enumerate() {
+                trailer[offset + body + i - body_len] = *byte;
+            }
+            offset += count;
+        }
+
+        let expected = u32::from_le_bytes(trailer);
+        let actual = crc.read_crc();
+        if actual != expected {
+            error!("Staged image CRC mismatch, expected {:#x}, got {:#x}", expected, actual);
+            return Err(Error::VerifyFailed);
+        }
+        Ok(())
+    }
+
+    /// Load the latest boot state
+    async fn load(&mut self) -> Result<(), Error<F::Error>> {
+        self.state = match self.state_log.load().await? {
+            Some(state) => {
+                trace!("Dual bank: loaded {:?}", state);
+                state
+            }
+            None => {
+                info!("Dual bank: no boot state recorded, using slot A");
+                BootState::default()
+            }
+        };
+        Ok(())
+    }
+
+    /// Append a new state record
+    async fn persist(&mut self, state: BootState) -> Result<(), Error<F::Error>> {
+        self.state_log.append(&state).await?;
+        self.state = state;
+        Ok(())
+    }
+}
+
+/// CFU component staging updates with a [`DualBank`]
+///
+/// Content is staged into the inactive slot and [`RequestData::FinalizeUpdate`] switches to it on trial. The new image
+/// runs after the next boot and has to confirm itself with [`DualBankComponent::confirm`], otherwise it's rolled back.
+/// Offers are rejected while the active slot is on trial since staging would overwrite the rollback image.
+pub struct DualBankComponent<F: NorFlash, S: NorFlash<Error = F::Error>> {
+    /// CFU device
+    cfu_device: CfuDevice,
+    /// Version of the running firmware
+    version: FwVersion,
+    /// Slot manager
+    bank: Mutex<GlobalRawMutex, DualBank<F, S>>,
+}
+
+impl<F: NorFlash, S: NorFlash<Error = F::Error>> DualBankComponent<F, S> {
+    /// Create a new dual bank component
+    ///
+    /// `bank` must have been booted with [`DualBank::boot`], `version` is the version of the running firmware.
+    pub fn new(component_id: ComponentId, version: FwVersion, bank: DualBank<F, S>) -> Self {
+        Self {
+            cfu_device: CfuDevice::new(component_id),
+            version,
+            bank: Mutex::new(bank),
+        }
+    }
+
+    /// Current boot state
+    pub async fn state(&self) -> BootState {
+        self.bank.lock().await.state()
+    }
+
+    /// Mark the running firmware healthy, ending its trial
+    pub async fn confirm(&self) -> Result<(), Error<F::Error>> {
+        self.bank.lock().await.confirm().await
+    }
+
+    /// Create an offer rejection response
+    fn create_offer_rejection(reason: OfferRejectReason) -> InternalResponseData {
+        InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_with_failure(
+            HostToken::Driver,
+            reason,
+            OfferStatus::Reject,
+        ))
+    }
+
+    /// Process a fw version request
+    fn process_get_fw_version(&self) -> InternalResponseData {
+        let dev_inf = FwVerComponentInfo::new(self.version, self.cfu_device.component_id());
+        InternalResponseData::FwVersionResponse(GetFwVersionResponse {
+            header: GetFwVersionResponseHeader::new(1, GetFwVerRespHeaderByte3::NoSpecialFlags),
+            component_info: [dev_inf; MAX_CMPT_COUNT],
+        })
+    }
+
+    /// Process a give offer request
+    async fn process_give_offer(&self, offer: &FwUpdateOffer) -> InternalResponseData {
+        if offer.component_info.component_id != self.cfu_device.component_id() {
+            return Self::create_offer_rejection(OfferRejectReason::InvalidComponent);
+        }
+
+        let state = self.bank.lock().await.state();
+        if state.trial {
+            info!("Slot {:?} on trial, rejecting offer", state.active);
+            return Self::create_offer_rejection(OfferRejectReason::SwapPending);
+        }
+
+        InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_accept(HostToken::Driver))
+    }
+
+    /// Process update content
+    async fn process_give_content(&self, content: &FwUpdateContentCommand) -> InternalResponseData {
+        let status = self.bank.lock().await.process_content(content).await;
+        if status != CfuUpdateContentResponseStatus::Success {
+            error!("Failed to stage content {}: {:?}", content.header.sequence_num, status);
+        }
+        InternalResponseData::ContentResponse(FwUpdateContentResponse::new(content.header.sequence_num, status))
+    }
+
+    /// Process a finalize request
+    async fn process_finalize_update(&self) -> InternalResponseData {
+        match self.bank.lock().await.finish_update().await {
+            Ok(slot) => {
+                info!("Update staged, slot {:?} boots next on trial", slot);
+                InternalResponseData::ComponentPrepared
+            }
+            Err(e) => {
+                error!("Failed to finish update: {:?}", e);
+                InternalResponseData::ComponentBusy
+            }
+        }
+    }
+
+    /// Wait for a CFU request
+    pub async fn wait_request(&self) -> RequestData {
+        self.cfu_device.wait_request().await
+    }
+
+    /// Process a CFU request and produce a response
+    pub async fn process(&self, request: RequestData) -> InternalResponseData {
+        match request {
+            RequestData::FwVersionRequest => {
+                trace!("Got FwVersionRequest");
+                self.process_get_fw_version()
+            }
+            RequestData::GiveOffer(offer) => {
+                trace!("Got GiveOffer");
+                self.process_give_offer(&offer).await
+            }
+            RequestData::GiveContent(content) => {
+                trace!("Got GiveContent");
+                self.process_give_content(&content).await
+            }
+            RequestData::FinalizeUpdate => {
+                trace!("Got FinalizeUpdate");
+                self.process_finalize_update().await
+            }
+            RequestData::PrepareComponentForUpdate => {
+                trace!("Got PrepareComponentForUpdate");
+                InternalResponseData::ComponentPrepared
+            }
+        }
+    }
+
+    /// Send a response to the CFU message
+    pub async fn send_response(&self, response: InternalResponseData) {
+        self.cfu_device.send_response(response).await;
+    }
+
+    /// Register the component with all relevant services
+    pub async fn register(&'static self) -> Result<(), intrusive_list::Error> {
+        cfu::register_device(&self.cfu_device).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embedded_services::testing::{RamFlash, RamFlashError};
+
+    use super::*;
+
+    const ERASE_SIZE: usize = 64;
+    const SLOT_SIZE: usize = 4 * ERASE_SIZE;
+    const STATE_SIZE: usize = 2 * ERASE_SIZE;
+
+    type SlotFlash = RamFlash<SLOT_SIZE, ERASE_SIZE>;
+    type StateFlash = RamFlash<STATE_SIZE, ERASE_SIZE>;
+
+    /// Flash backing a dual bank manager, kept across simulated reboots
+    struct Flash {
+        slot_a: SlotFlash,
+        slot_b: SlotFlash,
+        state: StateFlash,
+    }
+
+    type Bank<'a> = DualBank<&'a mut SlotFlash, &'a mut StateFlash>;
+    type Component<'a> = DualBankComponent<&'a mut SlotFlash, &'a mut StateFlash>;
+
+    /// Component ID of the dual bank component
+    const COMPONENT: ComponentId = 0x70;
+
+    impl Flash {
+        fn new() -> Self {
+            Self {
+                slot_a: RamFlash::new(),
+                slot_b: RamFlash::new(),
+                state: RamFlash::new(),
+            }
+        }
+
+        /// Boot, returning the manager and the slot that should run
+        fn boot(&mut self) -> (Bank<'_>, Slot) {
+            let config = Config { max_trial_boots: 2 };
+            let mut bank = DualBank::new(&mut self.slot_a, &mut self.slot_b, &mut self.state, config).unwrap();
+            let slot = block_on(bank.boot()).unwrap();
+            (bank, slot)
+        }
+
+        /// Boot and create a CFU component for the running firmware
+        fn component(&mut self) -> Component<'_> {
+            let (bank, _) = self.boot();
+            DualBankComponent::new(COMPONENT, FwVersion::new(0x100), bank)
+        }
+    }
+
+    /// Image of `len` bytes followed by its CRC
+    fn image(len: usize, seed: u8) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len).map(|i| (i as u8).wrapping_add(seed)).collect();
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&image);
+        image.extend_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    /// Stage an image in chunks of uneven size
+    fn stage(bank: &mut Bank<'_>, image: &[u8]) -> Result<Slot, Error<RamFlashError>> {
+        block_on(bank.begin_update())?;
+        for (i, chunk) in image.chunks(13).enumerate() {
+            block_on(bank.write((i * 13) as u32, chunk))?;
+        }
+        block_on(bank.finish_update())
+    }
+
+    #[test]
+    fn test_update_and_confirm() {
+        let mut flash = Flash::new();
+        let image = image(100, 1);
+
+        let (mut bank, slot) = flash.boot();
+        assert_eq!(slot, Slot::A);
+        assert_eq!(stage(&mut bank, &image), Ok(Slot::B));
+        assert_eq!(&flash.slot_b.data[..image.len()], image.as_slice());
+
+        let (mut bank, slot) = flash.boot();
+        assert_eq!(slot, Slot::B);
+        assert_eq!(
+            bank.state(),
+            BootState {
+                active: Slot::B,
+                trial: true,
+                trial_boots: 1,
+            }
+        );
+        block_on(bank.confirm()).unwrap();
+
+        // Confirmed slots stay put
+        for _ in 0..4 {
+            let (bank, slot) = flash.boot();
+            assert_eq!(slot, Slot::B);
+            assert!(!bank.state().trial);
+        }
+    }
+
+    #[test]
+    fn test_interrupted_update() {
+        let mut flash = Flash::new();
+        let image = image(100, 2);
+
+        // Power lost halfway through staging
+        let (mut bank, _) = flash.boot();
+        block_on(bank.begin_update()).unwrap();
+        block_on(bank.write(0, &image[..50])).unwrap();
+
+        let (mut bank, slot) = flash.boot();
+        assert_eq!(slot, Slot::A);
+        assert_eq!(bank.state(), BootState::default());
+        assert_eq!(block_on(bank.finish_update()), Err(Error::NotStaging));
+
+        // Resuming mid-image isn't possible, the update restarts from scratch
+        block_on(bank.begin_update()).unwrap();
+        assert_eq!(block_on(bank.write(50, &image[50..])), Err(Error::NonSequentialWrite));
+
+        // A corrupted image isn't switched to
+        let mut corrupted = image.clone();
+        corrupted[10] ^= 0x01;
+        assert_eq!(stage(&mut bank, &corrupted), Err(Error::VerifyFailed));
+        assert_eq!(bank.active_slot(), Slot::A);
+
+        assert_eq!(stage(&mut bank, &image), Ok(Slot::B));
+        let (_, slot) = flash.boot();
+        assert_eq!(slot, Slot::B);
+    }
+
+    #[test]
+    fn test_rollback_after_failed_confirm() {
+        let mut flash = Flash::new();
+
+        let (mut bank, _) = flash.boot();
+        assert_eq!(stage(&mut bank, &image(100, 3)), Ok(Slot::B));
+
+        // The new image gets max_trial_boots boots to confirm itself
+        for trial_boots in 1..=2 {
+            let (mut bank, slot) = flash.boot();
+            assert_eq!(slot, Slot::B);
+            assert_eq!(bank.state().trial_boots, trial_boots);
+
+            // The previous image is kept while on trial
+            assert_eq!(block_on(bank.begin_update()), Err(Error::TrialInProgress));
+        }
+
+        let (mut bank, slot) = flash.boot();
+        assert_eq!(slot, Slot::A);
+        assert_eq!(bank.state(), BootState::default());
+        assert_eq!(block_on(bank.rollback()), Err(Error::NoRollbackImage));
+
+        // Explicit rollback while on trial
+        assert_eq!(stage(&mut bank, &image(100, 4)), Ok(Slot::B));
+        let (mut bank, _) = flash.boot();
+        block_on(bank.rollback()).unwrap();
+        let (_, slot) = flash.boot();
+        assert_eq!(slot, Slot::A);
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let mut flash = Flash::new();
+
+        let (mut bank, _) = flash.boot();
+        assert_eq!(stage(&mut bank, &image(100, 5)), Ok(Slot::B));
+        let (mut bank, _) = flash.boot();
+        block_on(bank.confirm()).unwrap();
+
+        // Power lost while switching back to slot A, the last of four records is torn
+        let (mut bank, _) = flash.boot();
+        assert_eq!(stage(&mut bank, &image(100, 6)), Ok(Slot::A));
+        flash.state.data[3 * RECORD_SIZE + 8] = 0x00;
+
+        // The previous state is used
+        let (mut bank, slot) = flash.boot();
+        assert_eq!(slot, Slot::B);
+        assert!(!bank.state().trial);
+
+        // And the log keeps working past the torn record
+        assert_eq!(stage(&mut bank, &image(100, 7)), Ok(Slot::A));
+        let (_, slot) = flash.boot();
+        assert_eq!(slot, Slot::A);
+    }
+
+    fn offer(component: &Component<'_>) -> FwUpdateOfferResponse {
+        let offer = FwUpdateOffer::new(HostToken::Driver, COMPONENT, FwVersion::new(0x200), 0, 0);
+        match block_on(component.process(RequestData::GiveOffer(offer))) {
+            InternalResponseData::OfferResponse(response) => response,
+            response => panic!("Unexpected response {response:?}"),
+        }
+    }
+
+    /// Send an image to the component and finalize the update
+    fn update(component: &Component<'_>, image: &[u8]) -> InternalResponseData {
+        assert_eq!(offer(component).status, OfferStatus::Accept);
+
+        let blocks = image.len().div_ceil(DEFAULT_DATA_LENGTH);
+        for (i, chunk) in image.chunks(DEFAULT_DATA_LENGTH).enumerate() {
+            let mut flags = 0;
+            if i == 0 {
+                flags |= FW_UPDATE_FLAG_FIRST_BLOCK;
+            }
+            if i == blocks - 1 {
+                flags |= FW_UPDATE_FLAG_LAST_BLOCK;
+            }
+
+            let mut content = FwUpdateContentCommand {
+                header: FwUpdateContentHeader {
+                    data_length: chunk.len() as u8,
+                    sequence_num: i as u16,
+                    firmware_address: (i * DEFAULT_DATA_LENGTH) as u32,
+                    flags,
+                },
+                data: [0; DEFAULT_DATA_LENGTH],
+            };
+            content.data[..chunk.len()].copy_from_slice(chunk);
+            match block_on(component.process(RequestData::GiveContent(content))) {
+                InternalResponseData::ContentResponse(response) => {
+                    assert_eq!(response.status, CfuUpdateContentResponseStatus::Success)
+                }
+                response => panic!("Unexpected response {response:?}"),
+            }
+        }
+
+        block_on(component.process(RequestData::FinalizeUpdate))
+    }
+
+    #[test]
+    fn test_component_swap() {
+        let mut flash = Flash::new();
+        let image = image(150, 8);
+
+        let component = flash.component();
+        assert_eq!(update(&component, &image), InternalResponseData::ComponentPrepared);
+        assert_eq!(&flash.slot_b.data[..image.len()], image.as_slice());
+
+        // The new image boots on trial and keeps the previous one until it confirms itself
+        let component = flash.component();
+        assert_eq!(
+            block_on(component.state()),
+            BootState {
+                active: Slot::B,
+                trial: true,
+                trial_boots: 1,
+            }
+        );
+        let response = offer(&component);
+        assert_eq!(response.status, OfferStatus::Reject);
+        assert_eq!(response.reject_reason, OfferRejectReason::SwapPending);
+
+        block_on(component.confirm()).unwrap();
+        assert_eq!(offer(&component).status, OfferStatus::Accept);
+
+        // The next update goes to the other slot
+        assert_eq!(update(&component, &image), InternalResponseData::ComponentPrepared);
+        assert_eq!(&flash.slot_a.data[..image.len()], image.as_slice());
+        let component = flash.component();
+        assert_eq!(block_on(component.state()).active, Slot::A);
+    }
+
+    #[test]
+    fn test_component_rollback() {
+        let mut flash = Flash::new();
+
+        // A corrupted image isn't switched to
+        let mut corrupted = image(150, 9);
+        corrupted[60] ^= 0x01;
+        let component = flash.component();
+        assert_eq!(update(&component, &corrupted), InternalResponseData::ComponentBusy);
+        let component = flash.component();
+        assert_eq!(block_on(component.state()), BootState::default());
+
+        // An image that never confirms itself is rolled back once it runs out of trial boots
+        assert_eq!(
+            update(&component, &image(150, 10)),
+            InternalResponseData::ComponentPrepared
+        );
+        for _ in 0..2 {
+            let component = flash.component();
+            assert_eq!(block_on(component.state()).active, Slot::B);
+        }
+        let component = flash.component();
+        assert_eq!(block_on(component.state()), BootState::default());
+
+        // The previous image is back and can be updated again
+        assert_eq!(offer(&component).status, OfferStatus::Accept);
+    }
+}
@@ -1,15 +1,20 @@
 use core::future::Future;
 
-use embedded_cfu_protocol::components::CfuComponentTraits;
-use embedded_cfu_protocol::host::{CfuHostStates, CfuUpdater};
+use embassy_time::{Duration, Timer};
+use embedded_cfu_protocol::CfuImage;
+use embedded_cfu_protocol::host::CfuHostStates;
 use embedded_cfu_protocol::protocol_definitions::*;
-use embedded_cfu_protocol::{
-    CfuImage,
-    writer::{CfuWriterAsync, CfuWriterError, CfuWriterNop},
+use embedded_services::cfu::{
+    self,
+    component::{InternalResponseData, RequestData},
+    progress,
 };
+use embedded_services::{debug, error, info, warn};
+use embedded_storage_async::nor_flash::ReadNorFlash;
 use heapless::Vec;
 
-use crate::CfuError;
+use crate::image::{self, RecordKind, Records};
+use crate::{CfuError, prepare_component};
 
 /// All host side Cfu traits, in some cases this will originate from a OS driver for CFU
 pub trait CfuHost<W>: CfuHostStates<W> {
@@ -28,160 +33,613 @@ pub trait CfuHost<W>: CfuHostStates<W> {
     ) -> impl Future<Output = Result<&'a [FwUpdateOfferResponse], CfuError>>;
     /// For a specific component, update its content
     fn update_cfu_content(writer: &mut W) -> impl Future<Output = Result<FwUpdateContentResponse, CfuError>>;
-    /// For a specific image that was updated, validate its content
-    fn is_cfu_image_valid<I: CfuImage>(image: I) -> impl Future<Output = Result<bool, CfuError>>;
 }
 
-pub struct CfuHostInstance<I: CfuImage, C: CfuComponentTraits> {
-    pub updater: CfuUpdater,
-    pub images: heapless::Vec<I, MAX_CMPT_COUNT>,
-    pub writer: CfuWriterNop,
-    pub primary_cmpt: C,
-    pub host_token: HostToken,
+/// Bytes read from flash at a time when the flash has read alignment requirements
+const FLASH_SCRATCH_SIZE: usize = 64;
+
+/// Source of CFU offer or payload bytes
+pub trait ImageSource {
+    /// Error type
+    type Error;
+
+    /// Size of the image in bytes
+    fn size(&self) -> usize;
+
+    /// Read bytes starting at `offset`
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// Read outside of an in-memory image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OutOfBounds;
+
+/// Image held in memory
+pub struct MemoryImage<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MemoryImage<'a> {
+    /// Create a new in-memory image
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl ImageSource for MemoryImage<'_> {
+    type Error = OutOfBounds;
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let src = offset
+            .checked_add(buf.len())
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or(OutOfBounds)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+/// Image stored in flash, typically a partition from `partition-manager`
+pub struct FlashImage<F: ReadNorFlash> {
+    flash: F,
+    size: usize,
 }
 
-impl<I: CfuImage, C: CfuComponentTraits> CfuHostInstance<I, C> {
-    #[allow(unused)]
-    fn new(primary_cmpt: C) -> Self {
+impl<F: ReadNorFlash> FlashImage<F> {
+    /// Create an image spanning the whole flash
+    ///
+    /// Payload parsing stops at the first erased record, so the image doesn't need to fill the flash.
+    pub fn new(flash: F) -> Self {
+        let size = flash.capacity();
+        Self { flash, size }
+    }
+
+    /// Create an image of `size` bytes starting at the beginning of the flash
+    pub fn with_size(flash: F, size: usize) -> Self {
         Self {
-            updater: CfuUpdater {},
-            images: Vec::new(),
-            writer: CfuWriterNop,
-            primary_cmpt,
-            host_token: HostToken::Driver,
+            size: size.min(flash.capacity()),
+            flash,
         }
     }
+
+    /// Release the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
 }
 
-impl<I: CfuImage, C: CfuComponentTraits, W: CfuWriterAsync> CfuHostStates<W> for CfuHostInstance<I, C> {
-    async fn start_transaction(self, _writer: &mut W) -> Result<FwUpdateOfferResponse, CfuProtocolError> {
-        let _mock_cmd = FwUpdateOfferInformation::new(OfferInformationComponentInfo::new(
-            HostToken::Driver,
-            SpecialComponentIds::Info,
-            OfferInformationCodeValues::StartEntireTransaction,
-        ));
-        let mockresponse = FwUpdateOfferResponse::default();
-        Ok(mockresponse)
-    }
-    async fn notify_start_offer_list(self, writer: &mut W) -> Result<FwUpdateOfferResponse, CfuProtocolError> {
-        // Serialize FwUpdateOfferInformation to bytes
-        let mock_cmd = FwUpdateOfferInformation::new(OfferInformationComponentInfo::new(
-            HostToken::Driver,
-            SpecialComponentIds::Info,
-            OfferInformationCodeValues::StartOfferList,
-        ));
-        let serialized_mock: [u8; 16] = (&mock_cmd).into();
-
-        let mut read = [0u8; 16];
-        if let Ok(_result) = writer.cfu_write_read(None, &serialized_mock, &mut read).await {
-            // Collect offer response
-            let offer_response = FwUpdateOfferResponse::try_from(read)
-                .map_err(|_| CfuProtocolError::WriterError(CfuWriterError::ByteConversionError))?;
-            if offer_response.status != OfferStatus::Accept {
-                Err(CfuProtocolError::CfuOfferStatusError(offer_response.status))
-            } else {
-                Ok(offer_response)
+impl<F: ReadNorFlash> ImageSource for FlashImage<F> {
+    type Error = F::Error;
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        if F::READ_SIZE <= 1 {
+            return self.flash.read(offset as u32, buf).await;
+        }
+
+        // Read through an aligned scratch buffer
+        let align = F::READ_SIZE;
+        let chunk = FLASH_SCRATCH_SIZE - FLASH_SCRATCH_SIZE % align;
+        let mut scratch = [0u8; FLASH_SCRATCH_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let address = offset + done;
+            let aligned = address - address % align;
+            let skip = address - aligned;
+            let remaining = buf.len() - done;
+            let len = (skip + remaining).div_ceil(align) * align;
+            let len = len.min(chunk);
+
+            self.flash.read(aligned as u32, &mut scratch[..len]).await?;
+            let count = (len - skip).min(remaining);
+            buf[done..done + count].copy_from_slice(&scratch[skip..skip + count]);
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+/// Updater errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Image is unreadable or failed validation
+    Image(image::Error<E>),
+    /// Component rejected the offer
+    OfferRejected(OfferRejectReason),
+    /// Component rejected a content block
+    ContentRejected(CfuUpdateContentResponseStatus),
+    /// Component stayed busy or kept failing after all retries
+    RetriesExhausted,
+    /// Error from the CFU service
+    Cfu(CfuError),
+}
+
+impl<E> From<image::Error<E>> for Error<E> {
+    fn from(e: image::Error<E>) -> Self {
+        Error::Image(e)
+    }
+}
+
+/// Updater configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Number of times a request is retried when the component is busy or fails to respond
+    pub max_retries: u8,
+    /// Delay between retries
+    pub retry_delay: Duration,
+    /// Image validation done before anything is sent to the component
+    pub validation: image::Config,
+    /// Send the signature record to the component, see [`crate::verify`]
+    pub send_signature: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            validation: image::Config::default(),
+            send_signature: false,
+        }
+    }
+}
+
+/// Drives a CFU update of a local component from a staged offer and payload
+///
+/// See [`image`] for the offer and payload formats. The image is validated before the component is prepared.
+pub struct Updater {
+    config: Config,
+}
+
+impl Updater {
+    /// Create a new updater
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Send a request to a component, retrying while it is busy or the request fails
+    async fn send_request<E>(&self, comp: ComponentId, request: RequestData) -> Result<InternalResponseData, Error<E>> {
+        let mut attempt = 0;
+        loop {
+            match cfu::route_request(comp, request).await {
+                Ok(InternalResponseData::ComponentBusy) => warn!("Comp {} busy", comp),
+                Ok(response) => return Ok(response),
+                // There's nothing to retry if the component doesn't exist
+                Err(CfuError::InvalidComponent) => return Err(Error::Cfu(CfuError::InvalidComponent)),
+                Err(e) => warn!("Comp {} request failed: {:?}", comp, e),
+            }
+
+            if attempt >= self.config.max_retries {
+                error!("Comp {}: retries exhausted", comp);
+                return Err(Error::RetriesExhausted);
             }
-        } else {
-            Err(CfuProtocolError::WriterError(CfuWriterError::StorageError))
+            attempt += 1;
+            Timer::after(self.config.retry_delay).await;
         }
     }
 
-    async fn notify_end_offer_list(self, writer: &mut W) -> Result<FwUpdateOfferResponse, CfuProtocolError> {
-        let mock_cmd = FwUpdateOfferInformation::new(OfferInformationComponentInfo::new(
-            HostToken::Driver,
-            SpecialComponentIds::Info,
-            OfferInformationCodeValues::EndOfferList,
-        ));
-        let serialized_mock: [u8; 16] = (&mock_cmd).into();
-        let mut read = [0u8; 16];
-        if writer.cfu_write_read(None, &serialized_mock, &mut read).await.is_ok() {
-            // Collect offer response
-            let offer_response = FwUpdateOfferResponse::try_from(read)
-                .map_err(|_| CfuProtocolError::WriterError(CfuWriterError::ByteConversionError))?;
-            Ok(offer_response)
-        } else {
-            // unsuccessful write/read from the storage interface
-            // use result.err() eventually
-            Err(CfuProtocolError::WriterError(CfuWriterError::StorageError))
+    /// Prepare the component and its sub-components
+    async fn prepare<E>(&self, comp: ComponentId) -> Result<(), Error<E>> {
+        let mut attempt = 0;
+        loop {
+            match prepare_component(comp).await {
+                Ok(InternalResponseData::ComponentPrepared) => return Ok(()),
+                Ok(InternalResponseData::ComponentBusy) => warn!("Comp {} busy", comp),
+                Ok(response) => {
+                    error!("Invalid response to prepare {:?} from comp {}", response, comp);
+                    return Err(Error::Cfu(CfuError::ProtocolError(CfuProtocolError::BadResponse)));
+                }
+                Err(CfuError::InvalidComponent) => return Err(Error::Cfu(CfuError::InvalidComponent)),
+                Err(e) => warn!("Comp {} prepare failed: {:?}", comp, e),
+            }
+
+            if attempt >= self.config.max_retries {
+                error!("Comp {}: retries exhausted", comp);
+                return Err(Error::RetriesExhausted);
+            }
+            attempt += 1;
+            Timer::after(self.config.retry_delay).await;
         }
     }
 
-    async fn verify_all_updates_completed(resps: &[FwUpdateOfferResponse]) -> Result<bool, CfuProtocolError> {
-        let mut bad_components: heapless::Vec<u8, MAX_CMPT_COUNT> = Vec::new();
-        for (i, r) in resps.iter().enumerate() {
-            if r.status != OfferStatus::Reject {
-                let _ = bad_components.push(i as u8);
+    /// Send a single content block
+    async fn send_content<E>(&self, comp: ComponentId, content: FwUpdateContentCommand) -> Result<(), Error<E>> {
+        match self.send_request(comp, RequestData::GiveContent(content)).await? {
+            InternalResponseData::ContentResponse(response) => {
+                let sequence = content.header.sequence_num;
+                if response.status == CfuUpdateContentResponseStatus::Success {
+                    let update = progress::Update::ContentWritten {
+                        sequence,
+                        length: content.header.data_length,
+                        last_block: content.header.flags & FW_UPDATE_FLAG_LAST_BLOCK != 0,
+                    };
+                    progress::publish(comp, update).await;
+                    Ok(())
+                } else {
+                    error!("Comp {} rejected block {}: {:?}", comp, sequence, response.status);
+                    let update = progress::Update::ContentRejected {
+                        sequence,
+                        status: response.status,
+                    };
+                    progress::publish(comp, update).await;
+                    Err(Error::ContentRejected(response.status))
+                }
+            }
+            response => {
+                error!("Invalid response to content {:?} from comp {}", response, comp);
+                Err(Error::Cfu(CfuError::ProtocolError(CfuProtocolError::BadResponse)))
             }
         }
-        if bad_components.is_empty() {
-            Ok(true)
-        } else {
-            // probably want to have the component ids that didn't respond properly here too
-            Err(CfuProtocolError::BadResponse)
+    }
+
+    /// Parse the payload and send its data records, and signature record if configured, to the component
+    ///
+    /// Blocks are sent one behind the parser so the last block can be flagged once the end of the payload is found.
+    /// Signature blocks all carry [`image::SIGNATURE_ADDRESS`] so the component can tell them apart from firmware.
+    async fn send_payload<S: ImageSource>(&self, comp: ComponentId, payload: &mut S) -> Result<(), Error<S::Error>> {
+        let mut records = Records::new();
+        let mut sequence: u16 = 0;
+        let mut pending: Option<FwUpdateContentCommand> = None;
+
+        while let Some(record) = records.next(payload).await? {
+            let send = match record.kind {
+                RecordKind::Data => true,
+                RecordKind::SegmentCrc => false,
+                RecordKind::Signature => self.config.send_signature,
+            };
+            if !send {
+                continue;
+            }
+
+            let mut record_offset = 0;
+            while record_offset < record.length {
+                let count = (record.length - record_offset).min(DEFAULT_DATA_LENGTH);
+                let firmware_address = match record.kind {
+                    RecordKind::Data => record.address.wrapping_add(record_offset as u32),
+                    _ => record.address,
+                };
+                let mut content = FwUpdateContentCommand {
+                    header: FwUpdateContentHeader {
+                        flags: 0,
+                        data_length: count as u8,
+                        sequence_num: sequence,
+                        firmware_address,
+                    },
+                    data: [0u8; DEFAULT_DATA_LENGTH],
+                };
+                payload
+                    .read(record.offset + record_offset, &mut content.data[..count])
+                    .await
+                    .map_err(|e| Error::Image(image::Error::Source(e)))?;
+
+                if pending.is_none() {
+                    content.header.flags = FW_UPDATE_FLAG_FIRST_BLOCK;
+                }
+                if let Some(previous) = pending.replace(content) {
+                    self.send_content(comp, previous).await?;
+                }
+
+                record_offset += count;
+                sequence = sequence.wrapping_add(1);
+            }
         }
+
+        let Some(mut last) = pending else {
+            error!("Empty payload");
+            return Err(Error::Image(image::Error::InvalidPayload));
+        };
+        last.header.flags |= FW_UPDATE_FLAG_LAST_BLOCK;
+        self.send_content(comp, last).await
+    }
+
+    /// Update the component targeted by `offer` with the contents of `payload`
+    ///
+    /// Progress is reported through [`progress::publish`] as the update advances.
+    pub async fn update<O: ImageSource, P: ImageSource<Error = O::Error>>(
+        &self,
+        offer: &mut O,
+        payload: &mut P,
+    ) -> Result<(), Error<O::Error>> {
+        let info = image::validate(offer, payload, &self.config.validation).await?;
+        let offer = info.offer;
+        let comp = offer.component_info.component_id;
+        info!("Updating comp {}", comp);
+
+        self.prepare(comp).await?;
+
+        match self.send_request(comp, RequestData::GiveOffer(offer)).await? {
+            InternalResponseData::OfferResponse(response) => {
+                if response.status != OfferStatus::Accept {
+                    info!("Comp {} rejected offer: {:?}", comp, response.reject_reason);
+                    progress::publish(comp, progress::Update::OfferRejected(response.reject_reason)).await;
+                    return Err(Error::OfferRejected(response.reject_reason));
+                }
+                progress::publish(comp, progress::Update::OfferAccepted).await;
+                let signature_size = match info.signature {
+                    Some(signature) if self.config.send_signature => signature.length,
+                    _ => 0,
+                };
+                progress::set_total_bytes(comp, (info.data_size + signature_size) as u32).await;
+            }
+            response => {
+                error!("Invalid response to offer {:?} from comp {}", response, comp);
+                return Err(Error::Cfu(CfuError::ProtocolError(CfuProtocolError::BadResponse)));
+            }
+        }
+
+        debug!("Comp {} accepted offer, sending payload", comp);
+        self.send_payload(comp, payload).await?;
+
+        let response = self.send_request(comp, RequestData::FinalizeUpdate).await;
+        let success = matches!(response, Ok(InternalResponseData::ComponentPrepared));
+        progress::publish(comp, progress::Update::Finalized { success }).await;
+        match response? {
+            InternalResponseData::ComponentPrepared => {}
+            response => {
+                error!("Invalid response to finalize {:?} from comp {}", response, comp);
+                return Err(Error::Cfu(CfuError::ProtocolError(CfuProtocolError::BadResponse)));
+            }
+        }
+
+        info!("Comp {} update complete", comp);
+        Ok(())
     }
 }
 
-impl<I: CfuImage, C: CfuComponentTraits, W: CfuWriterAsync> CfuHost<W> for CfuHostInstance<I, C> {
-    async fn get_cfu_images<T: CfuImage>(&self) -> Result<Vec<T, MAX_CMPT_COUNT>, CfuError> {
-        Err(CfuError::BadImage)
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::boxed::Box;
+    use std::sync::{Mutex as StdMutex, OnceLock as StdOnceLock};
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{Either, select};
+    use embedded_services::cfu::component::{CfuDevice, ComponentState};
+    use embedded_services::comms;
+
+    use super::*;
+    use crate::image::tests::{offer_file, payload};
+
+    /// Mock component
+    struct Mock {
+        device: CfuDevice,
+        /// Number of prepare requests left to answer with busy
+        busy_prepare: AtomicUsize,
+        /// Number of content requests left to answer with busy
+        busy_content: AtomicUsize,
+        /// Sequence number of the content block to reject, `u32::MAX` to accept all blocks
+        reject_sequence: AtomicU32,
+        prepare_requests: AtomicUsize,
+        content_requests: AtomicUsize,
     }
 
-    async fn get_all_fw_versions(
-        self,
-        _writer: &mut W,
-        primary_cmpt: ComponentId,
-    ) -> Result<GetFwVersionResponse, CfuError> {
-        let mut component_count: u8 = 0;
-        self.primary_cmpt.get_subcomponents().iter().for_each(|x| {
-            if x.is_some() {
-                component_count += 1
+    impl Mock {
+        fn new(id: ComponentId) -> &'static Self {
+            let mock = Box::leak(Box::new(Self {
+                device: CfuDevice::new(id),
+                busy_prepare: AtomicUsize::new(0),
+                busy_content: AtomicUsize::new(0),
+                reject_sequence: AtomicU32::new(u32::MAX),
+                prepare_requests: AtomicUsize::new(0),
+                content_requests: AtomicUsize::new(0),
+            }));
+            block_on(cfu::register_device(&mock.device)).unwrap();
+            mock
+        }
+
+        /// Take one busy response from `busy` if any are left
+        fn busy(busy: &AtomicUsize) -> bool {
+            busy.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+        }
+
+        async fn process(&self) {
+            loop {
+                let response = match self.device.wait_request().await {
+                    RequestData::PrepareComponentForUpdate => {
+                        self.prepare_requests.fetch_add(1, Ordering::SeqCst);
+                        if Self::busy(&self.busy_prepare) {
+                            InternalResponseData::ComponentBusy
+                        } else {
+                            InternalResponseData::ComponentPrepared
+                        }
+                    }
+                    RequestData::GiveOffer(_) => {
+                        InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_accept(HostToken::Driver))
+                    }
+                    RequestData::GiveContent(content) => {
+                        self.content_requests.fetch_add(1, Ordering::SeqCst);
+                        let sequence = content.header.sequence_num;
+                        if Self::busy(&self.busy_content) {
+                            InternalResponseData::ComponentBusy
+                        } else {
+                            let status = if sequence as u32 == self.reject_sequence.load(Ordering::SeqCst) {
+                                CfuUpdateContentResponseStatus::ErrorWrite
+                            } else {
+                                CfuUpdateContentResponseStatus::Success
+                            };
+                            InternalResponseData::ContentResponse(FwUpdateContentResponse::new(sequence, status))
+                        }
+                    }
+                    RequestData::FinalizeUpdate => InternalResponseData::ComponentPrepared,
+                    RequestData::FwVersionRequest => {
+                        InternalResponseData::invalid_fw_version(self.device.component_id())
+                    }
+                };
+                self.device.send_response(response).await;
             }
-        });
-        let result = self.primary_cmpt.get_fw_version().await;
-        if result.is_ok() {
-            // convert bytes back to a GetFwVersionResponse
-            let primary_component_version_info = FwVerComponentInfo::new(
-                FwVersion {
-                    major: 0,
-                    minor: 1,
-                    variant: 0,
-                },
-                primary_cmpt,
-            );
-
-            let mut component_info = [FwVerComponentInfo::default(); MAX_CMPT_COUNT]; // Create an array with 7 default elements
-            component_info[0] = primary_component_version_info; // Set the first element to component_info
-
-            let resp = GetFwVersionResponse {
-                header: GetFwVersionResponseHeader::new(
-                    1, // Component count
-                    GetFwVerRespHeaderByte3::NoSpecialFlags,
-                ),
-                component_info,
-            };
-            Ok(resp)
-        } else {
-            Err(CfuError::ProtocolError(CfuProtocolError::BadResponse))
         }
     }
 
-    async fn process_cfu_offers<'a>(
-        _offer_commands: &'a [FwUpdateOffer],
-        _writer: &mut W,
-    ) -> Result<&'a [FwUpdateOfferResponse], CfuError> {
-        // TODO
-        Err(CfuError::BadImage)
+    /// Records progress messages broadcast to the host
+    struct Recorder {
+        endpoint: comms::Endpoint,
+        progress: StdMutex<Vec<progress::Progress>>,
+    }
+
+    impl comms::MailboxDelegate for Recorder {
+        fn receive(&self, message: &comms::Message) -> Result<(), comms::MailboxDelegateError> {
+            if let Some(progress) = message.data.get::<progress::Progress>() {
+                self.progress.lock().unwrap().push(*progress);
+            }
+            Ok(())
+        }
+    }
+
+    fn recorder() -> &'static Recorder {
+        static RECORDER: StdOnceLock<&'static Recorder> = StdOnceLock::new();
+        RECORDER.get_or_init(|| {
+            let recorder = Box::leak(Box::new(Recorder {
+                endpoint: comms::Endpoint::uninit(comms::EndpointID::External(comms::External::Host)),
+                progress: StdMutex::new(Vec::new()),
+            }));
+            block_on(comms::register_endpoint(recorder, &recorder.endpoint)).unwrap();
+            recorder
+        })
     }
 
-    async fn update_cfu_content(_writer: &mut W) -> Result<FwUpdateContentResponse, CfuError> {
-        Err(CfuError::ProtocolError(CfuProtocolError::WriterError(
-            CfuWriterError::Other,
-        )))
+    /// Progress recorded for a component
+    fn recorded(component: ComponentId) -> Vec<progress::Progress> {
+        recorder()
+            .progress
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|progress| progress.component == component)
+            .copied()
+            .collect()
     }
 
-    async fn is_cfu_image_valid<T: CfuImage>(_image: T) -> Result<bool, CfuError> {
-        Ok(true)
+    fn updater(max_retries: u8) -> Updater {
+        Updater::new(Config {
+            max_retries,
+            retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        })
+    }
+
+    /// Run an update with the mock answering requests
+    fn update(updater: &Updater, mock: &Mock, offer: &[u8], payload: &[u8]) -> Result<(), Error<OutOfBounds>> {
+        let update = updater.update(&mut MemoryImage::new(offer), &mut MemoryImage::new(payload));
+        match block_on(select(mock.process(), update)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(result) => result,
+        }
+    }
+
+    #[test]
+    fn test_retry() {
+        let _guard = crate::tests::lock();
+        let mock = Mock::new(0x30);
+        let offer = offer_file(0x30, 0x100);
+        let payload = payload(&[20]);
+
+        // Busy responses are retried
+        mock.busy_prepare.store(2, Ordering::SeqCst);
+        mock.busy_content.store(1, Ordering::SeqCst);
+        assert_eq!(update(&updater(2), mock, &offer, &payload), Ok(()));
+        assert_eq!(mock.prepare_requests.load(Ordering::SeqCst), 3);
+        assert_eq!(mock.content_requests.load(Ordering::SeqCst), 2);
+
+        // Until the retries run out
+        mock.prepare_requests.store(0, Ordering::SeqCst);
+        mock.content_requests.store(0, Ordering::SeqCst);
+        mock.busy_prepare.store(3, Ordering::SeqCst);
+        assert_eq!(
+            update(&updater(2), mock, &offer, &payload),
+            Err(Error::RetriesExhausted)
+        );
+        assert_eq!(mock.prepare_requests.load(Ordering::SeqCst), 3);
+        assert_eq!(mock.content_requests.load(Ordering::SeqCst), 0);
+
+        // Missing components aren't retried
+        let offer = offer_file(0x3f, 0x100);
+        assert_eq!(
+            update(&updater(2), mock, &offer, &payload),
+            Err(Error::Cfu(CfuError::InvalidComponent))
+        );
+    }
+
+    #[test]
+    fn test_progress() {
+        let _guard = crate::tests::lock();
+        recorder();
+        let mock = Mock::new(0x31);
+        let offer = offer_file(0x31, 0x100);
+        // Two blocks from the first record and one from the second
+        let first = DEFAULT_DATA_LENGTH + 8;
+        let payload = payload(&[first, 20]);
+        let total = (first + 20) as u32;
+
+        assert_eq!(update(&updater(0), mock, &offer, &payload), Ok(()));
+        let progress = recorded(0x31);
+        let steps: Vec<_> = progress
+            .iter()
+            .map(|progress| (progress.update, progress.bytes_written))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (progress::Update::OfferAccepted, 0),
+                (
+                    progress::Update::ContentWritten {
+                        sequence: 0,
+                        length: DEFAULT_DATA_LENGTH as u8,
+                        last_block: false,
+                    },
+                    DEFAULT_DATA_LENGTH as u32
+                ),
+                (
+                    progress::Update::ContentWritten {
+                        sequence: 1,
+                        length: 8,
+                        last_block: false,
+                    },
+                    first as u32
+                ),
+                (
+                    progress::Update::ContentWritten {
+                        sequence: 2,
+                        length: 20,
+                        last_block: true,
+                    },
+                    total
+                ),
+                (progress::Update::Finalized { success: true }, total),
+            ]
+        );
+        assert!(progress[1..].iter().all(|progress| progress.total_bytes == Some(total)));
+        assert_eq!(progress[4].state, ComponentState::Idle);
+
+        // A rejected block ends the update
+        recorder().progress.lock().unwrap().clear();
+        mock.reject_sequence.store(1, Ordering::SeqCst);
+        assert_eq!(
+            update(&updater(0), mock, &offer, &payload),
+            Err(Error::ContentRejected(CfuUpdateContentResponseStatus::ErrorWrite))
+        );
+        let progress = recorded(0x31);
+        assert_eq!(progress.len(), 3);
+        assert_eq!(
+            progress[2].update,
+            progress::Update::ContentRejected {
+                sequence: 1,
+                status: CfuUpdateContentResponseStatus::ErrorWrite,
+            }
+        );
+        assert_eq!(progress[2].state, ComponentState::Idle);
     }
 }
@@ -0,0 +1,544 @@
+//! CFU image parsing and validation
+//!
+//! An image is made of an offer file and a payload file. The offer file holds a standard 16 byte CFU offer.
+//! The payload file is a sequence of records, each one a little-endian 32-bit address, a length byte and that many
+//! data bytes. Records with a reserved address carry metadata instead of firmware:
+//!
+//! * [`SEGMENT_CRC_ADDRESS`]: CRC-32 (ISO-HDLC) of the raw bytes of every record since the previous segment CRC
+//!   record, or the start of the payload. Every data record must be covered by a segment CRC.
+//! * [`SIGNATURE_ADDRESS`]: signature over the data of every data record in order, must be the last record.
+//!
+//! Data records must be in ascending address order without overlapping. The payload ends at the end of the file or
+//! at an erased (all `0xff`) record header.
+use embedded_cfu_protocol::protocol_definitions::*;
+use embedded_services::{error, trace};
+use platform_service::embedded_crc::EmbeddedCrc;
+
+use crate::host::ImageSource;
+
+/// Size of a serialized offer
+pub const OFFER_SIZE: usize = 16;
+
+/// Size of a payload record header
+pub const RECORD_HEADER_SIZE: usize = 5;
+
+/// Address of records holding the CRC of the preceding segment
+pub const SEGMENT_CRC_ADDRESS: u32 = 0xffff_fff0;
+
+/// Address of the record holding the image signature
+pub const SIGNATURE_ADDRESS: u32 = 0xffff_fff1;
+
+/// Bytes read at a time while computing CRCs
+const CRC_CHUNK_SIZE: usize = 64;
+
+/// Image errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Failed to read the offer or payload
+    Source(E),
+    /// Offer file is malformed
+    InvalidOffer,
+    /// Payload file is malformed
+    InvalidPayload,
+    /// Data records that aren't covered by a segment CRC
+    MissingSegmentCrc,
+    /// Data record overlapping or below the previous one, contains the offset of its header
+    OutOfOrder(usize),
+    /// Segment CRC doesn't match, contains the index of the segment
+    CrcMismatch(usize),
+    /// CRC calculation failed
+    Crc,
+    /// Image has no signature but one is required
+    MissingSignature,
+}
+
+/// Payload record type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RecordKind {
+    /// Firmware data
+    Data,
+    /// CRC of the preceding segment
+    SegmentCrc,
+    /// Image signature
+    Signature,
+}
+
+/// Payload record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record {
+    /// Record type
+    pub kind: RecordKind,
+    /// Firmware address, only meaningful for data records
+    pub address: u32,
+    /// Offset of the record header in the payload
+    pub header_offset: usize,
+    /// Offset of the record data in the payload
+    pub offset: usize,
+    /// Length of the record data
+    pub length: usize,
+}
+
+/// Iterates over the records of a payload
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Records {
+    offset: usize,
+}
+
+impl Records {
+    /// Start iterating from the beginning of the payload
+    pub fn new() -> Self {
+        Self { offset: 0 }
+    }
+
+    /// Read the next record, returns `None` at the end of the payload
+    pub async fn next<S: ImageSource>(&mut self, payload: &mut S) -> Result<Option<Record>, Error<S::Error>> {
+        let total = payload.size();
+        if self.offset + RECORD_HEADER_SIZE > total {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        payload.read(self.offset, &mut header).await.map_err(Error::Source)?;
+        if header.iter().all(|b| *b == 0xff) {
+            // Erased flash, end of payload
+            return Ok(None);
+        }
+
+        let address = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let length = header[4] as usize;
+        let header_offset = self.offset;
+        let offset = header_offset + RECORD_HEADER_SIZE;
+        if length == 0 || offset + length > total {
+            error!("Invalid payload record at offset {}", header_offset);
+            return Err(Error::InvalidPayload);
+        }
+
+        let kind = match address {
+            SEGMENT_CRC_ADDRESS => RecordKind::SegmentCrc,
+            SIGNATURE_ADDRESS => RecordKind::Signature,
+            _ => RecordKind::Data,
+        };
+        if kind == RecordKind::SegmentCrc && length != 4 {
+            error!("Invalid segment CRC record at offset {}", header_offset);
+            return Err(Error::InvalidPayload);
+        }
+
+        self.offset = offset + length;
+        Ok(Some(Record {
+            kind,
+            address,
+            header_offset,
+            offset,
+            length,
+        }))
+    }
+}
+
+/// Host token of offers sent by the driver
+pub const TOKEN_DRIVER: u8 = 0xa0;
+
+/// Host token of offers sent by a tool
+pub const TOKEN_TOOL: u8 = 0xb0;
+
+/// Offer flag requesting the component reset as soon as the update completes
+pub const OFFER_FLAG_FORCE_IMMEDIATE_RESET: u8 = 1 << 6;
+
+/// Offer flag requesting the component accept the update regardless of version
+pub const OFFER_FLAG_FORCE_IGNORE_VERSION: u8 = 1 << 7;
+
+/// Read and parse an offer file
+pub async fn parse_offer<S: ImageSource>(offer: &mut S) -> Result<FwUpdateOffer, Error<S::Error>> {
+    if offer.size() < OFFER_SIZE {
+        return Err(Error::InvalidOffer);
+    }
+
+    let mut raw = [0u8; OFFER_SIZE];
+    offer.read(0, &mut raw).await.map_err(Error::Source)?;
+
+    let segment_number = raw[0];
+    let flags = raw[1];
+    let component_id = raw[2];
+    let token = match raw[3] {
+        TOKEN_DRIVER => HostToken::Driver,
+        TOKEN_TOOL => HostToken::Tool,
+        token => {
+            error!("Invalid offer token {:#x}", token);
+            return Err(Error::InvalidOffer);
+        }
+    };
+    let version = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+    let vendor_specific = u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]);
+    let misc = u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]);
+    if component_id == 0 {
+        return Err(Error::InvalidOffer);
+    }
+
+    let mut offer = FwUpdateOffer::new(token, component_id, FwVersion::new(version), vendor_specific, misc);
+    offer.component_info.segment_number = segment_number;
+    offer.component_info.force_immediate_reset = flags & OFFER_FLAG_FORCE_IMMEDIATE_RESET != 0;
+    offer.component_info.force_ignore_version = flags & OFFER_FLAG_FORCE_IGNORE_VERSION != 0;
+    Ok(offer)
+}
+
+/// Validation configuration
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Reject images without a signature record
+    pub require_signature: bool,
+}
+
+/// Summary of a validated image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageInfo {
+    /// Parsed offer
+    pub offer: FwUpdateOffer,
+    /// Total firmware bytes in data records
+    pub data_size: usize,
+    /// Number of CRC protected segments
+    pub segments: usize,
+    /// Signature record, if present
+    pub signature: Option<Record>,
+}
+
+/// Compute the CRC of `len` payload bytes starting at `offset`
+async fn segment_crc<S: ImageSource>(payload: &mut S, offset: usize, len: usize) -> Result<u32, Error<S::Error>> {
+    let mut crc = EmbeddedCrc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let mut buf = [0u8; CRC_CHUNK_SIZE];
+    let mut done = 0;
+    while done < len {
+        let count = (len - done).min(CRC_CHUNK_SIZE);
+        payload
+            .read(offset + done, &mut buf[..count])
+            .await
+            .map_err(Error::Source)?;
+        crc.calculate(&buf[..count]).await.map_err(|e| {
+            error!("CRC calculation failed: {:?}", e);
+            Error::Crc
+        })?;
+        done += count;
+    }
+    Ok(crc.read_crc())
+}
+
+/// Parse an image and verify its integrity
+pub async fn validate<O: ImageSource, P: ImageSource<Error = O::Error>>(
+    offer: &mut O,
+    payload: &mut P,
+    config: &Config,
+) -> Result<ImageInfo, Error<O::Error>> {
+    let offer = parse_offer(offer).await?;
+
+    let mut records = Records::new();
+    let mut segment_start = 0;
+    let mut segments = 0;
+    let mut data_size = 0;
+    let mut data_end = 0u64;
+    let mut unverified = false;
+    let mut signature = None;
+
+    while let Some(record) = records.next(payload).await? {
+        if signature.is_some() {
+            error!("Record after signature at offset {}", record.header_offset);
+            return Err(Error::InvalidPayload);
+        }
+
+        match record.kind {
+            RecordKind::Data => {
+                if u64::from(record.address) < data_end {
+                    error!(
+                        "Data record at offset {} starts at {:#x}, below the previous record end {:#x}",
+                        record.header_offset, record.address, data_end
+                    );
+                    return Err(Error::OutOfOrder(record.header_offset));
+                }
+
+                data_end = u64::from(record.address) + record.length as u64;
+                data_size += record.length;
+                unverified = true;
+            }
+            RecordKind::SegmentCrc => {
+                let mut raw = [0u8; 4];
+                payload.read(record.offset, &mut raw).await.map_err(Error::Source)?;
+                let expected = u32::from_le_bytes(raw);
+                let actual = segment_crc(payload, segment_start, record.header_offset - segment_start).await?;
+                if actual != expected {
+                    error!(
+                        "Segment {} CRC mismatch, expected {:#x}, got {:#x}",
+                        segments, expected, actual
+                    );
+                    return Err(Error::CrcMismatch(segments));
+                }
+
+                trace!("Segment {} CRC {:#x} valid", segments, actual);
+                segments += 1;
+                segment_start = record.offset + record.length;
+                unverified = false;
+            }
+            RecordKind::Signature => signature = Some(record),
+        }
+    }
+
+    if data_size == 0 {
+        error!("Payload has no data");
+        return Err(Error::InvalidPayload);
+    }
+
+    if unverified {
+        error!("Payload data after the last segment CRC");
+        return Err(Error::MissingSegmentCrc);
+    }
+
+    if config.require_signature && signature.is_none() {
+        error!("Payload is not signed");
+        return Err(Error::MissingSignature);
+    }
+
+    Ok(ImageInfo {
+        offer,
+        data_size,
+        segments,
+        signature,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::host::{MemoryImage, OutOfBounds};
+
+    /// Serialize an offer file
+    pub(crate) fn offer_file(component_id: ComponentId, version: u32) -> [u8; OFFER_SIZE] {
+        let mut raw = [0u8; OFFER_SIZE];
+        raw[2] = component_id;
+        raw[3] = TOKEN_DRIVER;
+        raw[4..8].copy_from_slice(&version.to_le_bytes());
+        raw
+    }
+
+    /// Append a record to a payload
+    pub(crate) fn push_record(payload: &mut Vec<u8>, address: u32, data: &[u8]) {
+        payload.extend_from_slice(&address.to_le_bytes());
+        payload.push(data.len() as u8);
+        payload.extend_from_slice(data);
+    }
+
+    /// Append a segment CRC record covering the payload from `segment_start`
+    pub(crate) fn push_segment_crc(payload: &mut Vec<u8>, segment_start: usize) {
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&payload[segment_start..]);
+        push_record(payload, SEGMENT_CRC_ADDRESS, &crc.to_le_bytes());
+    }
+
+    /// Payload with consecutive data records of the given lengths, covered by a single segment CRC
+    pub(crate) fn payload(lengths: &[usize]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let mut address = 0x1000;
+        for (i, length) in lengths.iter().enumerate() {
+            let data: Vec<u8> = (0..*length).map(|b| (b + i) as u8).collect();
+            push_record(&mut payload, address, &data);
+            address += *length as u32;
+        }
+        push_segment_crc(&mut payload, 0);
+        payload
+    }
+
+    fn validate_image(offer: &[u8], payload: &[u8], config: &Config) -> Result<ImageInfo, Error<OutOfBounds>> {
+        embassy_futures::block_on(validate(
+            &mut MemoryImage::new(offer),
+            &mut MemoryImage::new(payload),
+            config,
+        ))
+    }
+
+    #[test]
+    fn test_good_image() {
+        let offer = offer_file(0x10, 0x0102_0300);
+        let mut payload = payload(&[100, 20]);
+
+        let info = validate_image(&offer, &payload, &Config::default()).unwrap();
+        assert_eq!(
+            info.offer,
+            FwUpdateOffer::new(HostToken::Driver, 0x10, FwVersion::new(0x0102_0300), 0, 0)
+        );
+        assert_eq!(info.data_size, 120);
+        assert_eq!(info.segments, 1);
+        assert_eq!(info.signature, None);
+
+        // Erased flash after the payload ends it
+        payload.extend_from_slice(&[0xff; 16]);
+        assert_eq!(validate_image(&offer, &payload, &Config::default()), Ok(info));
+
+        // Signed image, with a second segment
+        let mut payload = self::payload(&[30]);
+        let segment_start = payload.len();
+        push_record(&mut payload, 0x2000, &[0x5a; 10]);
+        push_segment_crc(&mut payload, segment_start);
+        let signature_offset = payload.len();
+        push_record(&mut payload, SIGNATURE_ADDRESS, &[0xa5; 64]);
+
+        let config = Config {
+            require_signature: true,
+        };
+        let info = validate_image(&offer, &payload, &config).unwrap();
+        assert_eq!(info.data_size, 40);
+        assert_eq!(info.segments, 2);
+        assert_eq!(
+            info.signature,
+            Some(Record {
+                kind: RecordKind::Signature,
+                address: SIGNATURE_ADDRESS,
+                header_offset: signature_offset,
+                offset: signature_offset + RECORD_HEADER_SIZE,
+                length: 64,
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_image() {
+        let offer = offer_file(0x10, 0x100);
+        let payload = payload(&[100, 20]);
+
+        // Cut in the middle of the segment CRC record
+        assert_eq!(
+            validate_image(&offer, &payload[..payload.len() - 2], &Config::default()),
+            Err(Error::InvalidPayload)
+        );
+
+        // Cut before the segment CRC record, the data isn't covered
+        let end = payload.len() - RECORD_HEADER_SIZE - 4;
+        assert_eq!(
+            validate_image(&offer, &payload[..end], &Config::default()),
+            Err(Error::MissingSegmentCrc)
+        );
+
+        // Cut inside the first record header, no data at all
+        assert_eq!(
+            validate_image(&offer, &payload[..3], &Config::default()),
+            Err(Error::InvalidPayload)
+        );
+
+        // Truncated offer
+        assert_eq!(
+            validate_image(&offer[..OFFER_SIZE - 1], &payload, &Config::default()),
+            Err(Error::InvalidOffer)
+        );
+
+        // Unsigned image when a signature is required
+        let config = Config {
+            require_signature: true,
+        };
+        assert_eq!(validate_image(&offer, &payload, &config), Err(Error::MissingSignature));
+    }
+
+    #[test]
+    fn test_bad_crc() {
+        let offer = offer_file(0x10, 0x100);
+        let mut payload = payload(&[100]);
+        let segment_start = payload.len();
+        push_record(&mut payload, 0x2000, &[0x5a; 10]);
+        push_segment_crc(&mut payload, segment_start);
+
+        // Corrupt data in the second segment
+        payload[segment_start + RECORD_HEADER_SIZE] ^= 0x01;
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()),
+            Err(Error::CrcMismatch(1))
+        );
+
+        // Corrupt a record header in the first segment
+        payload[segment_start + RECORD_HEADER_SIZE] ^= 0x01;
+        payload[0] ^= 0x01;
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()),
+            Err(Error::CrcMismatch(0))
+        );
+    }
+
+    #[test]
+    fn test_record_order() {
+        let offer = offer_file(0x10, 0x100);
+
+        // Gaps between records are fine
+        let mut payload = Vec::new();
+        push_record(&mut payload, 0x1000, &[0x11; 16]);
+        push_record(&mut payload, 0x2000, &[0x22; 16]);
+        push_segment_crc(&mut payload, 0);
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()).unwrap().data_size,
+            32
+        );
+
+        // Overlapping records
+        let mut payload = Vec::new();
+        push_record(&mut payload, 0x1000, &[0x11; 16]);
+        let second = payload.len();
+        push_record(&mut payload, 0x100f, &[0x22; 16]);
+        push_segment_crc(&mut payload, 0);
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()),
+            Err(Error::OutOfOrder(second))
+        );
+
+        // Descending records, even across segments
+        let mut payload = Vec::new();
+        push_record(&mut payload, 0x2000, &[0x11; 16]);
+        push_segment_crc(&mut payload, 0);
+        let second = payload.len();
+        push_record(&mut payload, 0x1000, &[0x22; 16]);
+        push_segment_crc(&mut payload, second);
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()),
+            Err(Error::OutOfOrder(second))
+        );
+    }
+
+    #[test]
+    fn test_bad_offer() {
+        let payload = payload(&[10]);
+
+        // Component ID 0 is reserved
+        let offer = offer_file(0, 0x100);
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()),
+            Err(Error::InvalidOffer)
+        );
+
+        // Unknown host token
+        let mut offer = offer_file(0x10, 0x100);
+        offer[3] = 0x42;
+        assert_eq!(
+            validate_image(&offer, &payload, &Config::default()),
+            Err(Error::InvalidOffer)
+        );
+    }
+
+    #[test]
+    fn test_offer_flags() {
+        let mut raw = offer_file(0x10, 0x100);
+        raw[0] = 2;
+        raw[1] = OFFER_FLAG_FORCE_IGNORE_VERSION;
+        raw[3] = TOKEN_TOOL;
+        raw[8..12].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        raw[12..16].copy_from_slice(&0x9abc_def0u32.to_le_bytes());
+
+        let mut expected = FwUpdateOffer::new(HostToken::Tool, 0x10, FwVersion::new(0x100), 0x1234_5678, 0x9abc_def0);
+        expected.component_info.segment_number = 2;
+        expected.component_info.force_ignore_version = true;
+        let offer = embassy_futures::block_on(parse_offer(&mut MemoryImage::new(&raw))).unwrap();
+        assert_eq!(offer, expected);
+
+        raw[1] = OFFER_FLAG_FORCE_IMMEDIATE_RESET;
+        let offer = embassy_futures::block_on(parse_offer(&mut MemoryImage::new(&raw))).unwrap();
+        assert!(!offer.component_info.force_ignore_version);
+        assert!(offer.component_info.force_immediate_reset);
+    }
+}
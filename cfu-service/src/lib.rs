@@ -1,22 +1,56 @@
 #![no_std]
 
+use embassy_sync::mutex::Mutex;
 use embassy_sync::once_lock::OnceLock;
 use embedded_cfu_protocol::client::CfuReceiveContent;
 use embedded_cfu_protocol::components::CfuComponentTraits;
 use embedded_cfu_protocol::protocol_definitions::*;
 use embedded_services::cfu::component::*;
-use embedded_services::cfu::{CfuError, ContextToken};
-use embedded_services::{comms, error, info, trace};
+use embedded_services::cfu::{self, CfuError, ContextToken, progress};
+use embedded_services::{GlobalRawMutex, comms, debug, error, info, trace, warn};
 
 pub mod buffer;
+pub mod dual_bank;
 pub mod host;
+pub mod image;
+pub mod persistent_buffer;
 pub mod splitter;
+pub mod verify;
+
+/// State of the update session with the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum UpdateState {
+    /// No offer accepted
+    Idle,
+    /// An offer was accepted by the component, waiting for the first content block
+    OfferAccepted(ComponentId),
+    /// Receiving content for the component
+    ReceivingContent {
+        /// Component being updated
+        component: ComponentId,
+        /// Sequence number expected for the next content block
+        next_sequence: u16,
+    },
+}
+
+impl UpdateState {
+    /// Component currently being updated, if any
+    fn component(&self) -> Option<ComponentId> {
+        match self {
+            UpdateState::Idle => None,
+            UpdateState::OfferAccepted(component) | UpdateState::ReceivingContent { component, .. } => Some(*component),
+        }
+    }
+}
 
 pub struct CfuClient {
     /// Cfu Client context
     context: ContextToken,
     /// Comms endpoint
     tp: comms::Endpoint,
+    /// Update session state
+    state: Mutex<GlobalRawMutex, UpdateState>,
 }
 
 impl<T, C> CfuReceiveContent<T, C, ()> for CfuClient {
@@ -41,43 +75,273 @@ impl CfuClient {
         Some(Self {
             context: ContextToken::create()?,
             tp: comms::Endpoint::uninit(comms::EndpointID::Internal(comms::Internal::Nonvol)),
+            state: Mutex::new(UpdateState::Idle),
         })
     }
+
+    /// Create an offer rejection response
+    fn create_offer_rejection(reason: OfferRejectReason) -> InternalResponseData {
+        InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_with_failure(
+            HostToken::Driver,
+            reason,
+            OfferStatus::Reject,
+        ))
+    }
+
+    /// Create a content response with the given status
+    fn create_content_response(sequence: u16, status: CfuUpdateContentResponseStatus) -> InternalResponseData {
+        InternalResponseData::ContentResponse(FwUpdateContentResponse::new(sequence, status))
+    }
+
+    /// Response sent to the host when a request could not be processed
+    fn create_failure_response(comp: ComponentId, request: &RequestData) -> InternalResponseData {
+        match request {
+            RequestData::FwVersionRequest => InternalResponseData::invalid_fw_version(comp),
+            RequestData::GiveOffer(_) => Self::create_offer_rejection(OfferRejectReason::InvalidComponent),
+            RequestData::GiveContent(content) => Self::create_content_response(
+                content.header.sequence_num,
+                CfuUpdateContentResponseStatus::ErrorInvalid,
+            ),
+            RequestData::PrepareComponentForUpdate | RequestData::FinalizeUpdate => InternalResponseData::ComponentBusy,
+        }
+    }
+
+    /// Get the version of a component
+    async fn process_get_fw_version(&self, comp: ComponentId) -> Result<InternalResponseData, CfuError> {
+        info!("Received FwVersionRequest, comp {}", comp);
+        let resp = cfu::route_request(comp, RequestData::FwVersionRequest).await?;
+
+        match resp {
+            InternalResponseData::FwVersionResponse(r) => {
+                let ver = r.component_info[0].fw_version;
+                info!("got fw version {:?} for comp {}", ver, comp);
+                Ok(resp)
+            }
+            _ => {
+                error!("Invalid response to get fw version {:?} from comp {}", resp, comp);
+                Err(CfuError::ProtocolError(CfuProtocolError::BadResponse))
+            }
+        }
+    }
+
+    /// Route an offer to the component it targets
+    async fn process_give_offer(&self, offer: &FwUpdateOffer) -> Result<InternalResponseData, CfuError> {
+        let comp = offer.component_info.component_id;
+        let mut state = self.state.lock().await;
+
+        if let Some(current) = state.component() {
+            if current != comp {
+                warn!("Offer for comp {} while comp {} is updating", comp, current);
+                return Ok(InternalResponseData::ComponentBusy);
+            }
+        }
+
+        // A new offer always restarts the session for this component
+        *state = UpdateState::Idle;
+        let resp = cfu::route_request(comp, RequestData::GiveOffer(*offer)).await?;
+
+        match resp {
+            InternalResponseData::OfferResponse(r) => {
+                if r.status == OfferStatus::Accept {
+                    info!("Comp {} accepted offer", comp);
+                    *state = UpdateState::OfferAccepted(comp);
+                    progress::publish(comp, progress::Update::OfferAccepted).await;
+                } else {
+                    info!("Comp {} did not accept offer: {:?}", comp, r.status);
+                    progress::publish(comp, progress::Update::OfferRejected(r.reject_reason)).await;
+                }
+                Ok(resp)
+            }
+            InternalResponseData::ComponentBusy => Ok(resp),
+            _ => {
+                error!("Invalid response to offer {:?} from comp {}", resp, comp);
+                Err(CfuError::ProtocolError(CfuProtocolError::BadResponse))
+            }
+        }
+    }
+
+    /// Sequence a content block and route it to the component with an accepted offer
+    async fn process_give_content(&self, content: &FwUpdateContentCommand) -> Result<InternalResponseData, CfuError> {
+        let sequence = content.header.sequence_num;
+        let first_block = content.header.flags & FW_UPDATE_FLAG_FIRST_BLOCK != 0;
+        let last_block = content.header.flags & FW_UPDATE_FLAG_LAST_BLOCK != 0;
+        let mut state = self.state.lock().await;
+
+        let comp = match (*state, first_block) {
+            (UpdateState::OfferAccepted(component), true) => component,
+            (UpdateState::ReceivingContent { component, .. }, true) => {
+                warn!("Comp {}: update restarted by host", component);
+                component
+            }
+            (UpdateState::ReceivingContent { next_sequence, .. }, false)
+                if sequence.wrapping_add(1) == next_sequence =>
+            {
+                // Host retried a block that was already written, acknowledge it again
+                debug!("Duplicate content block {}", sequence);
+                return Ok(Self::create_content_response(
+                    sequence,
+                    CfuUpdateContentResponseStatus::Success,
+                ));
+            }
+            (
+                UpdateState::ReceivingContent {
+                    component,
+                    next_sequence,
+                },
+                false,
+            ) if sequence == next_sequence => component,
+            (UpdateState::ReceivingContent { next_sequence, .. }, false) => {
+                error!("Out of order content block {}, expected {}", sequence, next_sequence);
+                *state = UpdateState::Idle;
+                return Ok(Self::create_content_response(
+                    sequence,
+                    CfuUpdateContentResponseStatus::ErrorInvalid,
+                ));
+            }
+            (UpdateState::OfferAccepted(_), false) | (UpdateState::Idle, _) => {
+                error!("Content block {} without an accepted offer", sequence);
+                *state = UpdateState::Idle;
+                return Ok(Self::create_content_response(
+                    sequence,
+                    CfuUpdateContentResponseStatus::ErrorInvalid,
+                ));
+            }
+        };
+
+        let resp = match cfu::route_request(comp, RequestData::GiveContent(*content)).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                *state = UpdateState::Idle;
+                return Err(e);
+            }
+        };
+
+        match resp {
+            InternalResponseData::ContentResponse(r) if r.status == CfuUpdateContentResponseStatus::Success => {
+                *state = if last_block {
+                    info!("Comp {}: received last content block", comp);
+                    UpdateState::Idle
+                } else {
+                    UpdateState::ReceivingContent {
+                        component: comp,
+                        next_sequence: sequence.wrapping_add(1),
+                    }
+                };
+                progress::publish(
+                    comp,
+                    progress::Update::ContentWritten {
+                        sequence,
+                        length: content.header.data_length,
+                        last_block,
+                    },
+                )
+                .await;
+                Ok(resp)
+            }
+            InternalResponseData::ContentResponse(r) => {
+                error!("Comp {}: content block {} failed: {:?}", comp, sequence, r.status);
+                *state = UpdateState::Idle;
+                progress::publish(
+                    comp,
+                    progress::Update::ContentRejected {
+                        sequence,
+                        status: r.status,
+                    },
+                )
+                .await;
+                Ok(resp)
+            }
+            InternalResponseData::ComponentBusy => Ok(resp),
+            _ => {
+                error!("Invalid response to content {:?} from comp {}", resp, comp);
+                *state = UpdateState::Idle;
+                Err(CfuError::ProtocolError(CfuProtocolError::BadResponse))
+            }
+        }
+    }
+
+    /// Finalize an update, ending the session
+    ///
+    /// Finalizing before the last content block would commit a partial image, so the session is aborted instead.
+    async fn process_finalize_update(&self, comp: ComponentId) -> Result<InternalResponseData, CfuError> {
+        info!("Finalizing update for comp {}", comp);
+        let mut state = self.state.lock().await;
+        match state.component() {
+            Some(current) if current != comp => {
+                warn!("Finalize for comp {} while comp {} is updating", comp, current);
+                return Ok(InternalResponseData::ComponentBusy);
+            }
+            Some(_) => {
+                error!("Comp {}: finalize before the last content block, aborting update", comp);
+                *state = UpdateState::Idle;
+                progress::publish(comp, progress::Update::Finalized { success: false }).await;
+                return Ok(InternalResponseData::ComponentBusy);
+            }
+            None => {}
+        }
+
+        let resp = cfu::route_request(comp, RequestData::FinalizeUpdate).await;
+        let success = matches!(resp, Ok(InternalResponseData::ComponentPrepared));
+        progress::publish(comp, progress::Update::Finalized { success }).await;
+        resp
+    }
+
     pub async fn process_request(&self) -> Result<(), CfuError> {
         let request = self.context.wait_request().await;
-        //let device = self.context.get_device(request.id).await?;
         let comp = request.id;
 
-        match request.data {
-            RequestData::FwVersionRequest => {
-                info!("Received FwVersionRequest, comp {}", comp);
-                if let Ok(device) = self.context.get_device(comp).await {
-                    let resp = device
-                        .execute_device_request(request.data)
-                        .await
-                        .map_err(CfuError::ProtocolError)?;
-
-                    // TODO replace with signal to component to get its own fw version
-                    //cfu::send_request(comp, RequestData::FwVersionRequest).await?;
-                    match resp {
-                        InternalResponseData::FwVersionResponse(r) => {
-                            let ver = r.component_info[0].fw_version;
-                            info!("got fw version {:?} for comp {}", ver, comp);
-                        }
-                        _ => {
-                            error!("Invalid response to get fw version {:?} from comp {}", resp, comp);
-                            return Err(CfuError::ProtocolError(CfuProtocolError::BadResponse));
-                        }
+        let result = match request.data {
+            RequestData::FwVersionRequest => self.process_get_fw_version(comp).await,
+            RequestData::GiveOffer(offer) => self.process_give_offer(&offer).await,
+            RequestData::GiveContent(content) => self.process_give_content(&content).await,
+            RequestData::PrepareComponentForUpdate => prepare_component(comp).await,
+            RequestData::FinalizeUpdate => self.process_finalize_update(comp).await,
+        };
+
+        match result {
+            Ok(resp) => {
+                self.context.send_response(resp).await;
+                Ok(())
+            }
+            Err(e) => {
+                // Always answer so the host doesn't wait forever
+                self.context
+                    .send_response(Self::create_failure_response(comp, &request.data))
+                    .await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Prepare a component, and any sub-components it needs prepared, for an update
+pub(crate) async fn prepare_component(comp: ComponentId) -> Result<InternalResponseData, CfuError> {
+    info!("Preparing comp {} for update", comp);
+    let resp = cfu::route_request(comp, RequestData::PrepareComponentForUpdate).await?;
+
+    match resp {
+        InternalResponseData::ComponentPrepared | InternalResponseData::ComponentBusy => Ok(resp),
+        InternalResponseData::PrimaryNeedsSubcomponentsPrepared(subcomponents) => {
+            // Unused entries are zero
+            for sub in subcomponents.iter().copied().filter(|sub| *sub != 0) {
+                debug!("Comp {}: preparing sub-component {}", comp, sub);
+                match cfu::route_request(sub, RequestData::PrepareComponentForUpdate).await? {
+                    InternalResponseData::ComponentPrepared => {}
+                    InternalResponseData::ComponentBusy => {
+                        warn!("Comp {}: sub-component {} is busy", comp, sub);
+                        return Ok(InternalResponseData::ComponentBusy);
+                    }
+                    sub_resp => {
+                        error!("Invalid response to prepare {:?} from sub-component {}", sub_resp, sub);
+                        return Err(CfuError::ProtocolError(CfuProtocolError::BadResponse));
                     }
-                    self.context.send_response(resp).await;
-                    return Ok(());
                 }
-                Err(CfuError::InvalidComponent)
             }
-            RequestData::GiveContent(_content_cmd) => Ok(()),
-            RequestData::GiveOffer(_offer_cmd) => Ok(()),
-            RequestData::PrepareComponentForUpdate => Ok(()),
-            RequestData::FinalizeUpdate => Ok(()),
+            Ok(InternalResponseData::ComponentPrepared)
+        }
+        _ => {
+            error!("Invalid response to prepare {:?} from comp {}", resp, comp);
+            Err(CfuError::ProtocolError(CfuProtocolError::BadResponse))
         }
     }
 }
@@ -101,3 +365,320 @@ pub async fn task() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+    use std::boxed::Box;
+    use std::sync::{Mutex as StdMutex, MutexGuard, OnceLock as StdOnceLock};
+
+    use embassy_futures::block_on;
+    use embassy_futures::join::join_array;
+    use embassy_futures::select::{Either, select};
+
+    use super::*;
+
+    /// Component ID of the mock device
+    const COMPONENT: ComponentId = 0x20;
+
+    /// Component ID of the mock primary component
+    const PRIMARY: ComponentId = 0x21;
+    /// Component IDs of the mock sub-components of the primary component
+    const SUBCOMPONENTS: [ComponentId; 2] = [0x22, 0x23];
+
+    /// Mock sub-component behaviors
+    const PREPARED: u8 = 0;
+    const BUSY: u8 = 1;
+    const BAD_RESPONSE: u8 = 2;
+
+    /// Response of the last sub-component to prepare requests
+    static LAST_SUBCOMPONENT: AtomicU8 = AtomicU8::new(PREPARED);
+    /// Prepare requests that reached each sub-component
+    static SUBCOMPONENT_PREPARES: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+
+    /// Content requests that reached the mock device
+    static CONTENT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    /// Finalize requests that reached the mock device
+    static FINALIZE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Serialize tests, the CFU service and update progress tracking are shared by the whole crate
+    pub(crate) fn lock() -> MutexGuard<'static, ()> {
+        static LOCK: StdMutex<()> = StdMutex::new(());
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        block_on(embedded_services::init());
+        guard
+    }
+
+    /// Client shared by all tests
+    fn client() -> (&'static CfuClient, &'static CfuDevice, MutexGuard<'static, ()>) {
+        static CLIENT: StdOnceLock<(&'static CfuClient, &'static CfuDevice)> = StdOnceLock::new();
+
+        let guard = lock();
+        let (client, device) = *CLIENT.get_or_init(|| {
+            let device: &'static CfuDevice = Box::leak(Box::new(CfuDevice::new(COMPONENT)));
+            block_on(cfu::register_device(device)).unwrap();
+            (Box::leak(Box::new(CfuClient::create().unwrap())), device)
+        });
+
+        CONTENT_REQUESTS.store(0, Ordering::SeqCst);
+        FINALIZE_REQUESTS.store(0, Ordering::SeqCst);
+        (client, device, guard)
+    }
+
+    /// Mock device that accepts every request
+    async fn device_task(device: &CfuDevice) {
+        loop {
+            let response = match device.wait_request().await {
+                RequestData::FwVersionRequest => InternalResponseData::invalid_fw_version(COMPONENT),
+                RequestData::GiveOffer(_) => {
+                    InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_accept(HostToken::Driver))
+                }
+                RequestData::GiveContent(content) => {
+                    CONTENT_REQUESTS.fetch_add(1, Ordering::SeqCst);
+                    CfuClient::create_content_response(
+                        content.header.sequence_num,
+                        CfuUpdateContentResponseStatus::Success,
+                    )
+                }
+                RequestData::PrepareComponentForUpdate => InternalResponseData::ComponentPrepared,
+                RequestData::FinalizeUpdate => {
+                    FINALIZE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+                    InternalResponseData::ComponentPrepared
+                }
+            };
+            device.send_response(response).await;
+        }
+    }
+
+    /// Run a scenario against the client with the mock device answering requests
+    fn run(device: &CfuDevice, scenario: impl core::future::Future<Output = ()>) {
+        match block_on(select(device_task(device), scenario)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    }
+
+    fn offer() -> FwUpdateOffer {
+        FwUpdateOffer::new(HostToken::Driver, COMPONENT, FwVersion::new(0x211), 0, 0)
+    }
+
+    fn content(sequence: u16, flags: u8) -> FwUpdateContentCommand {
+        FwUpdateContentCommand {
+            header: FwUpdateContentHeader {
+                data_length: DEFAULT_DATA_LENGTH as u8,
+                sequence_num: sequence,
+                firmware_address: sequence as u32 * DEFAULT_DATA_LENGTH as u32,
+                flags,
+            },
+            data: [0u8; DEFAULT_DATA_LENGTH],
+        }
+    }
+
+    /// Content status returned for a block
+    async fn give_content(client: &CfuClient, sequence: u16, flags: u8) -> CfuUpdateContentResponseStatus {
+        match client.process_give_content(&content(sequence, flags)).await {
+            Ok(InternalResponseData::ContentResponse(r)) => r.status,
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+    }
+
+    async fn give_offer(client: &CfuClient) {
+        match client.process_give_offer(&offer()).await {
+            Ok(InternalResponseData::OfferResponse(r)) => assert_eq!(r.status, OfferStatus::Accept),
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+    }
+
+    #[test]
+    fn test_content_without_offer() {
+        let (client, device, _guard) = client();
+        run(device, async {
+            // No offer at all
+            assert_eq!(
+                give_content(client, 0, FW_UPDATE_FLAG_FIRST_BLOCK).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+
+            // Offer accepted but the first block is skipped
+            give_offer(client).await;
+            assert_eq!(
+                give_content(client, 1, 0).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+
+            // The session was reset, even the first block needs a new offer
+            assert_eq!(
+                give_content(client, 0, FW_UPDATE_FLAG_FIRST_BLOCK).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(*client.state.lock().await, UpdateState::Idle);
+        });
+        assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_out_of_order_content() {
+        let (client, device, _guard) = client();
+        run(device, async {
+            give_offer(client).await;
+            assert_eq!(
+                give_content(client, 0, FW_UPDATE_FLAG_FIRST_BLOCK).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            assert_eq!(
+                give_content(client, 1, 0).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+
+            // A retry of the previous block is acknowledged without being written again
+            assert_eq!(
+                give_content(client, 1, 0).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            assert_eq!(
+                *client.state.lock().await,
+                UpdateState::ReceivingContent {
+                    component: COMPONENT,
+                    next_sequence: 2,
+                }
+            );
+
+            // Skipping a block ends the session
+            assert_eq!(
+                give_content(client, 3, 0).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(*client.state.lock().await, UpdateState::Idle);
+            assert_eq!(
+                give_content(client, 2, 0).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+        });
+        assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_finalize_before_last_block() {
+        let (client, device, _guard) = client();
+        run(device, async {
+            give_offer(client).await;
+            assert_eq!(
+                give_content(client, 0, FW_UPDATE_FLAG_FIRST_BLOCK).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+
+            // Finalizing mid-stream aborts the update without reaching the component
+            assert_eq!(
+                client.process_finalize_update(COMPONENT).await,
+                Ok(InternalResponseData::ComponentBusy)
+            );
+            assert_eq!(*client.state.lock().await, UpdateState::Idle);
+            assert_eq!(
+                give_content(client, 1, 0).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 0);
+
+            // A complete update is finalized
+            give_offer(client).await;
+            assert_eq!(
+                give_content(client, 0, FW_UPDATE_FLAG_FIRST_BLOCK).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            assert_eq!(
+                give_content(client, 1, FW_UPDATE_FLAG_LAST_BLOCK).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            assert_eq!(
+                client.process_finalize_update(COMPONENT).await,
+                Ok(InternalResponseData::ComponentPrepared)
+            );
+        });
+        assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 1);
+    }
+
+    /// Mock primary component and its sub-components, shared by all tests
+    fn hierarchy() -> [&'static CfuDevice; 3] {
+        static DEVICES: StdOnceLock<[&'static CfuDevice; 3]> = StdOnceLock::new();
+
+        *DEVICES.get_or_init(|| {
+            [PRIMARY, SUBCOMPONENTS[0], SUBCOMPONENTS[1]].map(|id| {
+                let device: &'static CfuDevice = Box::leak(Box::new(CfuDevice::new(id)));
+                block_on(cfu::register_device(device)).unwrap();
+                device
+            })
+        })
+    }
+
+    /// Mock component of the hierarchy, only answers prepare requests
+    async fn hierarchy_task(device: &CfuDevice) {
+        loop {
+            let request = device.wait_request().await;
+            let id = device.component_id();
+            let response = match request {
+                RequestData::PrepareComponentForUpdate if id == PRIMARY => {
+                    let mut subcomponents = [0; MAX_CMPT_COUNT - 1];
+                    subcomponents[..SUBCOMPONENTS.len()].copy_from_slice(&SUBCOMPONENTS);
+                    InternalResponseData::PrimaryNeedsSubcomponentsPrepared(subcomponents)
+                }
+                RequestData::PrepareComponentForUpdate => {
+                    let index = SUBCOMPONENTS.iter().position(|sub| *sub == id).unwrap();
+                    SUBCOMPONENT_PREPARES[index].fetch_add(1, Ordering::SeqCst);
+                    match LAST_SUBCOMPONENT.load(Ordering::SeqCst) {
+                        BUSY if index == 1 => InternalResponseData::ComponentBusy,
+                        BAD_RESPONSE if index == 1 => InternalResponseData::invalid_fw_version(id),
+                        _ => InternalResponseData::ComponentPrepared,
+                    }
+                }
+                _ => InternalResponseData::invalid_fw_version(id),
+            };
+            device.send_response(response).await;
+        }
+    }
+
+    fn subcomponent_prepares() -> [usize; 2] {
+        core::array::from_fn(|i| SUBCOMPONENT_PREPARES[i].swap(0, Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_prepare_subcomponents() {
+        let _guard = lock();
+        let devices = hierarchy().map(hierarchy_task);
+        let scenario = async {
+            // Every sub-component is prepared before the primary reports ready
+            LAST_SUBCOMPONENT.store(PREPARED, Ordering::SeqCst);
+            assert_eq!(
+                prepare_component(PRIMARY).await,
+                Ok(InternalResponseData::ComponentPrepared)
+            );
+            assert_eq!(subcomponent_prepares(), [1, 1]);
+
+            // A busy sub-component makes the whole component busy
+            LAST_SUBCOMPONENT.store(BUSY, Ordering::SeqCst);
+            assert_eq!(
+                prepare_component(PRIMARY).await,
+                Ok(InternalResponseData::ComponentBusy)
+            );
+            assert_eq!(subcomponent_prepares(), [1, 1]);
+
+            // An unexpected response from a sub-component is a protocol error
+            LAST_SUBCOMPONENT.store(BAD_RESPONSE, Ordering::SeqCst);
+            assert_eq!(
+                prepare_component(PRIMARY).await,
+                Err(CfuError::ProtocolError(CfuProtocolError::BadResponse))
+            );
+            assert_eq!(subcomponent_prepares(), [1, 1]);
+
+            // Components that aren't registered fail the prepare
+            assert!(prepare_component(0x2f).await.is_err());
+        };
+
+        match block_on(select(join_array(devices), scenario)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+        LAST_SUBCOMPONENT.store(PREPARED, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,922 @@
+//! Flash-backed CFU content buffer
+//!
+//! [`PersistentBuffer`] is a variant of [`crate::buffer::Buffer`] that spools content to a scratch partition instead
+//! of RAM. Content is acknowledged to the host as soon as it is in flash and replayed to the buffered component at
+//! its own pace. Each spooled entry is marked once the component accepts it, [`PersistentBuffer::recover`] reports how
+//! far the component got before a reset.
+//!
+//! A reset also ends the component's update session, so nothing is replayed until the host restarts the update. Its
+//! offer is forwarded to the component as usual and when its first block matches the spooled update, the update is
+//! resumed: the first block is replayed to start a new session on the component, then replay continues after the last
+//! content the component accepted before the reset. Content that is already spooled is acknowledged to the host
+//! without being written again.
+//!
+//! Unlike the RAM buffer, component errors are not lost: once replay fails every following content request and
+//! [`RequestData::FinalizeUpdate`] is rejected. [`RequestData::FinalizeUpdate`] is only forwarded once all spooled
+//! content has been replayed, until then the component is reported busy.
+//!
+//! The scratch partition must be large enough to hold the whole update, see [`ENTRY_SIZE`].
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::{mutex::Mutex, signal::Signal};
+use embedded_cfu_protocol::protocol_definitions::*;
+use embedded_services::{
+    GlobalRawMutex,
+    cfu::{
+        self,
+        component::{CfuDevice, InternalResponseData, RequestData},
+    },
+    error, info, intrusive_list, trace, warn,
+};
+use embedded_storage_async::nor_flash::NorFlash;
+
+/// Entry magic value, distinguishes entries from erased or garbage flash
+const ENTRY_MAGIC: u16 = 0x5C0F;
+
+/// Size of the entry header
+const ENTRY_HEADER_SIZE: usize = 12;
+
+/// Size of the acknowledgement marker, written separately from the rest of the entry
+const ACK_SIZE: usize = 16;
+
+/// Offset of the acknowledgement marker in an entry
+const ACK_OFFSET: usize = (ENTRY_HEADER_SIZE + DEFAULT_DATA_LENGTH).next_multiple_of(ACK_SIZE);
+
+/// Size of a spooled content entry in flash
+pub const ENTRY_SIZE: usize = ACK_OFFSET + ACK_SIZE;
+
+/// Spool errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Partition size or flash alignment aren't supported
+    InvalidGeometry,
+    /// Underlying flash returned an error
+    Flash(E),
+    /// Spool is full
+    Full,
+    /// Content doesn't match the update being resumed
+    Mismatch,
+}
+
+/// Spooled entry
+struct Entry {
+    content: FwUpdateContentCommand,
+    acked: bool,
+}
+
+fn entry_crc(header: &[u8], data: &[u8]) -> u16 {
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+    let mut digest = crc.digest();
+    digest.update(header);
+    digest.update(data);
+    digest.finalize()
+}
+
+fn encode(content: &FwUpdateContentCommand) -> [u8; ACK_OFFSET] {
+    let mut entry = [0xFF; ACK_OFFSET];
+    let header = &content.header;
+    entry[0..2].copy_from_slice(&ENTRY_MAGIC.to_le_bytes());
+    entry[2] = header.flags;
+    entry[3] = header.data_length;
+    entry[4..6].copy_from_slice(&header.sequence_num.to_le_bytes());
+    entry[6..10].copy_from_slice(&header.firmware_address.to_le_bytes());
+    entry[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + DEFAULT_DATA_LENGTH].copy_from_slice(&content.data);
+    let crc = entry_crc(&entry[0..10], &content.data);
+    entry[10..12].copy_from_slice(&crc.to_le_bytes());
+    entry
+}
+
+fn decode(entry: &[u8; ENTRY_SIZE]) -> Option<Entry> {
+    let magic = u16::from_le_bytes([entry[0], entry[1]]);
+    let crc = u16::from_le_bytes([entry[10], entry[11]]);
+    let data = &entry[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + DEFAULT_DATA_LENGTH];
+    if magic != ENTRY_MAGIC || crc != entry_crc(&entry[0..10], data) {
+        return None;
+    }
+
+    let mut content = FwUpdateContentCommand {
+        header: FwUpdateContentHeader {
+            flags: entry[2],
+            data_length: entry[3],
+            sequence_num: u16::from_le_bytes([entry[4], entry[5]]),
+            firmware_address: u32::from_le_bytes([entry[6], entry[7], entry[8], entry[9]]),
+        },
+        data: [0; DEFAULT_DATA_LENGTH],
+    };
+    content.data.copy_from_slice(data);
+
+    Some(Entry {
+        content,
+        acked: entry[ACK_OFFSET..].iter().any(|b| *b != 0xFF),
+    })
+}
+
+/// Content log in a flash partition
+///
+/// Entries are written sequentially and the partition is only erased when a new update starts. An entry torn by a
+/// power loss fails its CRC and is skipped.
+pub struct Spool<F: NorFlash> {
+    flash: F,
+    /// Number of entries the partition can hold
+    capacity: u32,
+    /// Number of entries written, including torn ones
+    written: u32,
+    /// Next entry to compare against or write for the host
+    cursor: u32,
+    /// Next entry to replay to the component
+    replay: u32,
+    /// Number of leading entries already marked as accepted
+    acked: u32,
+    /// Entry replay continues from once the first block of a resumed update is accepted
+    resume: u32,
+    /// Sequence number of the last entry the component accepted
+    last_acked: Option<u16>,
+}
+
+impl<F: NorFlash> Spool<F> {
+    /// Create a new spool
+    ///
+    /// [`ENTRY_SIZE`] must be a multiple of the flash read and write sizes.
+    pub fn new(flash: F) -> Result<Self, Error<F::Error>> {
+        let capacity = flash.capacity();
+        if ACK_SIZE % F::WRITE_SIZE != 0 || ACK_SIZE % F::READ_SIZE != 0 || capacity % F::ERASE_SIZE != 0 {
+            return Err(Error::InvalidGeometry);
+        }
+
+        Ok(Self {
+            flash,
+            capacity: (capacity / ENTRY_SIZE) as u32,
+            written: 0,
+            cursor: 0,
+            replay: 0,
+            acked: 0,
+            resume: 0,
+            last_acked: None,
+        })
+    }
+
+    /// Scan the partition for an interrupted update
+    ///
+    /// Returns the sequence number of the last content the component accepted, if any. Nothing is replayed until the
+    /// update is resumed with [`Self::begin`].
+    pub async fn recover(&mut self) -> Result<Option<u16>, Error<F::Error>> {
+        self.written = 0;
+        self.cursor = 0;
+        self.acked = 0;
+        self.resume = 0;
+        self.last_acked = None;
+
+        for index in 0..self.capacity {
+            let raw = self.read_raw(index).await?;
+            if raw.iter().all(|b| *b == 0xFF) {
+                break;
+            }
+
+            self.written = index + 1;
+            match decode(&raw) {
+                Some(Entry { content, acked: true }) => {
+                    self.acked = index + 1;
+                    self.last_acked = Some(content.header.sequence_num);
+                }
+                Some(Entry { acked: false, .. }) => {}
+                None => warn!("Skipping torn CFU spool entry {}", index),
+            }
+        }
+
+        self.replay = self.written;
+        if self.written > 0 {
+            info!(
+                "Recovered {} CFU spool entries, last acknowledged {:?}",
+                self.written, self.last_acked
+            );
+        }
+        Ok(self.last_acked)
+    }
+
+    /// Sequence number of the last content the component accepted
+    pub fn last_acknowledged(&self) -> Option<u16> {
+        self.last_acked
+    }
+
+    /// Returns true if there is spooled content that hasn't been replayed
+    pub fn has_pending(&self) -> bool {
+        self.replay < self.written
+    }
+
+    /// Start an update with its first block, resuming a matching interrupted update
+    ///
+    /// A resumed update replays its first block, then continues after the last content the component accepted. The
+    /// component has to be offered the update again first.
+    pub async fn begin(&mut self, content: &FwUpdateContentCommand) -> Result<(), Error<F::Error>> {
+        if self.written > 0 && self.read_raw(0).await?[..ACK_OFFSET] == encode(content) {
+            info!("Resuming spooled CFU update after {:?}", self.last_acked);
+            self.cursor = 1;
+            self.replay = 0;
+            self.resume = self.acked;
+            return Ok(());
+        }
+
+        self.clear().await?;
+        self.append(content).await
+    }
+
+    /// Spool the next content block
+    pub async fn append(&mut self, content: &FwUpdateContentCommand) -> Result<(), Error<F::Error>> {
+        let encoded = encode(content);
+
+        // Resuming, skip over content that is already spooled
+        while self.cursor < self.written {
+            let index = self.cursor;
+            self.cursor += 1;
+            let raw = self.read_raw(index).await?;
+            if raw[..ACK_OFFSET] == encoded {
+                return Ok(());
+            }
+
+            // A torn entry is skipped, the content belongs in the next one
+            if decode(&raw).is_some() {
+                error!("Content {} doesn't match spooled update", content.header.sequence_num);
+                return Err(Error::Mismatch);
+            }
+        }
+
+        if self.written >= self.capacity {
+            return Err(Error::Full);
+        }
+
+        let offset = self.written * ENTRY_SIZE as u32;
+        self.flash.write(offset, &encoded).await.map_err(Error::Flash)?;
+        self.written += 1;
+        self.cursor = self.written;
+        Ok(())
+    }
+
+    /// Read the next content to replay, skipping torn entries
+    pub async fn next_pending(&mut self) -> Result<Option<FwUpdateContentCommand>, Error<F::Error>> {
+        while self.replay < self.written {
+            match self.read(self.replay).await? {
+                Some(Entry { content, .. }) => return Ok(Some(content)),
+                None => self.replay += 1,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Mark the content returned by [`Self::next_pending`] as accepted by the component
+    pub async fn ack(&mut self, sequence: u16) -> Result<(), Error<F::Error>> {
+        // Entries replayed again after a reset are already marked
+        if self.replay >= self.acked {
+            let offset = self.replay * ENTRY_SIZE as u32 + ACK_OFFSET as u32;
+            self.flash.write(offset, &[0; ACK_SIZE]).await.map_err(Error::Flash)?;
+            self.acked = self.replay + 1;
+        }
+        if self.resume > self.replay + 1 {
+            // A resumed update skips content the component accepted before the reset
+            self.replay = self.resume;
+        } else {
+            self.replay += 1;
+            self.last_acked = Some(sequence);
+        }
+        self.resume = 0;
+        Ok(())
+    }
+
+    /// Erase the spool
+    pub async fn clear(&mut self) -> Result<(), Error<F::Error>> {
+        let capacity = self.flash.capacity() as u32;
+        self.flash.erase(0, capacity).await.map_err(Error::Flash)?;
+        self.written = 0;
+        self.cursor = 0;
+        self.replay = 0;
+        self.acked = 0;
+        self.resume = 0;
+        self.last_acked = None;
+        Ok(())
+    }
+
+    async fn read_raw(&mut self, index: u32) -> Result<[u8; ENTRY_SIZE], Error<F::Error>> {
+        let mut raw = [0; ENTRY_SIZE];
+        self.flash
+            .read(index * ENTRY_SIZE as u32, &mut raw)
+            .await
+            .map_err(Error::Flash)?;
+        Ok(raw)
+    }
+
+    async fn read(&mut self, index: u32) -> Result<Option<Entry>, Error<F::Error>> {
+        Ok(decode(&self.read_raw(index).await?))
+    }
+}
+
+/// Internal state for [`PersistentBuffer`]
+#[derive(Copy, Clone, Default)]
+struct State {
+    /// Sequence number of the content being replayed to the component
+    in_flight: Option<u16>,
+    /// Status of the replay that failed
+    replay_error: Option<CfuUpdateContentResponseStatus>,
+}
+
+pub enum Event {
+    /// Request from the host
+    CfuRequest(RequestData),
+    /// Spooled content is waiting to be replayed
+    Replay,
+    /// Response from the buffered component
+    ComponentResponse(InternalResponseData),
+}
+
+/// Flash-backed CFU buffer
+pub struct PersistentBuffer<F: NorFlash> {
+    /// CFU device
+    cfu_device: CfuDevice,
+    /// Internal state
+    state: Mutex<GlobalRawMutex, State>,
+    /// Content spool
+    spool: Mutex<GlobalRawMutex, Spool<F>>,
+    /// Signaled when new content is spooled
+    spooled: Signal<GlobalRawMutex, ()>,
+    /// Component ID to buffer requests for
+    buffered_id: ComponentId,
+}
+
+impl<F: NorFlash> PersistentBuffer<F> {
+    /// Create a new persistent buffer
+    ///
+    /// The buffer receives requests send to external_id and forwards them to buffered_id.
+    pub fn new(external_id: ComponentId, buffered_id: ComponentId, spool: Spool<F>) -> Self {
+        Self {
+            cfu_device: CfuDevice::new(external_id),
+            state: Mutex::new(Default::default()),
+            spool: Mutex::new(spool),
+            spooled: Signal::new(),
+            buffered_id,
+        }
+    }
+
+    /// Recover an interrupted update
+    ///
+    /// Replay resumes once the host offers the update again and resends its first block.
+    pub async fn recover(&self) -> Result<Option<u16>, Error<F::Error>> {
+        self.spool.lock().await.recover().await
+    }
+
+    /// Create a content response
+    fn create_content_response(sequence: u16, status: CfuUpdateContentResponseStatus) -> InternalResponseData {
+        InternalResponseData::ContentResponse(FwUpdateContentResponse::new(sequence, status))
+    }
+
+    /// Process a fw version request
+    async fn process_get_fw_version(&self, state: &State) -> InternalResponseData {
+        if state.in_flight.is_some() {
+            return InternalResponseData::ComponentBusy;
+        }
+
+        if let Ok(InternalResponseData::FwVersionResponse(mut response)) =
+            cfu::route_request(self.buffered_id, RequestData::FwVersionRequest).await
+        {
+            // Update the component ID in the response to match our external ID
+            response.component_info[0].component_id = self.cfu_device.component_id();
+            InternalResponseData::FwVersionResponse(response)
+        } else {
+            error!("Failed to get FW version for device {}", self.buffered_id);
+            InternalResponseData::invalid_fw_version(self.cfu_device.component_id())
+        }
+    }
+
+    /// Process a give offer request
+    async fn process_give_offer(&self, state: &State, offer: &FwUpdateOffer) -> InternalResponseData {
+        if state.in_flight.is_some() || (state.replay_error.is_none() && self.spool.lock().await.has_pending()) {
+            // Still replaying the previous update
+            return InternalResponseData::ComponentBusy;
+        }
+
+        let mut offer = *offer;
+        offer.component_info.component_id = self.buffered_id;
+        if let Ok(response @ InternalResponseData::OfferResponse(_)) =
+            cfu::route_request(self.buffered_id, RequestData::GiveOffer(offer)).await
+        {
+            response
+        } else {
+            error!("Failed to give offer for device {}", self.buffered_id);
+            InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_with_failure(
+                HostToken::Driver,
+                OfferRejectReason::InvalidComponent,
+                OfferStatus::Reject,
+            ))
+        }
+    }
+
+    /// Spool update content
+    async fn process_give_content(&self, state: &mut State, content: &FwUpdateContentCommand) -> InternalResponseData {
+        let sequence = content.header.sequence_num;
+        let mut spool = self.spool.lock().await;
+
+        let result = if content.header.flags & FW_UPDATE_FLAG_FIRST_BLOCK != 0 {
+            state.replay_error = None;
+            spool.begin(content).await
+        } else if let Some(status) = state.replay_error {
+            // Report the component failure to the host
+            return Self::create_content_response(sequence, status);
+        } else {
+            spool.append(content).await
+        };
+
+        match result {
+            Ok(()) => {
+                trace!("Spooled content {}", sequence);
+                self.spooled.signal(());
+                Self::create_content_response(sequence, CfuUpdateContentResponseStatus::Success)
+            }
+            Err(Error::Mismatch) => {
+                Self::create_content_response(sequence, CfuUpdateContentResponseStatus::ErrorInvalid)
+            }
+            Err(e) => {
+                error!("Failed to spool content {}: {:?}", sequence, e);
+                Self::create_content_response(sequence, CfuUpdateContentResponseStatus::ErrorWrite)
+            }
+        }
+    }
+
+    /// Process a finalize request, only forwarded once all content has been replayed
+    async fn process_finalize_update(&self, state: &State) -> InternalResponseData {
+        if state.replay_error.is_some() {
+            error!("Device {}: replay failed, refusing to finalize", self.buffered_id);
+            return InternalResponseData::ComponentBusy;
+        }
+
+        let mut spool = self.spool.lock().await;
+        if state.in_flight.is_some() || spool.has_pending() {
+            trace!("Still replaying content, finalize later");
+            return InternalResponseData::ComponentBusy;
+        }
+
+        match cfu::route_request(self.buffered_id, RequestData::FinalizeUpdate).await {
+            Ok(response) => {
+                // Update complete, nothing left to resume
+                if let Err(e) = spool.clear().await {
+                    error!("Failed to clear CFU spool: {:?}", e);
+                }
+                response
+            }
+            Err(e) => {
+                error!("Failed to finalize device {}: {:?}", self.buffered_id, e);
+                InternalResponseData::ComponentBusy
+            }
+        }
+    }
+
+    /// Replay the next spooled content to the component
+    async fn process_replay(&self, state: &mut State) {
+        if state.in_flight.is_some() || state.replay_error.is_some() {
+            return;
+        }
+
+        let content = match self.spool.lock().await.next_pending().await {
+            Ok(Some(content)) => content,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to read CFU spool: {:?}", e);
+                state.replay_error = Some(CfuUpdateContentResponseStatus::ErrorWrite);
+                return;
+            }
+        };
+
+        // Don't need to wait for a response here, the response will be caught by [`Self::wait_event`]
+        if let Err(e) = cfu::send_device_request(self.buffered_id, RequestData::GiveContent(content)).await {
+            error!(
+                "Failed to send content to buffered component {:?}: {:?}",
+                self.buffered_id, e
+            );
+            state.replay_error = Some(CfuUpdateContentResponseStatus::ErrorInvalid);
+        } else {
+            state.in_flight = Some(content.header.sequence_num);
+        }
+    }
+
+    /// Process a response to replayed content
+    async fn process_component_response(&self, state: &mut State, response: InternalResponseData) {
+        let Some(sequence) = state.in_flight.take() else {
+            error!("Unexpected response from buffered component: {:?}", response);
+            return;
+        };
+
+        match response {
+            InternalResponseData::ContentResponse(response)
+                if response.status == CfuUpdateContentResponseStatus::Success =>
+            {
+                let mut spool = self.spool.lock().await;
+                if let Err(e) = spool.ack(sequence).await {
+                    // The content was delivered, it will just be replayed again after a reset
+                    error!("Failed to mark content {} replayed: {:?}", sequence, e);
+                }
+                if spool.has_pending() {
+                    self.spooled.signal(());
+                }
+            }
+            InternalResponseData::ContentResponse(response) => {
+                error!(
+                    "Buffered component rejected content {}: {:?}",
+                    sequence, response.status
+                );
+                state.replay_error = Some(response.status);
+            }
+            _ => {
+                error!("Invalid response to content {:?} from buffered component", response);
+                state.replay_error = Some(CfuUpdateContentResponseStatus::ErrorInvalid);
+            }
+        }
+    }
+
+    /// Wait for an event
+    pub async fn wait_event(&self) -> Event {
+        match select3(
+            // Wait for a request from the host
+            self.cfu_device.wait_request(),
+            // Wait for newly spooled content
+            self.spooled.wait(),
+            // Wait for response from the buffered component
+            cfu::wait_device_response(self.buffered_id),
+        )
+        .await
+        {
+            Either3::First(request) => {
+                trace!("Request received: {:?}", request);
+                Event::CfuRequest(request)
+            }
+            Either3::Second(()) => Event::Replay,
+            Either3::Third(response) => {
+                if let Ok(response) = response {
+                    trace!("Response received: {:?}", response);
+                    Event::ComponentResponse(response)
+                } else {
+                    error!("Failed to get response from buffered component: {:?}", response);
+                    Event::ComponentResponse(Self::create_content_response(
+                        0,
+                        CfuUpdateContentResponseStatus::ErrorInvalid,
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Top-level event processing function
+    pub async fn process(&self, event: Event) -> Option<InternalResponseData> {
+        let mut state = self.state.lock().await;
+        match event {
+            Event::CfuRequest(request) => Some(self.process_request(&mut state, request).await),
+            Event::Replay => {
+                self.process_replay(&mut state).await;
+                None
+            }
+            Event::ComponentResponse(response) => {
+                self.process_component_response(&mut state, response).await;
+                // Continue with the next spooled content
+                self.process_replay(&mut state).await;
+                None
+            }
+        }
+    }
+
+    /// Process a CFU message and produce a response
+    async fn process_request(&self, state: &mut State, request: RequestData) -> InternalResponseData {
+        match request {
+            RequestData::FwVersionRequest => {
+                trace!("Got FwVersionRequest");
+                self.process_get_fw_version(state).await
+            }
+            RequestData::GiveOffer(offer) => {
+                trace!("Got GiveOffer");
+                self.process_give_offer(state, &offer).await
+            }
+            RequestData::GiveContent(content) => {
+                trace!("Got GiveContent");
+                self.process_give_content(state, &content).await
+            }
+            RequestData::FinalizeUpdate => {
+                trace!("Got FinalizeUpdate");
+                self.process_finalize_update(state).await
+            }
+            RequestData::PrepareComponentForUpdate => {
+                trace!("Got PrepareComponentForUpdate");
+                InternalResponseData::ComponentPrepared
+            }
+        }
+    }
+
+    /// Send a response to the CFU message
+    pub async fn send_response(&self, response: InternalResponseData) {
+        self.cfu_device.send_response(response).await;
+    }
+
+    /// Register the buffer with all relevant services
+    pub async fn register(&'static self) -> Result<(), intrusive_list::Error> {
+        cfu::register_device(&self.cfu_device).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::boxed::Box;
+    use std::sync::{Mutex as StdMutex, OnceLock as StdOnceLock};
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{Either, select};
+    use embassy_futures::yield_now;
+    use embassy_sync::channel::Channel;
+    use embedded_services::testing::RamFlash;
+
+    use super::*;
+
+    /// ID the buffer is reached through
+    const EXTERNAL: ComponentId = 0x50;
+    /// ID of the mock component behind it
+    const BUFFERED: ComponentId = 0x51;
+
+    const ERASE_SIZE: usize = 256;
+    const FLASH_SIZE: usize = 4 * ERASE_SIZE;
+
+    /// Number of content blocks in the update
+    const BLOCKS: usize = 5;
+
+    /// Requests seen by the mock component
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Seen {
+        Offer,
+        Content(u16, u8),
+        Finalize,
+    }
+
+    static SEEN: StdMutex<Vec<Seen>> = StdMutex::new(Vec::new());
+    /// Host requests to the buffer
+    static REQUESTS: Channel<GlobalRawMutex, RequestData, 1> = Channel::new();
+    /// Buffer responses to the host
+    static RESPONSES: Channel<GlobalRawMutex, InternalResponseData, 1> = Channel::new();
+    /// The mock component stops responding after accepting this many blocks
+    static STALL_AFTER: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+    type MockFlash = RamFlash<FLASH_SIZE, ERASE_SIZE>;
+
+    type Buffer<'a> = PersistentBuffer<&'a mut MockFlash>;
+
+    fn device() -> &'static CfuDevice {
+        static DEVICE: StdOnceLock<&'static CfuDevice> = StdOnceLock::new();
+        DEVICE.get_or_init(|| {
+            let device: &'static CfuDevice = Box::leak(Box::new(CfuDevice::new(BUFFERED)));
+            block_on(cfu::register_device(device)).unwrap();
+            device
+        })
+    }
+
+    fn seen() -> Vec<Seen> {
+        SEEN.lock().unwrap().clone()
+    }
+
+    fn content_seen() -> usize {
+        seen().iter().filter(|s| matches!(s, Seen::Content(..))).count()
+    }
+
+    /// Mock component, accepts everything until it stalls
+    async fn device_task(device: &CfuDevice) {
+        loop {
+            let response = match device.wait_request().await {
+                RequestData::GiveOffer(_) => {
+                    SEEN.lock().unwrap().push(Seen::Offer);
+                    InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_accept(HostToken::Driver))
+                }
+                RequestData::GiveContent(content) => {
+                    let accepted = content_seen();
+                    SEEN.lock()
+                        .unwrap()
+                        .push(Seen::Content(content.header.sequence_num, content.header.flags));
+                    if accepted >= STALL_AFTER.load(Ordering::SeqCst) {
+                        // Simulate a reset while the component is busy with the block
+                        core::future::pending::<()>().await;
+                    }
+                    InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
+                        content.header.sequence_num,
+                        CfuUpdateContentResponseStatus::Success,
+                    ))
+                }
+                RequestData::FinalizeUpdate => {
+                    SEEN.lock().unwrap().push(Seen::Finalize);
+                    InternalResponseData::ComponentPrepared
+                }
+                _ => InternalResponseData::ComponentBusy,
+            };
+            device.send_response(response).await;
+        }
+    }
+
+    /// Buffer task, host requests are processed in between replay events like requests to the external ID
+    async fn buffer_task(buffer: &Buffer<'_>) {
+        loop {
+            match select(REQUESTS.receive(), buffer.wait_event()).await {
+                Either::First(request) => {
+                    let response = buffer.process(Event::CfuRequest(request)).await.unwrap();
+                    RESPONSES.send(response).await;
+                }
+                Either::Second(event) => {
+                    buffer.process(event).await;
+                }
+            }
+        }
+    }
+
+    /// Run a host scenario with the buffer and the mock component processing events
+    fn run(buffer: &Buffer<'_>, scenario: impl core::future::Future<Output = ()>) {
+        match block_on(select(select(device_task(device()), buffer_task(buffer)), scenario)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    }
+
+    fn content(sequence: usize) -> FwUpdateContentCommand {
+        let mut flags = 0;
+        if sequence == 0 {
+            flags |= FW_UPDATE_FLAG_FIRST_BLOCK;
+        }
+        if sequence == BLOCKS - 1 {
+            flags |= FW_UPDATE_FLAG_LAST_BLOCK;
+        }
+
+        FwUpdateContentCommand {
+            header: FwUpdateContentHeader {
+                data_length: DEFAULT_DATA_LENGTH as u8,
+                sequence_num: sequence as u16,
+                firmware_address: (sequence * DEFAULT_DATA_LENGTH) as u32,
+                flags,
+            },
+            data: [sequence as u8; DEFAULT_DATA_LENGTH],
+        }
+    }
+
+    async fn request(request: RequestData) -> InternalResponseData {
+        REQUESTS.send(request).await;
+        RESPONSES.receive().await
+    }
+
+    /// Offer the update and send all of its content
+    async fn send_update() {
+        send_blocks(BLOCKS).await;
+    }
+
+    /// Offer the update and send its first `count` blocks
+    async fn send_blocks(count: usize) {
+        let offer = FwUpdateOffer::new(HostToken::Driver, EXTERNAL, FwVersion::new(0x211), 0, 0);
+        match request(RequestData::GiveOffer(offer)).await {
+            InternalResponseData::OfferResponse(r) => assert_eq!(r.status, OfferStatus::Accept),
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+
+        for sequence in 0..count {
+            match request(RequestData::GiveContent(content(sequence))).await {
+                InternalResponseData::ContentResponse(r) => {
+                    assert_eq!(r.status, CfuUpdateContentResponseStatus::Success)
+                }
+                resp => panic!("Unexpected response {resp:?}"),
+            }
+        }
+    }
+
+    /// Wait until the component accepted `count` blocks
+    async fn wait_acked(buffer: &Buffer<'_>, count: usize) {
+        let expected = count.checked_sub(1).map(|s| s as u16);
+        while buffer.spool.lock().await.last_acknowledged() != expected || content_seen() < (count + 1).min(BLOCKS) {
+            yield_now().await;
+        }
+    }
+
+    /// Wait until all spooled content has been replayed after a reset
+    async fn wait_replayed(buffer: &Buffer<'_>) {
+        loop {
+            {
+                let spool = buffer.spool.lock().await;
+                if spool.last_acknowledged() == Some(BLOCKS as u16 - 1) && !spool.has_pending() {
+                    return;
+                }
+            }
+            yield_now().await;
+        }
+    }
+
+    /// Requests the component sees for a complete update
+    fn complete_update() -> Vec<Seen> {
+        resumed_update(0)
+    }
+
+    /// Requests the component sees for an update resumed after it accepted `accepted` blocks
+    fn resumed_update(accepted: usize) -> Vec<Seen> {
+        let mut expected = Vec::from([Seen::Offer, Seen::Content(0, content(0).header.flags)]);
+        expected.extend((accepted.max(1)..BLOCKS).map(|s| Seen::Content(s as u16, content(s).header.flags)));
+        expected.push(Seen::Finalize);
+        expected
+    }
+
+    /// Restart the update after a reset and return the last block the component accepted before the reset
+    fn resume(flash: &mut MockFlash) -> Option<u16> {
+        SEEN.lock().unwrap().clear();
+        STALL_AFTER.store(usize::MAX, Ordering::SeqCst);
+        let buffer = PersistentBuffer::new(EXTERNAL, BUFFERED, Spool::new(flash).unwrap());
+        let last_acked = block_on(buffer.recover()).unwrap();
+
+        run(&buffer, async {
+            // Nothing is replayed until the host restarts the update
+            for _ in 0..10 {
+                yield_now().await;
+            }
+            assert!(seen().is_empty());
+
+            send_update().await;
+            wait_replayed(&buffer).await;
+            assert_eq!(
+                request(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentPrepared
+            );
+        });
+        last_acked
+    }
+
+    #[test]
+    fn test_update() {
+        let _guard = crate::tests::lock();
+        SEEN.lock().unwrap().clear();
+        STALL_AFTER.store(usize::MAX, Ordering::SeqCst);
+
+        let mut flash = MockFlash::new();
+        let buffer = PersistentBuffer::new(EXTERNAL, BUFFERED, Spool::new(&mut flash).unwrap());
+        run(&buffer, async {
+            send_update().await;
+            wait_acked(&buffer, BLOCKS).await;
+            assert_eq!(
+                request(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentPrepared
+            );
+        });
+        assert_eq!(seen(), complete_update());
+        drop(buffer);
+
+        // Nothing is left to resume once the update is finalized
+        let buffer = PersistentBuffer::new(EXTERNAL, BUFFERED, Spool::new(&mut flash).unwrap());
+        assert_eq!(block_on(buffer.recover()), Ok(None));
+    }
+
+    #[test]
+    fn test_resume_after_reset() {
+        let _guard = crate::tests::lock();
+
+        for accepted in [0, 1, BLOCKS - 1, BLOCKS] {
+            SEEN.lock().unwrap().clear();
+            STALL_AFTER.store(accepted, Ordering::SeqCst);
+
+            let mut flash = MockFlash::new();
+            let buffer = PersistentBuffer::new(EXTERNAL, BUFFERED, Spool::new(&mut flash).unwrap());
+            run(&buffer, async {
+                send_update().await;
+                wait_acked(&buffer, accepted).await;
+            });
+            drop(buffer);
+
+            // Reset
+            let last_acked = accepted.checked_sub(1).map(|s| s as u16);
+            assert_eq!(resume(&mut flash), last_acked, "reset after {accepted} blocks");
+
+            // The component is offered the update again and gets its first block, then the blocks it didn't accept
+            assert_eq!(seen(), resumed_update(accepted), "reset after {accepted} blocks");
+        }
+    }
+
+    #[test]
+    fn test_resume_interrupted_transfer() {
+        let _guard = crate::tests::lock();
+        SEEN.lock().unwrap().clear();
+        STALL_AFTER.store(2, Ordering::SeqCst);
+
+        let mut flash = MockFlash::new();
+        let buffer = PersistentBuffer::new(EXTERNAL, BUFFERED, Spool::new(&mut flash).unwrap());
+        run(&buffer, async {
+            // The host is interrupted before sending the whole update
+            send_blocks(3).await;
+            wait_acked(&buffer, 2).await;
+        });
+        drop(buffer);
+
+        // Replay resumes after the second block, the rest of the update is spooled as the host resends it
+        assert_eq!(resume(&mut flash), Some(1));
+        assert_eq!(
+            seen(),
+            [
+                Seen::Offer,
+                Seen::Content(0, FW_UPDATE_FLAG_FIRST_BLOCK),
+                Seen::Content(2, 0),
+                Seen::Content(3, 0),
+                Seen::Content(4, FW_UPDATE_FLAG_LAST_BLOCK),
+                Seen::Finalize,
+            ]
+        );
+    }
+}
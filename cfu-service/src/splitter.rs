@@ -3,9 +3,11 @@
 
 use core::{future::Future, iter::zip};
 
-use embassy_futures::join::{join, join3, join4};
+use embassy_futures::join::join_array;
+use embassy_sync::mutex::Mutex;
 use embedded_cfu_protocol::protocol_definitions::*;
 use embedded_services::{
+    GlobalRawMutex,
     cfu::{
         self,
         component::{CfuDevice, InternalResponseData, RequestData},
@@ -25,43 +27,149 @@ pub trait Customization {
     fn resolve_content_response(&self, content_responses: &[FwUpdateContentResponse]) -> FwUpdateContentResponse;
 }
 
+/// How the splitter handles individual device failures
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Policy {
+    /// Requests are sent to all devices concurrently, the request fails if any device doesn't respond
+    #[default]
+    AllOrNothing,
+    /// Requests are sent to all devices concurrently, devices that fail are dropped from the rest of the update
+    ///
+    /// A request succeeds as long as at least `quorum` devices succeed, the responses from the remaining devices are
+    /// then resolved through [`Customization`]. Otherwise the request is rejected and so is further content until the
+    /// next offer.
+    BestEffort {
+        /// Minimum number of devices that must succeed
+        quorum: usize,
+    },
+    /// Requests are sent to one device at a time in order
+    ///
+    /// The first failure aborts the update, remaining devices aren't sent the request and further content is rejected
+    /// until the next offer.
+    Sequential,
+}
+
+/// Splitter configuration
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Device failure policy
+    pub policy: Policy,
+}
+
+/// Reason an individual device failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    /// Device didn't respond or returned an unexpected response
+    NoResponse,
+    /// Device didn't accept the offer
+    OfferRejected(OfferRejectReason),
+    /// Device rejected content
+    ContentRejected(CfuUpdateContentResponseStatus),
+}
+
+/// Failure of an individual device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceFailure {
+    /// Component ID of the device
+    pub device: ComponentId,
+    /// Reason for the failure
+    pub failure: Failure,
+}
+
+/// State of the current update
+struct State<const N: usize> {
+    /// Devices taking part in the update
+    active: [bool; N],
+    /// Failure recorded for each device
+    failures: [Option<Failure>; N],
+    /// Update was aborted by [`Policy::Sequential`] or a missed [`Policy::BestEffort`] quorum
+    aborted: bool,
+}
+
+impl<const N: usize> Default for State<N> {
+    fn default() -> Self {
+        Self {
+            active: [true; N],
+            failures: [None; N],
+            aborted: false,
+        }
+    }
+}
+
 /// Splitter struct
-pub struct Splitter<'a, C: Customization> {
+///
+/// `N` is the maximum number of devices the splitter can broadcast to.
+pub struct Splitter<'a, C: Customization, const N: usize = MAX_SUPPORTED_DEVICES> {
     /// CFU device
     cfu_device: CfuDevice,
     /// Component ID for each individual device
     devices: &'a [ComponentId],
     /// Customization for the Splitter
     customization: C,
+    /// Configuration
+    config: Config,
+    /// Update state
+    state: Mutex<GlobalRawMutex, State<N>>,
 }
 
-/// Maximum number of devices supported
+/// Default maximum number of devices
 pub const MAX_SUPPORTED_DEVICES: usize = 4;
 
-impl<'a, C: Customization> Splitter<'a, C> {
-    /// Create a new Splitter, returns None if the devices slice is empty or too large
-    pub fn new(component_id: ComponentId, devices: &'a [ComponentId], customization: C) -> Option<Self> {
-        if devices.is_empty() || devices.len() > MAX_SUPPORTED_DEVICES {
-            None
-        } else {
-            Some(Self {
-                cfu_device: CfuDevice::new(component_id),
-                devices,
-                customization,
-            })
+impl<'a, C: Customization, const N: usize> Splitter<'a, C, N> {
+    /// Create a new Splitter
+    ///
+    /// Returns None if the devices slice is empty, holds more than `N` devices or the quorum can't be met.
+    pub fn new(
+        component_id: ComponentId,
+        devices: &'a [ComponentId],
+        customization: C,
+        config: Config,
+    ) -> Option<Self> {
+        if devices.is_empty() || devices.len() > N {
+            return None;
         }
-    }
 
-    /// Create a new invalid FW version response
-    fn create_invalid_fw_version_response(&self) -> InternalResponseData {
-        let dev_inf = FwVerComponentInfo::new(FwVersion::new(0xffffffff), self.cfu_device.component_id());
-        let comp_info: [FwVerComponentInfo; MAX_CMPT_COUNT] = [dev_inf; MAX_CMPT_COUNT];
-        InternalResponseData::FwVersionResponse(GetFwVersionResponse {
-            header: GetFwVersionResponseHeader::new(1, GetFwVerRespHeaderByte3::NoSpecialFlags),
-            component_info: comp_info,
+        if let Policy::BestEffort { quorum } = config.policy {
+            if quorum == 0 || quorum > devices.len() {
+                return None;
+            }
+        }
+
+        Some(Self {
+            cfu_device: CfuDevice::new(component_id),
+            devices,
+            customization,
+            config,
+            state: Mutex::new(State::default()),
         })
     }
 
+    /// Failures recorded since the last offer
+    pub async fn failures(&self) -> heapless::Vec<DeviceFailure, N> {
+        let state = self.state.lock().await;
+        zip(self.devices, state.failures)
+            .filter_map(|(device, failure)| {
+                failure.map(|failure| DeviceFailure {
+                    device: *device,
+                    failure,
+                })
+            })
+            .collect()
+    }
+
+    /// Create an offer rejection response
+    fn create_offer_rejection() -> InternalResponseData {
+        InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_with_failure(
+            HostToken::Driver,
+            OfferRejectReason::InvalidComponent,
+            OfferStatus::Reject,
+        ))
+    }
+
     /// Create a content rejection response
     fn create_content_rejection(sequence: u16) -> InternalResponseData {
         InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
@@ -70,88 +178,222 @@ impl<'a, C: Customization> Splitter<'a, C> {
         ))
     }
 
-    /// Process a fw version request
-    async fn process_get_fw_version(&self) -> InternalResponseData {
-        let mut versions = [GetFwVersionResponse {
-            header: Default::default(),
-            component_info: Default::default(),
-        }; MAX_SUPPORTED_DEVICES];
-
-        let success = map_slice_join(self.devices, &mut versions, |device_id| async move {
-            if let Ok(InternalResponseData::FwVersionResponse(version_info)) =
-                cfu::route_request(*device_id, RequestData::FwVersionRequest).await
-            {
-                Some(version_info)
-            } else {
-                error!("Failed to get FW version for device {}", device_id);
+    /// Send a request to the targeted devices according to the policy
+    ///
+    /// Returns `None` for devices that weren't sent the request and `Some(None)` for devices that didn't respond.
+    async fn send_all<T, F: Future<Output = Option<T>>>(
+        &self,
+        targets: &[bool; N],
+        send: impl Fn(ComponentId) -> F,
+        check: impl Fn(&T) -> Option<Failure>,
+    ) -> [Option<Option<T>>; N] {
+        if self.config.policy == Policy::Sequential {
+            let mut results = [const { None }; N];
+            for ((device, target), result) in zip(zip(self.devices, targets), results.iter_mut()) {
+                if !*target {
+                    continue;
+                }
+
+                let response = send(*device).await;
+                let failed = response.as_ref().is_none_or(|response| check(response).is_some());
+                *result = Some(response);
+                if failed {
+                    // Abort on the first failure
+                    break;
+                }
+            }
+            results
+        } else {
+            let send = &send;
+            join_array(core::array::from_fn(|i| async move {
+                match self.devices.get(i) {
+                    Some(device) if targets[i] => Some(send(*device).await),
+                    _ => None,
+                }
+            }))
+            .await
+        }
+    }
+
+    /// Gather the responses to resolve and the failure of each device, without touching the update state
+    ///
+    /// The responses are `None` if the request failed outright.
+    fn gather<T: Copy>(
+        &self,
+        results: &[Option<Option<T>>; N],
+        check: impl Fn(&T) -> Option<Failure>,
+    ) -> (Option<heapless::Vec<T, N>>, [Option<Failure>; N]) {
+        let mut responses = heapless::Vec::<T, N>::new();
+        let mut succeeded = heapless::Vec::<T, N>::new();
+        let mut failures = [None; N];
+        let mut no_response = false;
+
+        for ((device, result), failure) in zip(zip(self.devices, results), failures.iter_mut()) {
+            *failure = match result {
+                None => continue,
+                Some(None) => {
+                    no_response = true;
+                    Some(Failure::NoResponse)
+                }
+                Some(Some(response)) => {
+                    // Can't overflow, both vecs have the same capacity as results
+                    let _ = responses.push(*response);
+                    let failure = check(response);
+                    if failure.is_none() {
+                        let _ = succeeded.push(*response);
+                    }
+                    failure
+                }
+            };
+
+            if let Some(failure) = failure {
+                error!("Device {} failed: {:?}", device, failure);
+            }
+        }
+
+        let responses = match self.config.policy {
+            Policy::BestEffort { quorum } if succeeded.len() >= quorum => Some(succeeded),
+            Policy::BestEffort { quorum } => {
+                error!("Only {} devices succeeded, quorum is {}", succeeded.len(), quorum);
                 None
             }
-        })
-        .await;
+            Policy::AllOrNothing | Policy::Sequential => (!no_response).then_some(responses),
+        };
+        (responses, failures)
+    }
 
-        if !success {
-            self.create_invalid_fw_version_response()
-        } else {
-            let mut overall_version = self.customization.resolve_fw_versions(&versions[..self.devices.len()]);
+    /// Record device failures of an update request and gather the responses to resolve
+    ///
+    /// Returns `None` if the request failed outright.
+    fn collect<T: Copy>(
+        &self,
+        state: &mut State<N>,
+        results: &[Option<Option<T>>; N],
+        check: impl Fn(&T) -> Option<Failure>,
+    ) -> Option<heapless::Vec<T, N>> {
+        let (responses, failures) = self.gather(results, check);
+
+        for (i, failure) in failures.into_iter().enumerate() {
+            if let Some(failure) = failure {
+                state.failures[i] = Some(failure);
+                match self.config.policy {
+                    Policy::BestEffort { .. } => state.active[i] = false,
+                    Policy::Sequential => state.aborted = true,
+                    Policy::AllOrNothing => {}
+                }
+            }
+        }
+
+        if responses.is_none() && matches!(self.config.policy, Policy::BestEffort { .. }) {
+            state.aborted = true;
+        }
+        responses
+    }
+
+    /// Process a fw version request
+    ///
+    /// The version isn't part of an update, so this doesn't touch the update state.
+    async fn process_get_fw_version(&self) -> InternalResponseData {
+        let check = |_: &GetFwVersionResponse| None;
+
+        // Always query every device, the version isn't part of an update
+        let results = self
+            .send_all(
+                &[true; N],
+                |device_id| async move {
+                    if let Ok(InternalResponseData::FwVersionResponse(version_info)) =
+                        cfu::route_request(device_id, RequestData::FwVersionRequest).await
+                    {
+                        Some(version_info)
+                    } else {
+                        error!("Failed to get FW version for device {}", device_id);
+                        None
+                    }
+                },
+                check,
+            )
+            .await;
+
+        if let (Some(versions), _) = self.gather(&results, check) {
+            let mut overall_version = self.customization.resolve_fw_versions(&versions);
             // The overall component version comes first
             overall_version.component_info[0].component_id = self.cfu_device.component_id();
             InternalResponseData::FwVersionResponse(overall_version)
+        } else {
+            InternalResponseData::invalid_fw_version(self.cfu_device.component_id())
         }
     }
 
     /// Process a give offer request
     async fn process_give_offer(&self, offer: &FwUpdateOffer) -> InternalResponseData {
-        let mut offer_responses = [FwUpdateOfferResponse::default(); MAX_SUPPORTED_DEVICES];
-
-        let success = map_slice_join(self.devices, &mut offer_responses, |device_id| async move {
-            let mut offer = *offer;
-
-            // Override with the correct component ID for the device
-            offer.component_info.component_id = *device_id;
-            if let Ok(InternalResponseData::OfferResponse(response)) =
-                cfu::route_request(*device_id, RequestData::GiveOffer(offer)).await
-            {
-                Some(response)
-            } else {
-                error!("Failed to get FW version for device {}", device_id);
-                None
-            }
-        })
-        .await;
+        let mut state = self.state.lock().await;
+        // An offer starts a new update
+        *state = State::default();
 
-        if !success {
-            self.create_invalid_fw_version_response()
-        } else {
-            InternalResponseData::OfferResponse(
-                self.customization
-                    .resolve_offer_response(&offer_responses[..self.devices.len()]),
+        let check = |response: &FwUpdateOfferResponse| {
+            (response.status != OfferStatus::Accept).then_some(Failure::OfferRejected(response.reject_reason))
+        };
+        let results = self
+            .send_all(
+                &state.active,
+                |device_id| async move {
+                    let mut offer = *offer;
+
+                    // Override with the correct component ID for the device
+                    offer.component_info.component_id = device_id;
+                    if let Ok(InternalResponseData::OfferResponse(response)) =
+                        cfu::route_request(device_id, RequestData::GiveOffer(offer)).await
+                    {
+                        Some(response)
+                    } else {
+                        error!("Failed to give offer to device {}", device_id);
+                        None
+                    }
+                },
+                check,
             )
+            .await;
+
+        if let Some(responses) = self.collect(&mut state, &results, check) {
+            InternalResponseData::OfferResponse(self.customization.resolve_offer_response(&responses))
+        } else {
+            Self::create_offer_rejection()
         }
     }
 
     /// Process update content
     async fn process_give_content(&self, content: &FwUpdateContentCommand) -> InternalResponseData {
-        let mut content_responses = [FwUpdateContentResponse::default(); MAX_SUPPORTED_DEVICES];
-
-        let success = map_slice_join(self.devices, &mut content_responses, |device_id| async move {
-            if let Ok(InternalResponseData::ContentResponse(response)) =
-                cfu::route_request(*device_id, RequestData::GiveContent(*content)).await
-            {
-                Some(response)
-            } else {
-                error!("Failed to get FW version for device {}", device_id);
-                None
-            }
-        })
-        .await;
+        let mut state = self.state.lock().await;
+        if state.aborted {
+            trace!("Update aborted, rejecting content");
+            return Self::create_content_rejection(content.header.sequence_num);
+        }
 
-        if !success {
-            Self::create_content_rejection(content.header.sequence_num)
-        } else {
-            InternalResponseData::ContentResponse(
-                self.customization
-                    .resolve_content_response(&content_responses[..self.devices.len()]),
+        let check = |response: &FwUpdateContentResponse| {
+            (response.status != CfuUpdateContentResponseStatus::Success)
+                .then_some(Failure::ContentRejected(response.status))
+        };
+        let results = self
+            .send_all(
+                &state.active,
+                |device_id| async move {
+                    if let Ok(InternalResponseData::ContentResponse(response)) =
+                        cfu::route_request(device_id, RequestData::GiveContent(*content)).await
+                    {
+                        Some(response)
+                    } else {
+                        error!("Failed to give content to device {}", device_id);
+                        None
+                    }
+                },
+                check,
             )
+            .await;
+
+        if let Some(responses) = self.collect(&mut state, &results, check) {
+            InternalResponseData::ContentResponse(self.customization.resolve_content_response(&responses))
+        } else {
+            Self::create_content_rejection(content.header.sequence_num)
         }
     }
 
@@ -196,64 +438,296 @@ impl<'a, C: Customization> Splitter<'a, C> {
     }
 }
 
-/// Map items in an input slice to an output slice using an async closure.
-///
-/// This function will execute the closure concurrently in groups up to four items at a time.
-/// Four is an arbitrary but is a balance between two (easy to implement, but not very concurrent) and eight (more implementation work).
-/// This will exit early and return false if any item results in `None`.
-async fn map_slice_join<'i, 'o, I, O, F: Future<Output = Option<O>>>(
-    input: &'i [I],
-    output: &'o mut [O],
-    f: impl Fn(&'i I) -> F,
-) -> bool {
-    let mut iter = zip(input.iter(), output.iter_mut());
-    loop {
-        match (iter.next(), iter.next(), iter.next(), iter.next()) {
-            (None, None, None, None) => {
-                // No more items to process
-                return true;
-            }
-            (Some((i0, o0)), None, None, None) => {
-                if let Some(result) = f(i0).await {
-                    *o0 = result;
-                } else {
-                    return false;
-                }
-            }
-            (Some((i0, o0)), Some((i1, o1)), None, None) => {
-                let results = join(f(i0), f(i1)).await;
-                if let (Some(r0), Some(r1)) = results {
-                    *o0 = r0;
-                    *o1 = r1;
-                } else {
-                    return false;
-                }
-            }
-            (Some((i0, o0)), Some((i1, o1)), Some((i2, o2)), None) => {
-                let results = join3(f(i0), f(i1), f(i2)).await;
-                if let (Some(r0), Some(r1), Some(r2)) = results {
-                    *o0 = r0;
-                    *o1 = r1;
-                    *o2 = r2;
-                } else {
-                    return false;
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+    use std::boxed::Box;
+    use std::sync::OnceLock as StdOnceLock;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{Either, select};
+
+    use super::*;
+
+    /// ID the splitter is reached through
+    const SPLITTER: ComponentId = 0x60;
+    /// IDs of the mock devices
+    const DEVICES: [ComponentId; 3] = [0x61, 0x62, 0x63];
+    /// ID without a device behind it
+    const MISSING: ComponentId = 0x6f;
+
+    /// Mock device behaviors
+    const ACCEPT: u8 = 0;
+    const REJECT_OFFER: u8 = 1;
+    const REJECT_CONTENT: u8 = 2;
+
+    static BEHAVIOR: [AtomicU8; 3] = [const { AtomicU8::new(ACCEPT) }; 3];
+    static CONTENT_REQUESTS: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+    /// Reports the first failure, otherwise the first response
+    struct FirstFailure;
+
+    impl Customization for FirstFailure {
+        fn resolve_fw_versions(&self, versions: &[GetFwVersionResponse]) -> GetFwVersionResponse {
+            versions[0]
+        }
+
+        fn resolve_offer_response(&self, offer_responses: &[FwUpdateOfferResponse]) -> FwUpdateOfferResponse {
+            *offer_responses
+                .iter()
+                .find(|r| r.status != OfferStatus::Accept)
+                .unwrap_or(&offer_responses[0])
+        }
+
+        fn resolve_content_response(&self, content_responses: &[FwUpdateContentResponse]) -> FwUpdateContentResponse {
+            *content_responses
+                .iter()
+                .find(|r| r.status != CfuUpdateContentResponseStatus::Success)
+                .unwrap_or(&content_responses[0])
+        }
+    }
+
+    fn devices(behaviors: [u8; 3]) -> [&'static CfuDevice; 3] {
+        static DEVICES_ONCE: StdOnceLock<[&'static CfuDevice; 3]> = StdOnceLock::new();
+
+        for (i, behavior) in behaviors.into_iter().enumerate() {
+            BEHAVIOR[i].store(behavior, Ordering::SeqCst);
+            CONTENT_REQUESTS[i].store(0, Ordering::SeqCst);
+        }
+
+        *DEVICES_ONCE.get_or_init(|| {
+            DEVICES.map(|id| {
+                let device: &'static CfuDevice = Box::leak(Box::new(CfuDevice::new(id)));
+                block_on(cfu::register_device(device)).unwrap();
+                device
+            })
+        })
+    }
+
+    /// Mock device
+    async fn device_task(index: usize, device: &CfuDevice) {
+        loop {
+            let request = device.wait_request().await;
+            let behavior = BEHAVIOR[index].load(Ordering::SeqCst);
+            let response = match request {
+                RequestData::GiveOffer(offer) => {
+                    assert_eq!(offer.component_info.component_id, DEVICES[index]);
+                    InternalResponseData::OfferResponse(if behavior == REJECT_OFFER {
+                        FwUpdateOfferResponse::new_with_failure(
+                            HostToken::Driver,
+                            OfferRejectReason::OldFw,
+                            OfferStatus::Reject,
+                        )
+                    } else {
+                        FwUpdateOfferResponse::new_accept(HostToken::Driver)
+                    })
                 }
-            }
-            (Some((i0, o0)), Some((i1, o1)), Some((i2, o2)), Some((i3, o3))) => {
-                let results = join4(f(i0), f(i1), f(i2), f(i3)).await;
-                if let (Some(r0), Some(r1), Some(r2), Some(r3)) = results {
-                    *o0 = r0;
-                    *o1 = r1;
-                    *o2 = r2;
-                    *o3 = r3;
-                } else {
-                    return false;
+                RequestData::GiveContent(content) => {
+                    CONTENT_REQUESTS[index].fetch_add(1, Ordering::SeqCst);
+                    let status = if behavior == REJECT_CONTENT {
+                        CfuUpdateContentResponseStatus::ErrorWrite
+                    } else {
+                        CfuUpdateContentResponseStatus::Success
+                    };
+                    InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
+                        content.header.sequence_num,
+                        status,
+                    ))
                 }
-            }
-            _ => {
-                // Other combinations aren't possible because we're using a fused iterator
-                unreachable!()
-            }
+                _ => InternalResponseData::ComponentPrepared,
+            };
+            device.send_response(response).await;
         }
     }
+
+    /// Run a scenario with the mock devices answering requests
+    fn run(behaviors: [u8; 3], scenario: impl Future<Output = ()>) {
+        let [d0, d1, d2] = devices(behaviors);
+        let devices = join_array([device_task(0, d0), device_task(1, d1), device_task(2, d2)]);
+        match block_on(select(devices, scenario)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    }
+
+    fn create(devices: &[ComponentId], policy: Policy) -> Splitter<'_, FirstFailure, 4> {
+        Splitter::new(SPLITTER, devices, FirstFailure, Config { policy }).unwrap()
+    }
+
+    async fn offer(splitter: &Splitter<'_, FirstFailure, 4>) -> FwUpdateOfferResponse {
+        let offer = FwUpdateOffer::new(HostToken::Driver, SPLITTER, FwVersion::new(0x211), 0, 0);
+        match splitter.process_request(RequestData::GiveOffer(offer)).await {
+            InternalResponseData::OfferResponse(r) => r,
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+    }
+
+    async fn content(splitter: &Splitter<'_, FirstFailure, 4>, sequence: u16) -> CfuUpdateContentResponseStatus {
+        let content = FwUpdateContentCommand {
+            header: FwUpdateContentHeader {
+                data_length: DEFAULT_DATA_LENGTH as u8,
+                sequence_num: sequence,
+                firmware_address: sequence as u32 * DEFAULT_DATA_LENGTH as u32,
+                flags: if sequence == 0 { FW_UPDATE_FLAG_FIRST_BLOCK } else { 0 },
+            },
+            data: [0; DEFAULT_DATA_LENGTH],
+        };
+        match splitter.process_request(RequestData::GiveContent(content)).await {
+            InternalResponseData::ContentResponse(r) => r.status,
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+    }
+
+    fn content_requests() -> [usize; 3] {
+        core::array::from_fn(|i| CONTENT_REQUESTS[i].load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_new() {
+        let config = |quorum| Config {
+            policy: Policy::BestEffort { quorum },
+        };
+        assert!(Splitter::<_, 4>::new(SPLITTER, &[], FirstFailure, Config::default()).is_none());
+        assert!(Splitter::<_, 2>::new(SPLITTER, &DEVICES, FirstFailure, Config::default()).is_none());
+        assert!(Splitter::<_, 4>::new(SPLITTER, &DEVICES, FirstFailure, config(0)).is_none());
+        assert!(Splitter::<_, 4>::new(SPLITTER, &DEVICES, FirstFailure, config(4)).is_none());
+        assert!(Splitter::<_, 4>::new(SPLITTER, &DEVICES, FirstFailure, config(3)).is_some());
+    }
+
+    #[test]
+    fn test_all_or_nothing() {
+        let _guard = crate::tests::lock();
+        run([ACCEPT, REJECT_CONTENT, ACCEPT], async {
+            let splitter = create(&DEVICES, Policy::AllOrNothing);
+            assert_eq!(offer(&splitter).await.status, OfferStatus::Accept);
+
+            // The failure is resolved through the customization, every device keeps getting content
+            assert_eq!(content(&splitter, 0).await, CfuUpdateContentResponseStatus::ErrorWrite);
+            assert_eq!(content(&splitter, 1).await, CfuUpdateContentResponseStatus::ErrorWrite);
+            assert_eq!(content_requests(), [2, 2, 2]);
+            assert_eq!(
+                splitter.failures().await.as_slice(),
+                [DeviceFailure {
+                    device: DEVICES[1],
+                    failure: Failure::ContentRejected(CfuUpdateContentResponseStatus::ErrorWrite),
+                }]
+            );
+
+            // A device that doesn't respond fails the request outright
+            let devices = [DEVICES[0], MISSING];
+            let splitter = create(&devices, Policy::AllOrNothing);
+            let response = offer(&splitter).await;
+            assert_eq!(response.status, OfferStatus::Reject);
+            assert_eq!(response.reject_reason, OfferRejectReason::InvalidComponent);
+        });
+    }
+
+    #[test]
+    fn test_best_effort() {
+        let _guard = crate::tests::lock();
+        run([ACCEPT, ACCEPT, REJECT_OFFER], async {
+            let splitter = create(&DEVICES, Policy::BestEffort { quorum: 2 });
+
+            // The rejecting device is dropped from the update
+            assert_eq!(offer(&splitter).await.status, OfferStatus::Accept);
+            assert_eq!(content(&splitter, 0).await, CfuUpdateContentResponseStatus::Success);
+            assert_eq!(content_requests(), [1, 1, 0]);
+            assert_eq!(
+                splitter.failures().await.as_slice(),
+                [DeviceFailure {
+                    device: DEVICES[2],
+                    failure: Failure::OfferRejected(OfferRejectReason::OldFw),
+                }]
+            );
+
+            // Losing another device misses the quorum, which is reported as a rejection
+            BEHAVIOR[1].store(REJECT_CONTENT, Ordering::SeqCst);
+            assert_eq!(
+                content(&splitter, 1).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(content_requests(), [2, 2, 0]);
+
+            // The update is aborted until the next offer
+            assert_eq!(
+                content(&splitter, 2).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(content_requests(), [2, 2, 0]);
+        });
+    }
+
+    #[test]
+    fn test_best_effort_offer_quorum() {
+        let _guard = crate::tests::lock();
+        run([REJECT_OFFER, REJECT_OFFER, ACCEPT], async {
+            // Only one device accepts, even though it succeeded the host must see a rejection
+            let splitter = create(&DEVICES, Policy::BestEffort { quorum: 2 });
+            let response = offer(&splitter).await;
+            assert_eq!(response.status, OfferStatus::Reject);
+            assert_eq!(response.reject_reason, OfferRejectReason::InvalidComponent);
+            assert_eq!(splitter.failures().await.len(), 2);
+
+            // A device that doesn't respond counts against the quorum
+            let devices = [DEVICES[2], MISSING];
+            let splitter = create(&devices, Policy::BestEffort { quorum: 2 });
+            assert_eq!(offer(&splitter).await.status, OfferStatus::Reject);
+            assert_eq!(
+                splitter.failures().await.as_slice(),
+                [DeviceFailure {
+                    device: MISSING,
+                    failure: Failure::NoResponse,
+                }]
+            );
+        });
+    }
+
+    #[test]
+    fn test_sequential() {
+        let _guard = crate::tests::lock();
+        run([ACCEPT, REJECT_CONTENT, ACCEPT], async {
+            let splitter = create(&DEVICES, Policy::Sequential);
+            assert_eq!(offer(&splitter).await.status, OfferStatus::Accept);
+
+            // The failing device stops the broadcast before the last device
+            assert_eq!(content(&splitter, 0).await, CfuUpdateContentResponseStatus::ErrorWrite);
+            assert_eq!(content_requests(), [1, 1, 0]);
+
+            // Further content is rejected without reaching any device
+            assert_eq!(
+                content(&splitter, 1).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(content_requests(), [1, 1, 0]);
+
+            // A new offer starts over
+            BEHAVIOR[1].store(ACCEPT, Ordering::SeqCst);
+            assert_eq!(offer(&splitter).await.status, OfferStatus::Accept);
+            assert!(splitter.failures().await.is_empty());
+            assert_eq!(content(&splitter, 0).await, CfuUpdateContentResponseStatus::Success);
+            assert_eq!(content_requests(), [2, 2, 1]);
+        });
+    }
+
+    #[test]
+    fn test_version_query_during_update() {
+        let _guard = crate::tests::lock();
+        run([ACCEPT, ACCEPT, ACCEPT], async {
+            let splitter = create(&DEVICES, Policy::Sequential);
+            assert_eq!(offer(&splitter).await.status, OfferStatus::Accept);
+
+            // The mock devices don't answer version queries, the failure is reported but isn't an update failure
+            assert_eq!(
+                splitter.process_request(RequestData::FwVersionRequest).await,
+                InternalResponseData::invalid_fw_version(SPLITTER)
+            );
+            assert!(splitter.failures().await.is_empty());
+
+            // The update carries on
+            assert_eq!(content(&splitter, 0).await, CfuUpdateContentResponseStatus::Success);
+            assert_eq!(content_requests(), [1, 1, 1]);
+        });
+    }
 }
@@ -0,0 +1,149 @@
+//! ECDSA P-256 with SHA-256 reference verifier
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use super::{Error, ImageVerifier};
+
+/// Verifies images signed with ECDSA P-256 over their SHA-256 hash
+///
+/// The signature is the 64 byte big-endian `r || s` pair.
+pub struct EcdsaP256Verifier {
+    key: VerifyingKey,
+    hasher: Sha256,
+}
+
+impl EcdsaP256Verifier {
+    /// Create a verifier from a SEC1 encoded public key
+    pub fn new(public_key: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            key: VerifyingKey::from_sec1_bytes(public_key).map_err(|_| Error::InvalidKey)?,
+            hasher: Sha256::new(),
+        })
+    }
+}
+
+impl ImageVerifier for EcdsaP256Verifier {
+    const SIGNATURE_SIZE: usize = 64;
+
+    fn reset(&mut self) {
+        self.hasher = Sha256::new();
+    }
+
+    async fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    async fn verify(&mut self, signature: &[u8]) -> Result<(), Error> {
+        let digest = self.hasher.finalize_reset();
+        let signature = Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+        self.key
+            .verify_prehash(&digest, &signature)
+            .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::verify::SignatureCheck;
+
+    /// Uncompressed SEC1 public key
+    const PUBLIC_KEY: [u8; 65] = [
+        0x04, 0x47, 0x1c, 0x3e, 0x75, 0x8c, 0x49, 0x04, 0x28, 0x5b, 0xba, 0x7e, 0x53, 0x11, 0x8e, 0xd0, 0xf5, 0x24,
+        0xad, 0xeb, 0x07, 0x57, 0xd2, 0x5b, 0xd2, 0xf8, 0xe7, 0xb0, 0xd7, 0x6d, 0xfa, 0x71, 0x4c, 0xdd, 0x52, 0x0f,
+        0x7a, 0xca, 0x8a, 0x8b, 0x91, 0x7a, 0xcc, 0x37, 0xf5, 0x1d, 0xe8, 0xf0, 0xc9, 0xbb, 0xe3, 0xad, 0x85, 0x83,
+        0x82, 0xe7, 0x02, 0xdc, 0x25, 0xa1, 0x2d, 0x09, 0xf7, 0xa8, 0x58,
+    ];
+
+    /// Signature over [`image_body`]
+    const SIGNATURE: [u8; 64] = [
+        0xfa, 0xcf, 0x19, 0x56, 0x06, 0xab, 0xa6, 0x70, 0xd5, 0xfb, 0xdb, 0x36, 0x20, 0x41, 0x19, 0xa9, 0xc3, 0x0b,
+        0x03, 0x69, 0x1f, 0x42, 0x76, 0x33, 0x81, 0x48, 0x7e, 0xed, 0xa2, 0xa3, 0x5c, 0xdc, 0x9a, 0xd7, 0x65, 0x66,
+        0xb8, 0xa4, 0x55, 0x85, 0x36, 0x69, 0xe2, 0x48, 0x46, 0xaa, 0x03, 0x8d, 0xf7, 0xbc, 0x20, 0x75, 0xf6, 0x8e,
+        0x2f, 0xa3, 0xd5, 0xa0, 0xb4, 0x69, 0x2f, 0x3b, 0x49, 0xd6,
+    ];
+
+    const BODY_SIZE: usize = 200;
+
+    /// CFU content block size
+    const BLOCK_SIZE: usize = 52;
+
+    fn image_body() -> [u8; BODY_SIZE] {
+        core::array::from_fn(|i| (i * 7 + 3) as u8)
+    }
+
+    fn check_image(body: &[u8], signature: &[u8], block_size: usize) -> Result<(), Error> {
+        let mut check = SignatureCheck::new(EcdsaP256Verifier::new(&PUBLIC_KEY).unwrap());
+        block_on(async {
+            check.begin();
+            for block in body.chunks(block_size) {
+                check.update(block).await?;
+            }
+            for block in signature.chunks(block_size) {
+                check.push_signature(block)?;
+            }
+            check.finish().await
+        })
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut verifier = EcdsaP256Verifier::new(&PUBLIC_KEY).unwrap();
+        block_on(async {
+            verifier.update(&image_body()).await;
+            assert_eq!(verifier.verify(&SIGNATURE).await, Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_streaming_block_sizes() {
+        let body = image_body();
+        for block_size in [1, 7, BLOCK_SIZE, 64, 100, BODY_SIZE] {
+            assert_eq!(
+                check_image(&body, &SIGNATURE, block_size),
+                Ok(()),
+                "block size {block_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tampered_image() {
+        let mut tampered_body = image_body();
+        tampered_body[10] ^= 1;
+        assert_eq!(
+            check_image(&tampered_body, &SIGNATURE, BLOCK_SIZE),
+            Err(Error::InvalidSignature)
+        );
+
+        let mut tampered_signature = SIGNATURE;
+        tampered_signature[5] ^= 1;
+        assert_eq!(
+            check_image(&image_body(), &tampered_signature, BLOCK_SIZE),
+            Err(Error::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_signature_length() {
+        assert_eq!(
+            check_image(&image_body(), &SIGNATURE[..32], BLOCK_SIZE),
+            Err(Error::InvalidLength)
+        );
+
+        let mut long = [0; 65];
+        long[..64].copy_from_slice(&SIGNATURE);
+        assert_eq!(check_image(&image_body(), &long, BLOCK_SIZE), Err(Error::InvalidLength));
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        assert!(matches!(
+            EcdsaP256Verifier::new(&PUBLIC_KEY[..64]),
+            Err(Error::InvalidKey)
+        ));
+    }
+}
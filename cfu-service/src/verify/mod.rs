@@ -0,0 +1,775 @@
+//! Firmware image signature verification
+//!
+//! [`SignatureCheck`] streams the firmware data of an image through an [`ImageVerifier`] block by block and checks it
+//! against the image's signature record, see [`crate::image`]. [`VerifiedComponent`] puts a check in front of a CFU
+//! component, the last content block and [`RequestData::FinalizeUpdate`] are only forwarded once the image verified.
+//! This covers components that activate the new image on either.
+//!
+//! The signature isn't firmware, so it isn't forwarded to the component. The host sends the signature record as the
+//! last content blocks of the update, each addressed to [`SIGNATURE_ADDRESS`], see
+//! [`crate::host::Config::send_signature`]. Once the signature verifies, the last block is forwarded without data so
+//! the component still sees the end of the update.
+use core::future::Future;
+
+use embassy_sync::mutex::Mutex;
+use embedded_cfu_protocol::protocol_definitions::*;
+use embedded_services::{
+    GlobalRawMutex,
+    cfu::{
+        self,
+        component::{CfuDevice, InternalResponseData, RequestData},
+    },
+    error, info, intrusive_list, trace,
+};
+
+use crate::image::SIGNATURE_ADDRESS;
+
+#[cfg(any(test, feature = "ecdsa-p256"))]
+pub mod ecdsa;
+
+/// Largest signature supported by [`SignatureCheck`]
+pub const MAX_SIGNATURE_SIZE: usize = 128;
+
+/// Verification errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Image data received before the first block
+    NotStarted,
+    /// Image data received after the signature
+    DataAfterSignature,
+    /// Signature is missing or has the wrong length
+    InvalidLength,
+    /// Verification key is malformed
+    InvalidKey,
+    /// Signature is malformed or doesn't match the image
+    InvalidSignature,
+}
+
+/// Image signature verifier
+pub trait ImageVerifier {
+    /// Size of the signature in the image's signature record
+    const SIGNATURE_SIZE: usize;
+
+    /// Start hashing a new image
+    fn reset(&mut self);
+
+    /// Hash the next image bytes
+    fn update(&mut self, data: &[u8]) -> impl Future<Output = ()>;
+
+    /// Verify `signature` against the bytes hashed since the last reset
+    fn verify(&mut self, signature: &[u8]) -> impl Future<Output = Result<(), Error>>;
+}
+
+/// Progress of a [`SignatureCheck`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CheckState {
+    /// No image in progress
+    Idle,
+    /// Hashing image data
+    Hashing,
+    /// The image signature is valid
+    Verified,
+    /// The image signature is invalid
+    Failed,
+}
+
+/// Streams an image through an [`ImageVerifier`] and checks it against the signature that follows it
+pub struct SignatureCheck<V: ImageVerifier> {
+    verifier: V,
+    state: CheckState,
+    /// Signature bytes received so far
+    signature: [u8; MAX_SIGNATURE_SIZE],
+    signature_len: usize,
+}
+
+impl<V: ImageVerifier> SignatureCheck<V> {
+    /// Create a new signature check
+    pub fn new(verifier: V) -> Self {
+        const { assert!(V::SIGNATURE_SIZE <= MAX_SIGNATURE_SIZE) };
+        Self {
+            verifier,
+            state: CheckState::Idle,
+            signature: [0; MAX_SIGNATURE_SIZE],
+            signature_len: 0,
+        }
+    }
+
+    /// Current state
+    pub fn state(&self) -> CheckState {
+        self.state
+    }
+
+    /// Start a new image
+    pub fn begin(&mut self) {
+        self.verifier.reset();
+        self.signature_len = 0;
+        self.state = CheckState::Hashing;
+    }
+
+    /// Return to idle, discarding any image in progress
+    pub fn reset(&mut self) {
+        self.signature_len = 0;
+        self.state = CheckState::Idle;
+    }
+
+    /// Check that image data can be processed
+    pub fn accepts_data(&self) -> Result<(), Error> {
+        if self.state != CheckState::Hashing {
+            Err(Error::NotStarted)
+        } else if self.signature_len > 0 {
+            Err(Error::DataAfterSignature)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Hash the next image bytes
+    pub async fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.accepts_data()?;
+        self.verifier.update(data).await;
+        Ok(())
+    }
+
+    /// Append the next signature bytes
+    pub fn push_signature(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.state != CheckState::Hashing {
+            return Err(Error::NotStarted);
+        }
+
+        let end = self.signature_len + data.len();
+        if end > V::SIGNATURE_SIZE {
+            return Err(Error::InvalidLength);
+        }
+        self.signature[self.signature_len..end].copy_from_slice(data);
+        self.signature_len = end;
+        Ok(())
+    }
+
+    /// Verify the signature once the whole image has been processed
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        if self.state != CheckState::Hashing {
+            return Err(Error::NotStarted);
+        }
+
+        let result = if self.signature_len != V::SIGNATURE_SIZE {
+            Err(Error::InvalidLength)
+        } else {
+            self.verifier.verify(&self.signature[..self.signature_len]).await
+        };
+        self.state = if result.is_ok() {
+            CheckState::Verified
+        } else {
+            CheckState::Failed
+        };
+        result
+    }
+}
+
+/// CFU device that verifies images before letting a component activate them
+///
+/// Requests sent to the external ID are forwarded to the verified component.
+pub struct VerifiedComponent<V: ImageVerifier> {
+    /// CFU device
+    cfu_device: CfuDevice,
+    /// Component ID to verify images for
+    verified_id: ComponentId,
+    /// Signature check for the image in progress
+    check: Mutex<GlobalRawMutex, SignatureCheck<V>>,
+}
+
+impl<V: ImageVerifier> VerifiedComponent<V> {
+    /// Create a new verified component
+    pub fn new(external_id: ComponentId, verified_id: ComponentId, verifier: V) -> Self {
+        Self {
+            cfu_device: CfuDevice::new(external_id),
+            verified_id,
+            check: Mutex::new(SignatureCheck::new(verifier)),
+        }
+    }
+
+    /// Create a content rejection response
+    fn create_content_rejection(sequence: u16) -> InternalResponseData {
+        InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
+            sequence,
+            CfuUpdateContentResponseStatus::ErrorInvalid,
+        ))
+    }
+
+    /// Process a fw version request
+    async fn process_get_fw_version(&self) -> InternalResponseData {
+        if let Ok(InternalResponseData::FwVersionResponse(mut response)) =
+            cfu::route_request(self.verified_id, RequestData::FwVersionRequest).await
+        {
+            // Update the component ID in the response to match our external ID
+            response.component_info[0].component_id = self.cfu_device.component_id();
+            InternalResponseData::FwVersionResponse(response)
+        } else {
+            error!("Failed to get FW version for device {}", self.verified_id);
+            InternalResponseData::invalid_fw_version(self.cfu_device.component_id())
+        }
+    }
+
+    /// Process a give offer request
+    async fn process_give_offer(&self, offer: &FwUpdateOffer) -> InternalResponseData {
+        let mut offer = *offer;
+        offer.component_info.component_id = self.verified_id;
+        if let Ok(response @ InternalResponseData::OfferResponse(_)) =
+            cfu::route_request(self.verified_id, RequestData::GiveOffer(offer)).await
+        {
+            response
+        } else {
+            error!("Failed to give offer for device {}", self.verified_id);
+            InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_with_failure(
+                HostToken::Driver,
+                OfferRejectReason::InvalidComponent,
+                OfferStatus::Reject,
+            ))
+        }
+    }
+
+    /// Forward a content block to the verified component
+    ///
+    /// Returns the response as `Ok` if the component accepted the block. Verification is reset if the component
+    /// rejected it, but not if it was busy since the host retries the block.
+    async fn forward_content(
+        &self,
+        check: &mut SignatureCheck<V>,
+        content: FwUpdateContentCommand,
+    ) -> Result<InternalResponseData, InternalResponseData> {
+        let sequence = content.header.sequence_num;
+        match cfu::route_request(self.verified_id, RequestData::GiveContent(content)).await {
+            Ok(
+                response @ InternalResponseData::ContentResponse(FwUpdateContentResponse {
+                    status: CfuUpdateContentResponseStatus::Success,
+                    ..
+                }),
+            ) => Ok(response),
+            Ok(InternalResponseData::ComponentBusy) => {
+                trace!("Device {}: busy, content {} not accepted", self.verified_id, sequence);
+                Err(InternalResponseData::ComponentBusy)
+            }
+            Ok(response) => {
+                // The component didn't take the block, so whatever it holds isn't the image being verified
+                error!(
+                    "Device {}: content {} rejected, resetting verification",
+                    self.verified_id, sequence
+                );
+                check.reset();
+                Err(response)
+            }
+            Err(e) => {
+                error!("Failed to send content to device {}: {:?}", self.verified_id, e);
+                check.reset();
+                Err(Self::create_content_rejection(sequence))
+            }
+        }
+    }
+
+    /// Process a block of the signature record
+    async fn process_signature(
+        &self,
+        check: &mut SignatureCheck<V>,
+        content: &FwUpdateContentCommand,
+    ) -> InternalResponseData {
+        let sequence = content.header.sequence_num;
+        let len = (content.header.data_length as usize).min(content.data.len());
+        let last = content.header.flags & FW_UPDATE_FLAG_LAST_BLOCK != 0;
+
+        // A retried last block has already been verified
+        if check.state() == CheckState::Hashing {
+            if let Err(e) = check.push_signature(&content.data[..len]) {
+                error!("Device {}: rejecting signature {}: {:?}", self.verified_id, sequence, e);
+                check.reset();
+                return Self::create_content_rejection(sequence);
+            }
+
+            if !last {
+                return InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
+                    sequence,
+                    CfuUpdateContentResponseStatus::Success,
+                ));
+            }
+
+            // Don't let the component see the last block unless the image is authentic
+            if let Err(e) = check.finish().await {
+                error!("Device {}: image verification failed: {:?}", self.verified_id, e);
+                return Self::create_content_rejection(sequence);
+            }
+            info!("Device {}: image verified", self.verified_id);
+        }
+
+        if !last || check.state() != CheckState::Verified {
+            error!(
+                "Device {}: unexpected signature {}, state {:?}",
+                self.verified_id,
+                sequence,
+                check.state()
+            );
+            check.reset();
+            return Self::create_content_rejection(sequence);
+        }
+
+        let mut end = *content;
+        end.header.data_length = 0;
+        end.data = [0; DEFAULT_DATA_LENGTH];
+        match self.forward_content(check, end).await {
+            Ok(response) | Err(response) => response,
+        }
+    }
+
+    /// Process update content
+    async fn process_give_content(&self, content: &FwUpdateContentCommand) -> InternalResponseData {
+        let sequence = content.header.sequence_num;
+        let len = (content.header.data_length as usize).min(content.data.len());
+        let mut check = self.check.lock().await;
+
+        if content.header.flags & FW_UPDATE_FLAG_FIRST_BLOCK != 0 {
+            check.begin();
+        }
+
+        if content.header.firmware_address == SIGNATURE_ADDRESS {
+            return self.process_signature(&mut check, content).await;
+        }
+
+        if content.header.flags & FW_UPDATE_FLAG_LAST_BLOCK != 0 {
+            error!("Device {}: image has no signature", self.verified_id);
+            check.reset();
+            return Self::create_content_rejection(sequence);
+        }
+
+        if let Err(e) = check.accepts_data() {
+            error!("Device {}: rejecting content {}: {:?}", self.verified_id, sequence, e);
+            check.reset();
+            return Self::create_content_rejection(sequence);
+        }
+
+        match self.forward_content(&mut check, *content).await {
+            Ok(response) => {
+                // Only hash blocks the component took so a retried block isn't hashed twice
+                if let Err(e) = check.update(&content.data[..len]).await {
+                    error!(
+                        "Device {}: failed to hash content {}: {:?}",
+                        self.verified_id, sequence, e
+                    );
+                    check.reset();
+                    return Self::create_content_rejection(sequence);
+                }
+                response
+            }
+            Err(response) => response,
+        }
+    }
+
+    /// Process a finalize request
+    async fn process_finalize_update(&self) -> InternalResponseData {
+        let mut check = self.check.lock().await;
+        if check.state() != CheckState::Verified {
+            error!(
+                "Device {}: refusing to finalize unverified image, state {:?}",
+                self.verified_id,
+                check.state()
+            );
+            check.reset();
+            return InternalResponseData::ComponentBusy;
+        }
+
+        check.reset();
+        match cfu::route_request(self.verified_id, RequestData::FinalizeUpdate).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to finalize device {}: {:?}", self.verified_id, e);
+                InternalResponseData::ComponentBusy
+            }
+        }
+    }
+
+    /// Wait for a CFU request
+    pub async fn wait_request(&self) -> RequestData {
+        self.cfu_device.wait_request().await
+    }
+
+    /// Process a CFU request and produce a response
+    pub async fn process(&self, request: RequestData) -> InternalResponseData {
+        match request {
+            RequestData::FwVersionRequest => {
+                trace!("Got FwVersionRequest");
+                self.process_get_fw_version().await
+            }
+            RequestData::GiveOffer(offer) => {
+                trace!("Got GiveOffer");
+                self.process_give_offer(&offer).await
+            }
+            RequestData::GiveContent(content) => {
+                trace!("Got GiveContent");
+                self.process_give_content(&content).await
+            }
+            RequestData::FinalizeUpdate => {
+                trace!("Got FinalizeUpdate");
+                self.process_finalize_update().await
+            }
+            RequestData::PrepareComponentForUpdate => {
+                trace!("Got PrepareComponentForUpdate");
+                match cfu::route_request(self.verified_id, RequestData::PrepareComponentForUpdate).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        error!("Failed to prepare device {}: {:?}", self.verified_id, e);
+                        InternalResponseData::ComponentBusy
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a response to the CFU message
+    pub async fn send_response(&self, response: InternalResponseData) {
+        self.cfu_device.send_response(response).await;
+    }
+
+    /// Register the verified component with all relevant services
+    pub async fn register(&'static self) -> Result<(), intrusive_list::Error> {
+        cfu::register_device(&self.cfu_device).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+    use std::boxed::Box;
+    use std::sync::OnceLock as StdOnceLock;
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{Either, select};
+    use embassy_time::Duration;
+
+    use super::*;
+    use crate::host::{self, MemoryImage, OutOfBounds, Updater};
+    use crate::image::{
+        self,
+        tests::{offer_file, push_record, push_segment_crc},
+    };
+
+    /// ID the verified component is reached through
+    const EXTERNAL: ComponentId = 0x40;
+    /// ID of the mock component behind it
+    const VERIFIED: ComponentId = 0x41;
+
+    /// Mock component rejects the last block
+    static REJECT_LAST: AtomicBool = AtomicBool::new(false);
+    /// Sequence number of a block the mock component answers with busy once, `u32::MAX` for none
+    static BUSY_SEQUENCE: AtomicU32 = AtomicU32::new(u32::MAX);
+    /// Content blocks that reached the mock component
+    static CONTENT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    /// Data length of the last block that reached the mock component
+    static LAST_LENGTH: AtomicUsize = AtomicUsize::new(usize::MAX);
+    /// Finalize requests that reached the mock component
+    static FINALIZE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Trivial verifier, the signature is a single byte XOR of the image
+    struct XorVerifier(u8);
+
+    impl ImageVerifier for XorVerifier {
+        const SIGNATURE_SIZE: usize = 1;
+
+        fn reset(&mut self) {
+            self.0 = 0;
+        }
+
+        async fn update(&mut self, data: &[u8]) {
+            self.0 = data.iter().fold(self.0, |acc, b| acc ^ b);
+        }
+
+        async fn verify(&mut self, signature: &[u8]) -> Result<(), Error> {
+            if signature == [self.0] {
+                Ok(())
+            } else {
+                Err(Error::InvalidSignature)
+            }
+        }
+    }
+
+    fn setup() -> (&'static VerifiedComponent<XorVerifier>, &'static CfuDevice) {
+        static SETUP: StdOnceLock<(&'static VerifiedComponent<XorVerifier>, &'static CfuDevice)> = StdOnceLock::new();
+
+        let (component, device) = *SETUP.get_or_init(|| {
+            let device: &'static CfuDevice = Box::leak(Box::new(CfuDevice::new(VERIFIED)));
+            block_on(cfu::register_device(device)).unwrap();
+            let component: &'static VerifiedComponent<XorVerifier> =
+                Box::leak(Box::new(VerifiedComponent::new(EXTERNAL, VERIFIED, XorVerifier(0))));
+            block_on(component.register()).unwrap();
+            (component, device)
+        });
+
+        REJECT_LAST.store(false, Ordering::SeqCst);
+        BUSY_SEQUENCE.store(u32::MAX, Ordering::SeqCst);
+        CONTENT_REQUESTS.store(0, Ordering::SeqCst);
+        LAST_LENGTH.store(usize::MAX, Ordering::SeqCst);
+        FINALIZE_REQUESTS.store(0, Ordering::SeqCst);
+        (component, device)
+    }
+
+    /// Mock component
+    async fn device_task(device: &CfuDevice) {
+        loop {
+            let response = match device.wait_request().await {
+                RequestData::GiveContent(content) => {
+                    let sequence = content.header.sequence_num;
+                    let last = content.header.flags & FW_UPDATE_FLAG_LAST_BLOCK != 0;
+                    if BUSY_SEQUENCE
+                        .compare_exchange(sequence as u32, u32::MAX, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                    {
+                        InternalResponseData::ComponentBusy
+                    } else {
+                        CONTENT_REQUESTS.fetch_add(1, Ordering::SeqCst);
+                        if last {
+                            LAST_LENGTH.store(content.header.data_length as usize, Ordering::SeqCst);
+                        }
+                        let status = if last && REJECT_LAST.load(Ordering::SeqCst) {
+                            CfuUpdateContentResponseStatus::ErrorWrite
+                        } else {
+                            CfuUpdateContentResponseStatus::Success
+                        };
+                        InternalResponseData::ContentResponse(FwUpdateContentResponse::new(sequence, status))
+                    }
+                }
+                RequestData::GiveOffer(_) => {
+                    InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_accept(HostToken::Driver))
+                }
+                RequestData::PrepareComponentForUpdate => InternalResponseData::ComponentPrepared,
+                RequestData::FinalizeUpdate => {
+                    FINALIZE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+                    InternalResponseData::ComponentPrepared
+                }
+                RequestData::FwVersionRequest => InternalResponseData::invalid_fw_version(VERIFIED),
+            };
+            device.send_response(response).await;
+        }
+    }
+
+    /// Run a scenario with the mock component answering requests
+    fn run(device: &CfuDevice, scenario: impl Future<Output = ()>) {
+        match block_on(select(device_task(device), scenario)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    }
+
+    fn content(sequence: u16, firmware_address: u32, flags: u8, data: &[u8]) -> FwUpdateContentCommand {
+        let mut content = FwUpdateContentCommand {
+            header: FwUpdateContentHeader {
+                data_length: data.len() as u8,
+                sequence_num: sequence,
+                firmware_address,
+                flags,
+            },
+            data: [0; DEFAULT_DATA_LENGTH],
+        };
+        content.data[..data.len()].copy_from_slice(data);
+        content
+    }
+
+    /// Two data blocks followed by the signature
+    fn image(valid: bool) -> [FwUpdateContentCommand; 3] {
+        let mut data = [[0x5a; DEFAULT_DATA_LENGTH]; 2];
+        data[0][0] = 0x12;
+        let signature = data.iter().flatten().fold(0, |acc, b| acc ^ b);
+        let signature = if valid { signature } else { !signature };
+        [
+            content(0, 0, FW_UPDATE_FLAG_FIRST_BLOCK, &data[0]),
+            content(1, DEFAULT_DATA_LENGTH as u32, 0, &data[1]),
+            content(2, SIGNATURE_ADDRESS, FW_UPDATE_FLAG_LAST_BLOCK, &[signature]),
+        ]
+    }
+
+    /// Send content blocks and return the status of the last one
+    async fn send(
+        component: &VerifiedComponent<XorVerifier>,
+        blocks: &[FwUpdateContentCommand],
+    ) -> CfuUpdateContentResponseStatus {
+        let mut status = CfuUpdateContentResponseStatus::Success;
+        for block in blocks {
+            match component.process(RequestData::GiveContent(*block)).await {
+                InternalResponseData::ContentResponse(r) => status = r.status,
+                resp => panic!("Unexpected response {resp:?}"),
+            }
+        }
+        status
+    }
+
+    #[test]
+    fn test_verified_image() {
+        let _guard = crate::tests::lock();
+        let (component, device) = setup();
+        run(device, async {
+            assert_eq!(
+                send(component, &image(true)).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            // The signature isn't forwarded, only the end of the update
+            assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 3);
+            assert_eq!(LAST_LENGTH.load(Ordering::SeqCst), 0);
+            assert_eq!(
+                component.process(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentPrepared
+            );
+            assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let _guard = crate::tests::lock();
+        let (component, device) = setup();
+        run(device, async {
+            // The last block never reaches the component
+            assert_eq!(
+                send(component, &image(false)).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 2);
+            assert_eq!(LAST_LENGTH.load(Ordering::SeqCst), usize::MAX);
+            assert_eq!(
+                component.process(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentBusy
+            );
+            assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    #[test]
+    fn test_unsigned_image() {
+        let _guard = crate::tests::lock();
+        let (component, device) = setup();
+        run(device, async {
+            let mut blocks = image(true);
+            blocks[1].header.flags = FW_UPDATE_FLAG_LAST_BLOCK;
+            assert_eq!(
+                send(component, &blocks[..2]).await,
+                CfuUpdateContentResponseStatus::ErrorInvalid
+            );
+            assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 1);
+            assert_eq!(
+                component.process(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentBusy
+            );
+        });
+    }
+
+    #[test]
+    fn test_busy_component() {
+        let _guard = crate::tests::lock();
+        let (component, device) = setup();
+        BUSY_SEQUENCE.store(1, Ordering::SeqCst);
+        run(device, async {
+            let blocks = image(true);
+            assert_eq!(
+                send(component, &blocks[..1]).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            assert_eq!(
+                component.process(RequestData::GiveContent(blocks[1])).await,
+                InternalResponseData::ComponentBusy
+            );
+
+            // The busy block is hashed once, after the retry is accepted
+            assert_eq!(
+                send(component, &blocks[1..]).await,
+                CfuUpdateContentResponseStatus::Success
+            );
+            assert_eq!(
+                component.process(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentPrepared
+            );
+        });
+    }
+
+    #[test]
+    fn test_component_rejects_last_block() {
+        let _guard = crate::tests::lock();
+        let (component, device) = setup();
+        REJECT_LAST.store(true, Ordering::SeqCst);
+        run(device, async {
+            assert_eq!(
+                send(component, &image(true)).await,
+                CfuUpdateContentResponseStatus::ErrorWrite
+            );
+            assert_eq!(
+                component.process(RequestData::FinalizeUpdate).await,
+                InternalResponseData::ComponentBusy
+            );
+            assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    /// Payload with a data record, its segment CRC and a signature record
+    fn signed_payload(valid: bool) -> Vec<u8> {
+        let data: Vec<u8> = (0..100).collect();
+        let signature = data.iter().fold(0, |acc, b| acc ^ b);
+        let mut payload = Vec::new();
+        push_record(&mut payload, 0x1000, &data);
+        push_segment_crc(&mut payload, 0);
+        push_record(
+            &mut payload,
+            SIGNATURE_ADDRESS,
+            &[if valid { signature } else { !signature }],
+        );
+        payload
+    }
+
+    /// Run a host update through the verified component
+    async fn update(
+        component: &VerifiedComponent<XorVerifier>,
+        updater: &Updater,
+        payload: &[u8],
+    ) -> Result<(), host::Error<OutOfBounds>> {
+        let verified = async {
+            loop {
+                let request = component.wait_request().await;
+                let response = component.process(request).await;
+                component.send_response(response).await;
+            }
+        };
+        let offer = offer_file(EXTERNAL, 0x100);
+        let update = updater.update(&mut MemoryImage::new(&offer), &mut MemoryImage::new(payload));
+        match select(verified, update).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(result) => result,
+        }
+    }
+
+    #[test]
+    fn test_signed_update() {
+        let _guard = crate::tests::lock();
+        let (component, device) = setup();
+        let updater = Updater::new(host::Config {
+            max_retries: 0,
+            retry_delay: Duration::from_millis(1),
+            validation: image::Config {
+                require_signature: true,
+            },
+            send_signature: true,
+        });
+        run(device, async {
+            assert_eq!(update(component, &updater, &signed_payload(true)).await, Ok(()));
+            // Both data blocks and the end of the update
+            assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 3);
+            assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 1);
+
+            CONTENT_REQUESTS.store(0, Ordering::SeqCst);
+            FINALIZE_REQUESTS.store(0, Ordering::SeqCst);
+            assert_eq!(
+                update(component, &updater, &signed_payload(false)).await,
+                Err(host::Error::ContentRejected(
+                    CfuUpdateContentResponseStatus::ErrorInvalid
+                ))
+            );
+            assert_eq!(CONTENT_REQUESTS.load(Ordering::SeqCst), 2);
+            assert_eq!(FINALIZE_REQUESTS.load(Ordering::SeqCst), 0);
+        });
+    }
+}
@@ -93,6 +93,17 @@ pub enum InternalResponseData {
     ComponentPrepared,
 }
 
+impl InternalResponseData {
+    /// FW version response reporting an invalid version, used when the real version can't be retrieved
+    pub fn invalid_fw_version(component_id: ComponentId) -> Self {
+        let dev_inf = FwVerComponentInfo::new(FwVersion::new(0xffffffff), component_id);
+        InternalResponseData::FwVersionResponse(GetFwVersionResponse {
+            header: GetFwVersionResponseHeader::new(1, GetFwVerRespHeaderByte3::NoSpecialFlags),
+            component_info: [dev_inf; MAX_CMPT_COUNT],
+        })
+    }
+}
+
 /// Channel size for device requests
 pub const DEVICE_CHANNEL_SIZE: usize = 1;
 
@@ -255,29 +266,63 @@ impl<W: CfuWriterAsync> CfuComponentDefault<W> {
                     .await;
             }
             RequestData::PrepareComponentForUpdate => {
-                self.storage_prepare()
-                    .await
-                    .map_err(|_| CfuError::ProtocolError(CfuProtocolError::BadResponse))?;
+                if let Err(e) = self.storage_prepare().await {
+                    self.device.send_response(InternalResponseData::ComponentBusy).await;
+                    return Err(CfuError::ProtocolError(CfuProtocolError::WriterError(e)));
+                }
+                let resp = if self.is_primary_component() && self.get_subcomponents()[0].is_some() {
+                    // unused entries are left as 0
+                    let mut subcomponents = [0; MAX_CMPT_COUNT - 1];
+                    for (dst, id) in subcomponents.iter_mut().zip(self.get_subcomponents().iter().flatten()) {
+                        *dst = *id;
+                    }
+                    InternalResponseData::PrimaryNeedsSubcomponentsPrepared(subcomponents)
+                } else {
+                    InternalResponseData::ComponentPrepared
+                };
+                self.device.send_response(resp).await;
             }
             RequestData::GiveOffer(buf) => {
                 // accept any and all offers regardless of what version it is
-                if buf.component_info.component_id == self.get_component_id() {
-                    let resp = FwUpdateOfferResponse::new_accept(HostToken::Driver);
-                    self.device
-                        .send_response(InternalResponseData::OfferResponse(resp))
-                        .await;
-                }
+                let resp = if buf.component_info.component_id == self.get_component_id() {
+                    FwUpdateOfferResponse::new_accept(HostToken::Driver)
+                } else {
+                    FwUpdateOfferResponse::new_with_failure(
+                        HostToken::Driver,
+                        OfferRejectReason::InvalidComponent,
+                        OfferStatus::Reject,
+                    )
+                };
+                self.device
+                    .send_response(InternalResponseData::OfferResponse(resp))
+                    .await;
             }
             RequestData::GiveContent(buf) => {
                 let offset = buf.header.firmware_address as usize;
-                self.writer
-                    .lock()
-                    .await
-                    .cfu_write(Some(offset), &buf.data)
-                    .await
-                    .map_err(|e| CfuError::ProtocolError(CfuProtocolError::WriterError(e)))?;
+                let result = self.writer.lock().await.cfu_write(Some(offset), &buf.data).await;
+                let status = if result.is_ok() {
+                    CfuUpdateContentResponseStatus::Success
+                } else {
+                    CfuUpdateContentResponseStatus::ErrorWrite
+                };
+                self.device
+                    .send_response(InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
+                        buf.header.sequence_num,
+                        status,
+                    )))
+                    .await;
+                result.map_err(|e| CfuError::ProtocolError(CfuProtocolError::WriterError(e)))?;
+            }
+            RequestData::FinalizeUpdate => {
+                let result = self.storage_finalize().await;
+                let resp = if result.is_ok() {
+                    InternalResponseData::ComponentPrepared
+                } else {
+                    InternalResponseData::ComponentBusy
+                };
+                self.device.send_response(resp).await;
+                result.map_err(|e| CfuError::ProtocolError(CfuProtocolError::WriterError(e)))?;
             }
-            RequestData::FinalizeUpdate => {}
         }
         Ok(())
     }
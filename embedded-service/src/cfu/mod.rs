@@ -1,6 +1,7 @@
 //! Cfu Service related data structures and messages
 //pub mod action;
 pub mod component;
+pub mod progress;
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -0,0 +1,244 @@
+//! CFU update progress telemetry
+//!
+//! The CFU service reports each step of an update through [`publish`]. Progress is tracked per component, so updates
+//! of different components can run at the same time, and broadcast as a [`Progress`] message to [`External::Host`], so
+//! host agents and UIs can follow an update in real time.
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embedded_cfu_protocol::protocol_definitions::{
+    CfuUpdateContentResponseStatus, ComponentId, MAX_CMPT_COUNT, OfferRejectReason,
+};
+use heapless::LinearMap;
+
+use crate::GlobalRawMutex;
+use crate::cfu::component::ComponentState;
+use crate::comms::{self, EndpointID, External, Internal};
+use crate::warn;
+
+/// Update step reported by the CFU service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Update {
+    /// Component accepted an offer, starts a new update
+    OfferAccepted,
+    /// Component didn't accept an offer
+    OfferRejected(OfferRejectReason),
+    /// Component accepted a content block
+    ContentWritten {
+        /// Sequence number of the block
+        sequence: u16,
+        /// Number of data bytes in the block
+        length: u8,
+        /// Block was the last of the image
+        last_block: bool,
+    },
+    /// Component rejected a content block, ends the update
+    ContentRejected {
+        /// Sequence number of the block
+        sequence: u16,
+        /// Status returned by the component
+        status: CfuUpdateContentResponseStatus,
+    },
+    /// Update finalized, ends the update
+    Finalized {
+        /// Component finalized the update successfully
+        success: bool,
+    },
+}
+
+/// Progress message broadcast to [`External::Host`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Progress {
+    /// Component being updated
+    pub component: ComponentId,
+    /// Component state after this step
+    pub state: ComponentState,
+    /// Step that produced this message
+    pub update: Update,
+    /// Firmware bytes written so far
+    pub bytes_written: u32,
+    /// Total firmware bytes in the image, if known
+    pub total_bytes: Option<u32>,
+    /// Sequence number of the last block written
+    pub sequence: Option<u16>,
+    /// Time since the offer was accepted
+    pub elapsed: Duration,
+}
+
+/// Tracking for an update in progress
+#[derive(Clone, Copy)]
+struct Tracker {
+    started: Instant,
+    bytes_written: u32,
+    total_bytes: Option<u32>,
+    sequence: Option<u16>,
+}
+
+impl Tracker {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes_written: 0,
+            total_bytes: None,
+            sequence: None,
+        }
+    }
+}
+
+/// Maximum number of updates tracked at the same time
+const MAX_TRACKED_UPDATES: usize = MAX_CMPT_COUNT;
+
+/// Updates in progress, keyed by component
+static TRACKERS: Mutex<GlobalRawMutex, LinearMap<ComponentId, Tracker, MAX_TRACKED_UPDATES>> =
+    Mutex::new(LinearMap::new());
+
+/// Set the size of the image for the update in progress on `component`, if the reporter knows it
+pub async fn set_total_bytes(component: ComponentId, total: u32) {
+    if let Some(tracker) = TRACKERS.lock().await.get_mut(&component) {
+        tracker.total_bytes = Some(total);
+    }
+}
+
+/// Record an update step and broadcast the resulting progress
+pub async fn publish(component: ComponentId, update: Update) -> Progress {
+    let mut trackers = TRACKERS.lock().await;
+
+    // Steps for a component that isn't tracked, e.g. after a reset, start tracking from here
+    let mut tracker = match trackers.get(&component) {
+        Some(tracker) if update != Update::OfferAccepted => *tracker,
+        _ => Tracker::new(),
+    };
+
+    let state = match update {
+        Update::OfferAccepted => ComponentState::Busy,
+        Update::ContentWritten {
+            sequence,
+            length,
+            last_block,
+        } => {
+            tracker.bytes_written = tracker.bytes_written.saturating_add(length as u32);
+            tracker.sequence = Some(sequence);
+            if last_block {
+                ComponentState::FinalizingUpdate
+            } else {
+                ComponentState::Busy
+            }
+        }
+        Update::OfferRejected(_) | Update::ContentRejected { .. } | Update::Finalized { .. } => ComponentState::Idle,
+    };
+
+    if state == ComponentState::Idle {
+        trackers.remove(&component);
+    } else if trackers.insert(component, tracker).is_err() {
+        warn!("Too many updates in progress, not tracking component {}", component);
+    }
+    drop(trackers);
+
+    let progress = Progress {
+        component,
+        state,
+        update,
+        bytes_written: tracker.bytes_written,
+        total_bytes: tracker.total_bytes,
+        sequence: tracker.sequence,
+        elapsed: tracker.started.elapsed(),
+    };
+
+    let _ = comms::send(
+        EndpointID::Internal(Internal::Nonvol),
+        EndpointID::External(External::Host),
+        &progress,
+    )
+    .await;
+    progress
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_tracking() {
+        comms::init();
+        embassy_futures::block_on(async {
+            let progress = publish(1, Update::OfferAccepted).await;
+            assert_eq!(progress.state, ComponentState::Busy);
+            assert_eq!(progress.bytes_written, 0);
+            assert_eq!(progress.sequence, None);
+
+            set_total_bytes(1, 100).await;
+            let progress = publish(
+                1,
+                Update::ContentWritten {
+                    sequence: 0,
+                    length: 60,
+                    last_block: false,
+                },
+            )
+            .await;
+            assert_eq!(progress.state, ComponentState::Busy);
+            assert_eq!(progress.total_bytes, Some(100));
+
+            // Another component's rejection doesn't affect the update in progress
+            let progress = publish(2, Update::OfferRejected(OfferRejectReason::InvalidComponent)).await;
+            assert_eq!(progress.state, ComponentState::Idle);
+            assert_eq!(progress.bytes_written, 0);
+
+            let progress = publish(
+                1,
+                Update::ContentWritten {
+                    sequence: 1,
+                    length: 40,
+                    last_block: true,
+                },
+            )
+            .await;
+            assert_eq!(progress.state, ComponentState::FinalizingUpdate);
+            assert_eq!(progress.bytes_written, 100);
+            assert_eq!(progress.sequence, Some(1));
+
+            let progress = publish(1, Update::Finalized { success: true }).await;
+            assert_eq!(progress.state, ComponentState::Idle);
+            assert_eq!(progress.bytes_written, 100);
+
+            // Update ended, a new one starts from scratch
+            let progress = publish(1, Update::OfferAccepted).await;
+            assert_eq!(progress.bytes_written, 0);
+            assert_eq!(progress.total_bytes, None);
+        });
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        comms::init();
+        embassy_futures::block_on(async {
+            let written = |sequence, length| Update::ContentWritten {
+                sequence,
+                length,
+                last_block: false,
+            };
+
+            publish(3, Update::OfferAccepted).await;
+            set_total_bytes(3, 200).await;
+            publish(4, Update::OfferAccepted).await;
+            set_total_bytes(4, 50).await;
+
+            // Interleaved steps are accounted to their own component
+            assert_eq!(publish(3, written(0, 60)).await.bytes_written, 60);
+            let progress = publish(4, written(0, 20)).await;
+            assert_eq!(progress.bytes_written, 20);
+            assert_eq!(progress.total_bytes, Some(50));
+            let progress = publish(3, written(1, 60)).await;
+            assert_eq!(progress.bytes_written, 120);
+            assert_eq!(progress.total_bytes, Some(200));
+            assert_eq!(progress.sequence, Some(1));
+
+            // Ending one update keeps tracking the other
+            let progress = publish(4, Update::Finalized { success: false }).await;
+            assert_eq!(progress.state, ComponentState::Idle);
+            assert_eq!(progress.bytes_written, 20);
+            assert_eq!(publish(3, written(2, 60)).await.bytes_written, 180);
+        });
+    }
+}
@@ -4,7 +4,7 @@ import sys,yaml
 def yaml_to_rust(data):
     rust_code = "//! EC Internal Data Structures\n\n"
     rust_code += "#[allow(missing_docs)]\n"
-    rust_code += "pub const EC_MEMMAP_VERSION: Version = Version {major: 0, minor: 1, spin: 0, res0: 0};\n\n"
+    rust_code += "pub const EC_MEMMAP_VERSION: Version = Version {major: 0, minor: 2, spin: 0, res0: 0};\n\n"
     for key, value in data.items():
         rust_code += "#[allow(missing_docs)]\n"
         rust_code += "#[repr(C, packed)]\n"
@@ -35,16 +35,20 @@ def yaml_to_c(data):
 
     c_code += "#pragma pack(pop)\n\n"
 
-    c_code += "const Version EC_MEMMAP_VERSION = {0x00, 0x01, 0x00, 0x00};\n"
+    c_code += "const Version EC_MEMMAP_VERSION = {0x00, 0x02, 0x00, 0x00};\n"
     return c_code
 
 def type_to_c_type(type_str):
-    if type_str == 'u32':
+    if type_str == 'u64':
+        return 'uint64_t'
+    elif type_str == 'u32':
         return 'uint32_t'
     elif type_str == 'u16':
         return 'uint16_t'
     elif type_str == 'u8':
         return 'uint8_t'
+    elif type_str == 'i64':
+        return 'int64_t'
     elif type_str == 'i32':
         return 'int32_t'
     elif type_str == 'i16':
@@ -65,7 +69,7 @@ def open_file(file_path):
     print(f"An error occurred: {e}")
 
 def check_for_32bit_alignment(data):
-  sizes = {'u32': 4, 'u16': 2, 'u8': 1, 'i32': 4, 'i16': 2, 'i8': 1}
+  sizes = {'u64': 8, 'u32': 4, 'u16': 2, 'u8': 1, 'i64': 8, 'i32': 4, 'i16': 2, 'i8': 1}
   for key, value in data.items():
     size = 0
     for sub_key, sub_value in value.items():
@@ -78,7 +82,7 @@ def check_for_32bit_alignment(data):
       print(f"Warning: {key} is not 32-bit aligned. Size: {size} bytes")
 
 def is_primitive_type(type_str):
-    return type_str in ['u32', 'u16', 'u8', 'i32', 'i16', 'i8']
+    return type_str in ['u64', 'u32', 'u16', 'u8', 'i64', 'i32', 'i16', 'i8']
 
 if __name__ == "__main__":
   if len(sys.argv) != 2:
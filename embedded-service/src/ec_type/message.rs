@@ -86,3 +86,20 @@ pub enum ThermalMessage {
     Tmp1Low(u32),
     Tmp1High(u32),
 }
+
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UcsiMessage {
+    Version(u16),
+    Res0(u16),
+    Cci(u32),
+    Control(u64),
+    MessageIn0(u32),
+    MessageIn1(u32),
+    MessageIn2(u32),
+    MessageIn3(u32),
+    MessageOut0(u32),
+    MessageOut1(u32),
+    MessageOut2(u32),
+    MessageOut3(u32),
+}
@@ -104,6 +104,24 @@ pub fn update_time_alarm_section(msg: &message::TimeAlarmMessage, memory_map: &m
     }
 }
 
+/// Update UCSI section of memory map based on UCSI message
+pub fn update_ucsi_section(msg: &message::UcsiMessage, memory_map: &mut structure::ECMemory) {
+    match msg {
+        message::UcsiMessage::Version(version) => memory_map.ucsi.version = *version,
+        message::UcsiMessage::Res0(res0) => memory_map.ucsi.res0 = *res0,
+        message::UcsiMessage::Cci(cci) => memory_map.ucsi.cci = *cci,
+        message::UcsiMessage::Control(control) => memory_map.ucsi.control = *control,
+        message::UcsiMessage::MessageIn0(message_in0) => memory_map.ucsi.message_in0 = *message_in0,
+        message::UcsiMessage::MessageIn1(message_in1) => memory_map.ucsi.message_in1 = *message_in1,
+        message::UcsiMessage::MessageIn2(message_in2) => memory_map.ucsi.message_in2 = *message_in2,
+        message::UcsiMessage::MessageIn3(message_in3) => memory_map.ucsi.message_in3 = *message_in3,
+        message::UcsiMessage::MessageOut0(message_out0) => memory_map.ucsi.message_out0 = *message_out0,
+        message::UcsiMessage::MessageOut1(message_out1) => memory_map.ucsi.message_out1 = *message_out1,
+        message::UcsiMessage::MessageOut2(message_out2) => memory_map.ucsi.message_out2 = *message_out2,
+        message::UcsiMessage::MessageOut3(message_out3) => memory_map.ucsi.message_out3 = *message_out3,
+    }
+}
+
 /// Helper macro to simplify the conversion of memory map to message
 macro_rules! into_message {
     ($offset:ident, $length:ident, $member:expr, $msg:expr) => {
@@ -502,6 +520,84 @@ pub fn mem_map_to_time_alarm_msg(
     }
 }
 
+/// Convert from memory map offset and length to UCSI message
+/// Modifies offset and length
+pub fn mem_map_to_ucsi_msg(
+    memory_map: &structure::ECMemory,
+    offset: &mut usize,
+    length: &mut usize,
+) -> Result<message::UcsiMessage, Error> {
+    let local_offset = *offset - offset_of!(structure::ECMemory, ucsi);
+
+    if local_offset == offset_of!(structure::Ucsi, version) {
+        into_message!(offset, length, memory_map.ucsi.version, message::UcsiMessage::Version);
+    } else if local_offset == offset_of!(structure::Ucsi, res0) {
+        into_message!(offset, length, memory_map.ucsi.res0, message::UcsiMessage::Res0);
+    } else if local_offset == offset_of!(structure::Ucsi, cci) {
+        into_message!(offset, length, memory_map.ucsi.cci, message::UcsiMessage::Cci);
+    } else if local_offset == offset_of!(structure::Ucsi, control) {
+        into_message!(offset, length, memory_map.ucsi.control, message::UcsiMessage::Control);
+    } else if local_offset == offset_of!(structure::Ucsi, message_in0) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_in0,
+            message::UcsiMessage::MessageIn0
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_in1) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_in1,
+            message::UcsiMessage::MessageIn1
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_in2) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_in2,
+            message::UcsiMessage::MessageIn2
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_in3) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_in3,
+            message::UcsiMessage::MessageIn3
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_out0) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_out0,
+            message::UcsiMessage::MessageOut0
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_out1) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_out1,
+            message::UcsiMessage::MessageOut1
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_out2) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_out2,
+            message::UcsiMessage::MessageOut2
+        );
+    } else if local_offset == offset_of!(structure::Ucsi, message_out3) {
+        into_message!(
+            offset,
+            length,
+            memory_map.ucsi.message_out3,
+            message::UcsiMessage::MessageOut3
+        );
+    } else {
+        Err(Error::InvalidLocation)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1202,4 +1298,130 @@ mod tests {
         let res = mem_map_to_time_alarm_msg(&memory_map, &mut offset, &mut length);
         assert!(res.is_err() && res.unwrap_err() == Error::InvalidLocation);
     }
+
+    #[test]
+    fn test_mem_map_to_ucsi_msg() {
+        use crate::ec_type::message::UcsiMessage;
+        use crate::ec_type::structure::{ECMemory, Ucsi};
+
+        let memory_map = ECMemory {
+            ucsi: Ucsi {
+                version: 1,
+                res0: 2,
+                cci: 3,
+                control: 4,
+                message_in0: 5,
+                message_in1: 6,
+                message_in2: 7,
+                message_in3: 8,
+                message_out0: 9,
+                message_out1: 10,
+                message_out2: 11,
+                message_out3: 12,
+            },
+            ..Default::default()
+        };
+
+        let mut offset = offset_of!(ECMemory, ucsi);
+        let mut length = size_of::<Ucsi>();
+
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.version,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::Version
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.res0,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::Res0
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.cci,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::Cci
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.control,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::Control
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_in0,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageIn0
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_in1,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageIn1
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_in2,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageIn2
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_in3,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageIn3
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_out0,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageOut0
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_out1,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageOut1
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_out2,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageOut2
+        );
+        test_field!(
+            memory_map,
+            offset,
+            length,
+            memory_map.ucsi.message_out3,
+            mem_map_to_ucsi_msg,
+            UcsiMessage::MessageOut3
+        );
+
+        assert_eq!(length, 0);
+    }
 }
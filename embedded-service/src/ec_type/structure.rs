@@ -3,7 +3,7 @@
 #[allow(missing_docs)]
 pub const EC_MEMMAP_VERSION: Version = Version {
     major: 0,
-    minor: 1,
+    minor: 2,
     spin: 0,
     res0: 0,
 };
@@ -110,6 +110,24 @@ pub struct Thermal {
     pub tmp1_high: u32,
 }
 
+#[allow(missing_docs)]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ucsi {
+    pub version: u16,
+    pub res0: u16,
+    pub cci: u32,
+    pub control: u64,
+    pub message_in0: u32,
+    pub message_in1: u32,
+    pub message_in2: u32,
+    pub message_in3: u32,
+    pub message_out0: u32,
+    pub message_out1: u32,
+    pub message_out2: u32,
+    pub message_out3: u32,
+}
+
 #[allow(missing_docs)]
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -128,4 +146,5 @@ pub struct ECMemory {
     pub alarm: TimeAlarm,
     pub batt: Battery,
     pub therm: Thermal,
+    pub ucsi: Ucsi,
 }
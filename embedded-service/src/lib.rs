@@ -20,6 +20,9 @@ pub mod init;
 pub mod ipc;
 pub mod keyboard;
 pub mod power;
+pub mod record_log;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod type_c;
 
 /// Global Mutex type, ThreadModeRawMutex is used in a microcontroller context, whereas CriticalSectionRawMutex is used
@@ -48,6 +48,7 @@ impl<'a, S: Kind> Device<'a, S> {
         self.device.set_state(device::State::Detached).await;
         self.device.update_consumer_capability(None).await;
         self.device.update_requested_provider_capability(None).await;
+        self.device.notify_dual_role(false).await;
         policy::send_request(self.device.id(), policy::RequestData::NotifyDetached)
             .await?
             .complete_or_err()?;
@@ -82,6 +83,17 @@ impl<'a, S: Kind> Device<'a, S> {
         .complete_or_err()
     }
 
+    /// Notify the power policy service that the port partner swapped power roles
+    async fn notify_power_role_swap_internal(&self) -> Result<(), Error> {
+        info!("Device {} power role swapped", self.device.id().0);
+        self.device.set_state(device::State::Idle).await;
+        self.device.update_consumer_capability(None).await;
+        self.device.update_requested_provider_capability(None).await;
+        policy::send_request(self.device.id(), policy::RequestData::NotifyPowerRoleSwap)
+            .await?
+            .complete_or_err()
+    }
+
     /// Request the given power from the power policy service
     async fn request_provider_power_capability_internal(&self, capability: PowerCapability) -> Result<(), Error> {
         if self.device.provider_capability().await == Some(capability) {
@@ -133,6 +145,37 @@ impl<'a> Device<'a, ConnectedConsumer> {
         Ok(Device::new(self.device))
     }
 
+    /// Notify the power policy service that the port partner swapped power roles, this device no longer consumes
+    ///
+    /// The device reports its new role by requesting to provide power.
+    pub async fn notify_power_role_swap(self) -> Result<Device<'a, Idle>, Error> {
+        self.notify_power_role_swap_internal().await?;
+        Ok(Device::new(self.device))
+    }
+
+    /// Notify the power policy service that the device is providing power after a fast role swap
+    ///
+    /// The swap has already happened in hardware when the port partner lost its power source.
+    pub async fn notify_fast_role_swap(
+        self,
+        capability: PowerCapability,
+    ) -> Result<Device<'a, ConnectedProvider>, Error> {
+        info!(
+            "Device {} fast role swapped, providing {:#?}",
+            self.device.id().0,
+            capability
+        );
+        self.device
+            .set_state(device::State::ConnectedProvider(capability))
+            .await;
+        self.device.update_consumer_capability(None).await;
+        self.device.update_requested_provider_capability(Some(capability)).await;
+        policy::send_request(self.device.id(), policy::RequestData::NotifyFastRoleSwap(capability))
+            .await?
+            .complete_or_err()?;
+        Ok(Device::new(self.device))
+    }
+
     /// Notify the power policy service of an updated consumer power capability
     pub async fn notify_consumer_power_capability(&self, capability: Option<PowerCapability>) -> Result<(), Error> {
         self.notify_consumer_power_capability_internal(capability).await
@@ -146,6 +189,14 @@ impl<'a> Device<'a, ConnectedProvider> {
         Ok(Device::new(self.device))
     }
 
+    /// Notify the power policy service that the port partner swapped power roles, this device no longer provides
+    ///
+    /// The device reports its new role by notifying its consumer power capability.
+    pub async fn notify_power_role_swap(self) -> Result<Device<'a, Idle>, Error> {
+        self.notify_power_role_swap_internal().await?;
+        Ok(Device::new(self.device))
+    }
+
     /// Request the given power from the power policy service
     pub async fn request_provider_power_capability(&self, capability: PowerCapability) -> Result<(), Error> {
         self.request_provider_power_capability_internal(capability).await
@@ -2,7 +2,7 @@
 use embassy_time::{Duration, TimeoutError, with_timeout};
 
 use super::*;
-use crate::power::policy::{Error, PowerCapability, device};
+use crate::power::policy::{Error, PowerCapability, PowerRole, device};
 use crate::{error, info};
 
 /// Default timeout for device commands to prevent the policy from getting stuck
@@ -66,6 +66,36 @@ impl<'a, S: Kind> Policy<'a, S> {
         }
     }
 
+    /// Common power role swap function used by multiple states
+    async fn power_role_swap_internal_no_timeout(&self, role: PowerRole) -> Result<(), Error> {
+        if !self.device.is_dual_role().await {
+            return Err(match role {
+                PowerRole::Provider => Error::CannotProvide(None),
+                PowerRole::Consumer => Error::CannotConsume(None),
+            });
+        }
+
+        info!("Device {} swapping to {:?}", self.device.id().0, role);
+        self.device
+            .execute_device_command(device::CommandData::PowerRoleSwap(role))
+            .await?
+            .complete_or_err()?;
+
+        // The device reports what it can consume or wants to provide in its new role
+        self.device.set_state(device::State::Idle).await;
+        self.device.update_consumer_capability(None).await;
+        self.device.update_requested_provider_capability(None).await;
+        Ok(())
+    }
+
+    /// Common power role swap function used by multiple states
+    async fn power_role_swap_internal(&self, role: PowerRole) -> Result<(), Error> {
+        match with_timeout(DEFAULT_TIMEOUT, self.power_role_swap_internal_no_timeout(role)).await {
+            Ok(r) => r,
+            Err(TimeoutError) => Err(Error::Timeout),
+        }
+    }
+
     /// Common connect as provider function used by multiple states
     async fn connect_as_provider_internal_no_timeout(&self, capability: PowerCapability) -> Result<(), Error> {
         info!("Device {} connecting provider", self.device.id().0);
@@ -142,6 +172,20 @@ impl<'a> Policy<'a, Idle> {
             .await
             .map(|_| Policy::new(self.device))
     }
+
+    /// Ask the device to become the power provider of its port partner
+    pub async fn swap_to_provider(self) -> Result<Policy<'a, Idle>, Error> {
+        self.power_role_swap_internal(PowerRole::Provider)
+            .await
+            .map(|_| Policy::new(self.device))
+    }
+
+    /// Ask the device to consume power from its port partner
+    pub async fn swap_to_consumer(self) -> Result<Policy<'a, Idle>, Error> {
+        self.power_role_swap_internal(PowerRole::Consumer)
+            .await
+            .map(|_| Policy::new(self.device))
+    }
 }
 
 impl<'a> Policy<'a, ConnectedConsumer> {
@@ -156,6 +200,13 @@ impl<'a> Policy<'a, ConnectedConsumer> {
     pub async fn disconnect(self) -> Result<Policy<'a, Idle>, Error> {
         self.disconnect_internal().await.map(|_| Policy::new(self.device))
     }
+
+    /// Stop consuming and ask the device to provide power to its port partner instead
+    pub async fn swap_to_provider(self) -> Result<Policy<'a, Idle>, Error> {
+        self.power_role_swap_internal(PowerRole::Provider)
+            .await
+            .map(|_| Policy::new(self.device))
+    }
 }
 
 impl<'a> Policy<'a, ConnectedProvider> {
@@ -219,6 +270,13 @@ impl<'a> Policy<'a, ConnectedProvider> {
             .map(|_| Policy::new(self.device))
     }
 
+    /// Stop providing and ask the device to consume power from its port partner instead
+    pub async fn swap_to_consumer(self) -> Result<Policy<'a, Idle>, Error> {
+        self.power_role_swap_internal(PowerRole::Consumer)
+            .await
+            .map(|_| Policy::new(self.device))
+    }
+
     /// Get the provider power capability of this device
     pub async fn power_capability(&self) -> PowerCapability {
         self.device.provider_capability().await.unwrap()
@@ -1,7 +1,7 @@
 //! Charger device struct and controller
 use core::{future::Future, ops::DerefMut};
 
-use embassy_sync::{channel::Channel, mutex::Mutex};
+use embassy_sync::{channel::Channel, mutex::Mutex, signal::Signal};
 
 use crate::{GlobalRawMutex, intrusive_list, power};
 
@@ -37,6 +37,13 @@ pub trait ChargeController: embedded_batteries_async::charger::Charger {
     fn is_ready(&mut self) -> impl Future<Output = Result<(), Self::ChargeControllerError>> {
         core::future::ready(Ok(()))
     }
+    /// Returns the charging phase the hardware is in, called whenever charging is started or reprogrammed.
+    ///
+    /// Hardware that doesn't distinguish pre-charge can keep the default. Later phase changes are reported with
+    /// [`ChargerEvent::ChargingStateChange`].
+    fn charging_state(&mut self) -> impl Future<Output = Result<ChargingSubstate, Self::ChargeControllerError>> {
+        core::future::ready(Ok(ChargingSubstate::FastCharge))
+    }
 }
 
 /// Charger Device ID new type
@@ -75,6 +82,22 @@ pub enum ChargerEvent {
     Timeout,
     /// An error occured on the bus
     BusError,
+    /// Charging phase changed, such as moving from pre-charge to fast-charge
+    ChargingStateChange(ChargingSubstate),
+    /// Charger hardware reported a fault, such as over-voltage or over-temperature
+    Fault,
+}
+
+/// Reason the charger is faulted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChargerFault {
+    /// Charger hardware timed out responding
+    Timeout,
+    /// Charger underlying bus error
+    BusError,
+    /// Charger hardware reported a fault
+    Hardware,
 }
 
 /// Charger state errors
@@ -106,6 +129,40 @@ pub enum PolicyEvent {
     /// Request to check if the charger hardware is ready to receive communications.
     /// For example, if the charger is powered.
     CheckReady,
+    /// New charge current and voltage, programmed while a PSU is attached
+    ChargingParameters(ChargingParameters),
+    /// Stop or resume charging, the PSU still powers the system while charging is inhibited
+    InhibitCharging(bool),
+}
+
+/// Charge current and voltage requested for the battery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChargingParameters {
+    /// Charge current in mA
+    pub current_ma: u16,
+    /// Charge voltage in mV
+    pub voltage_mv: u16,
+}
+
+impl ChargingParameters {
+    /// Calculate charge power
+    pub fn power_mw(&self) -> u32 {
+        self.voltage_mv as u32 * self.current_ma as u32 / 1000
+    }
+}
+
+/// Charging parameters requested for a charger
+///
+/// Sent to the power policy over comms by whichever service owns the battery. The policy forwards the parameters to the
+/// charger and shares the input power by the charge power each charger was asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChargingRequest {
+    /// Charger to program
+    pub charger_id: ChargerId,
+    /// Requested charge current and voltage
+    pub parameters: ChargingParameters,
 }
 
 /// Data for a device request
@@ -138,9 +195,26 @@ pub enum PoweredSubstate {
     /// Device is initializing
     Init,
     /// PSU is attached and device can charge if desired
-    PsuAttached,
+    PsuAttached(ChargingSubstate),
     /// PSU is detached
     PsuDetached,
+    /// Device faulted, entered as soon as the fault is detected and kept while recovery is attempted.
+    /// If every attempt fails the device stays here until a new `PolicyEvent::InitRequest`
+    Fault(ChargerFault),
+}
+
+/// PSU attached state substates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChargingSubstate {
+    /// Charging is inhibited or no charging parameters have been set
+    Inhibited,
+    /// Charging a deeply discharged battery at a reduced current
+    PreCharge,
+    /// Charging at the programmed current
+    FastCharge,
+    /// Battery is full, charging terminated
+    Done,
 }
 
 /// Current state of the charger
@@ -164,10 +238,14 @@ pub struct Device {
     id: ChargerId,
     /// Current state of the device
     state: Mutex<GlobalRawMutex, InternalState>,
+    /// Last charging parameters sent to the device
+    charging_parameters: Mutex<GlobalRawMutex, Option<ChargingParameters>>,
     /// Channel for requests to the device
     commands: Channel<GlobalRawMutex, PolicyEvent, CHARGER_CHANNEL_SIZE>,
     /// Channel for responses from the device
     response: Channel<GlobalRawMutex, ChargerResponse, CHARGER_CHANNEL_SIZE>,
+    /// Sink ready confirmation, the charger detected its power supply
+    sink_ready: Signal<GlobalRawMutex, ()>,
 }
 
 impl Device {
@@ -180,8 +258,10 @@ impl Device {
                 state: State::Unpowered,
                 capability: None,
             }),
+            charging_parameters: Mutex::new(None),
             commands: Channel::new(),
             response: Channel::new(),
+            sink_ready: Signal::new(),
         }
     }
 
@@ -202,13 +282,26 @@ impl Device {
         *current_state = new_state;
     }
 
+    /// Sink ready confirmation from this charger
+    pub(super) fn sink_ready(&self) -> &Signal<GlobalRawMutex, ()> {
+        &self.sink_ready
+    }
+
     /// Wait for a command from policy
     pub async fn wait_command(&self) -> PolicyEvent {
         self.commands.receive().await
     }
 
+    /// Last charging parameters sent to the device, the charge power the device demands
+    pub async fn charging_parameters(&self) -> Option<ChargingParameters> {
+        *self.charging_parameters.lock().await
+    }
+
     /// Send a command to the charger
     pub async fn send_command(&self, policy_event: PolicyEvent) {
+        if let PolicyEvent::ChargingParameters(parameters) = policy_event {
+            *self.charging_parameters.lock().await = Some(parameters);
+        }
         self.commands.send(policy_event).await
     }
 
@@ -2,8 +2,9 @@
 use core::ops::DerefMut;
 
 use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
 
-use super::{DeviceId, Error, PowerCapability, action};
+use super::{DeviceId, Error, PowerCapability, PowerRole, action, policy};
 use crate::ipc::deferred;
 use crate::{GlobalRawMutex, intrusive_list};
 
@@ -57,6 +58,8 @@ struct InternalState {
     pub consumer_capability: Option<PowerCapability>,
     /// Current requested provider capability
     pub requested_provider_capability: Option<PowerCapability>,
+    /// Port partner supports both power roles
+    pub dual_role: bool,
 }
 
 /// Data for a device request
@@ -69,6 +72,8 @@ pub enum CommandData {
     ConnectAsProvider(PowerCapability),
     /// Stop providing or consuming on this device
     Disconnect,
+    /// Swap power roles with the port partner, contains the new role of this device
+    PowerRoleSwap(PowerRole),
 }
 
 /// Request from power policy service to a device
@@ -119,6 +124,8 @@ pub struct Device {
     state: Mutex<GlobalRawMutex, InternalState>,
     /// Command channel
     command: deferred::Channel<GlobalRawMutex, CommandData, InternalResponseData>,
+    /// Sink ready confirmation
+    sink_ready: Signal<GlobalRawMutex, ()>,
 }
 
 impl Device {
@@ -131,8 +138,10 @@ impl Device {
                 state: State::Detached,
                 consumer_capability: None,
                 requested_provider_capability: None,
+                dual_role: false,
             }),
             command: deferred::Channel::new(),
+            sink_ready: Signal::new(),
         }
     }
 
@@ -174,6 +183,18 @@ impl Device {
         self.state().await.kind() == StateKind::ConnectedProvider
     }
 
+    /// Returns true if the port partner supports both power roles
+    pub async fn is_dual_role(&self) -> bool {
+        self.state.lock().await.dual_role
+    }
+
+    /// Update whether the port partner supports both power roles
+    ///
+    /// Only dual-role partners can swap power roles. Cleared when the device detaches.
+    pub async fn notify_dual_role(&self, dual_role: bool) {
+        self.state.lock().await.dual_role = dual_role;
+    }
+
     /// Execute a command on the device
     pub(super) async fn execute_device_command(&self, command: CommandData) -> Result<ResponseData, Error> {
         self.command.execute(command).await
@@ -253,6 +274,18 @@ impl Device {
         }
     }
 
+    /// Notify the power policy service that power is flowing from the port partner
+    ///
+    /// Call once the sink path is enabled after a [`CommandData::ConnectAsConsumer`] command.
+    pub async fn notify_sink_ready(&self) {
+        policy::notify_sink_ready(policy::SinkReady::Device(self.id)).await;
+    }
+
+    /// Sink ready confirmation from this device
+    pub(super) fn sink_ready(&self) -> &Signal<GlobalRawMutex, ()> {
+        &self.sink_ready
+    }
+
     /// Detach the device, this action is available in all states
     pub async fn detach(&self) -> Result<action::device::Device<'_, action::Detached>, Error> {
         match self.device_action().await {
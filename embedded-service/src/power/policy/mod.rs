@@ -68,6 +68,44 @@ impl Ord for PowerCapability {
     }
 }
 
+/// Power role of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerRole {
+    /// Device provides power to its port partner, USB PD source
+    Provider,
+    /// Device consumes power from its port partner, USB PD sink
+    Consumer,
+}
+
+/// Battery state used by the power policy to compute the [`PowerEnvelope`]
+///
+/// Sent to the power policy over comms by whichever service owns the battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatteryPowerState {
+    /// Relative state of charge in %
+    pub state_of_charge_pct: u8,
+    /// Maximum power the battery can currently deliver in mW
+    pub max_discharge_mw: u32,
+}
+
+/// Power available to the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PowerEnvelope {
+    /// Power the adapter delivers to the system after charger losses, in mW
+    pub adapter_mw: u32,
+    /// Power the battery can add on top of the adapter, in mW
+    pub battery_mw: u32,
+    /// Power the system can draw indefinitely, in mW
+    pub sustained_mw: u32,
+    /// Power the system can draw for short periods, in mW
+    pub peak_mw: u32,
+    /// The adapter can't sustain the rated system power
+    pub adapter_undersized: bool,
+}
+
 /// Data to send with the comms service
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -76,6 +114,10 @@ pub enum CommsData {
     ConsumerDisconnected(DeviceId),
     /// Consumer connected
     ConsumerConnected(DeviceId, PowerCapability),
+    /// System power envelope changed
+    PowerEnvelope(PowerEnvelope),
+    /// Charger state changed
+    ChargerState(charger::ChargerId, charger::State),
 }
 
 /// Message to send with the comms service
@@ -1,9 +1,12 @@
 //! Context for any power policy implementations
+use core::future::poll_fn;
 use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
 
 use crate::GlobalRawMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::once_lock::OnceLock;
+use embassy_sync::signal::Signal;
 
 use super::charger::ChargerResponse;
 use super::device::{self};
@@ -28,6 +31,20 @@ pub enum RequestData {
     NotifyDisconnect,
     /// Notify that a device has detached
     NotifyDetached,
+    /// Notify that the port partner swapped power roles
+    NotifyPowerRoleSwap,
+    /// Notify that the device started providing power after a fast role swap
+    NotifyFastRoleSwap(PowerCapability),
+}
+
+/// Confirmation that power is flowing from a newly connected consumer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SinkReady {
+    /// The device enabled its sink path
+    Device(DeviceId),
+    /// The charger detected its power supply
+    Charger(charger::ChargerId),
 }
 
 /// Request to the power policy service
@@ -80,6 +97,8 @@ struct Context {
     policy_response: Channel<GlobalRawMutex, InternalResponseData, POLICY_CHANNEL_SIZE>,
     /// Registered chargers
     chargers: intrusive_list::IntrusiveList,
+    /// Charger state change notification
+    charger_state: Signal<GlobalRawMutex, charger::ChargerId>,
 }
 
 impl Context {
@@ -89,6 +108,7 @@ impl Context {
             chargers: intrusive_list::IntrusiveList::new(),
             policy_request: Channel::new(),
             policy_response: Channel::new(),
+            charger_state: Signal::new(),
         }
     }
 }
@@ -163,6 +183,28 @@ pub(super) async fn send_request(from: DeviceId, request: RequestData) -> Result
     context.policy_response.receive().await
 }
 
+/// Notify the power policy service that power is flowing from a new consumer
+///
+/// Every device and charger keeps its own confirmation, so confirmations from different sources never replace each
+/// other.
+pub async fn notify_sink_ready(source: SinkReady) {
+    match source {
+        SinkReady::Device(id) => match get_device(id).await {
+            Some(device) => device.sink_ready().signal(()),
+            None => error!("Sink ready from unregistered device {}", id.0),
+        },
+        SinkReady::Charger(id) => match get_charger(id).await {
+            Some(charger) => charger.sink_ready().signal(()),
+            None => error!("Sink ready from unregistered charger {}", id.0),
+        },
+    }
+}
+
+/// Notify the power policy service that a charger changed state, such as faulting or finishing charging
+pub async fn notify_charger_state(id: charger::ChargerId) {
+    CONTEXT.get().await.charger_state.signal(id);
+}
+
 /// Initialize chargers in hardware
 pub async fn init_chargers() -> ChargerResponse {
     for charger in &CONTEXT.get().await.chargers {
@@ -222,6 +264,47 @@ impl ContextToken {
         CONTEXT.get().await.policy_response.send(response).await
     }
 
+    /// Discard pending sink ready confirmations from a device and from the chargers, call before connecting the
+    /// device as a consumer
+    pub async fn reset_sink_ready(&self, id: DeviceId) -> Result<(), Error> {
+        self.get_device(id).await?.sink_ready().reset();
+        for charger in &CONTEXT.get().await.chargers {
+            if let Some(charger) = charger.data::<charger::Device>() {
+                charger.sink_ready().reset();
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for a sink ready confirmation for a consumer
+    ///
+    /// Confirmations come from the device itself or from any charger, the chargers are powered by the consumer.
+    /// Confirmations from other devices are left pending.
+    pub async fn wait_sink_ready(&self, id: DeviceId) -> Result<SinkReady, Error> {
+        let device = self.get_device(id).await?;
+        let chargers = &CONTEXT.get().await.chargers;
+        Ok(poll_fn(|cx| {
+            if device.sink_ready().poll_wait(cx).is_ready() {
+                return Poll::Ready(SinkReady::Device(id));
+            }
+
+            for charger in chargers {
+                if let Some(charger) = charger.data::<charger::Device>() {
+                    if charger.sink_ready().poll_wait(cx).is_ready() {
+                        return Poll::Ready(SinkReady::Charger(charger.id()));
+                    }
+                }
+            }
+            Poll::Pending
+        })
+        .await)
+    }
+
+    /// Wait for a charger state change notification
+    pub async fn wait_charger_state(&self) -> charger::ChargerId {
+        CONTEXT.get().await.charger_state.wait().await
+    }
+
     /// Get a device by its ID
     pub async fn get_device(&self, id: DeviceId) -> Result<&'static device::Device, Error> {
         get_device(id).await.ok_or(Error::InvalidDevice)
@@ -0,0 +1,271 @@
+//! Append-only log of fixed size records in NOR flash
+//!
+//! Services that persist small, self-contained snapshots (statistics, boot state, etc.) store them in a
+//! [`RecordLog`] on a dedicated flash partition, typically a `partition_manager::Partition` mapped read/write.
+//! Records are written round-robin across the whole partition and a sector is only erased right before the log wraps
+//! into it, so every sector sees the same number of erase cycles and a power loss never destroys the latest record.
+//! Since each record holds a complete snapshot, only the record with the highest sequence number matters when loading.
+//!
+//! Each record is laid out as:
+//!
+//! * bytes 0..2: little-endian magic value, distinguishes records from erased or garbage flash
+//! * bytes 2..6: little-endian sequence number
+//! * bytes 6..SIZE-2: record data, see [`Record`]
+//! * bytes SIZE-2..SIZE: little-endian CRC-16/IBM-3740 (CCITT-FALSE) of all preceding bytes
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::trace;
+
+/// Offset of the record data
+const DATA_OFFSET: usize = 6;
+
+/// Bytes of each record used by the log itself
+pub const OVERHEAD: usize = DATA_OFFSET + 2;
+
+/// Data stored in a [`RecordLog`]
+pub trait Record: Sized {
+    /// Encode into `data`, which is [`OVERHEAD`] bytes shorter than the log record size and starts out erased
+    fn encode(&self, data: &mut [u8]);
+
+    /// Decode from `data`, returns `None` if it doesn't hold a valid record
+    fn decode(data: &[u8]) -> Option<Self>;
+}
+
+/// Record log errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Partition size or flash alignment cannot hold the record log
+    InvalidGeometry,
+    /// Underlying flash returned an error
+    Flash(E),
+}
+
+/// CRC-16/IBM-3740, also known as CRC-16/CCITT-FALSE
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+
+/// Append-only log of `SIZE` byte records
+pub struct RecordLog<F: NorFlash, const SIZE: usize> {
+    flash: F,
+    magic: u16,
+    slots: u32,
+    next_slot: u32,
+    seq: u32,
+}
+
+impl<F: NorFlash, const SIZE: usize> RecordLog<F, SIZE> {
+    /// Number of record slots in a flash sector
+    const SECTOR_SLOTS: u32 = (F::ERASE_SIZE / SIZE) as u32;
+
+    /// Create a new record log, `magic` identifies the records of this log
+    ///
+    /// The partition must span at least two erase sectors so that wrapping the log never erases the latest record, and
+    /// `SIZE` must be a multiple of the flash read and write sizes.
+    pub fn new(flash: F, magic: u16) -> Result<Self, Error<F::Error>> {
+        let capacity = flash.capacity();
+
+        if SIZE <= OVERHEAD
+            || SIZE % F::WRITE_SIZE != 0
+            || SIZE % F::READ_SIZE != 0
+            || F::ERASE_SIZE % SIZE != 0
+            || capacity % F::ERASE_SIZE != 0
+            || capacity / F::ERASE_SIZE < 2
+        {
+            return Err(Error::InvalidGeometry);
+        }
+
+        Ok(Self {
+            flash,
+            magic,
+            slots: (capacity / SIZE) as u32,
+            next_slot: 0,
+            seq: 0,
+        })
+    }
+
+    /// Scan the partition for the latest record and position the log after it
+    pub async fn load<R: Record>(&mut self) -> Result<Option<R>, Error<F::Error>> {
+        let mut latest: Option<(u32, u32, R)> = None;
+
+        for slot in 0..self.slots {
+            let raw = self.read_slot(slot).await?;
+            if let Some((seq, record)) = self.decode(&raw) {
+                if latest.as_ref().is_none_or(|(_, latest_seq, _)| seq > *latest_seq) {
+                    latest = Some((slot, seq, record));
+                }
+            }
+        }
+
+        Ok(match latest {
+            Some((slot, seq, record)) => {
+                trace!("Record log {:#x}: latest record {} in slot {}", self.magic, seq, slot);
+                self.next_slot = (slot + 1) % self.slots;
+                self.seq = seq.wrapping_add(1);
+                Some(record)
+            }
+            None => {
+                self.next_slot = 0;
+                self.seq = 0;
+                None
+            }
+        })
+    }
+
+    /// Append a record to the log
+    pub async fn append<R: Record>(&mut self, record: &R) -> Result<(), Error<F::Error>> {
+        if self.next_slot % Self::SECTOR_SLOTS != 0 && !self.slot_erased(self.next_slot).await? {
+            // Partially written slot, e.g. after a power loss mid-write. Move on to the next sector.
+            self.next_slot = (self.next_slot / Self::SECTOR_SLOTS + 1) * Self::SECTOR_SLOTS % self.slots;
+        }
+
+        let offset = self.next_slot * SIZE as u32;
+        if self.next_slot % Self::SECTOR_SLOTS == 0 {
+            self.flash
+                .erase(offset, offset + F::ERASE_SIZE as u32)
+                .await
+                .map_err(Error::Flash)?;
+        }
+
+        self.flash
+            .write(offset, &self.encode(record))
+            .await
+            .map_err(Error::Flash)?;
+
+        self.seq = self.seq.wrapping_add(1);
+        self.next_slot = (self.next_slot + 1) % self.slots;
+        Ok(())
+    }
+
+    /// Release the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn encode<R: Record>(&self, record: &R) -> [u8; SIZE] {
+        let mut raw = [0xFF; SIZE];
+        raw[0..2].copy_from_slice(&self.magic.to_le_bytes());
+        raw[2..DATA_OFFSET].copy_from_slice(&self.seq.to_le_bytes());
+        record.encode(&mut raw[DATA_OFFSET..SIZE - 2]);
+        let crc = CRC.checksum(&raw[..SIZE - 2]);
+        raw[SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        raw
+    }
+
+    fn decode<R: Record>(&self, raw: &[u8; SIZE]) -> Option<(u32, R)> {
+        let magic = u16::from_le_bytes([raw[0], raw[1]]);
+        let crc = u16::from_le_bytes([raw[SIZE - 2], raw[SIZE - 1]]);
+        if magic != self.magic || crc != CRC.checksum(&raw[..SIZE - 2]) {
+            return None;
+        }
+
+        let seq = u32::from_le_bytes([raw[2], raw[3], raw[4], raw[5]]);
+        Some((seq, R::decode(&raw[DATA_OFFSET..SIZE - 2])?))
+    }
+
+    async fn read_slot(&mut self, slot: u32) -> Result<[u8; SIZE], Error<F::Error>> {
+        let mut raw = [0; SIZE];
+        self.flash
+            .read(slot * SIZE as u32, &mut raw)
+            .await
+            .map_err(Error::Flash)?;
+        Ok(raw)
+    }
+
+    async fn slot_erased(&mut self, slot: u32) -> Result<bool, Error<F::Error>> {
+        Ok(self.read_slot(slot).await?.iter().all(|b| *b == 0xFF))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RamFlash;
+    use embassy_futures::block_on;
+
+    const ERASE_SIZE: usize = 64;
+    const SECTORS: usize = 2;
+    const SIZE: usize = 16;
+    const MAGIC: u16 = 0x1234;
+
+    type MockFlash = RamFlash<{ ERASE_SIZE * SECTORS }, ERASE_SIZE>;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Counter(u32);
+
+    impl Record for Counter {
+        fn encode(&self, data: &mut [u8]) {
+            data[0..4].copy_from_slice(&self.0.to_le_bytes());
+        }
+
+        fn decode(data: &[u8]) -> Option<Self> {
+            Some(Self(u32::from_le_bytes([data[0], data[1], data[2], data[3]])))
+        }
+    }
+
+    #[test]
+    fn test_wraps_and_reloads() {
+        let mut flash = MockFlash::new();
+        let total_slots = (ERASE_SIZE * SECTORS / SIZE) as u32;
+
+        {
+            let mut log = RecordLog::<_, SIZE>::new(&mut flash, MAGIC).unwrap();
+            assert_eq!(block_on(log.load::<Counter>()).unwrap(), None);
+
+            // Wrap around the log a couple of times
+            for i in 0..3 * total_slots {
+                block_on(log.append(&Counter(i))).unwrap();
+            }
+        }
+
+        // Every sector is erased the same number of times
+        assert_eq!(flash.erase_counts(), [3, 3]);
+
+        let mut log = RecordLog::<_, SIZE>::new(&mut flash, MAGIC).unwrap();
+        assert_eq!(block_on(log.load()).unwrap(), Some(Counter(3 * total_slots - 1)));
+
+        // Appending after a reload continues the sequence
+        block_on(log.append(&Counter(1000))).unwrap();
+        assert_eq!(block_on(log.load()).unwrap(), Some(Counter(1000)));
+
+        // Records of another log are ignored
+        let mut log = RecordLog::<_, SIZE>::new(&mut flash, MAGIC + 1).unwrap();
+        assert_eq!(block_on(log.load::<Counter>()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_skips_torn_record() {
+        let mut flash = MockFlash::new();
+
+        {
+            let mut log = RecordLog::<_, SIZE>::new(&mut flash, MAGIC).unwrap();
+            block_on(log.load::<Counter>()).unwrap();
+            block_on(log.append(&Counter(1))).unwrap();
+            block_on(log.append(&Counter(2))).unwrap();
+        }
+
+        // Simulate a power loss while writing the second record
+        flash.data[SIZE + DATA_OFFSET] = 0x00;
+
+        let mut log = RecordLog::<_, SIZE>::new(&mut flash, MAGIC).unwrap();
+        assert_eq!(block_on(log.load()).unwrap(), Some(Counter(1)));
+
+        // The torn slot isn't reused, the log moves on to the next sector
+        block_on(log.append(&Counter(3))).unwrap();
+        assert_eq!(log.next_slot, (ERASE_SIZE / SIZE) as u32 + 1);
+        assert_eq!(block_on(log.load()).unwrap(), Some(Counter(3)));
+    }
+
+    #[test]
+    fn test_invalid_geometry() {
+        // Records don't divide the sector
+        assert!(matches!(
+            RecordLog::<_, 24>::new(MockFlash::new(), MAGIC),
+            Err(Error::InvalidGeometry)
+        ));
+        // No room for data
+        assert!(matches!(
+            RecordLog::<_, OVERHEAD>::new(MockFlash::new(), MAGIC),
+            Err(Error::InvalidGeometry)
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+//! Fixtures for testing services
+//!
+//! Only available in tests or with the `testing` feature, enable it in the `dev-dependencies` of services that use it.
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+/// Maximum number of sectors a [`RamFlash`] tracks erase counts for
+pub const MAX_SECTORS: usize = 16;
+
+/// [`RamFlash`] error, never returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamFlashError;
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+/// RAM backed NOR flash of `SIZE` bytes in `ERASE_SIZE` byte sectors
+///
+/// Like real NOR flash, writes can only clear bits. Reads are byte aligned and writes are word aligned.
+pub struct RamFlash<const SIZE: usize, const ERASE_SIZE: usize> {
+    /// Flash contents
+    pub data: [u8; SIZE],
+    erase_count: [usize; MAX_SECTORS],
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> RamFlash<SIZE, ERASE_SIZE> {
+    /// Create an erased flash
+    pub fn new() -> Self {
+        const { assert!(SIZE % ERASE_SIZE == 0 && SIZE / ERASE_SIZE <= MAX_SECTORS) };
+        Self {
+            data: [0xFF; SIZE],
+            erase_count: [0; MAX_SECTORS],
+        }
+    }
+
+    /// Number of times each sector was erased
+    pub fn erase_counts(&self) -> &[usize] {
+        &self.erase_count[..SIZE / ERASE_SIZE]
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> Default for RamFlash<SIZE, ERASE_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> ErrorType for RamFlash<SIZE, ERASE_SIZE> {
+    type Error = RamFlashError;
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> ReadNorFlash for RamFlash<SIZE, ERASE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> NorFlash for RamFlash<SIZE, ERASE_SIZE> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.data[from as usize..to as usize].fill(0xFF);
+        for sector in from as usize / ERASE_SIZE..(to as usize).div_ceil(ERASE_SIZE) {
+            self.erase_count[sector] += 1;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        for (dst, src) in self.data[offset as usize..].iter_mut().zip(bytes) {
+            *dst &= *src;
+        }
+        Ok(())
+    }
+}
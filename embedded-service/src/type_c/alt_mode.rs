@@ -0,0 +1,227 @@
+//! Alternate mode definitions
+use embedded_usb_pd::GlobalPortId;
+
+/// Alternate mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AltModeKind {
+    /// DisplayPort
+    DisplayPort,
+    /// Thunderbolt
+    Thunderbolt,
+    /// USB4
+    Usb4,
+}
+
+/// DisplayPort pin assignment
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DpPinAssignment {
+    /// Four DP lanes
+    C,
+    /// Two DP lanes and USB3
+    D,
+    /// Four DP lanes, DP signaling on the cable
+    E,
+}
+
+/// DisplayPort status
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DpStatus {
+    /// Hot plug detect level
+    pub hpd: bool,
+    /// IRQ_HPD received since the last status
+    pub irq_hpd: bool,
+    /// Configured pin assignment, `None` until the partner is configured
+    pub pin_assignment: Option<DpPinAssignment>,
+}
+
+/// Alternate mode state of a port
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AltModeState {
+    /// Active alternate mode
+    pub mode: Option<AltModeKind>,
+    /// DisplayPort status, only present in DisplayPort mode
+    pub dp: Option<DpStatus>,
+}
+
+/// Mux and retimer configuration
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MuxConfig {
+    /// Nothing connected
+    #[default]
+    Disconnected,
+    /// Safe state, used while entering a mode
+    Safe,
+    /// USB only
+    Usb,
+    /// DisplayPort with the given pin assignment
+    DisplayPort(DpPinAssignment),
+    /// Thunderbolt
+    Thunderbolt,
+    /// USB4
+    Usb4,
+}
+
+impl MuxConfig {
+    /// Mux configuration for the given port state
+    pub fn new(connected: bool, state: &AltModeState) -> Self {
+        if !connected {
+            return MuxConfig::Disconnected;
+        }
+
+        match state.mode {
+            None => MuxConfig::Usb,
+            Some(AltModeKind::DisplayPort) => match state.dp.and_then(|dp| dp.pin_assignment) {
+                Some(pin_assignment) => MuxConfig::DisplayPort(pin_assignment),
+                // Not configured yet
+                None => MuxConfig::Safe,
+            },
+            Some(AltModeKind::Thunderbolt) => MuxConfig::Thunderbolt,
+            Some(AltModeKind::Usb4) => MuxConfig::Usb4,
+        }
+    }
+}
+
+/// Alternate modes the host allows the ports to enter
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EntryPolicy {
+    /// Allow DisplayPort
+    pub display_port: bool,
+    /// Allow Thunderbolt
+    pub thunderbolt: bool,
+    /// Allow USB4
+    pub usb4: bool,
+}
+
+impl EntryPolicy {
+    /// Returns true if the given mode is allowed
+    pub fn allows(&self, mode: AltModeKind) -> bool {
+        match mode {
+            AltModeKind::DisplayPort => self.display_port,
+            AltModeKind::Thunderbolt => self.thunderbolt,
+            AltModeKind::Usb4 => self.usb4,
+        }
+    }
+}
+
+/// Alternate mode event published to the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AltModeEvent {
+    /// Mode entered
+    Entered(AltModeKind),
+    /// Mode exited
+    Exited(AltModeKind),
+    /// DisplayPort pin assignment configured
+    PinAssignment(DpPinAssignment),
+    /// Hot plug detect level changed
+    Hpd(bool),
+    /// IRQ_HPD received
+    IrqHpd,
+}
+
+/// Message sent to the host on alternate mode changes
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AltModeMessage {
+    /// Port
+    pub port: GlobalPortId,
+    /// Event
+    pub event: AltModeEvent,
+}
+
+/// Message sent by the host to change the [`EntryPolicy`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EntryPolicyMessage(pub EntryPolicy);
+
+/// Events to publish when a port goes from `old` to `new`, in the order the host expects them
+pub fn events(old: &AltModeState, new: &AltModeState) -> heapless::Vec<AltModeEvent, 5> {
+    let mut events = heapless::Vec::new();
+    let old_dp = old.dp.unwrap_or_default();
+    let new_dp = new.dp.unwrap_or_default();
+
+    // Capacity covers every event below, pushes can't fail
+    if old.mode != new.mode {
+        if let Some(mode) = old.mode {
+            let _ = events.push(AltModeEvent::Exited(mode));
+        }
+        if let Some(mode) = new.mode {
+            let _ = events.push(AltModeEvent::Entered(mode));
+        }
+    }
+
+    if new_dp.pin_assignment != old_dp.pin_assignment {
+        if let Some(pin_assignment) = new_dp.pin_assignment {
+            let _ = events.push(AltModeEvent::PinAssignment(pin_assignment));
+        }
+    }
+
+    if new_dp.hpd != old_dp.hpd {
+        let _ = events.push(AltModeEvent::Hpd(new_dp.hpd));
+    }
+
+    if new_dp.irq_hpd {
+        let _ = events.push(AltModeEvent::IrqHpd);
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dp(hpd: bool, irq_hpd: bool, pin_assignment: Option<DpPinAssignment>) -> AltModeState {
+        AltModeState {
+            mode: Some(AltModeKind::DisplayPort),
+            dp: Some(DpStatus {
+                hpd,
+                irq_hpd,
+                pin_assignment,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_mux_config() {
+        let none = AltModeState::default();
+        assert_eq!(MuxConfig::new(false, &none), MuxConfig::Disconnected);
+        assert_eq!(MuxConfig::new(true, &none), MuxConfig::Usb);
+        assert_eq!(MuxConfig::new(true, &dp(false, false, None)), MuxConfig::Safe);
+        assert_eq!(
+            MuxConfig::new(true, &dp(false, false, Some(DpPinAssignment::D))),
+            MuxConfig::DisplayPort(DpPinAssignment::D)
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let none = AltModeState::default();
+        let entered = dp(false, false, None);
+        let configured = dp(true, false, Some(DpPinAssignment::C));
+
+        assert_eq!(
+            events(&none, &entered).as_slice(),
+            &[AltModeEvent::Entered(AltModeKind::DisplayPort)]
+        );
+        assert_eq!(
+            events(&entered, &configured).as_slice(),
+            &[AltModeEvent::PinAssignment(DpPinAssignment::C), AltModeEvent::Hpd(true)]
+        );
+        assert_eq!(
+            events(&configured, &dp(true, true, Some(DpPinAssignment::C))).as_slice(),
+            &[AltModeEvent::IrqHpd]
+        );
+        assert_eq!(
+            events(&configured, &none).as_slice(),
+            &[AltModeEvent::Exited(AltModeKind::DisplayPort), AltModeEvent::Hpd(false)]
+        );
+        assert!(events(&configured, &configured).is_empty());
+    }
+}
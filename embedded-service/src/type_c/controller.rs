@@ -9,10 +9,13 @@ use embedded_usb_pd::ucsi::lpm;
 use embedded_usb_pd::{
     Error, GlobalPortId, PdError, PortId as LocalPortId,
     pdinfo::{AltMode, PowerPathStatus},
+    pdo::{Rdo, sink, source},
     type_c::ConnectionState,
 };
 
-use super::event::{PortEventFlags, PortEventKind};
+use super::alt_mode::{AltModeState, EntryPolicy, MuxConfig};
+use super::event::{MAX_SUPPORTED_PORTS, PortEventFlags, PortEventKind};
+use super::vdm::{DiscoverCommand, Discovery, Sop, Vdos};
 use super::{ControllerId, external};
 use crate::ipc::deferred;
 use crate::power::policy;
@@ -28,6 +31,144 @@ pub enum Contract {
     Source(policy::PowerCapability),
 }
 
+/// Maximum number of PDOs in a capabilities message
+pub const MAX_PDOS: usize = 7;
+
+/// PDOs from a capabilities message, in object position order
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capabilities<T: Copy> {
+    pdos: [Option<T>; MAX_PDOS],
+}
+
+impl<T: Copy> Capabilities<T> {
+    /// Create capabilities from the given PDOs
+    pub fn from_slice(pdos: &[T]) -> Result<Self, PdError> {
+        if pdos.len() > MAX_PDOS {
+            return Err(PdError::InvalidParams);
+        }
+
+        let mut capabilities = Self::default();
+        for (slot, pdo) in capabilities.pdos.iter_mut().zip(pdos) {
+            *slot = Some(*pdo);
+        }
+        Ok(capabilities)
+    }
+
+    /// Get the PDO at the given 1-based object position
+    pub fn get(&self, position: u8) -> Option<&T> {
+        self.pdos.get((position as usize).checked_sub(1)?)?.as_ref()
+    }
+
+    /// Iterate over the PDOs
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.pdos.iter().map_while(Option::as_ref)
+    }
+
+    /// Number of PDOs
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns true if there are no PDOs
+    pub fn is_empty(&self) -> bool {
+        self.pdos[0].is_none()
+    }
+}
+
+impl<T: Copy> Default for Capabilities<T> {
+    fn default() -> Self {
+        Self { pdos: [None; MAX_PDOS] }
+    }
+}
+
+/// Source capabilities
+pub type SourceCapabilities = Capabilities<source::Pdo>;
+
+/// Sink capabilities
+pub type SinkCapabilities = Capabilities<sink::Pdo>;
+
+/// Power request to the port partner
+///
+/// PDOs are identified by their 1-based object position in the partner's source capabilities.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PdoRequest {
+    /// Request a fixed, variable or battery PDO at its maximum current
+    Pdo(u8),
+    /// Request a specific voltage and current from an APDO
+    Apdo {
+        /// Object position of the APDO
+        position: u8,
+        /// Requested voltage
+        voltage_mv: u16,
+        /// Requested operating current
+        current_ma: u16,
+    },
+}
+
+impl PdoRequest {
+    /// Object position of the requested PDO
+    pub fn position(&self) -> u8 {
+        match self {
+            PdoRequest::Pdo(position) => *position,
+            PdoRequest::Apdo { position, .. } => *position,
+        }
+    }
+
+    /// Check the request against the partner's source capabilities
+    pub fn validate(&self, capabilities: &SourceCapabilities) -> Result<(), PdError> {
+        match (self, capabilities.get(self.position())) {
+            (_, None) => Err(PdError::InvalidParams),
+            (PdoRequest::Pdo(_), Some(source::Pdo::Augmented(_))) => Err(PdError::InvalidParams),
+            (PdoRequest::Pdo(_), Some(_)) => Ok(()),
+            (
+                PdoRequest::Apdo {
+                    voltage_mv, current_ma, ..
+                },
+                Some(source::Pdo::Augmented(apdo)),
+            ) => match apdo_max_current_ma(apdo, *voltage_mv) {
+                Some(max_current_ma) if *current_ma <= max_current_ma => Ok(()),
+                _ => Err(PdError::InvalidParams),
+            },
+            (PdoRequest::Apdo { .. }, Some(_)) => Err(PdError::InvalidParams),
+        }
+    }
+}
+
+/// Lowest voltage of an SPR AVS APDO
+const SPR_AVS_MIN_VOLTAGE_MV: u16 = 9000;
+/// Highest SPR AVS voltage the 15V current limit applies to
+const SPR_AVS_15V_MAX_VOLTAGE_MV: u16 = 15000;
+/// Highest voltage of an SPR AVS APDO
+const SPR_AVS_MAX_VOLTAGE_MV: u16 = 20000;
+
+/// Largest operating current an APDO allows at the given voltage, `None` if the voltage is out of range
+fn apdo_max_current_ma(apdo: &source::Apdo, voltage_mv: u16) -> Option<u16> {
+    match apdo {
+        source::Apdo::SprPps(data) => (data.min_voltage_mv..=data.max_voltage_mv)
+            .contains(&voltage_mv)
+            .then_some(data.max_current_ma),
+        // EPR AVS is limited by power, the current allowed depends on the voltage
+        source::Apdo::EprAvs(data) => {
+            if !(data.min_voltage_mv..=data.max_voltage_mv).contains(&voltage_mv) {
+                return None;
+            }
+            let max_current_ma = data.pdp_mw.checked_div(voltage_mv as u32)?;
+            Some(max_current_ma.min(u16::MAX as u32) as u16)
+        }
+        source::Apdo::SprAvs(data) => {
+            if !(SPR_AVS_MIN_VOLTAGE_MV..=SPR_AVS_MAX_VOLTAGE_MV).contains(&voltage_mv) {
+                None
+            } else if voltage_mv <= SPR_AVS_15V_MAX_VOLTAGE_MV {
+                Some(data.max_current_15v_ma)
+            } else {
+                Some(data.max_current_20v_ma)
+            }
+        }
+    }
+}
+
 /// Port status
 #[derive(Copy, Clone, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -98,6 +239,24 @@ pub enum PortCommandData {
     RetimerFwUpdateClearState,
     /// Set retimer compliance
     SetRetimerCompliance,
+    /// Get alternate mode state
+    GetAltModeState,
+    /// Configure the mux
+    SetMux(MuxConfig),
+    /// Configure the retimer
+    SetRetimer(MuxConfig),
+    /// Set the alternate modes the port may enter
+    SetAltModeEntry(EntryPolicy),
+    /// Get the partner's source capabilities
+    GetPartnerSourceCaps,
+    /// Get the partner's sink capabilities
+    GetPartnerSinkCaps,
+    /// Request power from the partner
+    RequestPdo(PdoRequest),
+    /// Get the active request data object
+    GetActiveRdo,
+    /// Get the partner and cable discovery results
+    GetDiscovery,
 }
 
 /// Port-specific commands
@@ -132,6 +291,16 @@ pub enum PortResponseData {
     ClearEvents(PortEventKind),
     /// Retimer Fw Update status
     RtFwUpdateStatus(RetimerFwUpdateState),
+    /// Alternate mode state
+    AltModeState(AltModeState),
+    /// Partner source capabilities
+    PartnerSourceCaps(SourceCapabilities),
+    /// Partner sink capabilities
+    PartnerSinkCaps(SinkCapabilities),
+    /// Active request data object, `None` without an explicit contract
+    ActiveRdo(Option<Rdo>),
+    /// Partner and cable discovery results
+    Discovery(Discovery),
 }
 
 impl PortResponseData {
@@ -335,6 +504,108 @@ pub trait Controller {
         port: LocalPortId,
         enable: bool,
     ) -> impl Future<Output = Result<(), Error<Self::BusError>>>;
+    /// Swap power roles with the port partner
+    ///
+    /// Controllers that can't initiate a PR_Swap keep this default implementation.
+    fn power_role_swap(
+        &mut self,
+        _port: LocalPortId,
+        _role: policy::PowerRole,
+    ) -> impl Future<Output = Result<(), Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Get the alternate mode state
+    ///
+    /// Controllers without alternate mode support keep this default implementation.
+    fn get_alt_mode_state(
+        &mut self,
+        _port: LocalPortId,
+    ) -> impl Future<Output = Result<AltModeState, Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Configure the mux
+    ///
+    /// Controllers that configure the mux on their own keep this default implementation.
+    fn set_mux(
+        &mut self,
+        _port: LocalPortId,
+        _config: MuxConfig,
+    ) -> impl Future<Output = Result<(), Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Configure the retimer
+    ///
+    /// Controllers without a retimer keep this default implementation.
+    fn set_retimer(
+        &mut self,
+        _port: LocalPortId,
+        _config: MuxConfig,
+    ) -> impl Future<Output = Result<(), Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Set the alternate modes the port may enter, exiting any active mode that is no longer allowed
+    ///
+    /// Controllers that can't restrict mode entry keep this default implementation.
+    fn set_alt_mode_entry(
+        &mut self,
+        _port: LocalPortId,
+        _policy: EntryPolicy,
+    ) -> impl Future<Output = Result<(), Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Get the partner's source capabilities
+    ///
+    /// Controllers that don't keep the partner's capabilities keep this default implementation.
+    fn get_partner_source_caps(
+        &mut self,
+        _port: LocalPortId,
+    ) -> impl Future<Output = Result<SourceCapabilities, Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Get the partner's sink capabilities
+    ///
+    /// Controllers that don't keep the partner's capabilities keep this default implementation.
+    fn get_partner_sink_caps(
+        &mut self,
+        _port: LocalPortId,
+    ) -> impl Future<Output = Result<SinkCapabilities, Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Request power from the partner
+    ///
+    /// The request has already been checked against the partner's source capabilities if the controller reports them.
+    /// Controllers that negotiate on their own may only be able to steer their own selection, a [`PdoRequest::Pdo`]
+    /// might only cap the maximum voltage they negotiate. They must check the resulting contract and return
+    /// [`PdError::Rejected`] if it isn't the requested one.
+    ///
+    /// Controllers that only request power on their own keep this default implementation.
+    fn request_pdo(
+        &mut self,
+        _port: LocalPortId,
+        _request: PdoRequest,
+    ) -> impl Future<Output = Result<(), Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Get the active request data object
+    ///
+    /// Controllers that can't report the RDO keep this default implementation.
+    fn get_active_rdo(
+        &mut self,
+        _port: LocalPortId,
+    ) -> impl Future<Output = Result<Option<Rdo>, Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
+    /// Run a structured VDM discovery command, returns the VDOs of the ACK response
+    ///
+    /// Controllers that can't send structured VDMs keep this default implementation.
+    fn discover(
+        &mut self,
+        _port: LocalPortId,
+        _sop: Sop,
+        _command: DiscoverCommand,
+    ) -> impl Future<Output = Result<Vdos, Error<Self::BusError>>> {
+        async { Err(Error::Pd(PdError::UnrecognizedCommand)) }
+    }
     /// Get current controller status
     fn get_controller_status(
         &mut self,
@@ -402,12 +673,32 @@ pub fn init() {
 }
 
 /// Register a PD controller
-pub async fn register_controller(controller: &'static impl DeviceContainer) -> Result<(), intrusive_list::Error> {
-    CONTEXT
-        .get()
-        .await
-        .controllers
-        .push(controller.get_pd_controller_device())
+///
+/// Fails if any of the controller's ports is beyond [`MAX_SUPPORTED_PORTS`] or already belongs to a controller.
+pub async fn register_controller(controller: &'static impl DeviceContainer) -> Result<(), PdError> {
+    let context = CONTEXT.get().await;
+    let device = controller.get_pd_controller_device();
+
+    for local in 0..device.num_ports() {
+        let port = device.lookup_global_port(LocalPortId(local as u8))?;
+        if port.0 as usize >= MAX_SUPPORTED_PORTS {
+            error!("Controller{}: Port{} is not supported", device.id().0, port.0);
+            return Err(PdError::InvalidPort);
+        }
+
+        let duplicate = device.ports[..local].contains(&port)
+            || context
+                .controllers
+                .into_iter()
+                .filter_map(|node| node.data::<Device>())
+                .any(|other| other.has_port(port));
+        if duplicate {
+            error!("Controller{}: Port{} is already registered", device.id().0, port.0);
+            return Err(PdError::InvalidPort);
+        }
+    }
+
+    context.controllers.push(device).map_err(|_| PdError::Failed)
 }
 
 pub(super) async fn lookup_controller(controller_id: ControllerId) -> Result<&'static Device<'static>, PdError> {
@@ -498,6 +789,11 @@ impl ContextToken {
             .map(|_| ())
     }
 
+    /// Returns true if the given port belongs to a registered controller
+    pub async fn has_port(&self, port_id: GlobalPortId) -> bool {
+        self.find_node_by_port(port_id).await.is_ok()
+    }
+
     async fn find_node_by_port(&self, port_id: GlobalPortId) -> Result<&IntrusiveNode, PdError> {
         CONTEXT
             .get()
@@ -705,6 +1001,95 @@ impl ContextToken {
         }
     }
 
+    /// Get the alternate mode state
+    pub async fn get_alt_mode_state(&self, port: GlobalPortId) -> Result<AltModeState, PdError> {
+        match self.send_port_command(port, PortCommandData::GetAltModeState).await? {
+            PortResponseData::AltModeState(state) => Ok(state),
+            r => {
+                error!("Invalid response: expected alt mode state, got {:?}", r);
+                Err(PdError::InvalidResponse)
+            }
+        }
+    }
+
+    /// Configure the mux
+    pub async fn set_mux(&self, port: GlobalPortId, config: MuxConfig) -> Result<(), PdError> {
+        self.send_port_command(port, PortCommandData::SetMux(config))
+            .await?
+            .complete_or_err()
+    }
+
+    /// Configure the retimer
+    pub async fn set_retimer(&self, port: GlobalPortId, config: MuxConfig) -> Result<(), PdError> {
+        self.send_port_command(port, PortCommandData::SetRetimer(config))
+            .await?
+            .complete_or_err()
+    }
+
+    /// Set the alternate modes the port may enter
+    pub async fn set_alt_mode_entry(&self, port: GlobalPortId, policy: EntryPolicy) -> Result<(), PdError> {
+        self.send_port_command(port, PortCommandData::SetAltModeEntry(policy))
+            .await?
+            .complete_or_err()
+    }
+
+    /// Get the partner's source capabilities
+    pub async fn get_partner_source_caps(&self, port: GlobalPortId) -> Result<SourceCapabilities, PdError> {
+        match self
+            .send_port_command(port, PortCommandData::GetPartnerSourceCaps)
+            .await?
+        {
+            PortResponseData::PartnerSourceCaps(caps) => Ok(caps),
+            r => {
+                error!("Invalid response: expected source caps, got {:?}", r);
+                Err(PdError::InvalidResponse)
+            }
+        }
+    }
+
+    /// Get the partner's sink capabilities
+    pub async fn get_partner_sink_caps(&self, port: GlobalPortId) -> Result<SinkCapabilities, PdError> {
+        match self
+            .send_port_command(port, PortCommandData::GetPartnerSinkCaps)
+            .await?
+        {
+            PortResponseData::PartnerSinkCaps(caps) => Ok(caps),
+            r => {
+                error!("Invalid response: expected sink caps, got {:?}", r);
+                Err(PdError::InvalidResponse)
+            }
+        }
+    }
+
+    /// Request power from the partner
+    pub async fn request_pdo(&self, port: GlobalPortId, request: PdoRequest) -> Result<(), PdError> {
+        self.send_port_command(port, PortCommandData::RequestPdo(request))
+            .await?
+            .complete_or_err()
+    }
+
+    /// Get the active request data object
+    pub async fn get_active_rdo(&self, port: GlobalPortId) -> Result<Option<Rdo>, PdError> {
+        match self.send_port_command(port, PortCommandData::GetActiveRdo).await? {
+            PortResponseData::ActiveRdo(rdo) => Ok(rdo),
+            r => {
+                error!("Invalid response: expected active RDO, got {:?}", r);
+                Err(PdError::InvalidResponse)
+            }
+        }
+    }
+
+    /// Get the partner and cable discovery results
+    pub async fn get_discovery(&self, port: GlobalPortId) -> Result<Discovery, PdError> {
+        match self.send_port_command(port, PortCommandData::GetDiscovery).await? {
+            PortResponseData::Discovery(discovery) => Ok(discovery),
+            r => {
+                error!("Invalid response: expected discovery, got {:?}", r);
+                Err(PdError::InvalidResponse)
+            }
+        }
+    }
+
     /// Wait for an external command
     pub async fn wait_external_command(
         &self,
@@ -745,3 +1130,144 @@ pub(super) async fn execute_external_controller_command(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Leak a controller with the given global ports
+    fn controller(id: u8, ports: &[u8]) -> &'static Device<'static> {
+        let ports: Vec<GlobalPortId> = ports.iter().map(|port| GlobalPortId(*port)).collect();
+        Box::leak(Box::new(Device::new(
+            ControllerId(id),
+            Box::leak(ports.into_boxed_slice()),
+        )))
+    }
+
+    #[test]
+    fn test_register_controller() {
+        init();
+        embassy_futures::block_on(async {
+            assert_eq!(register_controller(controller(0, &[0, 1])).await, Ok(()));
+
+            // Ports past the supported range
+            let out_of_range = controller(1, &[2, MAX_SUPPORTED_PORTS as u8]);
+            assert_eq!(register_controller(out_of_range).await, Err(PdError::InvalidPort));
+            assert_eq!(
+                lookup_controller(ControllerId(1)).await.err(),
+                Some(PdError::InvalidController)
+            );
+
+            // The same port twice on one controller
+            let duplicate = controller(2, &[2, 2]);
+            assert_eq!(register_controller(duplicate).await, Err(PdError::InvalidPort));
+
+            // A port already owned by another controller
+            let duplicate = controller(3, &[2, 1]);
+            assert_eq!(register_controller(duplicate).await, Err(PdError::InvalidPort));
+            assert_eq!(
+                lookup_controller(ControllerId(3)).await.err(),
+                Some(PdError::InvalidController)
+            );
+
+            // The rejected registrations didn't claim any ports
+            let last_port = (MAX_SUPPORTED_PORTS - 1) as u8;
+            assert_eq!(register_controller(controller(4, &[2, last_port])).await, Ok(()));
+            assert!(
+                lookup_controller(ControllerId(4))
+                    .await
+                    .unwrap()
+                    .has_port(GlobalPortId(2))
+            );
+        });
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::from_slice(&[1u32, 2, 3]).unwrap();
+        assert_eq!(caps.len(), 3);
+        assert_eq!(caps.get(0), None);
+        assert_eq!(caps.get(1), Some(&1));
+        assert_eq!(caps.get(3), Some(&3));
+        assert_eq!(caps.get(4), None);
+        assert!(caps.iter().copied().eq([1, 2, 3]));
+
+        assert!(Capabilities::<u32>::default().is_empty());
+        assert_eq!(
+            Capabilities::from_slice(&[0u32; MAX_PDOS + 1]).err(),
+            Some(PdError::InvalidParams)
+        );
+    }
+
+    /// Fixed 5V@3A
+    const FIXED_5V: u32 = (100 << 10) | 300;
+    /// Fixed 20V@3A
+    const FIXED_20V: u32 = (400 << 10) | 300;
+    /// SPR PPS 3.3V-21V@3A
+    const SPR_PPS: u32 = (0b11 << 30) | (210 << 17) | (33 << 8) | 60;
+    /// EPR AVS 15V-28V@140W
+    const EPR_AVS: u32 = (0b11 << 30) | (0b01 << 28) | (280 << 17) | (150 << 8) | 140;
+
+    fn source_caps(pdos: &[u32]) -> SourceCapabilities {
+        let mut parsed = [source::Pdo::try_from(FIXED_5V).unwrap(); MAX_PDOS];
+        for (pdo, raw) in parsed.iter_mut().zip(pdos) {
+            *pdo = source::Pdo::try_from(*raw).unwrap();
+        }
+        Capabilities::from_slice(&parsed[..pdos.len()]).unwrap()
+    }
+
+    fn apdo(position: u8, voltage_mv: u16, current_ma: u16) -> PdoRequest {
+        PdoRequest::Apdo {
+            position,
+            voltage_mv,
+            current_ma,
+        }
+    }
+
+    #[test]
+    fn test_validate_pdo() {
+        let caps = source_caps(&[FIXED_5V, FIXED_20V, SPR_PPS]);
+        assert_eq!(PdoRequest::Pdo(1).validate(&caps), Ok(()));
+        assert_eq!(PdoRequest::Pdo(2).validate(&caps), Ok(()));
+
+        // Object positions are 1-based and must exist
+        assert_eq!(PdoRequest::Pdo(0).validate(&caps), Err(PdError::InvalidParams));
+        assert_eq!(PdoRequest::Pdo(4).validate(&caps), Err(PdError::InvalidParams));
+
+        // APDOs need a voltage and current, fixed PDOs can't take one
+        assert_eq!(PdoRequest::Pdo(3).validate(&caps), Err(PdError::InvalidParams));
+        assert_eq!(apdo(2, 20000, 3000).validate(&caps), Err(PdError::InvalidParams));
+    }
+
+    #[test]
+    fn test_validate_pps() {
+        let caps = source_caps(&[FIXED_5V, SPR_PPS]);
+        assert_eq!(apdo(2, 3300, 3000).validate(&caps), Ok(()));
+        assert_eq!(apdo(2, 21000, 1000).validate(&caps), Ok(()));
+
+        assert_eq!(apdo(2, 3200, 1000).validate(&caps), Err(PdError::InvalidParams));
+        assert_eq!(apdo(2, 21100, 1000).validate(&caps), Err(PdError::InvalidParams));
+        assert_eq!(apdo(2, 9000, 3050).validate(&caps), Err(PdError::InvalidParams));
+    }
+
+    #[test]
+    fn test_validate_epr_avs() {
+        let caps = source_caps(&[FIXED_5V, EPR_AVS]);
+        assert_eq!(apdo(2, 15000, 5000).validate(&caps), Ok(()));
+        assert_eq!(apdo(2, 28000, 5000).validate(&caps), Ok(()));
+
+        // Below the minimum and above the maximum voltage
+        assert_eq!(apdo(2, 14900, 1000).validate(&caps), Err(PdError::InvalidParams));
+        assert_eq!(apdo(2, 28100, 1000).validate(&caps), Err(PdError::InvalidParams));
+
+        // 140W allows more current at lower voltages
+        assert_eq!(apdo(2, 20000, 7000).validate(&caps), Ok(()));
+        assert_eq!(apdo(2, 20000, 7050).validate(&caps), Err(PdError::InvalidParams));
+        assert_eq!(apdo(2, 28000, 5050).validate(&caps), Err(PdError::InvalidParams));
+    }
+}
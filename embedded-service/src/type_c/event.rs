@@ -19,6 +19,14 @@ bitfield! {
     pub u8, source_caps_received, set_source_caps_received: 4, 4;
     /// Sink ready
     pub u8, sink_ready, set_sink_ready: 5, 5;
+    /// Fast role swap completed
+    pub u8, fast_role_swap, set_fast_role_swap: 6, 6;
+    /// Alternate mode entered
+    pub u8, alt_mode_entered, set_alt_mode_entered: 7, 7;
+    /// Alternate mode exited
+    pub u8, alt_mode_exited, set_alt_mode_exited: 8, 8;
+    /// DisplayPort status update
+    pub u8, dp_status_update, set_dp_status_update: 9, 9;
 }
 
 /// Type-safe wrapper for the raw port event kind
@@ -87,10 +95,53 @@ impl PortEventKind {
     pub fn set_sink_ready(&mut self, value: bool) {
         self.0.set_sink_ready(value.into());
     }
+
+    /// Returns true if the port started providing power after a fast role swap
+    pub fn fast_role_swap(self) -> bool {
+        self.0.fast_role_swap() != 0
+    }
+
+    /// Sets the fast role swap event
+    pub fn set_fast_role_swap(&mut self, value: bool) {
+        self.0.set_fast_role_swap(value.into());
+    }
+
+    /// Returns true if an alternate mode was entered
+    pub fn alt_mode_entered(self) -> bool {
+        self.0.alt_mode_entered() != 0
+    }
+
+    /// Sets the alternate mode entered event
+    pub fn set_alt_mode_entered(&mut self, value: bool) {
+        self.0.set_alt_mode_entered(value.into());
+    }
+
+    /// Returns true if an alternate mode was exited
+    pub fn alt_mode_exited(self) -> bool {
+        self.0.alt_mode_exited() != 0
+    }
+
+    /// Sets the alternate mode exited event
+    pub fn set_alt_mode_exited(&mut self, value: bool) {
+        self.0.set_alt_mode_exited(value.into());
+    }
+
+    /// Returns true if the DisplayPort status was updated
+    pub fn dp_status_update(self) -> bool {
+        self.0.dp_status_update() != 0
+    }
+
+    /// Sets the DisplayPort status update event
+    pub fn set_dp_status_update(&mut self, value: bool) {
+        self.0.set_dp_status_update(value.into());
+    }
 }
 
+/// Maximum number of global ports, limited by the width of [`PortEventFlags`]
+pub const MAX_SUPPORTED_PORTS: usize = 32;
+
 /// Bit vector type to store pending port events
-type PortEventFlagsVec = BitArr!(for 32, in u32);
+type PortEventFlagsVec = BitArr!(for MAX_SUPPORTED_PORTS, in u32);
 
 /// Pending port events
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -1,12 +1,13 @@
 //! Message definitions for external type-C commands
-use embedded_usb_pd::{GlobalPortId, PdError, PortId as LocalPortId};
+use embedded_usb_pd::{GlobalPortId, PdError, PortId as LocalPortId, pdo::Rdo};
 
 use super::{
     ControllerId,
     controller::{
-        ControllerStatus, PortStatus, RetimerFwUpdateState, execute_external_controller_command,
-        execute_external_port_command, lookup_controller,
+        ControllerStatus, PdoRequest, PortStatus, RetimerFwUpdateState, SinkCapabilities, SourceCapabilities,
+        execute_external_controller_command, execute_external_port_command, lookup_controller,
     },
+    vdm::Discovery,
 };
 
 /// Data for controller-specific commands
@@ -56,6 +57,16 @@ pub enum PortCommandData {
     RetimerFwUpdateClearState,
     /// Set retimer compliance
     SetRetimerCompliance,
+    /// Get the partner's source capabilities
+    GetPartnerSourceCaps,
+    /// Get the partner's sink capabilities
+    GetPartnerSinkCaps,
+    /// Request power from the partner
+    RequestPdo(PdoRequest),
+    /// Get the active request data object
+    GetActiveRdo,
+    /// Get the partner and cable discovery results
+    GetDiscovery,
 }
 
 /// Port-specific commands
@@ -78,6 +89,14 @@ pub enum PortResponseData {
     PortStatus(PortStatus),
     /// Get retimer fw update status
     RetimerFwUpdateGetState(RetimerFwUpdateState),
+    /// Partner source capabilities
+    PartnerSourceCaps(SourceCapabilities),
+    /// Partner sink capabilities
+    PartnerSinkCaps(SinkCapabilities),
+    /// Active request data object
+    ActiveRdo(Option<Rdo>),
+    /// Partner and cable discovery results
+    Discovery(Discovery),
 }
 
 /// Port-specific command response
@@ -201,6 +220,71 @@ pub async fn port_set_rt_compliance(port: GlobalPortId) -> Result<(), PdError> {
     }
 }
 
+/// Get the source capabilities of the partner on the given port
+pub async fn port_get_partner_source_caps(port: GlobalPortId) -> Result<SourceCapabilities, PdError> {
+    match execute_external_port_command(Command::Port(PortCommand {
+        port,
+        data: PortCommandData::GetPartnerSourceCaps,
+    }))
+    .await?
+    {
+        PortResponseData::PartnerSourceCaps(caps) => Ok(caps),
+        _ => Err(PdError::InvalidResponse),
+    }
+}
+
+/// Get the sink capabilities of the partner on the given port
+pub async fn port_get_partner_sink_caps(port: GlobalPortId) -> Result<SinkCapabilities, PdError> {
+    match execute_external_port_command(Command::Port(PortCommand {
+        port,
+        data: PortCommandData::GetPartnerSinkCaps,
+    }))
+    .await?
+    {
+        PortResponseData::PartnerSinkCaps(caps) => Ok(caps),
+        _ => Err(PdError::InvalidResponse),
+    }
+}
+
+/// Request a PDO or an APDO voltage and current from the partner on the given port
+pub async fn port_request_pdo(port: GlobalPortId, request: PdoRequest) -> Result<(), PdError> {
+    match execute_external_port_command(Command::Port(PortCommand {
+        port,
+        data: PortCommandData::RequestPdo(request),
+    }))
+    .await?
+    {
+        PortResponseData::Complete => Ok(()),
+        _ => Err(PdError::InvalidResponse),
+    }
+}
+
+/// Get the active request data object of the given port
+pub async fn port_get_active_rdo(port: GlobalPortId) -> Result<Option<Rdo>, PdError> {
+    match execute_external_port_command(Command::Port(PortCommand {
+        port,
+        data: PortCommandData::GetActiveRdo,
+    }))
+    .await?
+    {
+        PortResponseData::ActiveRdo(rdo) => Ok(rdo),
+        _ => Err(PdError::InvalidResponse),
+    }
+}
+
+/// Get the identities of the partner and cable on the given port, along with the partner's modes
+pub async fn port_get_discovery(port: GlobalPortId) -> Result<Discovery, PdError> {
+    match execute_external_port_command(Command::Port(PortCommand {
+        port,
+        data: PortCommandData::GetDiscovery,
+    }))
+    .await?
+    {
+        PortResponseData::Discovery(discovery) => Ok(discovery),
+        _ => Err(PdError::InvalidResponse),
+    }
+}
+
 /// Trigger a sync of the controller state
 pub async fn sync_controller_state(id: ControllerId) -> Result<(), PdError> {
     match execute_external_controller_command(Command::Controller(ControllerCommand {
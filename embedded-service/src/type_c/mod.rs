@@ -5,10 +5,12 @@ use embedded_usb_pd::type_c;
 
 use crate::power::policy;
 
+pub mod alt_mode;
 pub mod comms;
 pub mod controller;
 pub mod event;
 pub mod external;
+pub mod vdm;
 
 /// Controller ID
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -0,0 +1,343 @@
+//! Structured VDM discovery definitions
+use embedded_usb_pd::PdError;
+
+use crate::power::policy::PowerCapability;
+
+/// Maximum number of VDOs in a structured VDM response, excluding the VDM header
+pub const MAX_VDOS: usize = 6;
+
+/// Maximum number of SVIDs kept from Discover SVIDs
+pub const MAX_SVIDS: usize = 8;
+
+/// Current rating of cables without an e-marker
+pub const DEFAULT_CABLE_CURRENT_MA: u16 = 3000;
+
+/// Recipient of a structured VDM
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Sop {
+    /// Port partner
+    Sop,
+    /// Cable plug
+    SopPrime,
+}
+
+/// Discovery command
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DiscoverCommand {
+    /// Discover Identity
+    Identity,
+    /// Discover SVIDs
+    Svids,
+    /// Discover Modes for the given SVID
+    Modes(u16),
+}
+
+/// VDOs of a structured VDM response, excluding the VDM header
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Vdos {
+    len: u8,
+    vdos: [u32; MAX_VDOS],
+}
+
+impl Vdos {
+    /// Create a response from the given VDOs
+    pub fn from_slice(vdos: &[u32]) -> Result<Self, PdError> {
+        if vdos.len() > MAX_VDOS {
+            return Err(PdError::InvalidParams);
+        }
+
+        let mut response = Self::default();
+        response.vdos[..vdos.len()].copy_from_slice(vdos);
+        response.len = vdos.len() as u8;
+        Ok(response)
+    }
+
+    /// VDOs as a slice
+    pub fn as_slice(&self) -> &[u32] {
+        &self.vdos[..self.len as usize]
+    }
+
+    /// SVIDs from a Discover SVIDs response, two per VDO with the first in the upper half, ending at the first zero
+    pub fn svids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.as_slice()
+            .iter()
+            .flat_map(|vdo| [(vdo >> 16) as u16, *vdo as u16])
+            .take_while(|svid| *svid != 0)
+    }
+}
+
+/// Product type from the ID header
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProductType {
+    /// Not a UFP or cable
+    Undefined,
+    /// PDUSB hub
+    Hub,
+    /// PDUSB peripheral
+    Peripheral,
+    /// Power sink device
+    Psd,
+    /// Passive cable
+    PassiveCable,
+    /// Active cable
+    ActiveCable,
+    /// VCONN powered USB device
+    Vpd,
+    /// Reserved value
+    Reserved(u8),
+}
+
+impl ProductType {
+    fn new(sop: Sop, raw: u8) -> Self {
+        match (sop, raw) {
+            (_, 0) => ProductType::Undefined,
+            (Sop::Sop, 1) => ProductType::Hub,
+            (Sop::Sop, 2) => ProductType::Peripheral,
+            (Sop::Sop, 3) => ProductType::Psd,
+            (Sop::SopPrime, 3) => ProductType::PassiveCable,
+            (Sop::SopPrime, 4) => ProductType::ActiveCable,
+            (Sop::SopPrime, 6) => ProductType::Vpd,
+            (_, raw) => ProductType::Reserved(raw),
+        }
+    }
+
+    /// Returns true for cables
+    pub fn is_cable(&self) -> bool {
+        matches!(self, ProductType::PassiveCable | ProductType::ActiveCable)
+    }
+}
+
+/// Highest USB signaling supported by a cable
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CableSpeed {
+    /// USB 2.0 only
+    Usb2,
+    /// USB 3.2 Gen1
+    Usb32Gen1,
+    /// USB 3.2 or USB4 Gen2
+    Usb4Gen2,
+    /// USB4 Gen3
+    Usb4Gen3,
+    /// USB4 Gen4
+    Usb4Gen4,
+    /// Reserved value
+    Reserved(u8),
+}
+
+impl From<u8> for CableSpeed {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => CableSpeed::Usb2,
+            1 => CableSpeed::Usb32Gen1,
+            2 => CableSpeed::Usb4Gen2,
+            3 => CableSpeed::Usb4Gen3,
+            4 => CableSpeed::Usb4Gen4,
+            raw => CableSpeed::Reserved(raw),
+        }
+    }
+}
+
+/// Cable properties from the e-marker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CableProperties {
+    /// Highest USB signaling supported
+    pub speed: CableSpeed,
+    /// VBUS current rating
+    pub current_ma: u16,
+    /// Maximum VBUS voltage
+    pub max_voltage_mv: u16,
+}
+
+impl CableProperties {
+    /// Parse a passive or active cable VDO
+    fn new(vdo: u32) -> Self {
+        let current_ma = match (vdo >> 5) & 0x3 {
+            2 => 5000,
+            _ => DEFAULT_CABLE_CURRENT_MA,
+        };
+        let max_voltage_mv = match (vdo >> 9) & 0x3 {
+            0 => 20000,
+            1 => 30000,
+            2 => 40000,
+            _ => 50000,
+        };
+
+        Self {
+            speed: CableSpeed::from((vdo & 0x7) as u8),
+            current_ma,
+            max_voltage_mv,
+        }
+    }
+
+    /// Limit the current of a power capability to the cable's rating
+    ///
+    /// The voltage is left as is, a capability the cable isn't rated for can't be made safe by relabelling it. Check
+    /// it with [`Self::supports_voltage`] first.
+    pub fn limit(&self, capability: PowerCapability) -> PowerCapability {
+        PowerCapability {
+            voltage_mv: capability.voltage_mv,
+            current_ma: capability.current_ma.min(self.current_ma),
+        }
+    }
+
+    /// Returns true if the cable is rated for the voltage of a power capability
+    pub fn supports_voltage(&self, capability: PowerCapability) -> bool {
+        capability.voltage_mv <= self.max_voltage_mv
+    }
+}
+
+/// Identity from a Discover Identity response
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Identity {
+    /// USB vendor ID
+    pub vid: u16,
+    /// USB product ID
+    pub pid: u16,
+    /// Device release number, BCD
+    pub bcd_device: u16,
+    /// Product type
+    pub product_type: ProductType,
+    /// Supports alternate modes
+    pub modal: bool,
+    /// Cable properties, only present for cables
+    pub cable: Option<CableProperties>,
+}
+
+impl Identity {
+    /// Parse a Discover Identity response: ID header, cert stat, product and product type VDOs
+    pub fn parse(sop: Sop, vdos: &Vdos) -> Result<Self, PdError> {
+        let vdos = vdos.as_slice();
+        if vdos.len() < 3 {
+            return Err(PdError::InvalidResponse);
+        }
+
+        let id_header = vdos[0];
+        let product = vdos[2];
+        let product_type = ProductType::new(sop, ((id_header >> 27) & 0x7) as u8);
+        let cable = if product_type.is_cable() {
+            Some(CableProperties::new(*vdos.get(3).ok_or(PdError::InvalidResponse)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            vid: id_header as u16,
+            pid: (product >> 16) as u16,
+            bcd_device: product as u16,
+            product_type,
+            modal: id_header & (1 << 26) != 0,
+            cable,
+        })
+    }
+}
+
+/// Modes supported by the partner for a SVID
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SvidModes {
+    /// Standard or vendor ID
+    pub svid: u16,
+    /// Mode VDOs from Discover Modes
+    pub modes: Vdos,
+}
+
+/// Discovery results for a port
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Discovery {
+    /// Port partner identity
+    pub partner: Option<Identity>,
+    /// Cable identity, `None` if the cable doesn't have an e-marker
+    pub cable: Option<Identity>,
+    /// Modes supported by the port partner
+    pub modes: [Option<SvidModes>; MAX_SVIDS],
+}
+
+impl Discovery {
+    /// Cable properties, `None` if the cable doesn't have an e-marker
+    pub fn cable_properties(&self) -> Option<CableProperties> {
+        self.cable.and_then(|cable| cable.cable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        // Peripheral, modal, VID 0x1234, PID 0x5678, bcdDevice 0x0100
+        let vdos = Vdos::from_slice(&[(2 << 27) | (1 << 26) | 0x1234, 0, 0x5678_0100]).unwrap();
+        let identity = Identity::parse(Sop::Sop, &vdos).unwrap();
+        assert_eq!(identity.vid, 0x1234);
+        assert_eq!(identity.pid, 0x5678);
+        assert_eq!(identity.bcd_device, 0x0100);
+        assert_eq!(identity.product_type, ProductType::Peripheral);
+        assert!(identity.modal);
+        assert_eq!(identity.cable, None);
+
+        // Passive 5A 50V USB4 Gen3 cable
+        let vdos = Vdos::from_slice(&[(3 << 27) | 0x1234, 0, 0x5678_0100, (2 << 5) | (3 << 9) | 3]).unwrap();
+        let identity = Identity::parse(Sop::SopPrime, &vdos).unwrap();
+        assert_eq!(identity.product_type, ProductType::PassiveCable);
+        assert_eq!(
+            identity.cable,
+            Some(CableProperties {
+                speed: CableSpeed::Usb4Gen3,
+                current_ma: 5000,
+                max_voltage_mv: 50000,
+            })
+        );
+
+        // Cables must have a cable VDO
+        let vdos = Vdos::from_slice(&[3 << 27, 0, 0]).unwrap();
+        assert_eq!(Identity::parse(Sop::SopPrime, &vdos), Err(PdError::InvalidResponse));
+        assert_eq!(
+            Identity::parse(Sop::Sop, &Vdos::default()),
+            Err(PdError::InvalidResponse)
+        );
+    }
+
+    #[test]
+    fn test_svids() {
+        let vdos = Vdos::from_slice(&[0xff01_8087, 0x1234_0000]).unwrap();
+        assert!(vdos.svids().eq([0xff01, 0x8087, 0x1234]));
+        assert_eq!(Vdos::from_slice(&[0; MAX_VDOS + 1]), Err(PdError::InvalidParams));
+    }
+
+    #[test]
+    fn test_cable_limit() {
+        let cable = CableProperties {
+            speed: CableSpeed::Usb2,
+            current_ma: 3000,
+            max_voltage_mv: 20000,
+        };
+        let capability = PowerCapability {
+            voltage_mv: 48000,
+            current_ma: 5000,
+        };
+        assert_eq!(
+            cable.limit(capability),
+            PowerCapability {
+                voltage_mv: 48000,
+                current_ma: 3000,
+            }
+        );
+        assert!(!cable.supports_voltage(capability));
+
+        // Within the rating
+        let capability = PowerCapability {
+            voltage_mv: 20000,
+            current_ma: 3000,
+        };
+        assert_eq!(cable.limit(capability), capability);
+        assert!(cable.supports_voltage(capability));
+    }
+}
@@ -1,14 +1,17 @@
 use core::mem::offset_of;
 use core::slice;
 
+use embassy_futures::select::{Either, select};
 use embassy_sync::mutex::Mutex;
 use embassy_sync::once_lock::OnceLock;
+use embassy_sync::signal::Signal;
 use embedded_services::comms::{self, EndpointID, External, Internal};
 use embedded_services::{GlobalRawMutex, ec_type, error, info};
 
 pub struct Service<'a> {
     endpoint: comms::Endpoint,
     ec_memory: Mutex<GlobalRawMutex, &'a mut ec_type::structure::ECMemory>,
+    host_notification: Signal<GlobalRawMutex, ()>,
 }
 
 impl Service<'_> {
@@ -16,6 +19,7 @@ impl Service<'_> {
         Service {
             endpoint: comms::Endpoint::uninit(EndpointID::External(External::Host)),
             ec_memory: Mutex::new(ec_memory),
+            host_notification: Signal::new(),
         }
     }
 
@@ -49,6 +53,10 @@ impl Service<'_> {
                 && offset < offset_of!(ec_type::structure::ECMemory, alarm) + size_of::<ec_type::structure::TimeAlarm>()
             {
                 self.route_to_time_alarm_service(&mut offset, &mut length).await?;
+            } else if offset >= offset_of!(ec_type::structure::ECMemory, ucsi)
+                && offset < offset_of!(ec_type::structure::ECMemory, ucsi) + size_of::<ec_type::structure::Ucsi>()
+            {
+                self.route_to_usbc_service(&mut offset, &mut length).await?;
             }
         }
 
@@ -114,6 +122,26 @@ impl Service<'_> {
 
         Ok(())
     }
+
+    async fn route_to_usbc_service(&self, offset: &mut usize, length: &mut usize) -> Result<(), ec_type::Error> {
+        let msg = {
+            let memory_map = self
+                .ec_memory
+                .try_lock()
+                .expect("Messages handled one after another, should be infallible.");
+            ec_type::mem_map_to_ucsi_msg(&memory_map, offset, length)?
+        };
+
+        comms::send(
+            EndpointID::External(External::Host),
+            EndpointID::Internal(Internal::Usbc),
+            &msg,
+        )
+        .await
+        .unwrap();
+
+        Ok(())
+    }
 }
 
 impl comms::MailboxDelegate for Service<'_> {
@@ -130,6 +158,12 @@ impl comms::MailboxDelegate for Service<'_> {
             ec_type::update_thermal_section(msg, &mut memory_map);
         } else if let Some(msg) = message.data.get::<ec_type::message::TimeAlarmMessage>() {
             ec_type::update_time_alarm_section(msg, &mut memory_map);
+        } else if let Some(msg) = message.data.get::<ec_type::message::UcsiMessage>() {
+            ec_type::update_ucsi_section(msg, &mut memory_map);
+            // Every CCI update completes a command or indicates a connector change, the host reads CCI once notified
+            if let ec_type::message::UcsiMessage::Cci(_) = msg {
+                self.host_notification.signal(());
+            }
         } else {
             return Err(comms::MailboxDelegateError::MessageNotFound);
         }
@@ -142,6 +176,9 @@ static ESPI_SERVICE: OnceLock<Service> = OnceLock::new();
 
 use embassy_imxrt::espi;
 
+/// Serial IRQ pushed to notify the host of memory map changes
+const HOST_NOTIFICATION_IRQ: u8 = 1;
+
 #[embassy_executor::task]
 pub async fn espi_service(mut espi: espi::Espi<'static>, memory_map_buffer: &'static mut [u8]) {
     info!("Reserved eSPI memory map buffer size: {}", memory_map_buffer.len());
@@ -170,7 +207,14 @@ pub async fn espi_service(mut espi: espi::Espi<'static>, memory_map_buffer: &'st
         .unwrap();
 
     loop {
-        let event = espi.wait_for_event().await;
+        let event = match select(espi.wait_for_event(), espi_service.host_notification.wait()).await {
+            Either::First(event) => event,
+            Either::Second(()) => {
+                espi.irq_push(HOST_NOTIFICATION_IRQ);
+                continue;
+            }
+        };
+
         match event {
             Ok(espi::Event::PeripheralEvent(port_event)) => {
                 info!(
@@ -85,6 +85,14 @@ mod battery {
                     info!("Consumer connected: {} {:?}", id.0, capability);
                     Ok(())
                 }
+                policy::CommsData::PowerEnvelope(envelope) => {
+                    info!("Power envelope: {:?}", envelope);
+                    Ok(())
+                }
+                policy::CommsData::ChargerState(id, state) => {
+                    info!("Charger {} state: {:?}", id.0, state);
+                    Ok(())
+                }
             }
         }
     }
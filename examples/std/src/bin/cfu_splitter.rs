@@ -150,6 +150,9 @@ async fn splitter_task(splitter: &'static splitter::Splitter<'static, mock::Cust
     loop {
         let request = splitter.wait_request().await;
         let response = splitter.process_request(request).await;
+        for failure in splitter.failures().await {
+            warn!("Device {} failed: {:?}", failure.device, failure.failure);
+        }
         splitter.send_response(response).await;
     }
 }
@@ -192,7 +195,11 @@ async fn run(spawner: Spawner) {
     static SPLITTER: OnceLock<splitter::Splitter<'static, mock::Customization>> = OnceLock::new();
     static DEVICES: [ComponentId; 2] = [CFU_COMPONENT0_ID, CFU_COMPONENT1_ID];
     let customization = mock::Customization {};
-    let splitter = SPLITTER.get_or_init(|| splitter::Splitter::new(CFU_SPLITTER_ID, &DEVICES, customization).unwrap());
+    let config = splitter::Config {
+        policy: splitter::Policy::AllOrNothing,
+    };
+    let splitter =
+        SPLITTER.get_or_init(|| splitter::Splitter::new(CFU_SPLITTER_ID, &DEVICES, customization, config).unwrap());
     splitter.register().await.unwrap();
     spawner.must_spawn(splitter_task(splitter));
 
@@ -0,0 +1,213 @@
+use embassy_executor::{Executor, Spawner};
+use embassy_sync::once_lock::OnceLock;
+use log::*;
+use static_cell::StaticCell;
+
+use embedded_cfu_protocol::protocol_definitions::*;
+use embedded_services::cfu::{
+    self,
+    component::{InternalResponseData, RequestData},
+};
+
+use cfu_service::host::{self, MemoryImage, OutOfBounds, Updater};
+use cfu_service::image::{self, OFFER_SIZE, SEGMENT_CRC_ADDRESS, SIGNATURE_ADDRESS, TOKEN_DRIVER};
+use cfu_service::verify::{VerifiedComponent, ecdsa::EcdsaP256Verifier};
+
+/// Component ID the host updates through
+const CFU_VERIFIED_ID: ComponentId = 0x07;
+
+/// Component ID for the mock device
+const CFU_COMPONENT0_ID: ComponentId = 0x20;
+
+/// Uncompressed SEC1 public key images are signed with
+const PUBLIC_KEY: [u8; 65] = [
+    0x04, 0x47, 0x1c, 0x3e, 0x75, 0x8c, 0x49, 0x04, 0x28, 0x5b, 0xba, 0x7e, 0x53, 0x11, 0x8e, 0xd0, 0xf5, 0x24, 0xad,
+    0xeb, 0x07, 0x57, 0xd2, 0x5b, 0xd2, 0xf8, 0xe7, 0xb0, 0xd7, 0x6d, 0xfa, 0x71, 0x4c, 0xdd, 0x52, 0x0f, 0x7a, 0xca,
+    0x8a, 0x8b, 0x91, 0x7a, 0xcc, 0x37, 0xf5, 0x1d, 0xe8, 0xf0, 0xc9, 0xbb, 0xe3, 0xad, 0x85, 0x83, 0x82, 0xe7, 0x02,
+    0xdc, 0x25, 0xa1, 0x2d, 0x09, 0xf7, 0xa8, 0x58,
+];
+
+/// Signature over [`image_body`]
+const SIGNATURE: [u8; 64] = [
+    0xfa, 0xcf, 0x19, 0x56, 0x06, 0xab, 0xa6, 0x70, 0xd5, 0xfb, 0xdb, 0x36, 0x20, 0x41, 0x19, 0xa9, 0xc3, 0x0b, 0x03,
+    0x69, 0x1f, 0x42, 0x76, 0x33, 0x81, 0x48, 0x7e, 0xed, 0xa2, 0xa3, 0x5c, 0xdc, 0x9a, 0xd7, 0x65, 0x66, 0xb8, 0xa4,
+    0x55, 0x85, 0x36, 0x69, 0xe2, 0x48, 0x46, 0xaa, 0x03, 0x8d, 0xf7, 0xbc, 0x20, 0x75, 0xf6, 0x8e, 0x2f, 0xa3, 0xd5,
+    0xa0, 0xb4, 0x69, 0x2f, 0x3b, 0x49, 0xd6,
+];
+
+const BODY_SIZE: usize = 200;
+
+fn image_body() -> [u8; BODY_SIZE] {
+    core::array::from_fn(|i| (i * 7 + 3) as u8)
+}
+
+/// Append a record to a payload
+fn push_record(payload: &mut Vec<u8>, address: u32, data: &[u8]) {
+    payload.extend_from_slice(&address.to_le_bytes());
+    payload.push(data.len() as u8);
+    payload.extend_from_slice(data);
+}
+
+/// Payload with the image body, its segment CRC and the signature record
+fn payload(signature: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    push_record(&mut payload, 0, &image_body());
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&payload);
+    push_record(&mut payload, SEGMENT_CRC_ADDRESS, &crc.to_le_bytes());
+    push_record(&mut payload, SIGNATURE_ADDRESS, signature);
+    payload
+}
+
+mod mock {
+    use embedded_services::cfu::component::{CfuDevice, CfuDeviceContainer};
+
+    use super::*;
+
+    /// Mock CFU device that accepts every request
+    pub struct Device {
+        cfu_device: CfuDevice,
+    }
+
+    impl Device {
+        /// Create a new mock CFU device
+        pub fn new(component_id: ComponentId) -> Self {
+            Self {
+                cfu_device: CfuDevice::new(component_id),
+            }
+        }
+
+        /// Wait for a CFU message
+        pub async fn wait_request(&self) -> RequestData {
+            self.cfu_device.wait_request().await
+        }
+
+        /// Process a CFU message and produce a response
+        pub async fn process_request(&self, request: RequestData) -> InternalResponseData {
+            match request {
+                RequestData::FwVersionRequest => {
+                    let dev_inf = FwVerComponentInfo::new(FwVersion::new(0x100), self.cfu_device.component_id());
+                    InternalResponseData::FwVersionResponse(GetFwVersionResponse {
+                        header: GetFwVersionResponseHeader::new(1, GetFwVerRespHeaderByte3::NoSpecialFlags),
+                        component_info: [dev_inf; MAX_CMPT_COUNT],
+                    })
+                }
+                RequestData::GiveOffer(_) => {
+                    info!("Device: got offer");
+                    InternalResponseData::OfferResponse(FwUpdateOfferResponse::new_accept(HostToken::Driver))
+                }
+                RequestData::GiveContent(content) => {
+                    info!("Device: got content {}", content.header.sequence_num);
+                    InternalResponseData::ContentResponse(FwUpdateContentResponse::new(
+                        content.header.sequence_num,
+                        CfuUpdateContentResponseStatus::Success,
+                    ))
+                }
+                RequestData::FinalizeUpdate => {
+                    info!("Device: activating image");
+                    InternalResponseData::ComponentPrepared
+                }
+                RequestData::PrepareComponentForUpdate => InternalResponseData::ComponentPrepared,
+            }
+        }
+
+        pub async fn send_response(&self, response: InternalResponseData) {
+            self.cfu_device.send_response(response).await;
+        }
+    }
+
+    impl CfuDeviceContainer for Device {
+        fn get_cfu_component_device(&self) -> &CfuDevice {
+            &self.cfu_device
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn device_task(device: &'static mock::Device) {
+    loop {
+        let request = device.wait_request().await;
+        let response = device.process_request(request).await;
+        device.send_response(response).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn verified_task(component: &'static VerifiedComponent<EcdsaP256Verifier>) {
+    loop {
+        let request = component.wait_request().await;
+        let response = component.process(request).await;
+        component.send_response(response).await;
+    }
+}
+
+/// Offer and send a signed image, then ask the component to activate it
+async fn update(signature: &[u8]) -> Result<(), host::Error<OutOfBounds>> {
+    let mut offer = [0; OFFER_SIZE];
+    offer[2] = CFU_VERIFIED_ID;
+    offer[3] = TOKEN_DRIVER;
+    offer[4..8].copy_from_slice(&0x211u32.to_le_bytes());
+
+    let updater = Updater::new(host::Config {
+        validation: image::Config {
+            require_signature: true,
+        },
+        send_signature: true,
+        ..Default::default()
+    });
+    updater
+        .update(
+            &mut MemoryImage::new(&offer),
+            &mut MemoryImage::new(&payload(signature)),
+        )
+        .await
+}
+
+#[embassy_executor::task]
+async fn run(spawner: Spawner) {
+    embedded_services::init().await;
+
+    info!("Creating device 0");
+    static DEVICE0: OnceLock<mock::Device> = OnceLock::new();
+    let device0 = DEVICE0.get_or_init(|| mock::Device::new(CFU_COMPONENT0_ID));
+    cfu::register_device(device0).await.unwrap();
+    spawner.must_spawn(device_task(device0));
+
+    info!("Creating verified component");
+    static VERIFIED: OnceLock<VerifiedComponent<EcdsaP256Verifier>> = OnceLock::new();
+    let verified = VERIFIED.get_or_init(|| {
+        VerifiedComponent::new(
+            CFU_VERIFIED_ID,
+            CFU_COMPONENT0_ID,
+            EcdsaP256Verifier::new(&PUBLIC_KEY).unwrap(),
+        )
+    });
+    verified.register().await.unwrap();
+    spawner.must_spawn(verified_task(verified));
+
+    info!("Updating with a signed image");
+    let result = update(&SIGNATURE).await;
+    info!("Update result: {result:?}");
+    assert_eq!(result, Ok(()));
+
+    info!("Updating with a tampered signature");
+    let mut tampered = SIGNATURE;
+    tampered[10] ^= 1;
+    let result = update(&tampered).await;
+    info!("Update result: {result:?}");
+    assert_eq!(
+        result,
+        Err(host::Error::ContentRejected(
+            CfuUpdateContentResponseStatus::ErrorInvalid
+        ))
+    );
+}
+
+fn main() {
+    env_logger::builder().filter_level(log::LevelFilter::Trace).init();
+    static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.must_spawn(cfu_service::task());
+        spawner.must_spawn(run(spawner));
+    });
+}
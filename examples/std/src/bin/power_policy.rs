@@ -46,6 +46,9 @@ impl ExampleDevice {
             device::CommandData::Disconnect => {
                 info!("Device {} received disconnect", self.device.id().0);
             }
+            device::CommandData::PowerRoleSwap(role) => {
+                info!("Device {} received power role swap to {:?}", self.device.id().0, role);
+            }
         }
 
         request.respond(Ok(policy::device::ResponseData::Complete));
@@ -86,6 +86,10 @@ mod test_controller {
                     info!("Port status for port {}", command.port.0);
                     controller::PortResponseData::PortStatus(PortStatus::new())
                 }
+                controller::PortCommandData::GetAltModeState => {
+                    info!("Alt mode state for port {}", command.port.0);
+                    controller::PortResponseData::AltModeState(Default::default())
+                }
                 _ => {
                     info!("Port command for port {}", command.port.0);
                     controller::PortResponseData::Complete
@@ -0,0 +1,121 @@
+//! Scripted fault injection.
+//!
+//! Every SMBus style access made through the simulated fuel gauge counts as one transaction. A script is a queue of
+//! steps, each one letting a number of transactions complete normally before applying its fault.
+use heapless::Deque;
+
+/// Maximum number of steps a script can hold.
+pub const MAX_SCRIPT_STEPS: usize = 16;
+
+/// Fault to inject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Fault {
+    /// The transaction fails with a bus error.
+    BusError,
+    /// The transaction stalls past the controller timeout and then fails.
+    Timeout,
+    /// The pack is removed, all transactions fail until it is inserted again.
+    Remove,
+    /// The pack is inserted again.
+    Insert,
+}
+
+/// A single script step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Step {
+    /// Number of transactions that complete normally before the fault is applied.
+    pub after: u16,
+    /// Fault to apply.
+    pub fault: Fault,
+}
+
+/// Script errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Script is full.
+    Full,
+}
+
+/// Queue of faults to inject.
+#[derive(Debug, Default)]
+pub struct Script {
+    steps: Deque<Step, MAX_SCRIPT_STEPS>,
+}
+
+impl Script {
+    /// Create an empty script.
+    pub const fn new() -> Self {
+        Self { steps: Deque::new() }
+    }
+
+    /// Append a step to the script.
+    pub fn push(&mut self, step: Step) -> Result<(), Error> {
+        self.steps.push_back(step).map_err(|_| Error::Full)
+    }
+
+    /// Drop all pending steps.
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    /// True if there are no pending steps.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Advance the script by one transaction, returning the fault to apply to it, if any.
+    pub fn next_transaction(&mut self) -> Option<Fault> {
+        let step = self.steps.front_mut()?;
+        if step.after > 0 {
+            step.after -= 1;
+            None
+        } else {
+            self.steps.pop_front().map(|step| step.fault)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_ordering() {
+        let mut script = Script::new();
+        script
+            .push(Step {
+                after: 2,
+                fault: Fault::BusError,
+            })
+            .unwrap();
+        script
+            .push(Step {
+                after: 0,
+                fault: Fault::Remove,
+            })
+            .unwrap();
+
+        assert_eq!(script.next_transaction(), None);
+        assert_eq!(script.next_transaction(), None);
+        assert_eq!(script.next_transaction(), Some(Fault::BusError));
+        assert_eq!(script.next_transaction(), Some(Fault::Remove));
+        assert_eq!(script.next_transaction(), None);
+        assert!(script.is_empty());
+    }
+
+    #[test]
+    fn test_script_full() {
+        let mut script = Script::new();
+        let step = Step {
+            after: 0,
+            fault: Fault::Timeout,
+        };
+        for _ in 0..MAX_SCRIPT_STEPS {
+            script.push(step).unwrap();
+        }
+        assert_eq!(script.push(step), Err(Error::Full));
+    }
+}
@@ -0,0 +1,821 @@
+//! Simulated smart battery fuel gauge.
+//!
+//! [`Battery`] holds the simulated pack and is shared between the test and the [`FuelGauge`] controller handed to
+//! [`battery_service::wrapper::Wrapper`]. The test advances simulated time with [`Battery::step`], changes the load
+//! with [`Battery::set_current`] and scripts faults with [`Battery::inject`], while the battery service talks to the
+//! pack through the regular [`Controller`] and [`SmartBattery`] interfaces.
+#![no_std]
+
+use core::cell::RefCell;
+
+use battery_service::controller::{Controller, ControllerEvent};
+use battery_service::device::{DynamicBatteryMsgs, StaticBatteryMsgs};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_batteries_async::charger::MilliAmps;
+use embedded_batteries_async::smart_battery::{
+    self, BatteryModeFields, BatteryStatusFields, CapacityModeSignedValue, CapacityModeValue, Cycles, DeciKelvin,
+    ErrorKind, ManufactureDate, MilliAmpsSigned, MilliVolts, Minutes, Percent, SmartBattery, SpecificationInfoFields,
+};
+use embedded_services::{GlobalRawMutex, trace};
+
+pub mod fault;
+pub mod model;
+
+use fault::{Fault, Script, Step};
+use model::Model;
+
+const MANUFACTURER_NAME: &[u8] = b"ODP";
+const DEVICE_NAME: &[u8] = b"FuelGaugeSim";
+const DEVICE_CHEMISTRY: &[u8] = b"LION";
+const SERIAL_NUMBER: u16 = 0x5151;
+
+/// Temperature above which the over temperature alarm is raised, 60 C.
+const OVER_TEMP_ALARM_DK: u16 = 3332;
+
+// Battery status bits as defined by the SBS specification.
+const STATUS_FULLY_DISCHARGED: u16 = 1 << 4;
+const STATUS_FULLY_CHARGED: u16 = 1 << 5;
+const STATUS_DISCHARGING: u16 = 1 << 6;
+const STATUS_INITIALIZED: u16 = 1 << 7;
+const STATUS_REMAINING_TIME_ALARM: u16 = 1 << 8;
+const STATUS_REMAINING_CAPACITY_ALARM: u16 = 1 << 9;
+const STATUS_TERMINATE_DISCHARGE_ALARM: u16 = 1 << 11;
+const STATUS_OVER_TEMP_ALARM: u16 = 1 << 12;
+const STATUS_TERMINATE_CHARGE_ALARM: u16 = 1 << 14;
+
+/// Simulated fuel gauge errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Injected bus error.
+    Bus,
+    /// Injected timeout.
+    Timeout,
+    /// Pack is not present.
+    NotPresent,
+}
+
+impl smart_battery::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+struct Inner {
+    model: Model,
+    script: Script,
+    present: bool,
+    at_rate_ma: i16,
+    battery_mode: BatteryModeFields,
+    remaining_capacity_alarm_mah: u16,
+    remaining_time_alarm_min: u16,
+}
+
+impl Inner {
+    fn battery_status(&self) -> u16 {
+        let model = &self.model;
+        let mut status = STATUS_INITIALIZED;
+
+        if model.current_ma() <= 0 {
+            status |= STATUS_DISCHARGING;
+        }
+        if model.is_fully_charged() {
+            status |= STATUS_FULLY_CHARGED | STATUS_TERMINATE_CHARGE_ALARM;
+        }
+        if model.is_fully_discharged() {
+            status |= STATUS_FULLY_DISCHARGED | STATUS_TERMINATE_DISCHARGE_ALARM;
+        }
+        if model.remaining_capacity_mah() < self.remaining_capacity_alarm_mah {
+            status |= STATUS_REMAINING_CAPACITY_ALARM;
+        }
+        if model.time_to_empty_min(model.average_current_ma()) < self.remaining_time_alarm_min {
+            status |= STATUS_REMAINING_TIME_ALARM;
+        }
+        if model.temperature_dk() > OVER_TEMP_ALARM_DK {
+            status |= STATUS_OVER_TEMP_ALARM;
+        }
+
+        status
+    }
+}
+
+/// Simulated battery pack shared between a test and its [`FuelGauge`].
+pub struct Battery {
+    inner: Mutex<GlobalRawMutex, RefCell<Inner>>,
+}
+
+impl Battery {
+    /// Create a new simulated battery pack, initially present.
+    pub fn new(config: model::Config) -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                model: Model::new(config),
+                script: Script::new(),
+                present: true,
+                at_rate_ma: 0,
+                battery_mode: BatteryModeFields::new(),
+                remaining_capacity_alarm_mah: config.design_capacity_mah / 10,
+                remaining_time_alarm_min: 10,
+            })),
+        }
+    }
+
+    /// Advance simulated time.
+    pub fn step(&self, dt: Duration) {
+        self.inner.lock(|inner| inner.borrow_mut().model.step(dt));
+    }
+
+    /// Set the current drawn from (negative) or pushed into (positive) the pack, in mA.
+    pub fn set_current(&self, current_ma: i32) {
+        self.inner
+            .lock(|inner| inner.borrow_mut().model.set_current(current_ma));
+    }
+
+    /// Set the ambient temperature in dK.
+    pub fn set_ambient_temp(&self, temp_dk: u16) {
+        self.inner
+            .lock(|inner| inner.borrow_mut().model.set_ambient_temp(temp_dk));
+    }
+
+    /// Append a step to the fault script.
+    pub fn inject(&self, step: Step) -> Result<(), fault::Error> {
+        self.inner.lock(|inner| inner.borrow_mut().script.push(step))
+    }
+
+    /// Drop all pending scripted faults.
+    pub fn clear_faults(&self) {
+        self.inner.lock(|inner| inner.borrow_mut().script.clear());
+    }
+
+    /// Remove the pack immediately.
+    pub fn remove(&self) {
+        self.inner.lock(|inner| inner.borrow_mut().present = false);
+    }
+
+    /// Insert the pack immediately.
+    pub fn insert(&self) {
+        self.inner.lock(|inner| inner.borrow_mut().present = true);
+    }
+
+    /// True if the pack is present.
+    pub fn is_present(&self) -> bool {
+        self.inner.lock(|inner| inner.borrow().present)
+    }
+
+    /// Snapshot of the battery model.
+    pub fn model(&self) -> Model {
+        self.inner.lock(|inner| inner.borrow().model)
+    }
+
+    /// Run the next scripted transaction, returning the fault it should fail with, if any.
+    fn begin_transaction(&self) -> Result<(), Error> {
+        self.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            let fault = inner.script.next_transaction();
+
+            match fault {
+                Some(Fault::Remove) => inner.present = false,
+                Some(Fault::Insert) => inner.present = true,
+                _ => {}
+            }
+
+            if !inner.present {
+                return Err(Error::NotPresent);
+            }
+
+            match fault {
+                Some(Fault::BusError) => Err(Error::Bus),
+                Some(Fault::Timeout) => Err(Error::Timeout),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// Simulated fuel gauge controller.
+pub struct FuelGauge<'a> {
+    battery: &'a Battery,
+    timeout: Duration,
+}
+
+impl<'a> FuelGauge<'a> {
+    /// Create a new simulated fuel gauge for the given pack.
+    pub fn new(battery: &'a Battery) -> Self {
+        Self {
+            battery,
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Perform a single simulated bus transaction.
+    async fn transaction<R>(&mut self, f: impl FnOnce(&mut Inner) -> R) -> Result<R, Error> {
+        match self.battery.begin_transaction() {
+            Ok(()) => Ok(self.battery.inner.lock(|inner| f(&mut inner.borrow_mut()))),
+            Err(Error::Timeout) => {
+                trace!("Fuel gauge sim: stalling transaction");
+                // Stall past the controller timeout so the caller observes a timeout first
+                Timer::after(self.timeout + Duration::from_millis(1)).await;
+                Err(Error::Timeout)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn copy_str(dst: &mut [u8], src: &[u8]) {
+    let len = dst.len().min(src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+}
+
+impl smart_battery::ErrorType for FuelGauge<'_> {
+    type Error = Error;
+}
+
+impl SmartBattery for FuelGauge<'_> {
+    async fn remaining_capacity_alarm(&mut self) -> Result<CapacityModeValue, Self::Error> {
+        self.transaction(|inner| CapacityModeValue::MilliAmpUnsigned(inner.remaining_capacity_alarm_mah))
+            .await
+    }
+
+    async fn set_remaining_capacity_alarm(&mut self, capacity: CapacityModeValue) -> Result<(), Self::Error> {
+        self.transaction(|inner| {
+            if let CapacityModeValue::MilliAmpUnsigned(mah) = capacity {
+                inner.remaining_capacity_alarm_mah = mah;
+            }
+        })
+        .await
+    }
+
+    async fn remaining_time_alarm(&mut self) -> Result<Minutes, Self::Error> {
+        self.transaction(|inner| inner.remaining_time_alarm_min).await
+    }
+
+    async fn set_remaining_time_alarm(&mut self, time: Minutes) -> Result<(), Self::Error> {
+        self.transaction(|inner| inner.remaining_time_alarm_min = time).await
+    }
+
+    async fn battery_mode(&mut self) -> Result<BatteryModeFields, Self::Error> {
+        self.transaction(|inner| inner.battery_mode).await
+    }
+
+    async fn set_battery_mode(&mut self, flags: BatteryModeFields) -> Result<(), Self::Error> {
+        self.transaction(|inner| inner.battery_mode = flags).await
+    }
+
+    async fn at_rate(&mut self) -> Result<CapacityModeSignedValue, Self::Error> {
+        self.transaction(|inner| CapacityModeSignedValue::MilliAmpSigned(inner.at_rate_ma))
+            .await
+    }
+
+    async fn set_at_rate(&mut self, rate: CapacityModeSignedValue) -> Result<(), Self::Error> {
+        self.transaction(|inner| {
+            if let CapacityModeSignedValue::MilliAmpSigned(ma) = rate {
+                inner.at_rate_ma = ma;
+            }
+        })
+        .await
+    }
+
+    async fn at_rate_time_to_full(&mut self) -> Result<Minutes, Self::Error> {
+        self.transaction(|inner| inner.model.time_to_full_min(inner.at_rate_ma as i32))
+            .await
+    }
+
+    async fn at_rate_time_to_empty(&mut self) -> Result<Minutes, Self::Error> {
+        self.transaction(|inner| inner.model.time_to_empty_min(inner.at_rate_ma as i32))
+            .await
+    }
+
+    async fn at_rate_ok(&mut self) -> Result<bool, Self::Error> {
+        // Per SBS, true if the pack can sustain the at rate for at least 10 seconds
+        self.transaction(|inner| {
+            inner.at_rate_ma >= 0 || inner.model.remaining_capacity_mah() as i32 * 360 >= -(inner.at_rate_ma as i32)
+        })
+        .await
+    }
+
+    async fn temperature(&mut self) -> Result<DeciKelvin, Self::Error> {
+        self.transaction(|inner| inner.model.temperature_dk()).await
+    }
+
+    async fn voltage(&mut self) -> Result<MilliVolts, Self::Error> {
+        self.transaction(|inner| inner.model.voltage_mv()).await
+    }
+
+    async fn current(&mut self) -> Result<MilliAmpsSigned, Self::Error> {
+        self.transaction(|inner| inner.model.current_ma().clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .await
+    }
+
+    async fn average_current(&mut self) -> Result<MilliAmpsSigned, Self::Error> {
+        self.transaction(|inner| inner.model.average_current_ma().clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .await
+    }
+
+    async fn max_error(&mut self) -> Result<Percent, Self::Error> {
+        self.transaction(|_| 1).await
+    }
+
+    async fn relative_state_of_charge(&mut self) -> Result<Percent, Self::Error> {
+        self.transaction(|inner| inner.model.relative_soc_pct()).await
+    }
+
+    async fn absolute_state_of_charge(&mut self) -> Result<Percent, Self::Error> {
+        self.transaction(|inner| inner.model.absolute_soc_pct()).await
+    }
+
+    async fn remaining_capacity(&mut self) -> Result<CapacityModeValue, Self::Error> {
+        self.transaction(|inner| CapacityModeValue::MilliAmpUnsigned(inner.model.remaining_capacity_mah()))
+            .await
+    }
+
+    async fn full_charge_capacity(&mut self) -> Result<CapacityModeValue, Self::Error> {
+        self.transaction(|inner| CapacityModeValue::MilliAmpUnsigned(inner.model.full_charge_capacity_mah()))
+            .await
+    }
+
+    async fn run_time_to_empty(&mut self) -> Result<Minutes, Self::Error> {
+        self.transaction(|inner| inner.model.time_to_empty_min(inner.model.current_ma()))
+            .await
+    }
+
+    async fn average_time_to_empty(&mut self) -> Result<Minutes, Self::Error> {
+        self.transaction(|inner| inner.model.time_to_empty_min(inner.model.average_current_ma()))
+            .await
+    }
+
+    async fn average_time_to_full(&mut self) -> Result<Minutes, Self::Error> {
+        self.transaction(|inner| inner.model.time_to_full_min(inner.model.average_current_ma()))
+            .await
+    }
+
+    async fn charging_current(&mut self) -> Result<MilliAmps, Self::Error> {
+        self.transaction(|inner| {
+            if inner.model.is_fully_charged() {
+                0
+            } else {
+                inner.model.config().charging_current_ma
+            }
+        })
+        .await
+    }
+
+    async fn charging_voltage(&mut self) -> Result<MilliVolts, Self::Error> {
+        self.transaction(|inner| inner.model.config().charging_voltage_mv).await
+    }
+
+    async fn battery_status(&mut self) -> Result<BatteryStatusFields, Self::Error> {
+        self.transaction(|inner| BatteryStatusFields::from(inner.battery_status()))
+            .await
+    }
+
+    async fn cycle_count(&mut self) -> Result<Cycles, Self::Error> {
+        self.transaction(|inner| inner.model.cycle_count()).await
+    }
+
+    async fn design_capacity(&mut self) -> Result<CapacityModeValue, Self::Error> {
+        self.transaction(|inner| CapacityModeValue::MilliAmpUnsigned(inner.model.config().design_capacity_mah))
+            .await
+    }
+
+    async fn design_voltage(&mut self) -> Result<MilliVolts, Self::Error> {
+        self.transaction(|inner| inner.model.config().design_voltage_mv).await
+    }
+
+    async fn specification_info(&mut self) -> Result<SpecificationInfoFields, Self::Error> {
+        self.transaction(|_| SpecificationInfoFields::new()).await
+    }
+
+    async fn manufacture_date(&mut self) -> Result<ManufactureDate, Self::Error> {
+        self.transaction(|_| ManufactureDate::new()).await
+    }
+
+    async fn serial_number(&mut self) -> Result<u16, Self::Error> {
+        self.transaction(|_| SERIAL_NUMBER).await
+    }
+
+    async fn manufacturer_name(&mut self, name: &mut [u8]) -> Result<(), Self::Error> {
+        self.transaction(|_| copy_str(name, MANUFACTURER_NAME)).await
+    }
+
+    async fn device_name(&mut self, name: &mut [u8]) -> Result<(), Self::Error> {
+        self.transaction(|_| copy_str(name, DEVICE_NAME)).await
+    }
+
+    async fn device_chemistry(&mut self, chemistry: &mut [u8]) -> Result<(), Self::Error> {
+        self.transaction(|_| copy_str(chemistry, DEVICE_CHEMISTRY)).await
+    }
+}
+
+impl Controller for FuelGauge<'_> {
+    type ControllerError = Error;
+
+    async fn initialize(&mut self) -> Result<(), Self::ControllerError> {
+        self.transaction(|_| ()).await
+    }
+
+    async fn get_static_data(&mut self) -> Result<StaticBatteryMsgs, Self::ControllerError> {
+        self.transaction(|inner| {
+            let config = inner.model.config();
+            let mut msgs = StaticBatteryMsgs {
+                design_capacity_mwh: config.design_capacity_mah as u32 * config.design_voltage_mv as u32 / 1000,
+                design_voltage_mv: config.design_voltage_mv,
+                serial_num: (SERIAL_NUMBER as u32).to_le_bytes(),
+                ..Default::default()
+            };
+            copy_str(&mut msgs.manufacturer_name, MANUFACTURER_NAME);
+            copy_str(&mut msgs.device_name, DEVICE_NAME);
+            copy_str(&mut msgs.device_chemistry, DEVICE_CHEMISTRY);
+            msgs
+        })
+        .await
+    }
+
+    async fn get_dynamic_data(&mut self) -> Result<DynamicBatteryMsgs, Self::ControllerError> {
+        self.transaction(|inner| {
+            let model = &inner.model;
+            let config = model.config();
+            let max_power_mw = model.max_power_mw();
+            DynamicBatteryMsgs {
+                max_power_mw,
+                sus_power_mw: max_power_mw / 2,
+                full_charge_capacity_mwh: model.full_charge_capacity_mah() as u32 * config.design_voltage_mv as u32
+                    / 1000,
+                remaining_capacity_mwh: model.remaining_capacity_mah() as u32 * config.design_voltage_mv as u32 / 1000,
+                relative_soc_pct: model.relative_soc_pct() as u16,
+                cycle_count: model.cycle_count(),
+                voltage_mv: model.voltage_mv(),
+                max_error_pct: 1,
+                battery_status: inner.battery_status(),
+                charging_voltage_mv: config.charging_voltage_mv,
+                charging_current_ma: if model.is_fully_charged() {
+                    0
+                } else {
+                    config.charging_current_ma
+                },
+                battery_temp_dk: model.temperature_dk(),
+                current_ma: model.current_ma().clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                average_current_ma: model.average_current_ma().clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            }
+        })
+        .await
+    }
+
+    async fn get_device_event(&mut self) -> ControllerEvent {
+        // The simulated gauge does not raise hardware events
+        core::future::pending().await
+    }
+
+    async fn ping(&mut self) -> Result<(), Self::ControllerError> {
+        self.transaction(|_| ()).await
+    }
+
+    fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::future::Future;
+
+    use battery_service::context::{
+        BatteryEvent, BatteryEventInner, BatteryResponse, Context, ContextError, ContextResponse, OperationalSubstate,
+        PresentSubstate, State, StateMachineError,
+    };
+    use battery_service::device::{Device, DeviceId};
+    use battery_service::wrapper::Wrapper;
+    use embassy_futures::select::{Either, Either3, select, select3};
+    use embassy_futures::{block_on, yield_now};
+    use embedded_services::power::policy::BatteryPowerState;
+    use std::boxed::Box;
+
+    use super::*;
+
+    const DEVICE_ID: DeviceId = DeviceId(0);
+
+    /// Controller timeout, timeouts stall in real time so keep it short
+    const TIMEOUT: Duration = Duration::from_millis(20);
+
+    const INIT: State = State::Present(PresentSubstate::Operational(OperationalSubstate::Init));
+    const POLLING: State = State::Present(PresentSubstate::Operational(OperationalSubstate::Polling));
+    const NOT_OPERATIONAL: State = State::Present(PresentSubstate::NotOperational);
+
+    /// Pack that discharges from full to half in 30 minutes at 1 A
+    fn config() -> model::Config {
+        model::Config {
+            full_charge_capacity_mah: 1000,
+            initial_soc_pct: 100,
+            ..Default::default()
+        }
+    }
+
+    fn state_error(error: StateMachineError) -> BatteryResponse {
+        Err(ContextError::StateError(error))
+    }
+
+    async fn execute(context: &Context, event: BatteryEventInner) -> BatteryResponse {
+        context
+            .execute_event(BatteryEvent {
+                event,
+                device_id: DEVICE_ID,
+            })
+            .await
+    }
+
+    /// Initialize the gauge and bring the state machine to polling
+    async fn start(context: &Context) {
+        assert_eq!(
+            execute(context, BatteryEventInner::DoInit).await,
+            Ok(ContextResponse::Ack)
+        );
+        assert_eq!(context.get_state().await, INIT);
+        assert_eq!(
+            execute(context, BatteryEventInner::PollStaticData).await,
+            Ok(ContextResponse::Ack)
+        );
+        assert_eq!(context.get_state().await, POLLING);
+    }
+
+    /// Run a scenario against the battery service state machine and a simulated gauge
+    fn run<F: Future<Output = ()>>(scenario: impl FnOnce(&'static Battery, &'static Device, &'static Context) -> F) {
+        let battery: &'static Battery = Box::leak(Box::new(Battery::new(config())));
+        let device: &'static Device = Box::leak(Box::new(Device::new(DEVICE_ID)));
+        let context: &'static Context = Box::leak(Box::new(Context::new()));
+        let mut gauge = FuelGauge::new(battery);
+        gauge.set_timeout(TIMEOUT);
+        let wrapper = Wrapper::new(device, gauge);
+
+        let service = async {
+            loop {
+                let event = context.wait_event().await;
+                context.process(event).await;
+            }
+        };
+
+        let test = async {
+            context.register_fuel_gauge(device).await.unwrap();
+            scenario(battery, device, context).await
+        };
+
+        match block_on(select3(wrapper.process(), service, test)) {
+            Either3::Third(()) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    /// Run the battery service state machine against the simulated gauge
+    #[test]
+    fn test_battery_service() {
+        run(|battery, device, context| async move {
+            start(context).await;
+            let static_data = device.get_static_battery_cache().await;
+            assert_eq!(&static_data.device_name[..DEVICE_NAME.len()], DEVICE_NAME);
+
+            // Discharge to half and check the service sees what the pack reports
+            battery.set_current(-1000);
+            battery.step(Duration::from_secs(30 * 60));
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+            let dynamic_data = device.get_dynamic_battery_cache().await;
+            assert_eq!(dynamic_data.relative_soc_pct, 50);
+            assert_eq!(dynamic_data.current_ma, -1000);
+            assert_eq!(context.get_lifetime_stats().await.max_discharge_current_ma, 1000);
+            assert_eq!(
+                context.wait_power_state_update().await,
+                BatteryPowerState {
+                    state_of_charge_pct: 50,
+                    max_discharge_mw: dynamic_data.max_power_mw,
+                }
+            );
+
+            // Nothing to publish if the battery didn't change
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(
+                select(context.wait_power_state_update(), yield_now()).await,
+                Either::Second(())
+            );
+
+            // Charge back up
+            battery.set_current(2000);
+            battery.step(Duration::from_secs(15 * 60));
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+            let dynamic_data = device.get_dynamic_battery_cache().await;
+            assert_eq!(dynamic_data.relative_soc_pct, 100);
+            assert_eq!(context.get_lifetime_stats().await.max_charge_current_ma, 2000);
+            assert_eq!(context.wait_power_state_update().await.state_of_charge_pct, 100);
+        });
+    }
+
+    #[test]
+    fn test_bus_error() {
+        run(|battery, device, context| async move {
+            // Bus error on initialize, after a successful ping
+            battery
+                .inject(Step {
+                    after: 1,
+                    fault: Fault::BusError,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::DoInit).await,
+                state_error(StateMachineError::DeviceError)
+            );
+            assert_eq!(context.get_state().await, State::NotPresent);
+
+            // Bus error while collecting static data
+            assert_eq!(
+                execute(context, BatteryEventInner::DoInit).await,
+                Ok(ContextResponse::Ack)
+            );
+            battery
+                .inject(Step {
+                    after: 0,
+                    fault: Fault::BusError,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::PollStaticData).await,
+                state_error(StateMachineError::DeviceError)
+            );
+            assert_eq!(context.get_state().await, INIT);
+            assert_eq!(device.get_static_battery_cache().await, StaticBatteryMsgs::default());
+            start(context).await;
+
+            // Bus error while polling keeps the previous data, the next poll picks up the change
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+            battery.set_current(-1000);
+            battery.step(Duration::from_secs(30 * 60));
+            battery
+                .inject(Step {
+                    after: 0,
+                    fault: Fault::BusError,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                state_error(StateMachineError::DeviceError)
+            );
+            assert_eq!(context.get_state().await, POLLING);
+            assert_eq!(device.get_dynamic_battery_cache().await.relative_soc_pct, 100);
+
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(device.get_dynamic_battery_cache().await.relative_soc_pct, 50);
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        run(|battery, device, context| async move {
+            start(context).await;
+
+            // The gauge stalls past the controller timeout
+            battery
+                .inject(Step {
+                    after: 0,
+                    fault: Fault::Timeout,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                state_error(StateMachineError::DeviceError)
+            );
+            assert_eq!(context.get_state().await, POLLING);
+
+            // Recover through the not operational state, the late response to the stalled poll isn't taken as the
+            // response to the ping
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(context.get_state().await, INIT);
+
+            // A ping that times out can be retried
+            battery
+                .inject(Step {
+                    after: 0,
+                    fault: Fault::Timeout,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                state_error(StateMachineError::DeviceTimeout)
+            );
+            assert_eq!(context.get_state().await, NOT_OPERATIONAL);
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(context.get_state().await, INIT);
+
+            // Back to polling
+            assert_eq!(
+                execute(context, BatteryEventInner::PollStaticData).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(device.get_dynamic_battery_cache().await.relative_soc_pct, 100);
+        });
+    }
+
+    #[test]
+    fn test_remove() {
+        run(|battery, _, context| async move {
+            start(context).await;
+
+            // Polling fails once the pack is removed
+            battery
+                .inject(Step {
+                    after: 0,
+                    fault: Fault::Remove,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                state_error(StateMachineError::DeviceError)
+            );
+            assert!(!battery.is_present());
+
+            // Recovery fails while the pack is out and succeeds once it's back
+            for _ in 0..2 {
+                assert_eq!(
+                    execute(context, BatteryEventInner::Timeout).await,
+                    state_error(StateMachineError::DeviceTimeout)
+                );
+                assert_eq!(context.get_state().await, NOT_OPERATIONAL);
+            }
+            battery
+                .inject(Step {
+                    after: 0,
+                    fault: Fault::Insert,
+                })
+                .unwrap();
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                Ok(ContextResponse::Ack)
+            );
+            assert_eq!(context.get_state().await, INIT);
+
+            // Recovering reset the retries, the service gives up after the full count
+            battery.remove();
+            for _ in 0..5 {
+                assert_eq!(
+                    execute(context, BatteryEventInner::Timeout).await,
+                    state_error(StateMachineError::DeviceTimeout)
+                );
+            }
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                state_error(StateMachineError::NoOpRecoveryFailed)
+            );
+            assert_eq!(context.get_state().await, State::NotPresent);
+
+            // Without a battery only reinitializing is allowed, and it fails until the pack is inserted
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                state_error(StateMachineError::InvalidActionInState)
+            );
+            assert_eq!(
+                execute(context, BatteryEventInner::DoInit).await,
+                state_error(StateMachineError::DeviceError)
+            );
+            battery.insert();
+            start(context).await;
+            assert_eq!(
+                execute(context, BatteryEventInner::PollDynamicData).await,
+                Ok(ContextResponse::Ack)
+            );
+
+            // The retries start over after a reinitialization
+            battery.remove();
+            assert_eq!(
+                execute(context, BatteryEventInner::Timeout).await,
+                state_error(StateMachineError::DeviceTimeout)
+            );
+        });
+    }
+}
@@ -0,0 +1,331 @@
+//! Simple electrical and thermal battery model advanced over simulated time.
+//!
+//! All arithmetic is integer based so that a given sequence of [`Model::step`] calls always produces the same
+//! readings, regardless of host.
+use embassy_time::Duration;
+
+/// Window used for the rolling average current, as specified by the SBS `AverageCurrent()` command.
+const AVERAGE_CURRENT_WINDOW_MS: i64 = 60_000;
+
+/// Milliamp-milliseconds in one milliamp-hour.
+const MAMS_PER_MAH: i64 = 3_600_000;
+
+/// Value reported by time-to-empty/full commands when the battery is not charging/discharging.
+pub const TIME_NOT_APPLICABLE: u16 = u16::MAX;
+
+/// Battery model parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Design capacity in mAh.
+    pub design_capacity_mah: u16,
+    /// Full charge capacity in mAh, usually lower than design capacity on an aged pack.
+    pub full_charge_capacity_mah: u16,
+    /// Design voltage in mV.
+    pub design_voltage_mv: u16,
+    /// Open circuit voltage at 0% state of charge in mV.
+    pub empty_voltage_mv: u16,
+    /// Open circuit voltage at 100% state of charge in mV.
+    pub full_voltage_mv: u16,
+    /// Pack internal resistance in mOhm.
+    pub internal_resistance_mohm: u16,
+    /// Ambient temperature in dK.
+    pub ambient_temp_dk: u16,
+    /// Steady state temperature rise per watt dissipated in the pack, in dK/W.
+    pub thermal_resistance_dk_per_w: u16,
+    /// Thermal time constant of the pack.
+    pub thermal_time_constant: Duration,
+    /// Desired charging voltage in mV.
+    pub charging_voltage_mv: u16,
+    /// Desired charging current in mA.
+    pub charging_current_ma: u16,
+    /// State of charge the pack starts with, in %.
+    pub initial_soc_pct: u8,
+    /// Cycle count the pack starts with.
+    pub initial_cycle_count: u16,
+}
+
+impl Default for Config {
+    /// 3S Li-ion 5000 mAh pack at room temperature.
+    fn default() -> Self {
+        Self {
+            design_capacity_mah: 5000,
+            full_charge_capacity_mah: 4800,
+            design_voltage_mv: 11400,
+            empty_voltage_mv: 9000,
+            full_voltage_mv: 12600,
+            internal_resistance_mohm: 150,
+            ambient_temp_dk: 2982,
+            thermal_resistance_dk_per_w: 100,
+            thermal_time_constant: Duration::from_secs(300),
+            charging_voltage_mv: 12600,
+            charging_current_ma: 2500,
+            initial_soc_pct: 50,
+            initial_cycle_count: 0,
+        }
+    }
+}
+
+/// Battery model state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Model {
+    config: Config,
+    /// Remaining charge in mA*ms.
+    charge_mams: i64,
+    /// Current requested by the simulated system, positive when charging.
+    requested_current_ma: i32,
+    /// Current actually flowing, after end of charge/discharge clamping.
+    current_ma: i32,
+    average_current_ma: i32,
+    temp_dk: i32,
+    /// Charge discharged since the last cycle count increment, in mA*ms.
+    discharged_mams: i64,
+    cycle_count: u16,
+    elapsed: Duration,
+}
+
+impl Model {
+    /// Create a new battery model.
+    pub fn new(config: Config) -> Self {
+        let full = config.full_charge_capacity_mah as i64 * MAMS_PER_MAH;
+        Self {
+            config,
+            charge_mams: full * config.initial_soc_pct.min(100) as i64 / 100,
+            requested_current_ma: 0,
+            current_ma: 0,
+            average_current_ma: 0,
+            temp_dk: config.ambient_temp_dk as i32,
+            discharged_mams: 0,
+            cycle_count: config.initial_cycle_count,
+            elapsed: Duration::from_ticks(0),
+        }
+    }
+
+    /// Model parameters.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Set the current drawn from (negative) or pushed into (positive) the pack.
+    pub fn set_current(&mut self, current_ma: i32) {
+        self.requested_current_ma = current_ma;
+    }
+
+    /// Set the ambient temperature in dK.
+    pub fn set_ambient_temp(&mut self, temp_dk: u16) {
+        self.config.ambient_temp_dk = temp_dk;
+    }
+
+    /// Advance the model by `dt` of simulated time.
+    pub fn step(&mut self, dt: Duration) {
+        let ms = dt.as_millis() as i64;
+        if ms == 0 {
+            return;
+        }
+        self.elapsed += dt;
+
+        let full = self.full_charge_mams();
+        let current = match self.requested_current_ma {
+            c if c > 0 && self.charge_mams >= full => 0,
+            c if c < 0 && self.charge_mams <= 0 => 0,
+            c => c,
+        };
+
+        let previous = self.charge_mams;
+        self.charge_mams = (self.charge_mams + current as i64 * ms).clamp(0, full);
+        if self.charge_mams < previous {
+            self.discharged_mams += previous - self.charge_mams;
+            while full > 0 && self.discharged_mams >= full {
+                self.discharged_mams -= full;
+                self.cycle_count = self.cycle_count.saturating_add(1);
+            }
+        }
+
+        self.current_ma = current;
+        self.average_current_ma +=
+            ((current - self.average_current_ma) as i64 * ms / (ms + AVERAGE_CURRENT_WINDOW_MS)) as i32;
+
+        // First order thermal response towards the steady state temperature for the current I^2 * R dissipation.
+        let dissipation_mw = current as i64 * current as i64 * self.config.internal_resistance_mohm as i64 / 1_000_000;
+        let target_dk =
+            self.config.ambient_temp_dk as i64 + dissipation_mw * self.config.thermal_resistance_dk_per_w as i64 / 1000;
+        let tau_ms = self.config.thermal_time_constant.as_millis() as i64;
+        self.temp_dk += ((target_dk - self.temp_dk as i64) * ms / (ms + tau_ms)) as i32;
+    }
+
+    /// Total simulated time elapsed.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Current flowing through the pack in mA, positive when charging.
+    pub fn current_ma(&self) -> i32 {
+        self.current_ma
+    }
+
+    /// One minute rolling average of the pack current in mA.
+    pub fn average_current_ma(&self) -> i32 {
+        self.average_current_ma
+    }
+
+    /// Pack temperature in dK.
+    pub fn temperature_dk(&self) -> u16 {
+        self.temp_dk.clamp(0, u16::MAX as i32) as u16
+    }
+
+    /// Charge/discharge cycle count.
+    pub fn cycle_count(&self) -> u16 {
+        self.cycle_count
+    }
+
+    /// Remaining capacity in mAh.
+    pub fn remaining_capacity_mah(&self) -> u16 {
+        (self.charge_mams / MAMS_PER_MAH) as u16
+    }
+
+    /// Full charge capacity in mAh.
+    pub fn full_charge_capacity_mah(&self) -> u16 {
+        self.config.full_charge_capacity_mah
+    }
+
+    /// State of charge relative to full charge capacity, in %.
+    pub fn relative_soc_pct(&self) -> u8 {
+        let full = self.full_charge_mams();
+        if full == 0 {
+            0
+        } else {
+            (self.charge_mams * 100 / full) as u8
+        }
+    }
+
+    /// State of charge relative to design capacity, in %.
+    pub fn absolute_soc_pct(&self) -> u8 {
+        let design = self.config.design_capacity_mah as i64 * MAMS_PER_MAH;
+        if design == 0 {
+            0
+        } else {
+            (self.charge_mams * 100 / design).min(u8::MAX as i64) as u8
+        }
+    }
+
+    /// True if the pack can not accept any more charge.
+    pub fn is_fully_charged(&self) -> bool {
+        self.charge_mams >= self.full_charge_mams()
+    }
+
+    /// True if the pack can not deliver any more charge.
+    pub fn is_fully_discharged(&self) -> bool {
+        self.charge_mams <= 0
+    }
+
+    /// Open circuit voltage in mV, linear between the empty and full voltages.
+    pub fn open_circuit_voltage_mv(&self) -> u16 {
+        let full = self.full_charge_mams();
+        let span = self.config.full_voltage_mv as i64 - self.config.empty_voltage_mv as i64;
+        let ocv = if full == 0 {
+            self.config.empty_voltage_mv as i64
+        } else {
+            self.config.empty_voltage_mv as i64 + span * self.charge_mams / full
+        };
+        ocv.clamp(0, u16::MAX as i64) as u16
+    }
+
+    /// Terminal voltage in mV, including the drop/rise across the internal resistance.
+    pub fn voltage_mv(&self) -> u16 {
+        let drop = self.current_ma as i64 * self.config.internal_resistance_mohm as i64 / 1000;
+        (self.open_circuit_voltage_mv() as i64 + drop).clamp(0, u16::MAX as i64) as u16
+    }
+
+    /// Peak power in mW the pack can deliver before the terminal voltage reaches the empty voltage.
+    pub fn max_power_mw(&self) -> u32 {
+        let headroom_mv = self
+            .open_circuit_voltage_mv()
+            .saturating_sub(self.config.empty_voltage_mv) as u64;
+        let resistance = self.config.internal_resistance_mohm.max(1) as u64;
+        let max_current_ma = headroom_mv * 1000 / resistance;
+        (max_current_ma * self.config.empty_voltage_mv as u64 / 1000).min(u32::MAX as u64) as u32
+    }
+
+    /// Minutes until empty at the given discharge current, [`TIME_NOT_APPLICABLE`] if not discharging.
+    pub fn time_to_empty_min(&self, current_ma: i32) -> u16 {
+        if current_ma >= 0 {
+            return TIME_NOT_APPLICABLE;
+        }
+        (self.charge_mams / (-current_ma as i64 * 60_000)).min(TIME_NOT_APPLICABLE as i64 - 1) as u16
+    }
+
+    /// Minutes until full at the given charge current, [`TIME_NOT_APPLICABLE`] if not charging.
+    pub fn time_to_full_min(&self, current_ma: i32) -> u16 {
+        if current_ma <= 0 {
+            return TIME_NOT_APPLICABLE;
+        }
+        ((self.full_charge_mams() - self.charge_mams) / (current_ma as i64 * 60_000))
+            .min(TIME_NOT_APPLICABLE as i64 - 1) as u16
+    }
+
+    fn full_charge_mams(&self) -> i64 {
+        self.config.full_charge_capacity_mah as i64 * MAMS_PER_MAH
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discharge_to_empty() {
+        let mut model = Model::new(Config {
+            full_charge_capacity_mah: 1000,
+            initial_soc_pct: 100,
+            ..Default::default()
+        });
+
+        model.set_current(-1000);
+        model.step(Duration::from_secs(30 * 60));
+        assert_eq!(model.relative_soc_pct(), 50);
+        assert_eq!(model.remaining_capacity_mah(), 500);
+        assert_eq!(model.time_to_empty_min(model.current_ma()), 30);
+
+        model.step(Duration::from_secs(60 * 60));
+        assert!(model.is_fully_discharged());
+        assert_eq!(model.cycle_count(), 1);
+
+        // Current stops flowing once empty
+        model.step(Duration::from_secs(1));
+        assert_eq!(model.current_ma(), 0);
+        assert_eq!(model.cycle_count(), 1);
+    }
+
+    #[test]
+    fn test_charge_to_full() {
+        let mut model = Model::new(Config {
+            full_charge_capacity_mah: 1000,
+            initial_soc_pct: 0,
+            ..Default::default()
+        });
+
+        model.set_current(2000);
+        assert_eq!(model.time_to_full_min(2000), 30);
+        model.step(Duration::from_secs(60 * 60));
+        assert!(model.is_fully_charged());
+        assert_eq!(model.open_circuit_voltage_mv(), model.config().full_voltage_mv);
+        assert_eq!(model.cycle_count(), 0);
+    }
+
+    #[test]
+    fn test_voltage_and_heating() {
+        let config = Config::default();
+        let mut model = Model::new(config);
+
+        model.set_current(-4000);
+        model.step(Duration::from_millis(1));
+        // 4 A across 150 mOhm drops 600 mV
+        assert_eq!(model.voltage_mv(), model.open_circuit_voltage_mv() - 600);
+
+        model.step(Duration::from_secs(60 * 60));
+        // 2.4 W dissipated at 100 dK/W
+        assert!(model.temperature_dk() > config.ambient_temp_dk);
+        assert!(model.temperature_dk() <= config.ambient_temp_dk + 240);
+    }
+}
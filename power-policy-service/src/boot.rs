@@ -0,0 +1,212 @@
+//! This file implements the dead battery boot mode.
+//! When the system boots without a usable battery the adapter is the only thing keeping it running, switching sources
+//! risks a brownout. While in boot mode the policy:
+//! * Takes the first available source, ignoring the consumer selector
+//! * Keeps consuming from that source for as long as it's available
+//! * Defers charger `CheckReady`/`InitRequest` until the input power has been stable for
+//!   [stable_input](super::config::BootConfig::stable_input)
+//!
+//! The policy leaves boot mode and reselects the consumer normally once the battery service publishes a usable state of
+//! charge, or reports an operational battery when polled.
+use battery_service::context::{PresentSubstate, State as BatteryState};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_timeout};
+use embedded_services::{debug, trace};
+
+use super::*;
+use crate::config::BootConfig;
+
+/// How long to wait for the battery service, it might not be running at all
+const BATTERY_QUERY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Boot mode state
+#[derive(Debug, Clone, Copy)]
+pub(super) struct State {
+    /// When the current input power was connected, `None` without input power
+    input_since: Option<Instant>,
+    /// Charger initialization is waiting for the input power to be stable
+    chargers_deferred: bool,
+    /// Next time the battery service is checked
+    next_battery_check: Instant,
+}
+
+impl State {
+    pub(super) fn new() -> Self {
+        Self {
+            input_since: None,
+            chargers_deferred: false,
+            next_battery_check: Instant::now(),
+        }
+    }
+
+    /// Record that the input power was connected or disconnected
+    pub(super) fn set_input_connected(&mut self, connected: bool) {
+        self.input_since = connected.then(Instant::now);
+    }
+
+    /// Wait for the input power to be stable before initializing the chargers
+    pub(super) fn defer_chargers(&mut self) {
+        self.chargers_deferred = true;
+    }
+
+    /// Next time boot mode needs attention
+    fn deadline(&self, config: &BootConfig) -> Instant {
+        match self.input_since {
+            Some(since) if self.chargers_deferred => self.next_battery_check.min(since + config.stable_input),
+            _ => self.next_battery_check,
+        }
+    }
+}
+
+/// Returns true if the battery can keep the system running on its own
+pub fn battery_usable(config: &BootConfig, state: BatteryState, battery: Option<BatteryPowerState>) -> bool {
+    matches!(state, BatteryState::Present(PresentSubstate::Operational(_)))
+        && battery.is_none_or(|battery| battery.state_of_charge_pct >= config.min_soc_pct)
+}
+
+impl PowerPolicy {
+    /// Returns true while booting without a usable battery
+    pub(super) async fn in_boot_mode(&self) -> bool {
+        self.state.lock().await.boot.is_some()
+    }
+
+    /// Wait until boot mode needs attention, never returns outside of boot mode
+    pub(super) async fn wait_boot_deadline(&self) {
+        let deadline = match (self.state.lock().await.boot, self.config.boot) {
+            (Some(boot), Some(config)) => Some(boot.deadline(&config)),
+            _ => None,
+        };
+
+        match deadline {
+            Some(deadline) => Timer::at(deadline).await,
+            None => core::future::pending().await,
+        }
+    }
+
+    /// Initialize the chargers once the input power is stable and leave boot mode once the battery is usable
+    pub(super) async fn process_boot_deadline(&self) -> Result<(), Error> {
+        let Some(config) = self.config.boot else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let (input_stable, check_battery) = {
+            let mut state = self.state.lock().await;
+            let Some(boot) = state.boot.as_mut() else {
+                return Ok(());
+            };
+
+            let stable = boot.input_since.is_some_and(|since| now >= since + config.stable_input);
+            let check_battery = now >= boot.next_battery_check;
+            if check_battery {
+                boot.next_battery_check = now + config.battery_poll_interval;
+            }
+            (boot.chargers_deferred && stable, check_battery)
+        };
+
+        if input_stable {
+            info!("Input power stable, initializing chargers");
+            self.init_deferred_chargers().await?;
+        }
+
+        if check_battery && self.is_battery_usable(&config).await {
+            self.exit_boot_mode().await?;
+        }
+        Ok(())
+    }
+
+    /// Leave boot mode as soon as the battery reports enough charge
+    ///
+    /// The battery service only publishes its power state while the battery is operational.
+    pub(super) async fn process_boot_battery_state(&self, battery: BatteryPowerState) -> Result<(), Error> {
+        let Some(config) = self.config.boot else {
+            return Ok(());
+        };
+
+        if !self.in_boot_mode().await || battery.state_of_charge_pct < config.min_soc_pct {
+            return Ok(());
+        }
+        self.exit_boot_mode().await
+    }
+
+    /// Consult the battery service
+    async fn is_battery_usable(&self, config: &BootConfig) -> bool {
+        match with_timeout(BATTERY_QUERY_TIMEOUT, battery_service::get_state()).await {
+            Ok(state) => {
+                trace!("Battery service state: {:?}", state);
+                if state == BatteryState::NotPresent {
+                    debug!("No battery present, staying in boot mode");
+                }
+                battery_usable(config, state, self.state.lock().await.battery_state)
+            }
+            Err(TimeoutError) => {
+                debug!("Battery service not responding, staying in boot mode");
+                false
+            }
+        }
+    }
+
+    /// Run the charger sequence that was skipped while the input power settled
+    async fn init_deferred_chargers(&self) -> Result<(), Error> {
+        let mut guard = self.state.lock().await;
+        let state = guard.deref_mut();
+        if let Some(boot) = state.boot.as_mut() {
+            boot.chargers_deferred = false;
+        }
+
+        self.init_all_chargers().await?;
+
+        match state.current_consumer_state {
+            Some(consumer) => self.configure_chargers(state, consumer.power_capability()).await,
+            // Input power went away, the chargers are configured on the next connection
+            None => Ok(()),
+        }
+    }
+
+    /// Leave boot mode and select the consumer normally
+    async fn exit_boot_mode(&self) -> Result<(), Error> {
+        let chargers_deferred = match self.state.lock().await.boot.take() {
+            Some(boot) => boot.chargers_deferred,
+            None => return Ok(()),
+        };
+
+        info!("Battery usable, leaving boot mode");
+        if chargers_deferred {
+            self.init_deferred_chargers().await?;
+        }
+        self.update_current_consumer().await?;
+        self.update_power_envelope().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use battery_service::context::OperationalSubstate;
+
+    const OPERATIONAL: BatteryState = BatteryState::Present(PresentSubstate::Operational(OperationalSubstate::Polling));
+
+    fn battery(state_of_charge_pct: u8) -> BatteryPowerState {
+        BatteryPowerState {
+            state_of_charge_pct,
+            max_discharge_mw: 40000,
+        }
+    }
+
+    #[test]
+    fn test_battery_usable() {
+        let config = BootConfig::default();
+
+        assert!(!battery_usable(&config, BatteryState::NotPresent, None));
+        assert!(!battery_usable(
+            &config,
+            BatteryState::Present(PresentSubstate::NotOperational),
+            Some(battery(50))
+        ));
+        assert!(battery_usable(&config, OPERATIONAL, None));
+        assert!(battery_usable(&config, OPERATIONAL, Some(battery(50))));
+
+        // An operational but drained battery can't keep the system running yet
+        assert!(!battery_usable(&config, OPERATIONAL, Some(battery(2))));
+    }
+}
@@ -1,31 +1,106 @@
+//! Charger wrapper driving a [`ChargeController`] from power policy commands and controller events.
+//!
+//! While a PSU is attached the wrapper programs the charge current and voltage set by the policy and tracks the
+//! charging phase. The parameters are reprogrammed every
+//! [watchdog_period](crate::config::ChargerConfig::watchdog_period) so the charger hardware watchdog never expires.
+//! The charger keeps its hardware defaults until the policy sets charging parameters.
+//! Bus errors, timeouts and hardware faults put the charger in a fault state and it is reinitialized, retrying as
+//! configured. Recovery attempts are scheduled alongside policy commands so the policy is never kept waiting while the
+//! charger recovers. Every state change is broadcast over comms.
 use embassy_sync::mutex::Mutex;
+use embassy_time::{Instant, Timer};
 use embedded_services::GlobalRawMutex;
 
-use embassy_futures::select::select;
+use embassy_futures::select::{Either4, select4};
 use embedded_services::{
-    debug, error, info,
+    comms, debug, error, info,
     power::policy::charger::{
-        self, ChargeController, ChargerEvent, ChargerResponse, InternalState, PolicyEvent, PoweredSubstate, State,
+        self, ChargeController, ChargerError, ChargerEvent, ChargerFault, ChargerResponse, ChargingParameters,
+        ChargingSubstate, InternalState, PolicyEvent, PoweredSubstate, State,
     },
+    power::policy::{CommsData, CommsMessage, PowerCapability, policy},
     trace, warn,
 };
 
+use crate::config::ChargerConfig;
+
+/// Charging requested by the policy
+#[derive(Clone, Copy, Default)]
+struct ChargingControl {
+    /// Charge current and voltage, `None` until the policy sets them
+    parameters: Option<ChargingParameters>,
+    /// Charging is inhibited by the policy
+    inhibited: bool,
+    /// Next time the charger watchdog must be serviced, `None` while not charging
+    next_kick: Option<Instant>,
+}
+
+/// What to program into the charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Programming {
+    /// Leave the charger hardware untouched, the policy hasn't set any parameters yet
+    Defaults,
+    /// Stop charging
+    Inhibited,
+    /// Charge with the given current and voltage
+    Charge(ChargingParameters),
+}
+
+impl ChargingControl {
+    fn programming(&self) -> Programming {
+        if self.inhibited {
+            Programming::Inhibited
+        } else {
+            self.parameters.map_or(Programming::Defaults, Programming::Charge)
+        }
+    }
+}
+
+/// Pending recovery from a charger fault
+#[derive(Clone, Copy)]
+struct Recovery {
+    /// Fault being recovered from
+    fault: ChargerFault,
+    /// Attempts made so far
+    attempts: u8,
+    /// Time of the next attempt
+    next_attempt: Instant,
+}
+
+/// Fault reported for a failed charger operation
+fn fault(error: ChargerError) -> ChargerFault {
+    match error {
+        ChargerError::Timeout => ChargerFault::Timeout,
+        _ => ChargerFault::BusError,
+    }
+}
+
 pub struct Wrapper<'a, C: ChargeController>
 where
-    charger::ChargerError: From<<C as ChargeController>::ChargeControllerError>,
+    charger::ChargerError: From<<C as ChargeController>::ChargeControllerError> + From<<C as ChargeController>::Error>,
 {
     charger_policy_state: &'a charger::Device,
     controller: Mutex<GlobalRawMutex, C>,
+    config: ChargerConfig,
+    control: Mutex<GlobalRawMutex, ChargingControl>,
+    recovery: Mutex<GlobalRawMutex, Option<Recovery>>,
 }
 
 impl<'a, C: ChargeController> Wrapper<'a, C>
 where
-    charger::ChargerError: From<<C as ChargeController>::ChargeControllerError>,
+    charger::ChargerError: From<<C as ChargeController>::ChargeControllerError> + From<<C as ChargeController>::Error>,
 {
     pub fn new(charger_policy_state: &'a charger::Device, controller: C) -> Self {
+        Self::with_config(charger_policy_state, controller, ChargerConfig::default())
+    }
+
+    pub fn with_config(charger_policy_state: &'a charger::Device, controller: C, config: ChargerConfig) -> Self {
         Self {
             charger_policy_state,
             controller: Mutex::new(controller),
+            config,
+            control: Mutex::new(ChargingControl::default()),
+            recovery: Mutex::new(None),
         }
     }
 
@@ -33,64 +108,243 @@ where
         self.charger_policy_state.state().await
     }
 
+    /// Set the charger state, broadcasting the change over comms
     pub async fn set_state(&self, new_state: charger::InternalState) {
-        self.charger_policy_state.set_state(new_state).await
+        let old_state = self.get_state().await;
+        self.charger_policy_state.set_state(new_state).await;
+        if old_state.state == new_state.state {
+            return;
+        }
+
+        debug!("Charger state: {:?} -> {:?}", old_state.state, new_state.state);
+        let _ = comms::send(
+            comms::EndpointID::Internal(comms::Internal::Power),
+            comms::EndpointID::Internal(comms::Internal::Battery),
+            &CommsMessage {
+                data: CommsData::ChargerState(self.charger_policy_state.id(), new_state.state),
+            },
+        )
+        .await;
+        // Let the policy rebalance the input power
+        policy::notify_charger_state(self.charger_policy_state.id()).await;
+    }
+
+    /// Let the power policy know the power supply is up, confirms a new consumer is supplying power
+    async fn notify_psu_attached(&self) {
+        policy::notify_sink_ready(policy::SinkReady::Charger(self.charger_policy_state.id())).await;
     }
 
     async fn wait_policy_command(&self) -> PolicyEvent {
         self.charger_policy_state.wait_command().await
     }
 
+    /// Wait until the charger watchdog must be serviced, never returns while not charging
+    async fn wait_watchdog(&self) {
+        let next_kick = match self.get_state().await.state {
+            State::Powered(PoweredSubstate::PsuAttached(_)) => self.control.lock().await.next_kick,
+            _ => None,
+        };
+
+        match next_kick {
+            Some(next_kick) => Timer::at(next_kick).await,
+            None => core::future::pending().await,
+        }
+    }
+
+    /// Wait until the next recovery attempt, never returns without a pending recovery
+    async fn wait_recovery(&self) {
+        let next_attempt = self.recovery.lock().await.map(|recovery| recovery.next_attempt);
+        match next_attempt {
+            Some(next_attempt) => Timer::at(next_attempt).await,
+            None => core::future::pending().await,
+        }
+    }
+
+    /// Program the charge current and voltage, or stop charging, and return the resulting charging phase
+    async fn start_charging(&self, controller: &mut C) -> Result<ChargingSubstate, ChargerError> {
+        let mut control = self.control.lock().await;
+        let parameters = match control.programming() {
+            Programming::Defaults => {
+                trace!("No charging parameters set, leaving charger defaults");
+                control.next_kick = None;
+                return Ok(ChargingSubstate::Inhibited);
+            }
+            Programming::Inhibited => {
+                control.next_kick = None;
+                controller.charging_current(0).await?;
+                return Ok(ChargingSubstate::Inhibited);
+            }
+            Programming::Charge(parameters) => parameters,
+        };
+
+        controller.charging_voltage(parameters.voltage_mv).await?;
+        controller.charging_current(parameters.current_ma).await?;
+        control.next_kick = self.config.watchdog_period.map(|period| Instant::now() + period);
+        drop(control);
+
+        Ok(controller.charging_state().await?)
+    }
+
+    /// Start charging on a newly attached PSU, recovering the charger if that fails
+    async fn attach_psu(&self, controller: &mut C, capability: Option<PowerCapability>) {
+        match self.start_charging(controller).await {
+            Ok(substate) => {
+                self.set_state(InternalState {
+                    state: State::Powered(PoweredSubstate::PsuAttached(substate)),
+                    capability,
+                })
+                .await
+            }
+            Err(e) => self.recover(fault(e)).await,
+        }
+    }
+
+    /// Reprogram the charge current and voltage before the charger hardware watchdog expires
+    async fn kick_watchdog(&self, controller: &mut C) {
+        trace!("Servicing charger watchdog");
+        let state = self.get_state().await;
+        self.attach_psu(controller, state.capability).await;
+    }
+
+    /// Put the charger in the fault state and schedule its reinitialization
+    async fn recover(&self, fault: ChargerFault) {
+        let state = self.get_state().await;
+        warn!("Charger fault: {:?}, recovering", fault);
+        self.control.lock().await.next_kick = None;
+        self.set_state(InternalState {
+            state: State::Powered(PoweredSubstate::Fault(fault)),
+            capability: state.capability,
+        })
+        .await;
+
+        let recovery = if self.config.max_retries > 0 {
+            Some(Recovery {
+                fault,
+                attempts: 0,
+                next_attempt: Instant::now() + self.config.retry_delay,
+            })
+        } else {
+            error!("Charger recovery disabled, waiting for reinitialization");
+            None
+        };
+        *self.recovery.lock().await = recovery;
+    }
+
+    /// Make the next recovery attempt, the charger is left in the fault state if every attempt fails
+    async fn retry_recovery(&self, controller: &mut C) {
+        let Some(mut recovery) = self.recovery.lock().await.take() else {
+            return;
+        };
+
+        let state = self.get_state().await;
+        if !matches!(state.state, State::Powered(PoweredSubstate::Fault(_))) {
+            // Reinitialized in the meantime
+            debug!("Charger left the fault state, cancelling recovery");
+            return;
+        }
+
+        recovery.attempts += 1;
+        match self.reinitialize(controller, state.capability).await {
+            Ok(()) => info!("Charger recovered after {} attempt(s)", recovery.attempts),
+            Err(e) => {
+                warn!("Charger recovery attempt {} failed: {:?}", recovery.attempts, e);
+                if recovery.attempts < self.config.max_retries {
+                    recovery.next_attempt = Instant::now() + self.config.retry_delay;
+                    *self.recovery.lock().await = Some(recovery);
+                } else {
+                    error!(
+                        "Charger recovery from {:?} failed, waiting for reinitialization",
+                        recovery.fault
+                    );
+                }
+            }
+        }
+    }
+
+    /// Bring the charger back to the state it was in before a fault
+    async fn reinitialize(&self, controller: &mut C, capability: Option<PowerCapability>) -> Result<(), ChargerError> {
+        controller.is_ready().await?;
+        controller.init_charger().await?;
+        let psu_attached = controller.is_psu_attached().await?;
+        if let Some(capability) = capability {
+            controller.attach_handler(capability).await?;
+        }
+
+        let substate = if psu_attached {
+            PoweredSubstate::PsuAttached(self.start_charging(controller).await?)
+        } else {
+            PoweredSubstate::PsuDetached
+        };
+        self.set_state(InternalState {
+            state: State::Powered(substate),
+            capability,
+        })
+        .await;
+        Ok(())
+    }
+
     #[allow(clippy::single_match)]
-    async fn process_controller_event(&self, _controller: &mut C, event: ChargerEvent) {
+    async fn process_controller_event(&self, controller: &mut C, event: ChargerEvent) {
         let state = self.get_state().await;
         match state.state {
             State::Powered(powered_substate) => match powered_substate {
-                PoweredSubstate::Init => match event {
-                    ChargerEvent::Initialized(psu_state) => {
-                        self.set_state(InternalState {
-                            state: match psu_state {
-                                charger::PsuState::Attached => State::Powered(PoweredSubstate::PsuAttached),
-                                charger::PsuState::Detached => State::Powered(PoweredSubstate::PsuDetached),
-                            },
-                            capability: state.capability,
-                        })
-                        .await
-                    }
-                    // If we are initializing, we don't care about anything else
+                PoweredSubstate::Init | PoweredSubstate::Fault(_) => match event {
+                    ChargerEvent::Initialized(psu_state) => match psu_state {
+                        charger::PsuState::Attached => {
+                            self.attach_psu(controller, state.capability).await;
+                            self.notify_psu_attached().await;
+                        }
+                        charger::PsuState::Detached => {
+                            self.set_state(InternalState {
+                                state: State::Powered(PoweredSubstate::PsuDetached),
+                                capability: state.capability,
+                            })
+                            .await
+                        }
+                    },
+                    // If we are initializing or waiting to be reinitialized, we don't care about anything else
                     _ => (),
                 },
-                PoweredSubstate::PsuAttached => match event {
+                PoweredSubstate::PsuAttached(_) => match event {
                     ChargerEvent::PsuStateChange(charger::PsuState::Detached) => {
+                        self.control.lock().await.next_kick = None;
                         self.set_state(InternalState {
                             state: State::Powered(PoweredSubstate::PsuDetached),
                             capability: state.capability,
                         })
                         .await
                     }
-                    ChargerEvent::Timeout => {
+                    ChargerEvent::ChargingStateChange(substate) => {
                         self.set_state(InternalState {
-                            state: State::Powered(PoweredSubstate::Init),
-                            capability: None,
+                            state: State::Powered(PoweredSubstate::PsuAttached(substate)),
+                            capability: state.capability,
                         })
                         .await
                     }
+                    ChargerEvent::Timeout => {
+                        self.recover(ChargerFault::Timeout).await;
+                    }
+                    ChargerEvent::BusError => {
+                        self.recover(ChargerFault::BusError).await;
+                    }
+                    ChargerEvent::Fault => {
+                        self.recover(ChargerFault::Hardware).await;
+                    }
                     _ => (),
                 },
                 PoweredSubstate::PsuDetached => match event {
                     ChargerEvent::PsuStateChange(charger::PsuState::Attached) => {
-                        self.set_state(InternalState {
-                            state: State::Powered(PoweredSubstate::PsuAttached),
-                            capability: state.capability,
-                        })
-                        .await
+                        self.attach_psu(controller, state.capability).await;
+                        self.notify_psu_attached().await;
                     }
                     ChargerEvent::Timeout => {
-                        self.set_state(InternalState {
-                            state: State::Powered(PoweredSubstate::Init),
-                            capability: None,
-                        })
-                        .await
+                        self.recover(ChargerFault::Timeout).await;
+                    }
+                    ChargerEvent::BusError => {
+                        self.recover(ChargerFault::BusError).await;
+                    }
+                    ChargerEvent::Fault => {
+                        self.recover(ChargerFault::Hardware).await;
                     }
                     _ => (),
                 },
@@ -102,6 +356,29 @@ where
         }
     }
 
+    /// Apply a charging change from the policy, takes effect immediately if a PSU is attached
+    async fn update_charging(&self, controller: &mut C) -> ChargerResponse {
+        let state = self.get_state().await;
+        match state.state {
+            State::Unpowered => Ok(charger::ChargerResponseData::UnpoweredAck),
+            State::Powered(PoweredSubstate::PsuAttached(_)) => match self.start_charging(controller).await {
+                Ok(substate) => {
+                    self.set_state(InternalState {
+                        state: State::Powered(PoweredSubstate::PsuAttached(substate)),
+                        capability: state.capability,
+                    })
+                    .await;
+                    Ok(charger::ChargerResponseData::Ack)
+                }
+                Err(e) => {
+                    self.recover(fault(e)).await;
+                    Err(e)
+                }
+            },
+            State::Powered(_) => Ok(charger::ChargerResponseData::Ack),
+        }
+    }
+
     async fn process_policy_command(&self, controller: &mut C, event: PolicyEvent) {
         let state = self.get_state().await;
         let res: ChargerResponse = match event {
@@ -134,13 +411,21 @@ where
                     Ok(charger::ChargerResponseData::UnpoweredAck)
                 }
                 State::Powered(substate) => match substate {
-                    PoweredSubstate::Init => {
+                    PoweredSubstate::Fault(_) if power_capability.current_ma == 0 => {
+                        // Policy took the input away from the faulted charger, it's attached again once recovered
+                        debug!("Faulted charger released from the power policy configuration");
+                        self.set_state(InternalState {
+                            state: state.state,
+                            capability: None,
+                        })
+                        .await;
+                        Ok(charger::ChargerResponseData::Ack)
+                    }
+                    PoweredSubstate::Init | PoweredSubstate::Fault(_) => {
                         error!("Charger detected new power policy configuration but charger is still initializing.");
-                        Err(charger::ChargerError::InvalidState(State::Powered(
-                            PoweredSubstate::Init,
-                        )))
+                        Err(charger::ChargerError::InvalidState(State::Powered(substate)))
                     }
-                    PoweredSubstate::PsuAttached | PoweredSubstate::PsuDetached => {
+                    PoweredSubstate::PsuAttached(_) | PoweredSubstate::PsuDetached => {
                         if power_capability.current_ma == 0 {
                             // Policy detected a detach
                             debug!("Charger detected new power policy configuration. Executing detach sequence");
@@ -193,6 +478,7 @@ where
                 match state.state {
                     State::Powered(_) => {
                         if let Err(e) = ret {
+                            self.control.lock().await.next_kick = None;
                             self.set_state(InternalState {
                                 state: State::Unpowered,
                                 // Cache capability for logging/debug
@@ -218,6 +504,16 @@ where
                     }
                 }
             }
+            PolicyEvent::ChargingParameters(parameters) => {
+                debug!("Charger received new charging parameters: {:?}", parameters);
+                self.control.lock().await.parameters = Some(parameters);
+                self.update_charging(controller).await
+            }
+            PolicyEvent::InhibitCharging(inhibit) => {
+                debug!("Charger received inhibit charging: {}", inhibit);
+                self.control.lock().await.inhibited = inhibit;
+                self.update_charging(controller).await
+            }
         };
 
         // Send response
@@ -227,17 +523,392 @@ where
     pub async fn process(&self) {
         let mut controller = self.controller.lock().await;
         loop {
-            let res = select(controller.wait_event(), self.wait_policy_command()).await;
+            let res = select4(
+                controller.wait_event(),
+                self.wait_policy_command(),
+                self.wait_watchdog(),
+                self.wait_recovery(),
+            )
+            .await;
             match res {
-                embassy_futures::select::Either::First(event) => {
+                Either4::First(event) => {
                     trace!("New charger device event.");
                     self.process_controller_event(&mut controller, event).await;
                 }
-                embassy_futures::select::Either::Second(event) => {
+                Either4::Second(event) => {
                     trace!("New charger policy command.");
                     self.process_policy_command(&mut controller, event).await;
                 }
+                Either4::Third(()) => self.kick_watchdog(&mut controller).await,
+                Either4::Fourth(()) => self.retry_recovery(&mut controller).await,
             };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    use embassy_futures::select::{Either, select};
+    use embassy_sync::channel::Channel;
+    use embassy_time::Duration;
+    use embedded_batteries_async::charger::{Charger, ErrorKind, ErrorType, MilliAmps, MilliVolts};
+    use embedded_services::power::policy::charger::{ChargerId, ChargerResponseData, PsuState};
+
+    use super::*;
+    use crate::sim::{Sim, block_on_sim};
+
+    const PARAMETERS: ChargingParameters = ChargingParameters {
+        current_ma: 2000,
+        voltage_mv: 12600,
+    };
+
+    const WATCHDOG_PERIOD: Duration = Duration::from_millis(20);
+    const RETRY_DELAY: Duration = Duration::from_millis(50);
+    const MAX_RETRIES: u8 = 2;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Call {
+        Init,
+        Current(MilliAmps),
+        Voltage(MilliVolts),
+    }
+
+    #[derive(Debug)]
+    struct MockError;
+
+    impl embedded_batteries_async::charger::Error for MockError {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    impl From<MockError> for ChargerError {
+        fn from(_: MockError) -> Self {
+            ChargerError::BusError
+        }
+    }
+
+    /// Mock charge controller that records the calls made to it
+    struct MockController<'a> {
+        events: &'a Channel<GlobalRawMutex, ChargerEvent, 1>,
+        calls: &'a RefCell<Vec<Call>>,
+        /// Number of upcoming `init_charger` calls that fail
+        init_failures: &'a Cell<u8>,
+    }
+
+    impl ErrorType for MockController<'_> {
+        type Error = MockError;
+    }
+
+    impl Charger for MockController<'_> {
+        async fn charging_current(&mut self, current: MilliAmps) -> Result<MilliAmps, Self::Error> {
+            self.calls.borrow_mut().push(Call::Current(current));
+            Ok(current)
+        }
+
+        async fn charging_voltage(&mut self, voltage: MilliVolts) -> Result<MilliVolts, Self::Error> {
+            self.calls.borrow_mut().push(Call::Voltage(voltage));
+            Ok(voltage)
+        }
+    }
+
+    impl ChargeController for MockController<'_> {
+        type ChargeControllerError = MockError;
+
+        async fn wait_event(&mut self) -> ChargerEvent {
+            self.events.receive().await
+        }
+
+        async fn init_charger(&mut self) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push(Call::Init);
+            match self.init_failures.get() {
+                0 => Ok(()),
+                failures => {
+                    self.init_failures.set(failures - 1);
+                    Err(MockError)
+                }
+            }
+        }
+
+        async fn is_psu_attached(&mut self) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn attach_handler(&mut self, _capability: PowerCapability) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn detach_handler(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Mock controller and its charger wrapper
+    struct Harness<'a> {
+        device: &'a charger::Device,
+        wrapper: Wrapper<'a, MockController<'a>>,
+        events: &'a Channel<GlobalRawMutex, ChargerEvent, 1>,
+        calls: &'a RefCell<Vec<Call>>,
+    }
+
+    impl Harness<'_> {
+        fn calls(&self) -> Vec<Call> {
+            self.calls.borrow().clone()
+        }
+
+        /// Run `test` in simulated time while the wrapper processes commands and events
+        fn run(&self, test: impl Future<Output = ()>) {
+            match block_on_sim(Duration::from_secs(5), select(self.wrapper.process(), test)) {
+                Some(Either::Second(())) => (),
+                Some(Either::First(())) => unreachable!(),
+                None => panic!("Charger test timed out"),
+            }
+        }
+
+        /// Wait until the wrapper reaches `state`
+        async fn wait_state(&self, state: State) {
+            while self.device.state().await.state != state {
+                Timer::after_millis(1).await;
+            }
+        }
+
+        /// Power up the charger and attach a PSU
+        async fn attach(&self) {
+            assert_eq!(
+                self.device.execute_command(PolicyEvent::CheckReady).await,
+                Ok(ChargerResponseData::Ack)
+            );
+            self.events.send(ChargerEvent::Initialized(PsuState::Attached)).await;
+            self.wait_state(State::Powered(PoweredSubstate::PsuAttached(
+                ChargingSubstate::Inhibited,
+            )))
+            .await;
+        }
+    }
+
+    /// Run a test against a fresh mock controller and wrapper
+    fn with_harness(init_failures: u8, test: impl FnOnce(&Harness)) {
+        // The charger notifies the policy context of state changes, don't interfere with the sim
+        let (_guard, _sim) = Sim::get();
+        let device = charger::Device::new(ChargerId(0x10));
+        let events = Channel::new();
+        let calls = RefCell::new(Vec::new());
+        let init_failures = Cell::new(init_failures);
+        let controller = MockController {
+            events: &events,
+            calls: &calls,
+            init_failures: &init_failures,
+        };
+        let harness = Harness {
+            device: &device,
+            wrapper: Wrapper::with_config(
+                &device,
+                controller,
+                ChargerConfig {
+                    watchdog_period: Some(WATCHDOG_PERIOD),
+                    max_retries: MAX_RETRIES,
+                    retry_delay: RETRY_DELAY,
+                },
+            ),
+            events: &events,
+            calls: &calls,
+        };
+        test(&harness);
+    }
+
+    #[test]
+    fn test_programming() {
+        let mut control = ChargingControl::default();
+        assert_eq!(control.programming(), Programming::Defaults);
+
+        control.parameters = Some(PARAMETERS);
+        assert_eq!(control.programming(), Programming::Charge(PARAMETERS));
+
+        // Inhibiting keeps the parameters for when charging resumes
+        control.inhibited = true;
+        assert_eq!(control.programming(), Programming::Inhibited);
+        control.inhibited = false;
+        assert_eq!(control.programming(), Programming::Charge(PARAMETERS));
+    }
+
+    #[test]
+    fn test_fault() {
+        assert_eq!(fault(ChargerError::Timeout), ChargerFault::Timeout);
+        assert_eq!(fault(ChargerError::BusError), ChargerFault::BusError);
+    }
+
+    #[test]
+    fn test_charging_parameters() {
+        with_harness(0, |harness| {
+            harness.run(async {
+                harness.attach().await;
+                // Nothing is programmed until the policy sets the parameters
+                Timer::after(WATCHDOG_PERIOD * 3).await;
+                assert!(harness.calls().is_empty());
+
+                assert_eq!(
+                    harness
+                        .device
+                        .execute_command(PolicyEvent::ChargingParameters(PARAMETERS))
+                        .await,
+                    Ok(ChargerResponseData::Ack)
+                );
+                assert_eq!(
+                    harness.device.state().await.state,
+                    State::Powered(PoweredSubstate::PsuAttached(ChargingSubstate::FastCharge))
+                );
+
+                assert_eq!(
+                    harness.device.execute_command(PolicyEvent::InhibitCharging(true)).await,
+                    Ok(ChargerResponseData::Ack)
+                );
+                assert_eq!(
+                    harness.device.state().await.state,
+                    State::Powered(PoweredSubstate::PsuAttached(ChargingSubstate::Inhibited))
+                );
+            });
+            assert_eq!(
+                harness.calls(),
+                [
+                    Call::Voltage(PARAMETERS.voltage_mv),
+                    Call::Current(PARAMETERS.current_ma),
+                    Call::Current(0),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_watchdog() {
+        with_harness(0, |harness| {
+            harness.run(async {
+                harness.attach().await;
+                harness
+                    .device
+                    .execute_command(PolicyEvent::ChargingParameters(PARAMETERS))
+                    .await
+                    .unwrap();
+
+                // The parameters are reprogrammed every watchdog period, twice in two and a half periods
+                Timer::after(WATCHDOG_PERIOD * 5 / 2).await;
+                assert_eq!(harness.calls().len(), 6);
+
+                // And no longer once charging stops
+                harness
+                    .device
+                    .execute_command(PolicyEvent::InhibitCharging(true))
+                    .await
+                    .unwrap();
+                let calls = harness.calls().len();
+                Timer::after(WATCHDOG_PERIOD * 3).await;
+                assert_eq!(harness.calls().len(), calls);
+            });
+            assert_eq!(
+                harness.calls()[..6],
+                [
+                    Call::Voltage(PARAMETERS.voltage_mv),
+                    Call::Current(PARAMETERS.current_ma),
+                    Call::Voltage(PARAMETERS.voltage_mv),
+                    Call::Current(PARAMETERS.current_ma),
+                    Call::Voltage(PARAMETERS.voltage_mv),
+                    Call::Current(PARAMETERS.current_ma),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_charging_state_change() {
+        with_harness(0, |harness| {
+            harness.run(async {
+                harness.attach().await;
+                harness
+                    .device
+                    .execute_command(PolicyEvent::ChargingParameters(PARAMETERS))
+                    .await
+                    .unwrap();
+
+                harness
+                    .events
+                    .send(ChargerEvent::ChargingStateChange(ChargingSubstate::Done))
+                    .await;
+                harness
+                    .wait_state(State::Powered(PoweredSubstate::PsuAttached(ChargingSubstate::Done)))
+                    .await;
+                // Losing the PSU ends the charging phase
+                harness
+                    .events
+                    .send(ChargerEvent::PsuStateChange(PsuState::Detached))
+                    .await;
+                harness.wait_state(State::Powered(PoweredSubstate::PsuDetached)).await;
+            });
+        });
+    }
+
+    #[test]
+    fn test_recovery() {
+        with_harness(1, |harness| {
+            harness.run(async {
+                harness.attach().await;
+                harness
+                    .device
+                    .execute_command(PolicyEvent::ChargingParameters(PARAMETERS))
+                    .await
+                    .unwrap();
+
+                let faulted_at = Instant::now();
+                harness.events.send(ChargerEvent::Fault).await;
+                harness
+                    .wait_state(State::Powered(PoweredSubstate::Fault(ChargerFault::Hardware)))
+                    .await;
+                // The first attempt fails, the second brings charging back a retry delay later
+                harness
+                    .wait_state(State::Powered(PoweredSubstate::PsuAttached(
+                        ChargingSubstate::FastCharge,
+                    )))
+                    .await;
+                assert_eq!(faulted_at.elapsed(), RETRY_DELAY * 2);
+            });
+            assert_eq!(harness.calls().iter().filter(|call| **call == Call::Init).count(), 2);
+        });
+    }
+
+    #[test]
+    fn test_recovery_fails() {
+        with_harness(u8::MAX, |harness| {
+            harness.run(async {
+                harness.attach().await;
+                harness.events.send(ChargerEvent::BusError).await;
+                let faulted = State::Powered(PoweredSubstate::Fault(ChargerFault::BusError));
+                harness.wait_state(faulted).await;
+
+                // Policy commands are answered right away while recovery is pending
+                let started = Instant::now();
+                assert_eq!(
+                    harness
+                        .device
+                        .execute_command(PolicyEvent::PolicyConfiguration(PowerCapability {
+                            voltage_mv: 5000,
+                            current_ma: 3000,
+                        }))
+                        .await,
+                    Err(ChargerError::InvalidState(faulted))
+                );
+                assert_eq!(Instant::now(), started);
+
+                // Every attempt fails and the charger is left in the fault state
+                Timer::after(RETRY_DELAY * (MAX_RETRIES as u32 + 2)).await;
+                assert_eq!(harness.device.state().await.state, faulted);
+            });
+            assert_eq!(
+                harness.calls().iter().filter(|call| **call == Call::Init).count(),
+                MAX_RETRIES as usize
+            );
+        });
+    }
+}
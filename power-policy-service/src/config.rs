@@ -1,32 +1,502 @@
 //! Configuration types for the power policy service
 
-use embedded_services::power::policy::PowerCapability;
+use embassy_time::Duration;
+use embedded_services::power::policy::charger::ChargerId;
+use embedded_services::power::policy::{DeviceId, PowerCapability, PowerRole};
 
-#[derive(Clone, Copy)]
-pub struct Config {
-    /// Above this threshold, the system is in limited power mode
-    pub limited_power_threshold_mw: u32,
-    /// Power capability of every provider in normal power mode
-    pub provider_unlimited: PowerCapability,
-    /// Power capability of every provider in limited power mode
-    pub provider_limited: PowerCapability,
+/// Power source the policy could consume from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Candidate {
+    /// Device offering the power
+    pub device_id: DeviceId,
+    /// Power offered by the device
+    pub capability: PowerCapability,
 }
 
-impl Default for Config {
+/// Decides which power source the policy consumes from
+///
+/// The policy walks all available sources, dropping the ones that aren't acceptable and keeping the preferred one.
+pub trait ConsumerSelector: Sync {
+    /// Returns true if the policy may consume from `candidate`
+    fn is_acceptable(&self, candidate: &Candidate) -> bool;
+
+    /// Returns true if `candidate` should be preferred over `best`, the best source found so far
+    ///
+    /// `current` is the source the policy is currently consuming from, if any.
+    fn prefer(&self, candidate: &Candidate, best: &Candidate, current: Option<&Candidate>) -> bool;
+}
+
+/// Priority of a port, higher priority ports are preferred regardless of power
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortPriority {
+    /// Port device
+    pub device_id: DeviceId,
+    /// Priority, ports that aren't listed have priority 0
+    pub priority: u8,
+}
+
+/// Look up the priority of a port
+pub(crate) fn port_priority(priorities: &[PortPriority], device_id: DeviceId) -> u8 {
+    priorities
+        .iter()
+        .find(|p| p.device_id == device_id)
+        .map_or(0, |p| p.priority)
+}
+
+/// Multi-criteria [`ConsumerSelector`]
+///
+/// Sources are ranked by port priority, then by power. The default configuration picks the source with the most power.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConsumerSelection {
+    /// Port priorities, such as a preferred dock port or a barrel jack over Type-C
+    pub priorities: &'static [PortPriority],
+    /// Sources offering less power are ignored
+    pub min_power_mw: u32,
+    /// Additional power a source must offer over the current source before the policy switches
+    pub hysteresis_mw: u32,
+    /// Keep the current source over sources of the same priority, regardless of power
+    pub sticky: bool,
+}
+
+impl ConsumerSelection {
+    /// Select the source with the most power
+    pub const fn new() -> Self {
+        Self {
+            priorities: &[],
+            min_power_mw: 0,
+            hysteresis_mw: 0,
+            sticky: false,
+        }
+    }
+
+    /// Priority of the given port
+    pub fn priority(&self, device_id: DeviceId) -> u8 {
+        port_priority(self.priorities, device_id)
+    }
+}
+
+impl Default for ConsumerSelection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConsumerSelector for ConsumerSelection {
+    fn is_acceptable(&self, candidate: &Candidate) -> bool {
+        candidate.capability.max_power_mw() >= self.min_power_mw
+    }
+
+    fn prefer(&self, candidate: &Candidate, best: &Candidate, current: Option<&Candidate>) -> bool {
+        let candidate_priority = self.priority(candidate.device_id);
+        let best_priority = self.priority(best.device_id);
+        if candidate_priority != best_priority {
+            return candidate_priority > best_priority;
+        }
+
+        let is_current = |c: &Candidate| current.is_some_and(|current| current.device_id == c.device_id);
+        if self.sticky {
+            if is_current(best) {
+                return false;
+            }
+            if is_current(candidate) {
+                return true;
+            }
+        }
+
+        // The current source keeps its place unless another source offers more than the hysteresis
+        let candidate_power = candidate.capability.max_power_mw();
+        let best_power = best.capability.max_power_mw();
+        if is_current(candidate) {
+            candidate_power.saturating_add(self.hysteresis_mw) >= best_power
+        } else if is_current(best) {
+            candidate_power > best_power.saturating_add(self.hysteresis_mw)
+        } else {
+            candidate_power > best_power
+        }
+    }
+}
+
+/// Default consumer selection, used by [`Config::default`]
+static DEFAULT_CONSUMER_SELECTION: ConsumerSelection = ConsumerSelection::new();
+
+/// Power budget shared by all provider ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProviderBudget {
+    /// Total power available to all provider ports
+    pub total_mw: u32,
+    /// Most a single port is given
+    pub max_per_port: PowerCapability,
+    /// Least a port is given, existing contracts are reduced down to this to make room for new ports
+    pub min_per_port: PowerCapability,
+    /// Port priorities, higher priority ports are served first, then ports in the order they connected
+    pub priorities: &'static [PortPriority],
+}
+
+/// The default budget matches the former limited power threshold: a single port gets Type-C 5V@3A, as soon as a second
+/// port connects both are reduced to Type-C 5V@1A5. Unlike the threshold, a port that doesn't fit at the minimum isn't
+/// connected at all.
+impl Default for ProviderBudget {
     fn default() -> Self {
         Self {
+            // One port at Type-C 5V@3A
+            total_mw: 15000,
             // Type-C 5V@3A
-            limited_power_threshold_mw: 15000,
-            // Type-C 5V@3A
-            provider_unlimited: PowerCapability {
+            max_per_port: PowerCapability {
                 voltage_mv: 5000,
                 current_ma: 3000,
             },
             // Type-C 5V@1A5
-            provider_limited: PowerCapability {
+            min_per_port: PowerCapability {
                 voltage_mv: 5000,
                 current_ma: 1500,
             },
+            priorities: &[],
+        }
+    }
+}
+
+/// System power envelope configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnvelopeConfig {
+    /// Charger efficiency in %, applied to the adapter power
+    pub charger_efficiency_pct: u8,
+    /// Below this state of charge the battery no longer boosts the adapter
+    pub min_boost_soc_pct: u8,
+    /// Power the system needs to sustain full performance, smaller adapters are reported as undersized
+    pub system_rated_mw: u32,
+}
+
+impl Default for EnvelopeConfig {
+    fn default() -> Self {
+        Self {
+            charger_efficiency_pct: 90,
+            min_boost_soc_pct: 10,
+            // Never report an undersized adapter
+            system_rated_mw: 0,
+        }
+    }
+}
+
+/// Power role a dual-role port should be swapped to, such as making a dock the consumer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PreferredRole {
+    /// Port device
+    pub device_id: DeviceId,
+    /// Role the port should take
+    pub role: PowerRole,
+}
+
+/// What to do when a new consumer doesn't confirm it is supplying power in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SinkReadyFallback {
+    /// Assume power is flowing and continue
+    #[default]
+    Proceed,
+    /// Disconnect the consumer and report a timeout
+    Disconnect,
+}
+
+/// Sink ready handshake configuration
+///
+/// After connecting a consumer the policy waits for the device to report its sink path is enabled or for a charger to
+/// report its power supply as attached before configuring the chargers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SinkReadyConfig {
+    /// How long to wait for confirmation
+    pub timeout: Duration,
+    /// Action taken on timeout
+    pub fallback: SinkReadyFallback,
+}
+
+impl Default for SinkReadyConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(800),
+            fallback: SinkReadyFallback::Proceed,
+        }
+    }
+}
+
+/// Dead battery boot configuration
+///
+/// While booting without a usable battery the adapter is the only thing keeping the system running. The policy takes
+/// the first available source, keeps it for as long as it's available and holds off charger initialization until the
+/// input power is stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BootConfig {
+    /// How long the input power must stay connected before the chargers are initialized
+    pub stable_input: Duration,
+    /// How often the battery service is checked for a usable battery
+    pub battery_poll_interval: Duration,
+    /// Least state of charge the battery must report, if known, before leaving boot mode
+    pub min_soc_pct: u8,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            stable_input: Duration::from_millis(500),
+            battery_poll_interval: Duration::from_secs(1),
+            min_soc_pct: 5,
+        }
+    }
+}
+
+/// Share of the input power given to a charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChargerRatio {
+    /// Charger device
+    pub charger_id: ChargerId,
+    /// Relative weight, chargers that aren't listed have weight 1
+    pub weight: u8,
+}
+
+/// How the input power is split between chargers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SharingMode {
+    /// Split by fixed weights
+    Ratio(&'static [ChargerRatio]),
+    /// Split by the charge power requested for each charger, chargers that aren't charging only get the minimum
+    Demand,
+}
+
+/// Input power sharing between chargers, such as one charger per battery
+///
+/// Faulted chargers are left out. Each remaining charger is given up to the minimum current, the rest of the input
+/// current is then split according to the mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChargerSharing {
+    /// How the input current beyond the minimum is split
+    pub mode: SharingMode,
+    /// Least input current given to each charger, if the input allows
+    pub min_current_ma: u16,
+}
+
+impl Default for ChargerSharing {
+    fn default() -> Self {
+        Self {
+            // Split evenly
+            mode: SharingMode::Ratio(&[]),
+            min_current_ma: 0,
+        }
+    }
+}
+
+/// Charger watchdog and fault recovery configuration, used by the [charger wrapper](crate::charger::Wrapper)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChargerConfig {
+    /// How often the charge current and voltage are reprogrammed while charging, must be shorter than the charger
+    /// hardware watchdog. `None` if the hardware has no watchdog
+    pub watchdog_period: Option<Duration>,
+    /// Number of recovery attempts after a charger fault before giving up
+    pub max_retries: u8,
+    /// Delay before each recovery attempt
+    pub retry_delay: Duration,
+}
+
+impl Default for ChargerConfig {
+    fn default() -> Self {
+        Self {
+            watchdog_period: Some(Duration::from_secs(60)),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Power policy history configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HistoryConfig {
+    /// NVRAM sections the most recent history is persisted to across a reset, empty to not persist the history
+    ///
+    /// These are indices into the table passed to `platform_service::nvram::init`, which must be initialized before
+    /// the policy starts.
+    pub nvram_sections: &'static [usize],
+}
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// Power budget shared by all providers
+    pub provider_budget: ProviderBudget,
+    /// Selects the power source to consume from
+    pub consumer_selector: &'static dyn ConsumerSelector,
+    /// System power envelope configuration
+    pub envelope: EnvelopeConfig,
+    /// Sink ready handshake configuration
+    pub sink_ready: SinkReadyConfig,
+    /// Preferred power roles of dual-role ports, ports that aren't listed keep the role they attached with
+    pub preferred_roles: &'static [PreferredRole],
+    /// Dead battery boot mode, `None` disables it
+    pub boot: Option<BootConfig>,
+    /// Input power sharing between chargers
+    pub charger_sharing: ChargerSharing,
+    /// Power policy history configuration
+    pub history: HistoryConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            provider_budget: ProviderBudget::default(),
+            consumer_selector: &DEFAULT_CONSUMER_SELECTION,
+            envelope: EnvelopeConfig::default(),
+            sink_ready: SinkReadyConfig::default(),
+            preferred_roles: &[],
+            boot: None,
+            charger_sharing: ChargerSharing::default(),
+            history: HistoryConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCK: DeviceId = DeviceId(0);
+    const TYPE_C: DeviceId = DeviceId(1);
+    const BARREL: DeviceId = DeviceId(2);
+
+    fn candidate(device_id: DeviceId, voltage_mv: u16, current_ma: u16) -> Candidate {
+        Candidate {
+            device_id,
+            capability: PowerCapability { voltage_mv, current_ma },
         }
     }
+
+    /// Run the selector over the candidates the same way the policy does
+    fn select(
+        selector: &impl ConsumerSelector,
+        candidates: &[Candidate],
+        current: Option<&Candidate>,
+    ) -> Option<Candidate> {
+        candidates
+            .iter()
+            .filter(|candidate| selector.is_acceptable(candidate))
+            .fold(None, |best, candidate| match best {
+                Some(best) if !selector.prefer(candidate, &best, current) => Some(best),
+                _ => Some(*candidate),
+            })
+    }
+
+    #[test]
+    fn test_default_selects_highest_power() {
+        let selector = ConsumerSelection::default();
+        let candidates = [
+            candidate(DOCK, 5000, 3000),
+            candidate(TYPE_C, 20000, 3250),
+            candidate(BARREL, 19500, 2310),
+        ];
+
+        assert_eq!(select(&selector, &candidates, None), Some(candidates[1]));
+        assert_eq!(select(&selector, &[], None), None);
+
+        // Ties keep the first source found
+        let candidates = [candidate(DOCK, 20000, 3000), candidate(TYPE_C, 20000, 3000)];
+        assert_eq!(select(&selector, &candidates, None), Some(candidates[0]));
+    }
+
+    #[test]
+    fn test_priority() {
+        static PRIORITIES: [PortPriority; 2] = [
+            PortPriority {
+                device_id: BARREL,
+                priority: 2,
+            },
+            PortPriority {
+                device_id: DOCK,
+                priority: 1,
+            },
+        ];
+        let selector = ConsumerSelection {
+            priorities: &PRIORITIES,
+            ..Default::default()
+        };
+
+        let candidates = [
+            candidate(TYPE_C, 20000, 5000),
+            candidate(DOCK, 20000, 3000),
+            candidate(BARREL, 19500, 2310),
+        ];
+        assert_eq!(select(&selector, &candidates, None), Some(candidates[2]));
+        assert_eq!(select(&selector, &candidates[..2], None), Some(candidates[1]));
+        assert_eq!(selector.priority(TYPE_C), 0);
+    }
+
+    #[test]
+    fn test_min_power() {
+        let selector = ConsumerSelection {
+            min_power_mw: 27000,
+            ..Default::default()
+        };
+
+        let candidates = [candidate(DOCK, 5000, 3000), candidate(TYPE_C, 9000, 3000)];
+        assert_eq!(select(&selector, &candidates, None), Some(candidates[1]));
+        assert_eq!(select(&selector, &candidates[..1], None), None);
+
+        // The current source is dropped once it falls below the minimum
+        let current = candidate(DOCK, 5000, 3000);
+        assert_eq!(select(&selector, &candidates, Some(&current)), Some(candidates[1]));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let selector = ConsumerSelection {
+            hysteresis_mw: 5000,
+            ..Default::default()
+        };
+        let current = candidate(DOCK, 20000, 2250);
+
+        // 50 W doesn't beat 45 W + 5 W hysteresis, regardless of order
+        let candidates = [current, candidate(TYPE_C, 20000, 2500)];
+        assert_eq!(select(&selector, &candidates, Some(&current)), Some(current));
+        let candidates = [candidate(TYPE_C, 20000, 2500), current];
+        assert_eq!(select(&selector, &candidates, Some(&current)), Some(current));
+
+        // 65 W does
+        let candidates = [current, candidate(TYPE_C, 20000, 3250)];
+        assert_eq!(select(&selector, &candidates, Some(&current)), Some(candidates[1]));
+
+        // Without a current source, hysteresis doesn't apply
+        let candidates = [current, candidate(TYPE_C, 20000, 2500)];
+        assert_eq!(select(&selector, &candidates, None), Some(candidates[1]));
+    }
+
+    #[test]
+    fn test_sticky() {
+        let selector = ConsumerSelection {
+            sticky: true,
+            ..Default::default()
+        };
+        let current = candidate(DOCK, 5000, 3000);
+
+        let candidates = [candidate(TYPE_C, 20000, 5000), current];
+        assert_eq!(select(&selector, &candidates, Some(&current)), Some(current));
+
+        // Priority still wins over stickiness
+        static PRIORITIES: [PortPriority; 1] = [PortPriority {
+            device_id: BARREL,
+            priority: 1,
+        }];
+        let selector = ConsumerSelection {
+            priorities: &PRIORITIES,
+            ..selector
+        };
+        let candidates = [current, candidate(BARREL, 19500, 2310)];
+        assert_eq!(select(&selector, &candidates, Some(&current)), Some(candidates[1]));
+    }
 }
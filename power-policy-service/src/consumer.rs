@@ -1,10 +1,10 @@
-use embedded_services::debug;
+use embassy_time::{TimeoutError, with_timeout};
 use embedded_services::power::policy::charger::Device as ChargerDevice;
 use embedded_services::power::policy::charger::PolicyEvent;
-use embedded_services::power::policy::policy::check_chargers_ready;
-use embedded_services::power::policy::policy::init_chargers;
+use embedded_services::{debug, warn};
 
 use super::*;
+use crate::config::{Candidate, SinkReadyFallback};
 
 /// State of the current consumer
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,6 +16,13 @@ pub struct State {
     power_capability: PowerCapability,
 }
 
+impl State {
+    /// The power capability of the current consumer
+    pub(crate) fn power_capability(&self) -> PowerCapability {
+        self.power_capability
+    }
+}
+
 impl PartialOrd for State {
     fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
         Some(self.cmp(other))
@@ -28,40 +35,113 @@ impl Ord for State {
     }
 }
 
+impl From<State> for Candidate {
+    fn from(state: State) -> Self {
+        Self {
+            device_id: state.device_id,
+            capability: state.power_capability,
+        }
+    }
+}
+
+impl From<Candidate> for State {
+    fn from(candidate: Candidate) -> Self {
+        Self {
+            device_id: candidate.device_id,
+            power_capability: candidate.capability,
+        }
+    }
+}
+
 impl PowerPolicy {
-    /// Iterate over all devices to determine what is best power port provides the highest power
-    async fn find_highest_power_consumer(&self) -> Result<Option<State>, Error> {
-        let mut best_consumer = None;
+    /// Iterate over all devices to determine the best power port according to the configured selector
+    ///
+    /// In boot mode the current source is kept while it's available, otherwise the first available source is taken.
+    async fn find_best_consumer(&self, current: Option<State>, boot: bool) -> Result<Option<State>, Error> {
+        let selector = self.config.consumer_selector;
+        let current = current.map(Candidate::from);
+        let mut best_consumer: Option<Candidate> = None;
 
         for node in self.context.devices().await {
             let device = node.data::<Device>().ok_or(Error::InvalidDevice)?;
+            let Some(capability) = device.consumer_capability().await else {
+                continue;
+            };
 
-            // Update the best available consumer
-            best_consumer = match (best_consumer, device.consumer_capability().await) {
-                // Nothing available
-                (None, None) => None,
-                // No existing consumer
-                (None, Some(power_capability)) => Some(State {
-                    device_id: device.id(),
-                    power_capability,
-                }),
-                // Existing consumer, no new consumer
-                (Some(_), None) => best_consumer,
-                // Existing consumer, new available consumer
-                (Some(best), Some(available)) => {
-                    if available > best.power_capability {
-                        Some(State {
-                            device_id: device.id(),
-                            power_capability: available,
-                        })
-                    } else {
-                        best_consumer
-                    }
+            let candidate = Candidate {
+                device_id: device.id(),
+                capability,
+            };
+            if boot {
+                if current.is_some_and(|current| current.device_id == candidate.device_id) {
+                    debug!(
+                        "Device {}, keeping current consumer in boot mode",
+                        candidate.device_id.0
+                    );
+                    return Ok(Some(candidate.into()));
                 }
+                best_consumer = best_consumer.or(Some(candidate));
+                continue;
+            }
+
+            if !selector.is_acceptable(&candidate) {
+                debug!("Device {}, consumer capability not acceptable", candidate.device_id.0);
+                continue;
+            }
+
+            // Update the best available consumer
+            best_consumer = match best_consumer {
+                Some(best) if !selector.prefer(&candidate, &best, current.as_ref()) => Some(best),
+                _ => Some(candidate),
             };
         }
 
-        Ok(best_consumer)
+        Ok(best_consumer.map(State::from))
+    }
+
+    /// Stop consuming from the current consumer
+    async fn disconnect_consumer(&self, current_consumer: State) -> Result<(), Error> {
+        // Disconnect the current consumer if needed
+        if let Ok(consumer) = self
+            .context
+            .try_policy_action::<action::ConnectedConsumer>(current_consumer.device_id)
+            .await
+        {
+            info!(
+                "Device {}, disconnecting current consumer",
+                current_consumer.device_id.0
+            );
+            // disconnect current consumer and set idle
+            consumer.disconnect().await?;
+        }
+
+        // If no chargers are registered, they won't receive the new power capability.
+        // Also, if chargers return UnpoweredAck, that means the charger isn't powered.
+        // When switching consumers the power rails are enabled again afterwards and thus the charger will get power,
+        // so just continue execution.
+        for node in self.context.chargers().await {
+            let device = node.data::<ChargerDevice>().ok_or(Error::InvalidDevice)?;
+            if let embedded_services::power::policy::charger::ChargerResponseData::UnpoweredAck = self
+                .execute_charger_command(
+                    device,
+                    PolicyEvent::PolicyConfiguration(PowerCapability {
+                        voltage_mv: 0,
+                        current_ma: 0,
+                    }),
+                )
+                .await?
+            {
+                debug!("Charger is unpowered, continuing...");
+            }
+        }
+
+        self.record(history::Event::ConsumerDisconnected(current_consumer.device_id))
+            .await;
+        self.comms_notify(CommsMessage {
+            data: CommsData::ConsumerDisconnected(current_consumer.device_id),
+        })
+        .await;
+        Ok(())
     }
 
     /// Connect to a new consumer
@@ -77,115 +157,83 @@ impl PowerPolicy {
             }
 
             state.current_consumer_state = None;
-            // Disconnect the current consumer if needed
-            if let Ok(consumer) = self
-                .context
-                .try_policy_action::<action::ConnectedConsumer>(current_consumer.device_id)
-                .await
-            {
-                info!(
-                    "Device {}, disconnecting current consumer",
-                    current_consumer.device_id.0
-                );
-                // disconnect current consumer and set idle
-                consumer.disconnect().await?;
-            }
-
-            // If no chargers are registered, they won't receive the new power capability.
-            // Also, if chargers return UnpoweredAck, that means the charger isn't powered.
-            // Further down this fn the power rails are enabled and thus the charger will get power,
-            // so just continue execution.
-            for node in self.context.chargers().await {
-                let device = node.data::<ChargerDevice>().ok_or(Error::InvalidDevice)?;
-                if let embedded_services::power::policy::charger::ChargerResponseData::UnpoweredAck = device
-                    .execute_command(PolicyEvent::PolicyConfiguration(PowerCapability {
-                        voltage_mv: 0,
-                        current_ma: 0,
-                    }))
-                    .await?
-                {
-                    debug!("Charger is unpowered, continuing connect_new_consumer()...");
-                }
-            }
-
-            self.comms_notify(CommsMessage {
-                data: CommsData::ConsumerDisconnected(current_consumer.device_id),
-            })
-            .await;
+            self.disconnect_consumer(current_consumer).await?;
         }
 
         info!("Device {}, connecting new consumer", new_consumer.device_id.0);
+        // Drop confirmations left over from previous connections
+        self.context.reset_sink_ready(new_consumer.device_id).await?;
         if let Ok(idle) = self
             .context
             .try_policy_action::<action::Idle>(new_consumer.device_id)
             .await
         {
             idle.connect_consumer(new_consumer.power_capability).await?;
-            state.current_consumer_state = Some(new_consumer);
-            // todo: review the delay time
-            embassy_time::Timer::after_millis(800).await;
-
-            // If no chargers are registered, they won't receive the new power capability.
-            for node in self.context.chargers().await {
-                let device = node.data::<ChargerDevice>().ok_or(Error::InvalidDevice)?;
-                // Chargers should be powered at this point, but in case they are not...
-                if let embedded_services::power::policy::charger::ChargerResponseData::UnpoweredAck = device
-                    .execute_command(PolicyEvent::PolicyConfiguration(new_consumer.power_capability))
-                    .await?
-                {
-                    // Force charger CheckReady and InitRequest to get it into an initialized state.
-                    // This condition can get hit if we did not have a previous consumer and the charger is unpowered.
-                    info!("Charger is unpowered, forcing charger CheckReady and Init sequence");
-                    check_chargers_ready().await?;
-                    init_chargers().await?;
-                    device
-                        .execute_command(PolicyEvent::PolicyConfiguration(new_consumer.power_capability))
-                        .await?;
-                }
-            }
-            self.comms_notify(CommsMessage {
-                data: CommsData::ConsumerConnected(new_consumer.device_id, new_consumer.power_capability),
-            })
-            .await;
         } else if let Ok(provider) = self
             .context
             .try_policy_action::<action::ConnectedProvider>(new_consumer.device_id)
             .await
         {
             provider.connect_consumer(new_consumer.power_capability).await?;
-            state.current_consumer_state = Some(new_consumer);
-            // todo: review the delay time
-            embassy_time::Timer::after_millis(800).await;
-
-            // If no chargers are registered, they won't receive the new power capability.
-            for node in self.context.chargers().await {
-                let device = node.data::<ChargerDevice>().ok_or(Error::InvalidDevice)?;
-                // Chargers should be powered at this point, but in case they are not...
-                if let embedded_services::power::policy::charger::ChargerResponseData::UnpoweredAck = device
-                    .execute_command(PolicyEvent::PolicyConfiguration(new_consumer.power_capability))
-                    .await?
-                {
-                    // Force charger CheckReady and InitRequest to get it into an initialized state.
-                    // This condition can get hit if we did not have a previous consumer and the charger is unpowered.
-                    info!("Charger is unpowered, forcing charger CheckReady and Init sequence");
-                    check_chargers_ready().await?;
-                    init_chargers().await?;
-                    device
-                        .execute_command(PolicyEvent::PolicyConfiguration(new_consumer.power_capability))
-                        .await?;
-                }
-            }
-            self.comms_notify(CommsMessage {
-                data: CommsData::ConsumerConnected(new_consumer.device_id, new_consumer.power_capability),
-            })
-            .await;
         } else {
             error!("Error obtaining device in idle state");
+            return Ok(());
+        }
+
+        state.current_consumer_state = Some(new_consumer);
+        // Never give up the only source while booting without a battery
+        let fallback = match state.boot {
+            Some(_) => SinkReadyFallback::Proceed,
+            None => self.config.sink_ready.fallback,
+        };
+        if let Err(e) = self.wait_sink_ready(new_consumer, fallback).await {
+            state.current_consumer_state = None;
+            return Err(e);
+        }
+        if let Some(boot) = state.boot.as_mut() {
+            boot.set_input_connected(true);
         }
 
+        self.configure_chargers(state, new_consumer.power_capability).await?;
+        self.record(history::Event::ConsumerConnected(
+            new_consumer.device_id,
+            new_consumer.power_capability,
+        ))
+        .await;
+        self.comms_notify(CommsMessage {
+            data: CommsData::ConsumerConnected(new_consumer.device_id, new_consumer.power_capability),
+        })
+        .await;
+
         Ok(())
     }
 
+    /// Wait for confirmation that a newly connected consumer is supplying power
+    ///
+    /// Either the device enabling its sink path or a charger detecting its power supply confirms the connection.
+    async fn wait_sink_ready(&self, consumer: State, fallback: SinkReadyFallback) -> Result<(), Error> {
+        let device_id = consumer.device_id;
+        let sink_ready = &self.config.sink_ready;
+        match with_timeout(sink_ready.timeout, self.context.wait_sink_ready(device_id)).await {
+            Ok(source) => {
+                let source = source?;
+                debug!("Device {}, sink ready: {:?}", device_id.0, source);
+                Ok(())
+            }
+            Err(TimeoutError) => match fallback {
+                SinkReadyFallback::Proceed => {
+                    warn!("Device {}, no sink ready confirmation, proceeding", device_id.0);
+                    Ok(())
+                }
+                SinkReadyFallback::Disconnect => {
+                    warn!("Device {}, no sink ready confirmation, disconnecting", device_id.0);
+                    self.disconnect_consumer(consumer).await?;
+                    Err(Error::Timeout)
+                }
+            },
+        }
+    }
+
     /// Determines and connects the best external power
     pub(super) async fn update_current_consumer(&self) -> Result<(), Error> {
         let mut guard = self.state.lock().await;
@@ -195,11 +243,18 @@ impl PowerPolicy {
             state.current_consumer_state
         );
 
-        let best_consumer = self.find_highest_power_consumer().await?;
+        let best_consumer = self
+            .find_best_consumer(state.current_consumer_state, state.boot.is_some())
+            .await?;
         info!("Best consumer: {:#?}", best_consumer);
         if best_consumer.is_none() {
-            state.current_consumer_state = None;
             // No new consumer available
+            if let Some(boot) = state.boot.as_mut() {
+                boot.set_input_connected(false);
+            }
+            if let Some(current_consumer) = state.current_consumer_state.take() {
+                self.disconnect_consumer(current_consumer).await?;
+            }
             return Ok(());
         }
         let best_consumer = best_consumer.unwrap();
@@ -0,0 +1,151 @@
+//! This file computes the power available to the system from the adapter contract and battery state.
+//! The adapter power is derated by the charger efficiency. The battery adds its discharge limit on top of the adapter
+//! (hybrid boost) while its state of charge is at least
+//! [min_boost_soc_pct](super::config::EnvelopeConfig::min_boost_soc_pct).
+//!
+//! The envelope is published as `PsrMaxIn`/`PeakPower` to the host and as a [`CommsData::PowerEnvelope`] event so
+//! CPU power limits can be throttled when the adapter is undersized.
+use embedded_services::ec_type::message::BatteryMessage;
+use embedded_services::{debug, trace};
+
+use super::*;
+use crate::config::EnvelopeConfig;
+
+/// Compute the system power envelope
+pub fn compute(
+    config: &EnvelopeConfig,
+    adapter: Option<PowerCapability>,
+    battery: Option<BatteryPowerState>,
+) -> PowerEnvelope {
+    let adapter_mw = adapter.map_or(0, |adapter| {
+        (adapter.max_power_mw() as u64 * config.charger_efficiency_pct.min(100) as u64 / 100) as u32
+    });
+
+    // Without an adapter the battery always powers the system
+    let battery_mw = match battery {
+        Some(battery) if adapter_mw == 0 || battery.state_of_charge_pct >= config.min_boost_soc_pct => {
+            battery.max_discharge_mw
+        }
+        _ => 0,
+    };
+
+    PowerEnvelope {
+        adapter_mw,
+        battery_mw,
+        sustained_mw: if adapter_mw > 0 { adapter_mw } else { battery_mw },
+        peak_mw: adapter_mw.saturating_add(battery_mw),
+        adapter_undersized: adapter_mw > 0 && adapter_mw < config.system_rated_mw,
+    }
+}
+
+impl PowerPolicy {
+    /// Record a new battery state and update the envelope
+    pub(super) async fn process_battery_state(&self, battery: BatteryPowerState) {
+        trace!("Battery state: {:?}", battery);
+        self.state.lock().await.battery_state = Some(battery);
+        self.update_power_envelope().await;
+    }
+
+    /// Recompute the system power envelope and publish it if it changed
+    pub(super) async fn update_power_envelope(&self) {
+        let envelope = {
+            let mut state = self.state.lock().await;
+            let adapter = state.current_consumer_state.map(|consumer| consumer.power_capability());
+            let envelope = compute(&self.config.envelope, adapter, state.battery_state);
+            if state.power_envelope == Some(envelope) {
+                return;
+            }
+            state.power_envelope = Some(envelope);
+            envelope
+        };
+
+        debug!("New power envelope: {:?}", envelope);
+        let _ = self
+            .tp
+            .send(
+                comms::EndpointID::External(comms::External::Host),
+                &BatteryMessage::PsrMaxIn(envelope.sustained_mw),
+            )
+            .await;
+        let _ = self
+            .tp
+            .send(
+                comms::EndpointID::External(comms::External::Host),
+                &BatteryMessage::PeakPower(envelope.peak_mw),
+            )
+            .await;
+        self.comms_notify(CommsMessage {
+            data: CommsData::PowerEnvelope(envelope),
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER_65W: PowerCapability = PowerCapability {
+        voltage_mv: 20000,
+        current_ma: 3250,
+    };
+
+    fn battery(state_of_charge_pct: u8) -> BatteryPowerState {
+        BatteryPowerState {
+            state_of_charge_pct,
+            max_discharge_mw: 40000,
+        }
+    }
+
+    #[test]
+    fn test_adapter_only() {
+        let envelope = compute(&EnvelopeConfig::default(), Some(ADAPTER_65W), None);
+        assert_eq!(envelope.adapter_mw, 58500);
+        assert_eq!(envelope.battery_mw, 0);
+        assert_eq!(envelope.sustained_mw, 58500);
+        assert_eq!(envelope.peak_mw, 58500);
+        assert!(!envelope.adapter_undersized);
+    }
+
+    #[test]
+    fn test_hybrid_boost() {
+        let config = EnvelopeConfig::default();
+        let envelope = compute(&config, Some(ADAPTER_65W), Some(battery(50)));
+        assert_eq!(envelope.sustained_mw, 58500);
+        assert_eq!(envelope.peak_mw, 98500);
+
+        // No boost from a low battery
+        let envelope = compute(&config, Some(ADAPTER_65W), Some(battery(5)));
+        assert_eq!(envelope.battery_mw, 0);
+        assert_eq!(envelope.peak_mw, 58500);
+    }
+
+    #[test]
+    fn test_battery_only() {
+        let envelope = compute(&EnvelopeConfig::default(), None, Some(battery(5)));
+        assert_eq!(envelope.adapter_mw, 0);
+        assert_eq!(envelope.sustained_mw, 40000);
+        assert_eq!(envelope.peak_mw, 40000);
+        assert!(!envelope.adapter_undersized);
+
+        assert_eq!(
+            compute(&EnvelopeConfig::default(), None, None),
+            PowerEnvelope::default()
+        );
+    }
+
+    #[test]
+    fn test_undersized_adapter() {
+        let config = EnvelopeConfig {
+            system_rated_mw: 60000,
+            ..Default::default()
+        };
+        assert!(compute(&config, Some(ADAPTER_65W), None).adapter_undersized);
+
+        let config = EnvelopeConfig {
+            charger_efficiency_pct: 100,
+            ..config
+        };
+        assert!(!compute(&config, Some(ADAPTER_65W), None).adapter_undersized);
+    }
+}
@@ -0,0 +1,382 @@
+//! This file implements the power policy history used for diagnostics.
+//! Every policy request, consumer switch, provider allocation and charger response is recorded with a timestamp in a
+//! bounded buffer, the oldest entries are dropped once it's full. Sending a [`HistoryRequest`] to the policy over comms
+//! answers the requester, typically [`External::Debug`](comms::External::Debug), with the [`History`].
+//!
+//! The most recent entries can be persisted to the [NVRAM sections](super::config::HistoryConfig::nvram_sections)
+//! when `platform_service::reset::system_reset` is called. Only the kind of each event, the device and a timestamp in
+//! seconds fit in an NVRAM word, they are restored as [`Event::Restored`] on the next boot.
+use embassy_time::Instant;
+use embedded_services::power::policy::charger::{
+    ChargerId, ChargerResponse, Device as ChargerDevice, PolicyEvent as ChargerCommand,
+};
+use embedded_services::power::policy::policy::{check_chargers_ready, init_chargers};
+use embedded_services::trace;
+use platform_service::nvram;
+
+use super::*;
+
+/// Number of entries kept in the history
+pub const HISTORY_SIZE: usize = 64;
+
+/// Most NVRAM words the history is persisted to
+const MAX_NVRAM_WORDS: usize = 16;
+
+/// Marks a valid persisted history header
+const NVRAM_MAGIC: u16 = 0x5048;
+
+/// Device ID stored for events that aren't tied to a single device
+const NO_ID: u8 = u8::MAX;
+
+/// Request the power policy history over comms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HistoryRequest;
+
+/// Kind of a recorded event, all that is kept of events restored from NVRAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum EventKind {
+    /// Policy request from a device
+    Request = 1,
+    /// Policy started consuming from a device
+    ConsumerConnected,
+    /// Policy stopped consuming from a device
+    ConsumerDisconnected,
+    /// Provider allocation of a device changed
+    ProviderAllocation,
+    /// Charger completed a command
+    ChargerResponse,
+    /// Charger failed a command
+    ChargerError,
+}
+
+impl EventKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Request,
+            2 => Self::ConsumerConnected,
+            3 => Self::ConsumerDisconnected,
+            4 => Self::ProviderAllocation,
+            5 => Self::ChargerResponse,
+            6 => Self::ChargerError,
+            _ => return None,
+        })
+    }
+}
+
+/// Recorded event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// Policy request from a device
+    Request(DeviceId, policy::RequestData),
+    /// Policy started consuming from a device
+    ConsumerConnected(DeviceId, PowerCapability),
+    /// Policy stopped consuming from a device
+    ConsumerDisconnected(DeviceId),
+    /// Provider allocation of a device, `None` if the budget is exhausted
+    ProviderAllocation(DeviceId, Option<PowerCapability>),
+    /// Charger response to a command, the charger is `None` for commands sent to all chargers
+    ChargerResponse(Option<ChargerId>, ChargerCommand, ChargerResponse),
+    /// Event from before the last reset, with its device ID if any
+    Restored(EventKind, Option<u8>),
+}
+
+impl Event {
+    /// Kind of the event
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Request(..) => EventKind::Request,
+            Event::ConsumerConnected(..) => EventKind::ConsumerConnected,
+            Event::ConsumerDisconnected(_) => EventKind::ConsumerDisconnected,
+            Event::ProviderAllocation(..) => EventKind::ProviderAllocation,
+            Event::ChargerResponse(_, _, Ok(_)) => EventKind::ChargerResponse,
+            Event::ChargerResponse(_, _, Err(_)) => EventKind::ChargerError,
+            Event::Restored(kind, _) => *kind,
+        }
+    }
+
+    /// ID of the device or charger the event is about, if any
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Event::Request(id, _)
+            | Event::ConsumerConnected(id, _)
+            | Event::ConsumerDisconnected(id)
+            | Event::ProviderAllocation(id, _) => Some(id.0),
+            Event::ChargerResponse(id, _, _) => id.map(|id| id.0),
+            Event::Restored(_, id) => *id,
+        }
+    }
+}
+
+/// Timestamped history entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    /// Time since boot in ms, restored entries have the time since the boot they were recorded in
+    pub at_ms: u64,
+    /// Recorded event
+    pub event: Event,
+}
+
+impl Entry {
+    /// Encode the entry into an NVRAM word
+    fn encode(&self) -> u32 {
+        let at_s = (self.at_ms / 1000).min(u16::MAX as u64) as u32;
+        (at_s << 16) | ((self.event.kind() as u32) << 8) | self.event.id().unwrap_or(NO_ID) as u32
+    }
+
+    /// Decode an entry from an NVRAM word
+    fn decode(word: u32) -> Option<Self> {
+        let kind = EventKind::from_u8((word >> 8) as u8)?;
+        let id = word as u8;
+        Some(Self {
+            at_ms: (word >> 16) as u64 * 1000,
+            event: Event::Restored(kind, (id != NO_ID).then_some(id)),
+        })
+    }
+}
+
+/// Bounded power policy history, oldest entry first
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    entries: heapless::Deque<Entry, HISTORY_SIZE>,
+}
+
+impl History {
+    /// Create an empty history
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Deque::new(),
+        }
+    }
+
+    /// Record an event, dropping the oldest entry if the history is full
+    pub fn record(&mut self, at_ms: u64, event: Event) {
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        // Can't fail, there's room now
+        let _ = self.entries.push_back(Entry { at_ms, event });
+    }
+
+    /// Iterate over the entries, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if nothing was recorded
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drop all entries
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Encode a header and the most recent entries into `words`, returns the number of words used
+    fn encode(&self, words: &mut [u32]) -> usize {
+        let Some((header, slots)) = words.split_first_mut() else {
+            return 0;
+        };
+
+        let count = self.entries.len().min(slots.len());
+        let recent = self.entries.iter().skip(self.entries.len() - count);
+        for (slot, entry) in slots.iter_mut().zip(recent) {
+            *slot = entry.encode();
+        }
+        *header = ((NVRAM_MAGIC as u32) << 16) | count as u32;
+        count + 1
+    }
+
+    /// Restore entries encoded by [`Self::encode`], ahead of the entries recorded since boot
+    fn restore(&mut self, words: &[u32]) {
+        let Some((header, slots)) = words.split_first() else {
+            return;
+        };
+        if (header >> 16) as u16 != NVRAM_MAGIC {
+            return;
+        }
+
+        let count = (*header as u16 as usize).min(slots.len());
+        let mut restored = History::new();
+        for entry in slots[..count].iter().filter_map(|word| Entry::decode(*word)) {
+            restored.record(entry.at_ms, entry.event);
+        }
+        for entry in self.entries.iter() {
+            restored.record(entry.at_ms, entry.event);
+        }
+        *self = restored;
+    }
+}
+
+impl PowerPolicy {
+    /// Record an event in the history
+    pub(super) async fn record(&self, event: Event) {
+        trace!("Recording {:?}", event);
+        self.history.lock().await.record(Instant::now().as_millis(), event);
+    }
+
+    /// Send a command to a charger, recording its response
+    pub(super) async fn execute_charger_command(
+        &self,
+        device: &ChargerDevice,
+        command: ChargerCommand,
+    ) -> ChargerResponse {
+        let response = device.execute_command(command).await;
+        self.record(Event::ChargerResponse(Some(device.id()), command, response))
+            .await;
+        response
+    }
+
+    /// Force charger CheckReady and InitRequest on all chargers, recording the responses
+    pub(super) async fn init_all_chargers(&self) -> Result<(), Error> {
+        let response = check_chargers_ready().await;
+        self.record(Event::ChargerResponse(None, ChargerCommand::CheckReady, response))
+            .await;
+        response?;
+
+        let response = init_chargers().await;
+        self.record(Event::ChargerResponse(None, ChargerCommand::InitRequest, response))
+            .await;
+        response?;
+        Ok(())
+    }
+
+    /// Answer a history request received over comms
+    pub(super) async fn process_history_request(&self) {
+        let requester = self.history_requests.receive().await;
+        let history = self.history.lock().await;
+        trace!("Sending {} history entries", history.len());
+        let _ = self.tp.send(requester, &*history).await;
+    }
+
+    /// Persist the most recent history to NVRAM, call before a reset
+    pub(super) async fn persist_history(&self) {
+        let sections = self.config.history.nvram_sections;
+        let mut words = [0u32; MAX_NVRAM_WORDS];
+        let count = self
+            .history
+            .lock()
+            .await
+            .encode(&mut words[..sections.len().min(MAX_NVRAM_WORDS)]);
+
+        for (index, word) in sections.iter().zip(&words[..count]) {
+            match nvram::lookup_section(*index).await {
+                Some(mut section) => section.write(*word),
+                None => error!("Invalid history NVRAM section {}", index),
+            }
+        }
+        info!("Persisted {} history entries", count.saturating_sub(1));
+    }
+
+    /// Restore the history persisted before the last reset
+    pub(super) async fn restore_history(&self) {
+        let sections = self.config.history.nvram_sections;
+        let mut words = [0u32; MAX_NVRAM_WORDS];
+        let count = sections.len().min(MAX_NVRAM_WORDS);
+        for (index, word) in sections.iter().zip(&mut words[..count]) {
+            if let Some(section) = nvram::lookup_section(*index).await {
+                *word = section.read();
+            }
+        }
+
+        self.history.lock().await.restore(&words[..count]);
+
+        // Don't restore the same entries again after a power loss
+        if let Some(index) = sections.first() {
+            if let Some(mut section) = nvram::lookup_section(*index).await {
+                section.write(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_services::power::policy::charger::{ChargerError, ChargerResponseData};
+
+    const CAPABILITY: PowerCapability = PowerCapability {
+        voltage_mv: 20000,
+        current_ma: 3250,
+    };
+
+    #[test]
+    fn test_bounded() {
+        let mut history = History::new();
+        for i in 0..HISTORY_SIZE as u64 + 10 {
+            history.record(i, Event::ConsumerDisconnected(DeviceId(0)));
+        }
+
+        assert_eq!(history.len(), HISTORY_SIZE);
+        // Oldest entries were dropped
+        assert_eq!(history.iter().next().unwrap().at_ms, 10);
+        assert_eq!(history.iter().next_back().unwrap().at_ms, HISTORY_SIZE as u64 + 9);
+    }
+
+    #[test]
+    fn test_kind() {
+        let ok = Event::ChargerResponse(
+            Some(ChargerId(1)),
+            ChargerCommand::PolicyConfiguration(CAPABILITY),
+            Ok(ChargerResponseData::Ack),
+        );
+        assert_eq!(ok.kind(), EventKind::ChargerResponse);
+        assert_eq!(ok.id(), Some(1));
+
+        let err = Event::ChargerResponse(None, ChargerCommand::InitRequest, Err(ChargerError::Timeout));
+        assert_eq!(err.kind(), EventKind::ChargerError);
+        assert_eq!(err.id(), None);
+    }
+
+    #[test]
+    fn test_persist() {
+        let mut history = History::new();
+        history.record(1000, Event::ConsumerConnected(DeviceId(0), CAPABILITY));
+        history.record(2500, Event::Request(DeviceId(1), policy::RequestData::NotifyDetached));
+        history.record(
+            3000,
+            Event::ChargerResponse(None, ChargerCommand::CheckReady, Err(ChargerError::BusError)),
+        );
+
+        // Only room for the two most recent entries
+        let mut words = [0u32; 3];
+        assert_eq!(history.encode(&mut words), 3);
+
+        let mut restored = History::new();
+        restored.record(10, Event::ConsumerDisconnected(DeviceId(2)));
+        restored.restore(&words);
+        let entries: heapless::Vec<Entry, 4> = restored.iter().copied().collect();
+        assert_eq!(
+            entries.as_slice(),
+            &[
+                Entry {
+                    at_ms: 2000,
+                    event: Event::Restored(EventKind::Request, Some(1)),
+                },
+                Entry {
+                    at_ms: 3000,
+                    event: Event::Restored(EventKind::ChargerError, None),
+                },
+                Entry {
+                    at_ms: 10,
+                    event: Event::ConsumerDisconnected(DeviceId(2)),
+                },
+            ]
+        );
+
+        // Nothing valid persisted
+        let mut restored = History::new();
+        restored.restore(&[0, 0, 0]);
+        assert!(restored.is_empty());
+    }
+}
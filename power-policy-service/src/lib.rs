@@ -1,15 +1,28 @@
 #![no_std]
 use core::ops::DerefMut;
+use embassy_futures::join::join3;
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_sync::channel::{Channel, TrySendError};
 use embassy_sync::mutex::Mutex;
 use embassy_sync::once_lock::OnceLock;
+use embassy_sync::signal::Signal;
 use embedded_services::GlobalRawMutex;
+use embedded_services::power::policy::charger::ChargingRequest;
 use embedded_services::power::policy::device::Device;
 use embedded_services::power::policy::{action, policy, *};
 use embedded_services::{comms, error, info};
+use platform_service::reset;
 
+mod boot;
 pub mod config;
 pub mod consumer;
+pub mod envelope;
+pub mod history;
 pub mod provider;
+pub mod sharing;
+#[cfg(test)]
+mod sim;
+mod swap;
 
 pub mod charger;
 
@@ -18,13 +31,22 @@ struct InternalState {
     current_consumer_state: Option<consumer::State>,
     /// Current provider global state
     current_provider_state: provider::State,
+    /// Last reported battery state, if any
+    battery_state: Option<BatteryPowerState>,
+    /// Last published power envelope
+    power_envelope: Option<PowerEnvelope>,
+    /// Boot mode state while booting without a usable battery
+    boot: Option<boot::State>,
 }
 
 impl InternalState {
-    fn new() -> Self {
+    fn new(config: &config::Config) -> Self {
         Self {
             current_consumer_state: None,
             current_provider_state: provider::State::default(),
+            battery_state: None,
+            power_envelope: None,
+            boot: config.boot.map(|_| boot::State::new()),
         }
     }
 }
@@ -39,6 +61,14 @@ pub struct PowerPolicy {
     tp: comms::Endpoint,
     /// Config
     config: config::Config,
+    /// Battery state received over comms
+    battery_state: Signal<GlobalRawMutex, BatteryPowerState>,
+    /// Event and state history
+    history: Mutex<GlobalRawMutex, history::History>,
+    /// Endpoints that requested the history over comms
+    history_requests: Channel<GlobalRawMutex, comms::EndpointID, 1>,
+    /// Charging parameters requested over comms
+    charging_requests: Channel<GlobalRawMutex, ChargingRequest, sharing::MAX_CHARGERS>,
 }
 
 impl PowerPolicy {
@@ -46,9 +76,13 @@ impl PowerPolicy {
     pub fn create(config: config::Config) -> Option<Self> {
         Some(Self {
             context: policy::ContextToken::create()?,
-            state: Mutex::new(InternalState::new()),
+            state: Mutex::new(InternalState::new(&config)),
             tp: comms::Endpoint::uninit(comms::EndpointID::Internal(comms::Internal::Power)),
             config,
+            battery_state: Signal::new(),
+            history: Mutex::new(history::History::new()),
+            history_requests: Channel::new(),
+            charging_requests: Channel::new(),
         })
     }
 
@@ -60,17 +94,33 @@ impl PowerPolicy {
     async fn process_notify_detach(&self) -> Result<(), Error> {
         self.context.send_response(Ok(policy::ResponseData::Complete)).await;
         self.update_current_consumer().await?;
+        self.update_power_envelope().await;
+        // Give the power freed up by the device to the remaining providers
+        self.update_providers(None).await;
         Ok(())
     }
 
-    async fn process_notify_consumer_power_capability(&self) -> Result<(), Error> {
+    async fn process_notify_consumer_power_capability(
+        &self,
+        device: DeviceId,
+        capability: Option<PowerCapability>,
+    ) -> Result<(), Error> {
         self.context.send_response(Ok(policy::ResponseData::Complete)).await;
+        if capability.is_some() && self.swap_to_preferred_role(device, PowerRole::Consumer).await {
+            // The device will request to provide instead
+            return Ok(());
+        }
         self.update_current_consumer().await?;
+        self.update_power_envelope().await;
         Ok(())
     }
 
     async fn process_request_provider_power_capabilities(&self, device: DeviceId) -> Result<(), Error> {
         self.context.send_response(Ok(policy::ResponseData::Complete)).await;
+        if self.swap_to_preferred_role(device, PowerRole::Provider).await {
+            // The device will report what it can consume instead
+            return Ok(());
+        }
         self.connect_provider(device).await;
         Ok(())
     }
@@ -78,6 +128,18 @@ impl PowerPolicy {
     async fn process_notify_disconnect(&self) -> Result<(), Error> {
         self.context.send_response(Ok(policy::ResponseData::Complete)).await;
         self.update_current_consumer().await?;
+        self.update_power_envelope().await;
+        // Give the power freed up by the device to the remaining providers
+        self.update_providers(None).await;
+        Ok(())
+    }
+
+    async fn process_notify_role_swap(&self) -> Result<(), Error> {
+        self.context.send_response(Ok(policy::ResponseData::Complete)).await;
+        // The device may have been the current consumer or a provider, reevaluate both
+        self.update_current_consumer().await?;
+        self.update_power_envelope().await;
+        self.update_providers(None).await;
         Ok(())
     }
 
@@ -95,6 +157,7 @@ impl PowerPolicy {
 
     async fn process_request(&self, request: policy::Request) -> Result<(), Error> {
         let device = self.context.get_device(request.id).await?;
+        self.record(history::Event::Request(request.id, request.data)).await;
 
         match request.data {
             policy::RequestData::NotifyAttached => {
@@ -111,7 +174,8 @@ impl PowerPolicy {
                     device.id().0,
                     capability
                 );
-                self.process_notify_consumer_power_capability().await
+                self.process_notify_consumer_power_capability(device.id(), capability)
+                    .await
             }
             policy::RequestData::RequestProviderCapability(capability) => {
                 info!(
@@ -125,17 +189,59 @@ impl PowerPolicy {
                 info!("Received notify disconnect from device {}", device.id().0);
                 self.process_notify_disconnect().await
             }
+            policy::RequestData::NotifyPowerRoleSwap => {
+                info!("Received notify power role swap from device {}", device.id().0);
+                self.process_notify_role_swap().await
+            }
+            policy::RequestData::NotifyFastRoleSwap(capability) => {
+                info!(
+                    "Received notify fast role swap from device {}: {:#?}",
+                    device.id().0,
+                    capability
+                );
+                self.process_notify_role_swap().await
+            }
         }
     }
 
     /// Top-level event loop function
     pub async fn process(&self) -> Result<(), Error> {
-        let request = self.wait_request().await;
-        self.process_request(request).await
+        match select4(
+            self.wait_request(),
+            self.battery_state.wait(),
+            self.wait_boot_deadline(),
+            select(self.context.wait_charger_state(), self.charging_requests.receive()),
+        )
+        .await
+        {
+            Either4::First(request) => self.process_request(request).await,
+            Either4::Second(battery) => {
+                self.process_battery_state(battery).await;
+                self.process_boot_battery_state(battery).await
+            }
+            Either4::Third(()) => self.process_boot_deadline().await,
+            Either4::Fourth(Either::First(id)) => self.process_charger_state(id).await,
+            Either4::Fourth(Either::Second(request)) => self.process_charging_request(request).await,
+        }
     }
 }
 
-impl comms::MailboxDelegate for PowerPolicy {}
+impl comms::MailboxDelegate for PowerPolicy {
+    fn receive(&self, message: &comms::Message) -> Result<(), comms::MailboxDelegateError> {
+        if let Some(battery) = message.data.get::<BatteryPowerState>() {
+            self.battery_state.signal(*battery);
+        } else if let Some(request) = message.data.get::<ChargingRequest>() {
+            self.charging_requests.try_send(*request).map_err(|e| match e {
+                TrySendError::Full(_) => comms::MailboxDelegateError::BufferFull,
+            })?
+        } else if message.data.is_a::<history::HistoryRequest>() {
+            self.history_requests.try_send(message.from).map_err(|e| match e {
+                TrySendError::Full(_) => comms::MailboxDelegateError::BufferFull,
+            })?
+        }
+        Ok(())
+    }
+}
 
 #[embassy_executor::task]
 pub async fn task(config: config::Config) {
@@ -149,9 +255,36 @@ pub async fn task(config: config::Config) {
         return;
     }
 
-    loop {
-        if let Err(e) = policy.process().await {
-            error!("Error processing request: {:?}", e);
+    // Persist the history across resets if configured
+    static RESET_BLOCKER: reset::Blocker = reset::Blocker::uninit();
+    let persist_history = !policy.config.history.nvram_sections.is_empty();
+    if persist_history {
+        policy.restore_history().await;
+        if RESET_BLOCKER.register().await.is_err() {
+            error!("Failed to register power policy reset blocker");
         }
     }
+
+    join3(
+        async {
+            loop {
+                if let Err(e) = policy.process().await {
+                    error!("Error processing request: {:?}", e);
+                }
+            }
+        },
+        async {
+            loop {
+                policy.process_history_request().await;
+            }
+        },
+        async {
+            if persist_history {
+                loop {
+                    RESET_BLOCKER.wait_for_reset(|| policy.persist_history()).await;
+                }
+            }
+        },
+    )
+    .await;
 }
@@ -1,28 +1,89 @@
 //! This file implements logic to determine how much power to provide to each connected device.
-//! When total provided power is below [limited_power_threshold_mw](super::Config::limited_power_threshold_mw)
-//! the system is in unlimited power state. In this mode up to [provider_unlimited](super::Config::provider_unlimited)
-//! is provided to each device. Above this threshold, the system is in limited power state.
-//! In this mode [provider_limited](super::Config::provider_limited) is provided to each device
-use embedded_services::{debug, trace};
+//! Providers share a total [power budget](super::config::ProviderBudget). Ports are served by priority and then in
+//! the order they connected. Every port is first given up to the per-port minimum, the rest of the budget is then
+//! handed out in the same order up to what each port requested.
+//!
+//! The budget is reallocated whenever a port requests power or a device disconnects. Existing contracts are reduced
+//! to make room for a new port and restored when a port leaves.
+use embedded_services::{debug, trace, warn};
 
 use super::*;
+use crate::config::{ProviderBudget, port_priority};
 
-/// Current system provider power state
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum PowerState {
-    /// System is capable of providing high power
-    #[default]
-    Unlimited,
-    /// System can only provide limited power
-    Limited,
-}
+/// Maximum number of ports that can provide power at the same time
+pub const MAX_PROVIDERS: usize = 8;
 
 /// Power policy provider global state
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Default)]
 pub(super) struct State {
-    /// Current power state
-    state: PowerState,
+    /// Providers in the order they connected
+    order: heapless::Vec<DeviceId, MAX_PROVIDERS>,
+}
+
+/// Allocation of the budget to a single port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Allocation {
+    /// Port device
+    pub device_id: DeviceId,
+    /// Power capability requested by the port
+    pub requested: PowerCapability,
+    /// Port priority
+    pub priority: u8,
+    /// Position in connection order, lower values connected first
+    pub arrival: usize,
+    /// Power capability allocated to the port, `None` if the budget can't cover the per-port minimum
+    pub allocated: Option<PowerCapability>,
+}
+
+/// Limit a requested capability to `max` and `max_mw`, keeping the requested voltage
+fn limit(requested: PowerCapability, max: PowerCapability, max_mw: u32) -> PowerCapability {
+    let current_ma = if requested.voltage_mv == 0 {
+        0
+    } else {
+        (max_mw * 1000 / requested.voltage_mv as u32).min(u16::MAX as u32) as u16
+    };
+
+    PowerCapability {
+        voltage_mv: requested.voltage_mv,
+        current_ma: requested.current_ma.min(max.current_ma).min(current_ma),
+    }
+}
+
+/// Distribute the budget across the requesting ports
+///
+/// `ports` is sorted into service order.
+pub fn allocate(budget: &ProviderBudget, ports: &mut [Allocation]) {
+    ports.sort_unstable_by(|a, b| b.priority.cmp(&a.priority).then(a.arrival.cmp(&b.arrival)));
+    let mut remaining_mw = budget.total_mw;
+
+    // Guarantee the minimum to as many ports as possible
+    for port in ports.iter_mut() {
+        let base = limit(port.requested, budget.min_per_port, budget.min_per_port.max_power_mw());
+        if base.max_power_mw() <= remaining_mw {
+            remaining_mw -= base.max_power_mw();
+            port.allocated = Some(base);
+        } else {
+            port.allocated = None;
+        }
+    }
+
+    // Then hand out the rest
+    for port in ports.iter_mut() {
+        let Some(base) = port.allocated else {
+            continue;
+        };
+
+        let max_mw = budget
+            .max_per_port
+            .max_power_mw()
+            .min(base.max_power_mw() + remaining_mw);
+        let target = limit(port.requested, budget.max_per_port, max_mw);
+        if target > base {
+            remaining_mw -= target.max_power_mw() - base.max_power_mw();
+            port.allocated = Some(target);
+        }
+    }
 }
 
 impl PowerPolicy {
@@ -36,70 +97,245 @@ impl PowerPolicy {
                 return;
             }
         };
-        let requested_power_capability = match requester.requested_provider_capability().await {
-            Some(cap) => cap,
+
+        if requester.requested_provider_capability().await.is_none() {
             // Requester is no longer requesting power
-            _ => {
-                info!("Device{}: No-longer requesting power", requester.id().0);
-                return;
-            }
-        };
-        let mut state = self.state.lock().await;
-        let mut total_power_mw = 0;
+            info!("Device{}: No-longer requesting power", requester.id().0);
+            return;
+        }
+
+        self.update_providers(Some(requester_id)).await;
+    }
+
+    /// Reallocate the provider budget and renegotiate contracts that changed
+    ///
+    /// `requester` is a device requesting to connect as a provider, other devices take part if they are already
+    /// providing power.
+    pub(super) async fn update_providers(&self, requester: Option<DeviceId>) {
+        let mut guard = self.state.lock().await;
+        let state = &mut guard.current_provider_state;
+        let budget = &self.config.provider_budget;
+        let mut ports = heapless::Vec::<Allocation, MAX_PROVIDERS>::new();
 
-        // Determine total requested power draw
         for device in self.context.devices().await.iter_only::<device::Device>() {
-            let target_provider_cap = if device.id() == requester_id {
-                // Use the requester's requested power capability
-                // this handles both new connections and upgrade requests
-                Some(requested_power_capability)
-            } else {
-                // Use the device's current working provider capability
-                device.provider_capability().await
+            let Some(requested) = device.requested_provider_capability().await else {
+                continue;
             };
-            total_power_mw += target_provider_cap.map_or(0, |cap| cap.max_power_mw());
+            if Some(device.id()) != requester && !device.is_provider().await {
+                continue;
+            }
 
-            if total_power_mw > self.config.limited_power_threshold_mw {
-                state.current_provider_state.state = PowerState::Limited;
-            } else {
-                state.current_provider_state.state = PowerState::Unlimited;
+            if !state.order.contains(&device.id()) && state.order.push(device.id()).is_err() {
+                warn!("Device{}: Too many providers", device.id().0);
+                continue;
             }
+
+            let allocation = Allocation {
+                device_id: device.id(),
+                requested,
+                priority: port_priority(budget.priorities, device.id()),
+                arrival: 0,
+                allocated: None,
+            };
+            // Can't overflow, every port is also in the order list
+            let _ = ports.push(allocation);
         }
 
-        debug!("New power state: {:?}", state.current_provider_state.state);
+        // Forget ports that are no longer providing power
+        state.order.retain(|id| ports.iter().any(|port| port.device_id == *id));
+        for port in ports.iter_mut() {
+            port.arrival = state
+                .order
+                .iter()
+                .position(|id| *id == port.device_id)
+                .unwrap_or(usize::MAX);
+        }
 
-        let target_power = match state.current_provider_state.state {
-            PowerState::Limited => self.config.provider_limited,
-            PowerState::Unlimited => {
-                if requested_power_capability.max_power_mw() < self.config.provider_unlimited.max_power_mw() {
-                    // Don't auto upgrade to a higher contract
-                    requested_power_capability
-                } else {
-                    self.config.provider_unlimited
+        allocate(budget, &mut ports);
+        debug!("Provider allocations: {:#?}", ports.as_slice());
+
+        // Reduce contracts before raising others so the budget is never exceeded
+        for reducing in [true, false] {
+            for port in ports.iter() {
+                let Ok(device) = self.context.get_device(port.device_id).await else {
+                    continue;
+                };
+
+                let current = device.provider_capability().await;
+                if current == port.allocated {
+                    continue;
+                }
+
+                let reduction = match (current, port.allocated) {
+                    (Some(current), Some(allocated)) => allocated < current,
+                    (_, None) => true,
+                    (None, Some(_)) => false,
+                };
+                if reduction == reducing {
+                    self.apply_allocation(device, port).await;
                 }
             }
-        };
+        }
+    }
 
-        let connected = if let Ok(action) = self.context.try_policy_action::<action::Idle>(requester.id()).await {
-            let _ = action.connect_provider(target_power).await;
-            Ok(())
-        } else if let Ok(action) = self
-            .context
-            .try_policy_action::<action::ConnectedProvider>(requester.id())
-            .await
-        {
-            let _ = action.connect_provider(target_power).await;
-            Ok(())
-        } else {
-            Err(Error::InvalidState(
-                device::StateKind::Idle,
-                requester.state().await.kind(),
-            ))
+    /// Connect, renegotiate or disconnect a provider to match its allocation
+    async fn apply_allocation(&self, device: &device::Device, port: &Allocation) {
+        let id = port.device_id;
+        self.record(history::Event::ProviderAllocation(id, port.allocated))
+            .await;
+        let result = match port.allocated {
+            Some(capability) => {
+                info!("Device{}: Providing {:#?}", id.0, capability);
+                if let Ok(action) = self.context.try_policy_action::<action::Idle>(id).await {
+                    action.connect_provider(capability).await.map(|_| ())
+                } else if let Ok(action) = self.context.try_policy_action::<action::ConnectedProvider>(id).await {
+                    action.connect_provider(capability).await.map(|_| ())
+                } else {
+                    Err(Error::InvalidState(
+                        device::StateKind::Idle,
+                        device.state().await.kind(),
+                    ))
+                }
+            }
+            None => {
+                warn!("Device{}: Power budget exhausted", id.0);
+                match self.context.try_policy_action::<action::ConnectedProvider>(id).await {
+                    Ok(action) => action.disconnect().await.map(|_| ()),
+                    // Not connected yet, nothing to do
+                    Err(_) => Ok(()),
+                }
+            }
         };
 
         // Don't need to do anything special, the device is responsible for attempting to reconnect
-        if let Err(e) = connected {
-            error!("Device{}: Failed to connect as provider, {:#?}", requester.id().0, e);
+        if let Err(e) = result {
+            error!("Device{}: Failed to update provider contract, {:#?}", id.0, e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn capability(voltage_mv: u16, current_ma: u16) -> PowerCapability {
+        PowerCapability { voltage_mv, current_ma }
+    }
+
+    fn port(id: u8, requested: PowerCapability, priority: u8, arrival: usize) -> Allocation {
+        Allocation {
+            device_id: DeviceId(id),
+            requested,
+            priority,
+            arrival,
+            allocated: None,
+        }
+    }
+
+    fn allocated(ports: &[Allocation], id: u8) -> Option<PowerCapability> {
+        ports.iter().find(|p| p.device_id == DeviceId(id)).unwrap().allocated
+    }
+
+    /// Default budget with a different total
+    fn budget(total_mw: u32) -> ProviderBudget {
+        ProviderBudget {
+            total_mw,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_default_budget() {
+        let budget = ProviderBudget::default();
+
+        // A single port gets 5V@3A
+        let mut ports = [port(0, capability(5000, 3000), 0, 0)];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 3000)));
+
+        // Two ports share 15W
+        let mut ports = [
+            port(0, capability(5000, 3000), 0, 0),
+            port(1, capability(5000, 3000), 0, 1),
+        ];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 1500)));
+        assert_eq!(allocated(&ports, 1), Some(capability(5000, 1500)));
+    }
+
+    #[test]
+    fn test_first_come() {
+        let budget = budget(30000);
+
+        // Single port gets everything it asks for, up to the per-port max
+        let mut ports = [port(0, capability(5000, 3000), 0, 0)];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 3000)));
+
+        // Two ports fit
+        let mut ports = [
+            port(0, capability(5000, 3000), 0, 0),
+            port(1, capability(5000, 3000), 0, 1),
+        ];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 3000)));
+        assert_eq!(allocated(&ports, 1), Some(capability(5000, 3000)));
+
+        // A third port reduces the last one to make room
+        let mut ports = [
+            port(2, capability(5000, 3000), 0, 2),
+            port(0, capability(5000, 3000), 0, 0),
+            port(1, capability(5000, 3000), 0, 1),
+        ];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 3000)));
+        assert_eq!(allocated(&ports, 1), Some(capability(5000, 1500)));
+        assert_eq!(allocated(&ports, 2), Some(capability(5000, 1500)));
+
+        // Low power requests aren't upgraded
+        let mut ports = [port(0, capability(5000, 900), 0, 0)];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 900)));
+    }
+
+    #[test]
+    fn test_priority() {
+        let budget = budget(30000);
+        let mut ports = [
+            port(0, capability(5000, 3000), 0, 0),
+            port(1, capability(5000, 3000), 0, 1),
+            port(2, capability(5000, 3000), 1, 2),
+        ];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 2), Some(capability(5000, 3000)));
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 1500)));
+        assert_eq!(allocated(&ports, 1), Some(capability(5000, 1500)));
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let budget = budget(15000);
+        let mut ports = [
+            port(0, capability(5000, 3000), 0, 0),
+            port(1, capability(5000, 3000), 0, 1),
+            port(2, capability(5000, 3000), 0, 2),
+        ];
+        allocate(&budget, &mut ports);
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 1500)));
+        assert_eq!(allocated(&ports, 1), Some(capability(5000, 1500)));
+        assert_eq!(allocated(&ports, 2), None);
+    }
+
+    #[test]
+    fn test_partial() {
+        let budget = budget(20000);
+        let mut ports = [
+            port(0, capability(5000, 3000), 0, 0),
+            port(1, capability(5000, 3000), 0, 1),
+        ];
+        allocate(&budget, &mut ports);
+        // The first port gets what's left after the minimum for the second
+        assert_eq!(allocated(&ports, 0), Some(capability(5000, 2500)));
+        assert_eq!(allocated(&ports, 1), Some(capability(5000, 1500)));
+    }
+}
@@ -0,0 +1,291 @@
+//! This file implements sharing of the input power between chargers.
+//! The current of the consumer is split between all chargers that aren't faulted, at the consumer voltage. Every
+//! charger is first given up to the [minimum](super::config::ChargerSharing::min_current_ma), the rest is then split
+//! by configured ratio or by the charge power each charger demands.
+//!
+//! The demand of a charger is the charge power of the last [`ChargingRequest`] for it, counted only while it's charging.
+//! The input is shared again whenever a new consumer is connected, whenever a charger changes state and whenever new
+//! charging parameters are requested, so the share of a charger that faults or finishes charging goes to the others.
+//! A faulted charger is configured with no input current before its share is handed out.
+use embedded_services::power::policy::charger::{
+    self, ChargerId, ChargerResponseData, ChargingRequest, ChargingSubstate, Device as ChargerDevice, PolicyEvent,
+    PoweredSubstate,
+};
+use embedded_services::{debug, trace, warn};
+
+use super::*;
+use crate::config::{ChargerSharing, SharingMode};
+
+/// Maximum number of chargers sharing the input
+pub const MAX_CHARGERS: usize = 4;
+
+/// Share of the input given to a single charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Share {
+    /// Charger device
+    pub charger_id: ChargerId,
+    /// Relative weight, a ratio or a charge power demand
+    pub weight: u32,
+    /// Input current allocated to the charger
+    pub current_ma: u16,
+}
+
+/// Split the input current between the chargers
+pub fn split(min_current_ma: u16, input_current_ma: u16, shares: &mut [Share]) {
+    if shares.is_empty() {
+        return;
+    }
+
+    let count = shares.len() as u32;
+    let base = (min_current_ma as u32).min(input_current_ma as u32 / count);
+    let extra = input_current_ma as u32 - base * count;
+    let total_weight: u32 = shares.iter().map(|share| share.weight).sum();
+
+    let mut remaining = extra;
+    for share in shares.iter_mut() {
+        let share_extra = if total_weight == 0 {
+            extra / count
+        } else {
+            (extra as u64 * share.weight as u64 / total_weight as u64) as u32
+        };
+        share.current_ma = (base + share_extra) as u16;
+        remaining -= share_extra;
+    }
+
+    // Rounding leftovers go to the charger with the largest weight
+    if let Some(share) = shares.iter_mut().max_by_key(|share| share.weight) {
+        share.current_ma += remaining as u16;
+    }
+}
+
+/// Weight of a charger
+async fn weight(sharing: &ChargerSharing, device: &ChargerDevice) -> u32 {
+    match sharing.mode {
+        SharingMode::Ratio(ratios) => ratios
+            .iter()
+            .find(|ratio| ratio.charger_id == device.id())
+            .map_or(1, |ratio| ratio.weight as u32),
+        SharingMode::Demand => match device.state().await.state {
+            charger::State::Powered(PoweredSubstate::PsuAttached(
+                ChargingSubstate::PreCharge | ChargingSubstate::FastCharge,
+            )) => device
+                .charging_parameters()
+                .await
+                .map_or(0, |parameters| parameters.power_mw()),
+            _ => 0,
+        },
+    }
+}
+
+impl PowerPolicy {
+    /// Take the input away from faulted chargers that still have a share of it
+    async fn release_faulted_chargers(&self, voltage_mv: u16) -> Result<(), Error> {
+        for node in self.context.chargers().await {
+            let device = node.data::<ChargerDevice>().ok_or(Error::InvalidDevice)?;
+            let state = device.state().await;
+            let faulted = matches!(state.state, charger::State::Powered(PoweredSubstate::Fault(_)));
+            if !faulted || state.capability.is_none() {
+                continue;
+            }
+
+            info!("Charger {:?}: Faulted, releasing its input", device.id());
+            self.execute_charger_command(
+                device,
+                PolicyEvent::PolicyConfiguration(PowerCapability {
+                    voltage_mv,
+                    current_ma: 0,
+                }),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Split the input between the chargers that aren't faulted
+    async fn share_input(&self, input: PowerCapability) -> Result<heapless::Vec<Share, MAX_CHARGERS>, Error> {
+        self.release_faulted_chargers(input.voltage_mv).await?;
+
+        let mut shares = heapless::Vec::new();
+        for node in self.context.chargers().await {
+            let device = node.data::<ChargerDevice>().ok_or(Error::InvalidDevice)?;
+            if let charger::State::Powered(PoweredSubstate::Fault(fault)) = device.state().await.state {
+                debug!(
+                    "Charger {:?} faulted: {:?}, leaving out of input sharing",
+                    device.id(),
+                    fault
+                );
+                continue;
+            }
+
+            let share = Share {
+                charger_id: device.id(),
+                weight: weight(&self.config.charger_sharing, device).await,
+                current_ma: 0,
+            };
+            if shares.push(share).is_err() {
+                warn!("Charger {:?}: Too many chargers", device.id());
+            }
+        }
+
+        split(
+            self.config.charger_sharing.min_current_ma,
+            input.current_ma,
+            &mut shares,
+        );
+        trace!("Charger shares: {:#?}", shares.as_slice());
+        Ok(shares)
+    }
+
+    /// Share the new consumer's capability between the chargers and configure them
+    pub(super) async fn configure_chargers(
+        &self,
+        state: &mut InternalState,
+        capability: PowerCapability,
+    ) -> Result<(), Error> {
+        // If no chargers are registered, they won't receive the new power capability.
+        for share in self.share_input(capability).await? {
+            let device = self.context.get_charger(share.charger_id).await?;
+            let capability = PowerCapability {
+                voltage_mv: capability.voltage_mv,
+                current_ma: share.current_ma,
+            };
+
+            // Chargers should be powered at this point, but in case they are not...
+            if let ChargerResponseData::UnpoweredAck = self
+                .execute_charger_command(device, PolicyEvent::PolicyConfiguration(capability))
+                .await?
+            {
+                if let Some(boot) = state.boot.as_mut() {
+                    // Bring the chargers up once the input power has settled
+                    info!("Charger is unpowered, deferring charger CheckReady and Init sequence");
+                    boot.defer_chargers();
+                    continue;
+                }
+
+                // Force charger CheckReady and InitRequest to get it into an initialized state.
+                // This condition can get hit if we did not have a previous consumer and the charger is unpowered.
+                info!("Charger is unpowered, forcing charger CheckReady and Init sequence");
+                self.init_all_chargers().await?;
+                self.execute_charger_command(device, PolicyEvent::PolicyConfiguration(capability))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward the charging parameters requested for a charger and share the input again by the new demand
+    pub(super) async fn process_charging_request(&self, request: ChargingRequest) -> Result<(), Error> {
+        debug!(
+            "Charger {:?}: Charging requested: {:?}",
+            request.charger_id, request.parameters
+        );
+        let device = self.context.get_charger(request.charger_id).await?;
+        self.execute_charger_command(device, PolicyEvent::ChargingParameters(request.parameters))
+            .await?;
+        self.process_charger_state(request.charger_id).await
+    }
+
+    /// Share the input again after a charger changed state
+    pub(super) async fn process_charger_state(&self, id: ChargerId) -> Result<(), Error> {
+        trace!("Charger {:?} changed state", id);
+        let Some(capability) = self
+            .state
+            .lock()
+            .await
+            .current_consumer_state
+            .map(|consumer| consumer.power_capability())
+        else {
+            return Ok(());
+        };
+
+        for share in self.share_input(capability).await? {
+            let device = self.context.get_charger(share.charger_id).await?;
+            let capability = PowerCapability {
+                voltage_mv: capability.voltage_mv,
+                current_ma: share.current_ma,
+            };
+
+            // Only reconfigure chargers that are ready for it and whose share changed
+            let charger_state = device.state().await;
+            if charger_state.capability == Some(capability)
+                || !matches!(
+                    charger_state.state,
+                    charger::State::Powered(PoweredSubstate::PsuAttached(_) | PoweredSubstate::PsuDetached)
+                )
+            {
+                continue;
+            }
+
+            info!("Charger {:?}: Rebalancing input to {:#?}", device.id(), capability);
+            self.execute_charger_command(device, PolicyEvent::PolicyConfiguration(capability))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share(id: u8, weight: u32) -> Share {
+        Share {
+            charger_id: ChargerId(id),
+            weight,
+            current_ma: 0,
+        }
+    }
+
+    fn currents(shares: &[Share]) -> heapless::Vec<u16, MAX_CHARGERS> {
+        shares.iter().map(|share| share.current_ma).collect()
+    }
+
+    #[test]
+    fn test_single_charger() {
+        let mut shares = [share(0, 1)];
+        split(0, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[3000]);
+
+        // Even without demand
+        let mut shares = [share(0, 0)];
+        split(500, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[3000]);
+    }
+
+    #[test]
+    fn test_ratio() {
+        let mut shares = [share(0, 1), share(1, 1)];
+        split(0, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[1500, 1500]);
+
+        let mut shares = [share(0, 2), share(1, 1)];
+        split(0, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[2000, 1000]);
+
+        // Rounding leftovers aren't lost
+        let mut shares = [share(0, 1), share(1, 1), share(2, 1)];
+        split(0, 3250, &mut shares);
+        assert_eq!(currents(&shares).iter().map(|c| *c as u32).sum::<u32>(), 3250);
+    }
+
+    #[test]
+    fn test_demand() {
+        // A charger that is done only gets the minimum
+        let mut shares = [share(0, 25000), share(1, 0)];
+        split(500, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[2500, 500]);
+
+        // Nobody charging, split evenly
+        let mut shares = [share(0, 0), share(1, 0)];
+        split(500, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[1500, 1500]);
+    }
+
+    #[test]
+    fn test_minimum_exceeds_input() {
+        let mut shares = [share(0, 1), share(1, 0)];
+        split(2000, 3000, &mut shares);
+        assert_eq!(currents(&shares).as_slice(), &[1500, 1500]);
+    }
+}
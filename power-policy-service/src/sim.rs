@@ -0,0 +1,980 @@
+//! Deterministic simulation harness for the power policy
+//!
+//! The harness registers mock power devices and mock chargers with a single policy instance and replays scripted
+//! [`Event`]s against them. Each event is stamped with a simulated time and the policy runs until it has fully
+//! processed the event before the next one is applied, so results only depend on the script and never on real time.
+//! Time is simulated with embassy-time's mock driver, which is advanced to each event's time before it's applied.
+//! Timers the policy waits on while processing an event, like a battery service that doesn't answer, run in
+//! simulated time as well.
+//!
+//! Commands sent to the mocks, comms notifications and policy errors are recorded as [`Observation`]s so scenarios can
+//! assert both the resulting device states and how the policy got there.
+extern crate std;
+
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use std::boxed::Box;
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard, OnceLock as StdOnceLock};
+use std::task::Wake;
+use std::vec::Vec;
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::{Either, select, select_array};
+use embassy_time::{Duration, Instant, MockDriver};
+use embedded_services::power::policy::action::device::AnyState;
+use embedded_services::power::policy::charger::{
+    self, ChargerId, ChargerResponseData, ChargingParameters, ChargingRequest, Device as ChargerDevice,
+    PolicyEvent as ChargerCommand,
+};
+use embedded_services::power::policy::device::{CommandData, ResponseData, State};
+
+use super::*;
+
+/// Number of mock power devices
+pub(crate) const NUM_DEVICES: usize = 3;
+
+/// Number of mock chargers, sharing the input by demand
+pub(crate) const NUM_CHARGERS: usize = 2;
+
+/// Longest the policy may take to process a single event
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Simulated time that passes each time everything is waiting on a timer
+const TICK: Duration = Duration::from_millis(1);
+
+/// How long the input power must be stable in boot mode
+pub(crate) const BOOT_STABLE_INPUT: Duration = Duration::from_millis(50);
+
+/// Port that prefers to consume when its partner is dual-role, like a dock
+pub(crate) const DOCK_PORT: u8 = 2;
+
+/// Scripted event, each event other than [`Event::DualRole`] must result in exactly one policy request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// Attach a detached device
+    Attach(u8),
+    /// Detach an attached device
+    Detach(u8),
+    /// Update the consumer capability of an idle or consuming device
+    ConsumerCapability(u8, Option<PowerCapability>),
+    /// Request to provide power from an idle or providing device
+    RequestProvider(u8, PowerCapability),
+    /// Disconnect a consuming or providing device
+    Disconnect(u8),
+    /// Report whether the port partner supports both power roles
+    DualRole(u8, bool),
+    /// Port partner swapped power roles with a consuming or providing device
+    PowerRoleSwap(u8),
+    /// Consuming device started providing after a fast role swap
+    FastRoleSwap(u8, PowerCapability),
+    /// Report a new battery state
+    Battery(BatteryPowerState),
+    /// Let the policy handle a boot mode deadline that has passed
+    BootDeadline,
+    /// Charger reported a new state, such as a fault or charging done
+    ChargerState(u8, charger::State),
+    /// Request charging parameters for a charger
+    ChargingRequest(u8, ChargingParameters),
+}
+
+/// Event applied at a given simulated time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Step {
+    /// Simulated time in ms
+    pub at_ms: u64,
+    /// Event to apply
+    pub event: Event,
+}
+
+/// Something the harness saw the policy do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Observation {
+    /// Command sent to a mock device
+    Command(DeviceId, CommandData),
+    /// Command sent to a mock charger
+    Charger(ChargerId, ChargerCommand),
+    /// Notification broadcast over comms
+    Comms(CommsData),
+    /// Error returned while processing an event
+    Error(Error),
+}
+
+/// Observation with the simulated time it happened at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Record {
+    /// Simulated time in ms
+    pub at_ms: u64,
+    /// What happened
+    pub observation: Observation,
+}
+
+/// Simulation harness
+pub(crate) struct Sim {
+    policy: PowerPolicy,
+    devices: [Device; NUM_DEVICES],
+    chargers: [ChargerDevice; NUM_CHARGERS],
+    /// The mock chargers answer with [`ChargerResponseData::UnpoweredAck`] until initialized, they're powered together
+    chargers_powered: AtomicBool,
+    /// The next device confirms sink ready instead of the one connected as a consumer
+    misdirected_sink_ready: AtomicBool,
+    /// Receives the policy's comms notifications
+    endpoint: comms::Endpoint,
+    /// Simulated time the scenario started at, the mock driver keeps running across scenarios
+    epoch: StdMutex<Instant>,
+    now_ms: StdMutex<u64>,
+    records: StdMutex<Vec<Record>>,
+}
+
+impl Sim {
+    fn new() -> Self {
+        Self {
+            policy: PowerPolicy::create(config::Config {
+                preferred_roles: &[config::PreferredRole {
+                    device_id: DeviceId(DOCK_PORT),
+                    role: PowerRole::Consumer,
+                }],
+                // Room for two ports at Type-C 5V@3A
+                provider_budget: config::ProviderBudget {
+                    total_mw: 30000,
+                    ..Default::default()
+                },
+                // Scenarios start outside of boot mode, only the initial battery check runs within a scenario
+                boot: Some(config::BootConfig {
+                    stable_input: BOOT_STABLE_INPUT,
+                    battery_poll_interval: Duration::from_secs(3600),
+                    ..Default::default()
+                }),
+                charger_sharing: config::ChargerSharing {
+                    mode: config::SharingMode::Demand,
+                    min_current_ma: 500,
+                },
+                sink_ready: config::SinkReadyConfig {
+                    fallback: config::SinkReadyFallback::Disconnect,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .expect("Power policy already created"),
+            devices: core::array::from_fn(|i| Device::new(DeviceId(i as u8))),
+            chargers: core::array::from_fn(|i| ChargerDevice::new(ChargerId(i as u8))),
+            chargers_powered: AtomicBool::new(true),
+            misdirected_sink_ready: AtomicBool::new(false),
+            endpoint: comms::Endpoint::uninit(comms::EndpointID::Internal(comms::Internal::Battery)),
+            epoch: StdMutex::new(Instant::from_ticks(0)),
+            now_ms: StdMutex::new(0),
+            records: StdMutex::new(Vec::new()),
+        }
+    }
+
+    async fn register(&'static self) {
+        embedded_services::init().await;
+        comms::register_endpoint(&self.policy, &self.policy.tp).await.unwrap();
+        comms::register_endpoint(self, &self.endpoint).await.unwrap();
+        for device in &self.devices {
+            policy::register_device(device).await.unwrap();
+        }
+        for charger in &self.chargers {
+            policy::register_charger(charger).await.unwrap();
+        }
+    }
+
+    /// Get exclusive access to the harness, reset to all devices detached
+    ///
+    /// The policy context is a singleton so every scenario shares the same harness.
+    pub(crate) fn get() -> (MutexGuard<'static, ()>, &'static Sim) {
+        static LOCK: StdMutex<()> = StdMutex::new(());
+        static SIM: StdOnceLock<&'static Sim> = StdOnceLock::new();
+
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let sim = *SIM.get_or_init(|| {
+            let sim = Box::leak(Box::new(Sim::new()));
+            block_on(sim.register());
+            sim
+        });
+        sim.reset();
+        (guard, sim)
+    }
+
+    fn reset(&'static self) {
+        block_on(self.policy.state.lock()).boot = None;
+        // Drop charger state changes left by the charger wrapper tests, no policy was processing them
+        let _ = block_on(select(
+            self.policy.context.wait_charger_state(),
+            core::future::ready(()),
+        ));
+        self.chargers_powered.store(true, Ordering::SeqCst);
+        self.misdirected_sink_ready.store(false, Ordering::SeqCst);
+        for charger in &self.chargers {
+            block_on(charger.set_state(charger::InternalState {
+                state: charger::State::Unpowered,
+                capability: None,
+            }));
+        }
+        for device in &self.devices {
+            if block_on(device.state()) != State::Detached {
+                self.step(Event::Detach(device.id().0));
+            }
+            block_on(device.notify_dual_role(false));
+        }
+        self.start_clock();
+        self.records.lock().unwrap().clear();
+        block_on(self.policy.history.lock()).clear();
+    }
+
+    /// Start the scenario clock at the current simulated time
+    fn start_clock(&self) {
+        *self.epoch.lock().unwrap() = Instant::now();
+        *self.now_ms.lock().unwrap() = 0;
+    }
+
+    /// Boot without a usable battery, returns once the initial battery check has been handled
+    ///
+    /// The battery service isn't running so the check takes until the query times out, the scenario starts after it.
+    pub(crate) fn enter_boot_mode(&'static self) {
+        block_on(self.policy.state.lock()).boot = Some(boot::State::new());
+        self.step(Event::BootDeadline);
+        self.start_clock();
+    }
+
+    /// Set whether the mock chargers are powered, unpowered chargers are powered by an `InitRequest`
+    pub(crate) fn set_chargers_powered(&self, powered: bool) {
+        self.chargers_powered.store(powered, Ordering::SeqCst);
+    }
+
+    /// Set whether sink ready is confirmed by the wrong device, so the connecting device never confirms
+    pub(crate) fn set_misdirected_sink_ready(&self, misdirected: bool) {
+        self.misdirected_sink_ready.store(misdirected, Ordering::SeqCst);
+    }
+
+    fn record(&self, observation: Observation) {
+        let at_ms = *self.now_ms.lock().unwrap();
+        self.records.lock().unwrap().push(Record { at_ms, observation });
+    }
+
+    fn device(&self, id: u8) -> &Device {
+        &self.devices[id as usize]
+    }
+
+    fn charger(&self, id: u8) -> &ChargerDevice {
+        &self.chargers[id as usize]
+    }
+
+    /// Run a script, steps must be in time order
+    pub(crate) fn run(&'static self, script: &[Step]) {
+        for step in script {
+            {
+                let mut now_ms = self.now_ms.lock().unwrap();
+                assert!(step.at_ms >= *now_ms, "Step {step:?} is out of order");
+                *now_ms = step.at_ms;
+            }
+            // Time might already be past the step if the policy waited on a timer while processing the previous one
+            let at = *self.epoch.lock().unwrap() + Duration::from_millis(step.at_ms);
+            let now = Instant::now();
+            if at > now {
+                MockDriver::get().advance(at - now);
+            }
+            self.step(step.event);
+        }
+    }
+
+    /// Apply a single event and let the policy process it
+    fn step(&'static self, event: Event) {
+        if let Event::DualRole(id, dual_role) = event {
+            block_on(self.device(id).notify_dual_role(dual_role));
+            return;
+        }
+
+        let process = join(self.apply(event), async {
+            if let Err(e) = self.policy.process().await {
+                self.record(Observation::Error(e));
+            }
+        });
+
+        if block_on_sim(STEP_TIMEOUT, select(process, self.serve())).is_none() {
+            panic!("Policy didn't process {event:?}");
+        }
+    }
+
+    /// Apply an event to the mocks
+    async fn apply(&self, event: Event) {
+        let result = match event {
+            Event::Attach(id) => match self.device(id).device_action().await {
+                AnyState::Detached(device) => device.attach().await.map(|_| ()),
+                state => panic!("Device{} can't attach in {:?}", id, state.kind()),
+            },
+            Event::Detach(id) => self.device(id).detach().await.map(|_| ()),
+            Event::ConsumerCapability(id, capability) => match self.device(id).device_action().await {
+                AnyState::Idle(device) => device.notify_consumer_power_capability(capability).await,
+                AnyState::ConnectedConsumer(device) => device.notify_consumer_power_capability(capability).await,
+                state => panic!("Device{} can't consume in {:?}", id, state.kind()),
+            },
+            Event::RequestProvider(id, capability) => match self.device(id).device_action().await {
+                AnyState::Idle(device) => device.request_provider_power_capability(capability).await,
+                AnyState::ConnectedProvider(device) => device.request_provider_power_capability(capability).await,
+                state => panic!("Device{} can't provide in {:?}", id, state.kind()),
+            },
+            Event::Disconnect(id) => match self.device(id).device_action().await {
+                AnyState::ConnectedConsumer(device) => device.disconnect().await.map(|_| ()),
+                AnyState::ConnectedProvider(device) => device.disconnect().await.map(|_| ()),
+                state => panic!("Device{} can't disconnect in {:?}", id, state.kind()),
+            },
+            Event::PowerRoleSwap(id) => match self.device(id).device_action().await {
+                AnyState::ConnectedConsumer(device) => device.notify_power_role_swap().await.map(|_| ()),
+                AnyState::ConnectedProvider(device) => device.notify_power_role_swap().await.map(|_| ()),
+                state => panic!("Device{} can't swap in {:?}", id, state.kind()),
+            },
+            Event::FastRoleSwap(id, capability) => match self.device(id).device_action().await {
+                AnyState::ConnectedConsumer(device) => device.notify_fast_role_swap(capability).await.map(|_| ()),
+                state => panic!("Device{} can't fast role swap in {:?}", id, state.kind()),
+            },
+            Event::DualRole(..) => unreachable!(),
+            Event::Battery(battery) => {
+                let _ = comms::send(
+                    comms::EndpointID::Internal(comms::Internal::Battery),
+                    comms::EndpointID::Internal(comms::Internal::Power),
+                    &battery,
+                )
+                .await;
+                Ok(())
+            }
+            // Nothing to apply, the policy wakes up on its own
+            Event::BootDeadline => Ok(()),
+            Event::ChargerState(id, new_state) => {
+                let charger = self.charger(id);
+                let state = charger.state().await;
+                charger
+                    .set_state(charger::InternalState {
+                        state: new_state,
+                        ..state
+                    })
+                    .await;
+                policy::notify_charger_state(charger.id()).await;
+                Ok(())
+            }
+            Event::ChargingRequest(id, parameters) => {
+                let _ = comms::send(
+                    comms::EndpointID::Internal(comms::Internal::Battery),
+                    comms::EndpointID::Internal(comms::Internal::Power),
+                    &ChargingRequest {
+                        charger_id: ChargerId(id),
+                        parameters,
+                    },
+                )
+                .await;
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            panic!("Failed to apply {event:?}: {e:?}");
+        }
+    }
+
+    /// Respond to commands sent to the mocks, never returns
+    async fn serve(&self) {
+        loop {
+            let devices = select_array(core::array::from_fn::<_, NUM_DEVICES, _>(|i| self.devices[i].receive()));
+            let chargers = select_array(core::array::from_fn::<_, NUM_CHARGERS, _>(|i| {
+                self.chargers[i].wait_command()
+            }));
+            match select(devices, chargers).await {
+                Either::First((request, i)) => {
+                    let device = &self.devices[i];
+                    self.record(Observation::Command(device.id(), request.command));
+                    if let CommandData::ConnectAsConsumer(_) = request.command {
+                        let confirming = match self.misdirected_sink_ready.load(Ordering::SeqCst) {
+                            true => &self.devices[(i + 1) % NUM_DEVICES],
+                            false => device,
+                        };
+                        confirming.notify_sink_ready().await;
+                    }
+                    request.respond(Ok(ResponseData::Complete));
+                }
+                Either::Second((command, i)) => {
+                    let charger = &self.chargers[i];
+                    self.record(Observation::Charger(charger.id(), command));
+                    let response = match command {
+                        ChargerCommand::InitRequest => {
+                            self.chargers_powered.store(true, Ordering::SeqCst);
+                            ChargerResponseData::Ack
+                        }
+                        ChargerCommand::PolicyConfiguration(_) if !self.chargers_powered.load(Ordering::SeqCst) => {
+                            ChargerResponseData::UnpoweredAck
+                        }
+                        ChargerCommand::PolicyConfiguration(capability) => {
+                            // Track the capability like the charger wrapper, the charger state itself is scripted
+                            let state = charger.state().await;
+                            charger
+                                .set_state(charger::InternalState {
+                                    capability: (capability.current_ma > 0).then_some(capability),
+                                    ..state
+                                })
+                                .await;
+                            ChargerResponseData::Ack
+                        }
+                        _ => ChargerResponseData::Ack,
+                    };
+                    charger.send_response(Ok(response)).await;
+                }
+            }
+        }
+    }
+
+    /// Current state of a device
+    pub(crate) fn state(&self, id: u8) -> State {
+        block_on(self.device(id).state())
+    }
+
+    /// Everything recorded since the harness was reset
+    pub(crate) fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Recorded observations matching `filter`
+    pub(crate) fn observations(&self, filter: impl Fn(&Observation) -> bool) -> Vec<Observation> {
+        self.records()
+            .into_iter()
+            .map(|record| record.observation)
+            .filter(filter)
+            .collect()
+    }
+}
+
+/// Waker that records being woken
+struct WakeFlag(AtomicBool);
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Run a future to completion in simulated time
+///
+/// Nothing else advances the mock driver, so a future that is pending without having been woken is waiting on a timer.
+/// Simulated time then passes in [`TICK`]s until a timer wakes it. Returns `None` if it didn't complete within `timeout`.
+pub(crate) fn block_on_sim<F: Future>(timeout: Duration, future: F) -> Option<F::Output> {
+    let woken = Arc::new(WakeFlag(AtomicBool::new(true)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if woken.0.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return Some(output);
+            }
+        } else if Instant::now() >= deadline {
+            return None;
+        } else {
+            MockDriver::get().advance(TICK);
+        }
+    }
+}
+
+impl comms::MailboxDelegate for Sim {
+    fn receive(&self, message: &comms::Message) -> Result<(), comms::MailboxDelegateError> {
+        if let Some(message) = message.data.get::<CommsMessage>() {
+            self.record(Observation::Comms(message.data));
+        }
+        Ok(())
+    }
+}
+
+mod tests {
+    use super::*;
+
+    const LOW_POWER: PowerCapability = PowerCapability {
+        voltage_mv: 5000,
+        current_ma: 1500,
+    };
+
+    const HIGH_POWER: PowerCapability = PowerCapability {
+        voltage_mv: 5000,
+        current_ma: 3000,
+    };
+
+    const ADAPTER_65W: PowerCapability = PowerCapability {
+        voltage_mv: 20000,
+        current_ma: 3250,
+    };
+
+    const fn at(at_ms: u64, event: Event) -> Step {
+        Step { at_ms, event }
+    }
+
+    fn is_consumer_event(observation: &Observation) -> bool {
+        matches!(
+            observation,
+            Observation::Comms(CommsData::ConsumerConnected(..) | CommsData::ConsumerDisconnected(_))
+        )
+    }
+
+    #[test]
+    fn test_consumer_switching() {
+        let (_guard, sim) = Sim::get();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(LOW_POWER))),
+            at(100, Event::Attach(1)),
+            at(100, Event::ConsumerCapability(1, Some(HIGH_POWER))),
+        ]);
+        assert_eq!(sim.state(0), State::Idle);
+        assert_eq!(sim.state(1), State::ConnectedConsumer(HIGH_POWER));
+
+        // Losing the current consumer falls back to the remaining one
+        sim.run(&[at(200, Event::Detach(1))]);
+        assert_eq!(sim.state(0), State::ConnectedConsumer(LOW_POWER));
+
+        assert_eq!(
+            sim.observations(is_consumer_event),
+            [
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(0), LOW_POWER)),
+                Observation::Comms(CommsData::ConsumerDisconnected(DeviceId(0))),
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(1), HIGH_POWER)),
+                Observation::Comms(CommsData::ConsumerDisconnected(DeviceId(1))),
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(0), LOW_POWER)),
+            ]
+        );
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Command(DeviceId(0), _))),
+            [
+                Observation::Command(DeviceId(0), CommandData::ConnectAsConsumer(LOW_POWER)),
+                Observation::Command(DeviceId(0), CommandData::Disconnect),
+                Observation::Command(DeviceId(0), CommandData::ConnectAsConsumer(LOW_POWER)),
+            ]
+        );
+        // The chargers follow every consumer change, splitting the input evenly while neither is charging
+        for id in 0..NUM_CHARGERS as u8 {
+            assert_eq!(
+                sim.observations(|o| matches!(o, Observation::Charger(charger, _) if charger.0 == id))
+                    .last(),
+                Some(&Observation::Charger(
+                    ChargerId(id),
+                    ChargerCommand::PolicyConfiguration(PowerCapability {
+                        voltage_mv: 5000,
+                        current_ma: 750,
+                    })
+                ))
+            );
+        }
+        assert!(sim.observations(|o| matches!(o, Observation::Error(_))).is_empty());
+    }
+
+    #[test]
+    fn test_sink_ready_timeout() {
+        let (_guard, sim) = Sim::get();
+        // Another device confirming doesn't count for the new consumer
+        sim.set_misdirected_sink_ready(true);
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(LOW_POWER))),
+        ]);
+        assert_eq!(sim.state(0), State::Idle);
+        assert_eq!(
+            sim.observations(|o| {
+                is_consumer_event(o) || matches!(o, Observation::Command(..) | Observation::Error(_))
+            }),
+            [
+                Observation::Command(DeviceId(0), CommandData::ConnectAsConsumer(LOW_POWER)),
+                Observation::Command(DeviceId(0), CommandData::Disconnect),
+                Observation::Comms(CommsData::ConsumerDisconnected(DeviceId(0))),
+                Observation::Error(Error::Timeout),
+            ]
+        );
+        // The chargers are left without input
+        let no_input = ChargerCommand::PolicyConfiguration(PowerCapability {
+            voltage_mv: 0,
+            current_ma: 0,
+        });
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Charger(..))),
+            [
+                Observation::Charger(ChargerId(0), no_input),
+                Observation::Charger(ChargerId(1), no_input),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let (_guard, sim) = Sim::get();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(LOW_POWER))),
+            at(100, Event::Detach(0)),
+        ]);
+
+        let history = block_on(sim.policy.history.lock());
+        let consumer_events: Vec<_> = history
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    entry.event,
+                    history::Event::ConsumerConnected(..) | history::Event::ConsumerDisconnected(_)
+                )
+            })
+            .map(|entry| entry.event)
+            .collect();
+        assert_eq!(
+            consumer_events,
+            [
+                history::Event::ConsumerConnected(DeviceId(0), LOW_POWER),
+                history::Event::ConsumerDisconnected(DeviceId(0)),
+            ]
+        );
+        // Every request is recorded, in order
+        assert_eq!(
+            history
+                .iter()
+                .find(|entry| matches!(entry.event, history::Event::Request(..)))
+                .map(|entry| entry.event),
+            Some(history::Event::Request(
+                DeviceId(0),
+                policy::RequestData::NotifyAttached
+            ))
+        );
+    }
+
+    #[test]
+    fn test_provider_budget() {
+        let (_guard, sim) = Sim::get();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::RequestProvider(0, HIGH_POWER)),
+            at(10, Event::Attach(1)),
+            at(10, Event::RequestProvider(1, HIGH_POWER)),
+        ]);
+        assert_eq!(sim.state(0), State::ConnectedProvider(HIGH_POWER));
+        assert_eq!(sim.state(1), State::ConnectedProvider(HIGH_POWER));
+
+        // A third port exceeds the budget, the latest port makes room
+        sim.run(&[at(20, Event::Attach(2)), at(20, Event::RequestProvider(2, HIGH_POWER))]);
+        assert_eq!(sim.state(0), State::ConnectedProvider(HIGH_POWER));
+        assert_eq!(sim.state(1), State::ConnectedProvider(LOW_POWER));
+        assert_eq!(sim.state(2), State::ConnectedProvider(LOW_POWER));
+
+        // Power freed by a detach is handed back
+        sim.run(&[at(30, Event::Detach(0))]);
+        assert_eq!(sim.state(1), State::ConnectedProvider(HIGH_POWER));
+        assert_eq!(sim.state(2), State::ConnectedProvider(HIGH_POWER));
+
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Command(DeviceId(1), _))),
+            [
+                Observation::Command(DeviceId(1), CommandData::ConnectAsProvider(HIGH_POWER)),
+                Observation::Command(DeviceId(1), CommandData::ConnectAsProvider(LOW_POWER)),
+                Observation::Command(DeviceId(1), CommandData::ConnectAsProvider(HIGH_POWER)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preferred_role() {
+        let (_guard, sim) = Sim::get();
+        let dock = DOCK_PORT;
+        sim.run(&[
+            at(0, Event::Attach(dock)),
+            at(0, Event::DualRole(dock, true)),
+            // The dock asks to be powered, the policy swaps it to be the source instead
+            at(0, Event::RequestProvider(dock, HIGH_POWER)),
+        ]);
+        assert_eq!(sim.state(dock), State::Idle);
+
+        sim.run(&[at(10, Event::ConsumerCapability(dock, Some(ADAPTER_65W)))]);
+        assert_eq!(sim.state(dock), State::ConnectedConsumer(ADAPTER_65W));
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Command(..))),
+            [
+                Observation::Command(DeviceId(dock), CommandData::PowerRoleSwap(PowerRole::Consumer)),
+                Observation::Command(DeviceId(dock), CommandData::ConnectAsConsumer(ADAPTER_65W)),
+            ]
+        );
+
+        // Ports that aren't dual-role keep their role
+        sim.run(&[at(20, Event::Detach(dock)), at(30, Event::Attach(dock))]);
+        sim.run(&[at(30, Event::RequestProvider(dock, HIGH_POWER))]);
+        assert_eq!(sim.state(dock), State::ConnectedProvider(HIGH_POWER));
+    }
+
+    #[test]
+    fn test_partner_role_swap() {
+        let (_guard, sim) = Sim::get();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(HIGH_POWER))),
+            at(10, Event::Attach(1)),
+            at(10, Event::RequestProvider(1, HIGH_POWER)),
+        ]);
+        assert_eq!(sim.state(0), State::ConnectedConsumer(HIGH_POWER));
+        assert_eq!(sim.state(1), State::ConnectedProvider(HIGH_POWER));
+
+        // Partner swap on the provider, then the port reports its new role
+        sim.run(&[
+            at(20, Event::PowerRoleSwap(1)),
+            at(20, Event::ConsumerCapability(1, Some(LOW_POWER))),
+        ]);
+        assert_eq!(sim.state(1), State::Idle);
+        assert_eq!(sim.state(0), State::ConnectedConsumer(HIGH_POWER));
+
+        // Fast role swap on the consumer, the only remaining source is the swapped port
+        sim.run(&[at(30, Event::FastRoleSwap(0, LOW_POWER))]);
+        assert_eq!(sim.state(0), State::ConnectedProvider(LOW_POWER));
+        assert_eq!(sim.state(1), State::ConnectedConsumer(LOW_POWER));
+        assert_eq!(
+            sim.observations(is_consumer_event),
+            [
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(0), HIGH_POWER)),
+                Observation::Comms(CommsData::ConsumerDisconnected(DeviceId(0))),
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(1), LOW_POWER)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_power_envelope() {
+        let (_guard, sim) = Sim::get();
+        sim.run(&[
+            at(
+                0,
+                Event::Battery(BatteryPowerState {
+                    state_of_charge_pct: 50,
+                    max_discharge_mw: 40000,
+                }),
+            ),
+            at(100, Event::Attach(0)),
+            at(100, Event::ConsumerCapability(0, Some(ADAPTER_65W))),
+            at(
+                200,
+                Event::Battery(BatteryPowerState {
+                    state_of_charge_pct: 5,
+                    max_discharge_mw: 40000,
+                }),
+            ),
+        ]);
+
+        let envelopes: Vec<_> = sim
+            .records()
+            .into_iter()
+            .filter(|record| record.at_ms >= 100)
+            .filter_map(|record| match record.observation {
+                Observation::Comms(CommsData::PowerEnvelope(envelope)) => Some((record.at_ms, envelope.peak_mw)),
+                _ => None,
+            })
+            .collect();
+        // Adapter and battery boost, then the adapter alone once the battery is low
+        assert_eq!(envelopes, [(100, 98500), (200, 58500)]);
+    }
+
+    #[test]
+    fn test_boot_first_source() {
+        let (_guard, sim) = Sim::get();
+        sim.enter_boot_mode();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(LOW_POWER))),
+            // A better source doesn't pull the system off the one keeping it running
+            at(100, Event::Attach(1)),
+            at(100, Event::ConsumerCapability(1, Some(ADAPTER_65W))),
+        ]);
+        assert_eq!(sim.state(0), State::ConnectedConsumer(LOW_POWER));
+        assert_eq!(sim.state(1), State::Idle);
+
+        // Losing the source takes the next available one, which is then kept as well
+        sim.run(&[
+            at(200, Event::Detach(0)),
+            at(300, Event::Attach(0)),
+            at(300, Event::ConsumerCapability(0, Some(ADAPTER_65W))),
+        ]);
+        assert_eq!(sim.state(0), State::Idle);
+        assert_eq!(sim.state(1), State::ConnectedConsumer(ADAPTER_65W));
+
+        assert_eq!(
+            sim.observations(is_consumer_event),
+            [
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(0), LOW_POWER)),
+                Observation::Comms(CommsData::ConsumerDisconnected(DeviceId(0))),
+                Observation::Comms(CommsData::ConsumerConnected(DeviceId(1), ADAPTER_65W)),
+            ]
+        );
+        // The kept sources are never renegotiated
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Command(_, CommandData::ConnectAsConsumer(_)))),
+            [
+                Observation::Command(DeviceId(0), CommandData::ConnectAsConsumer(LOW_POWER)),
+                Observation::Command(DeviceId(1), CommandData::ConnectAsConsumer(ADAPTER_65W)),
+            ]
+        );
+        assert!(sim.observations(|o| matches!(o, Observation::Error(_))).is_empty());
+    }
+
+    #[test]
+    fn test_boot_deferred_chargers() {
+        let (_guard, sim) = Sim::get();
+        sim.enter_boot_mode();
+        sim.set_chargers_powered(false);
+
+        let connected = Instant::now();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(HIGH_POWER))),
+        ]);
+        assert_eq!(sim.state(0), State::ConnectedConsumer(HIGH_POWER));
+        // The unpowered chargers aren't brought up while the input might still bounce
+        let share = ChargerCommand::PolicyConfiguration(PowerCapability {
+            voltage_mv: 5000,
+            current_ma: 1500,
+        });
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Charger(..))),
+            [
+                Observation::Charger(ChargerId(0), share),
+                Observation::Charger(ChargerId(1), share),
+            ]
+        );
+
+        sim.run(&[at(50, Event::BootDeadline)]);
+        assert!(connected.elapsed() >= BOOT_STABLE_INPUT);
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Charger(ChargerId(0), _))),
+            [
+                Observation::Charger(ChargerId(0), share),
+                Observation::Charger(ChargerId(0), ChargerCommand::CheckReady),
+                Observation::Charger(ChargerId(0), ChargerCommand::InitRequest),
+                Observation::Charger(ChargerId(0), share),
+            ]
+        );
+        assert!(sim.observations(|o| matches!(o, Observation::Error(_))).is_empty());
+    }
+
+    #[test]
+    fn test_boot_exit() {
+        let (_guard, sim) = Sim::get();
+        sim.enter_boot_mode();
+        sim.run(&[
+            at(0, Event::Attach(0)),
+            at(0, Event::ConsumerCapability(0, Some(LOW_POWER))),
+            at(100, Event::Attach(1)),
+            at(100, Event::ConsumerCapability(1, Some(ADAPTER_65W))),
+            // Not enough charge to run from the battery yet
+            at(
+                200,
+                Event::Battery(BatteryPowerState {
+                    state_of_charge_pct: 2,
+                    max_discharge_mw: 40000,
+                }),
+            ),
+        ]);
+        assert!(block_on(sim.policy.in_boot_mode()));
+        assert_eq!(sim.state(0), State::ConnectedConsumer(LOW_POWER));
+
+        // A usable battery ends boot mode and the best source is selected again
+        sim.run(&[at(
+            300,
+            Event::Battery(BatteryPowerState {
+                state_of_charge_pct: 50,
+                max_discharge_mw: 40000,
+            }),
+        )]);
+        assert!(!block_on(sim.policy.in_boot_mode()));
+        assert_eq!(sim.state(0), State::Idle);
+        assert_eq!(sim.state(1), State::ConnectedConsumer(ADAPTER_65W));
+        assert_eq!(
+            sim.records()
+                .into_iter()
+                .filter(|record| is_consumer_event(&record.observation))
+                .map(|record| (record.at_ms, record.observation))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    0,
+                    Observation::Comms(CommsData::ConsumerConnected(DeviceId(0), LOW_POWER))
+                ),
+                (300, Observation::Comms(CommsData::ConsumerDisconnected(DeviceId(0)))),
+                (
+                    300,
+                    Observation::Comms(CommsData::ConsumerConnected(DeviceId(1), ADAPTER_65W))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_charger_sharing() {
+        use charger::{ChargerFault, ChargingSubstate, PoweredSubstate};
+
+        const DEMAND_HIGH: ChargingParameters = ChargingParameters {
+            current_ma: 2000,
+            voltage_mv: 12600,
+        };
+        const DEMAND_LOW: ChargingParameters = ChargingParameters {
+            current_ma: 1000,
+            voltage_mv: 12600,
+        };
+        const CHARGING: charger::State =
+            charger::State::Powered(PoweredSubstate::PsuAttached(ChargingSubstate::FastCharge));
+
+        let (_guard, sim) = Sim::get();
+        sim.run(&[
+            at(0, Event::ChargerState(0, CHARGING)),
+            at(0, Event::ChargerState(1, CHARGING)),
+            // Charger 0 asks for twice the power of charger 1
+            at(0, Event::ChargingRequest(0, DEMAND_HIGH)),
+            at(0, Event::ChargingRequest(1, DEMAND_LOW)),
+            at(100, Event::Attach(0)),
+            at(100, Event::ConsumerCapability(0, Some(ADAPTER_65W))),
+            // Charger 1 finishes, only keeping the minimum
+            at(
+                200,
+                Event::ChargerState(
+                    1,
+                    charger::State::Powered(PoweredSubstate::PsuAttached(ChargingSubstate::Done)),
+                ),
+            ),
+            // Charger 0 faults, charger 1 gets everything
+            at(
+                300,
+                Event::ChargerState(
+                    0,
+                    charger::State::Powered(PoweredSubstate::Fault(ChargerFault::Hardware)),
+                ),
+            ),
+        ]);
+
+        let shares: Vec<_> = sim
+            .records()
+            .into_iter()
+            .filter_map(|record| match record.observation {
+                Observation::Charger(id, ChargerCommand::PolicyConfiguration(capability)) => {
+                    assert_eq!(capability.voltage_mv, ADAPTER_65W.voltage_mv);
+                    Some((record.at_ms, id.0, capability.current_ma))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            shares,
+            [
+                // 500mA minimum each, the remaining 2250mA split by demand
+                (100, 0, 2000),
+                (100, 1, 1250),
+                (200, 0, 2750),
+                (200, 1, 500),
+                // The faulted charger is released before its share is handed out
+                (300, 0, 0),
+                (300, 1, 3250),
+            ]
+        );
+        assert_eq!(
+            sim.observations(|o| matches!(o, Observation::Charger(_, ChargerCommand::ChargingParameters(_)))),
+            [
+                Observation::Charger(ChargerId(0), ChargerCommand::ChargingParameters(DEMAND_HIGH)),
+                Observation::Charger(ChargerId(1), ChargerCommand::ChargingParameters(DEMAND_LOW)),
+            ]
+        );
+        assert!(sim.observations(|o| matches!(o, Observation::Error(_))).is_empty());
+    }
+}
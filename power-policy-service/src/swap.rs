@@ -0,0 +1,66 @@
+//! This file implements power role swaps of dual-role ports.
+//! The policy swaps an idle dual-role port to its [preferred role](super::config::PreferredRole) when the port asks
+//! for the other role. Ports that are already providing or consuming are left alone so an active contract is never
+//! interrupted. Sources aren't swapped away while booting without a usable battery.
+//!
+//! Swaps started by the port partner, including fast role swaps, are reported by the device and handled like a
+//! disconnect: the consumer is reselected and the provider budget reallocated.
+use embedded_services::{debug, warn};
+
+use super::*;
+
+impl PowerPolicy {
+    /// Preferred power role of a port, if any
+    fn preferred_role(&self, device_id: DeviceId) -> Option<PowerRole> {
+        self.config
+            .preferred_roles
+            .iter()
+            .find(|preferred| preferred.device_id == device_id)
+            .map(|preferred| preferred.role)
+    }
+
+    /// Swap an idle dual-role port that asked for `role` to its preferred role
+    ///
+    /// Returns true if the port swapped roles, the port then reports what it needs in its new role.
+    pub(super) async fn swap_to_preferred_role(&self, device_id: DeviceId, role: PowerRole) -> bool {
+        let preferred = match self.preferred_role(device_id) {
+            Some(preferred) if preferred != role => preferred,
+            _ => return false,
+        };
+
+        if role == PowerRole::Consumer && self.in_boot_mode().await {
+            debug!(
+                "Device{}: Keeping power source while booting without a battery",
+                device_id.0
+            );
+            return false;
+        }
+
+        let Ok(device) = self.context.get_device(device_id).await else {
+            return false;
+        };
+        if !device.is_dual_role().await {
+            debug!("Device{}: Port partner can't swap roles", device_id.0);
+            return false;
+        }
+
+        let Ok(idle) = self.context.try_policy_action::<action::Idle>(device_id).await else {
+            return false;
+        };
+
+        info!("Device{}: Swapping to preferred role {:?}", device_id.0, preferred);
+        let result = match preferred {
+            PowerRole::Provider => idle.swap_to_provider().await,
+            PowerRole::Consumer => idle.swap_to_consumer().await,
+        };
+
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                // Carry on in the current role
+                warn!("Device{}: Power role swap failed, {:?}", device_id.0, e);
+                false
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use core::array::from_fn;
 use core::iter::zip;
 
+use ::tps6699x::command::{Command as TpsCommand, ReturnValue};
 use ::tps6699x::registers::field_sets::IntEventBus1;
 use ::tps6699x::registers::{PdCcPullUp, PpExtVbusSw, PpIntVbusSw};
 use ::tps6699x::{PORT0, PORT1, TPS66993_NUM_PORTS, TPS66994_NUM_PORTS};
@@ -9,13 +10,17 @@ use embassy_futures::select::select;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
-use embassy_time::Delay;
+use embassy_time::{Delay, Duration, Instant, Timer};
 use embedded_cfu_protocol::protocol_definitions::ComponentId;
 use embedded_hal_async::i2c::I2c;
 use embedded_services::cfu::component::CfuDevice;
 use embedded_services::power::policy::{self, PowerCapability};
+use embedded_services::type_c::alt_mode::{
+    AltModeKind, AltModeState, DpPinAssignment, DpStatus, EntryPolicy, MuxConfig,
+};
 use embedded_services::type_c::controller::{self, Controller, ControllerStatus, PortStatus};
 use embedded_services::type_c::event::PortEventKind;
+use embedded_services::type_c::vdm::{DiscoverCommand, Sop, Vdos};
 use embedded_services::type_c::ControllerId;
 use embedded_services::{debug, info, trace, type_c, warn, GlobalRawMutex};
 use embedded_usb_pd::pdinfo::PowerPathStatus;
@@ -33,6 +38,15 @@ type Updater<'a, M, B> = BorrowedUpdaterInProgress<tps6699x_drv::Tps6699x<'a, M,
 
 use crate::wrapper::{ControllerWrapper, FwOfferValidator};
 
+/// DisplayPort SVID
+const DP_SVID: u16 = 0xFF01;
+/// Thunderbolt SVID
+const TBT_SVID: u16 = 0x8087;
+/// Time allowed for the controller to renegotiate after a PDO request
+const REQUEST_PDO_TIMEOUT: Duration = Duration::from_millis(500);
+/// Interval between active RDO checks while waiting for a renegotiation
+const REQUEST_PDO_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
 /// Firmware update state
 struct FwUpdateState<'a, M: RawMutex, B: I2c> {
     /// Updater state
@@ -161,6 +175,29 @@ impl<'a, const N: usize, M: RawMutex, B: I2c> Tps6699x<'a, N, M, B> {
         Ok(events)
     }
 
+    /// Wait for the active RDO to match a PDO request, the contract registers are the ones reported by
+    /// [`Controller::get_active_rdo`]
+    async fn wait_active_rdo(
+        &self,
+        tps6699x: &mut tps6699x_drv::Tps6699x<'a, M, B>,
+        port: LocalPortId,
+        request: controller::PdoRequest,
+    ) -> Result<(), Error<B::Error>> {
+        let deadline = Instant::now() + REQUEST_PDO_TIMEOUT;
+        loop {
+            let rdo_raw = tps6699x.get_active_rdo_contract(port).await?.active_rdo();
+            if rdo_matches(rdo_raw, request) {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                warn!("Port{}: Active RDO {:#X} doesn't match {:?}", port.0, rdo_raw, request);
+                return Err(PdError::Rejected.into());
+            }
+            Timer::after(REQUEST_PDO_POLL_INTERVAL).await;
+        }
+    }
+
     /// Wait for an event on any port
     async fn wait_interrupt_event(
         &self,
@@ -200,6 +237,12 @@ impl<'a, const N: usize, M: RawMutex, B: I2c> Tps6699x<'a, N, M, B> {
                     // Port is provider and power negotiation is complete
                     event.set_new_power_contract_as_provider(true);
                 }
+
+                if interrupt.fr_swap_complete() {
+                    debug!("Event: Fast role swap complete, PD controller act as source");
+                    // The partner lost its supply and the port now provides power
+                    event.set_fast_role_swap(true);
+                }
             }
         }
         Ok(())
@@ -353,6 +396,256 @@ impl<const N: usize, M: RawMutex, B: I2c> Controller for Tps6699x<'_, N, M, B> {
         }
     }
 
+    async fn power_role_swap(
+        &mut self,
+        port: LocalPortId,
+        role: policy::PowerRole,
+    ) -> Result<(), Error<Self::BusError>> {
+        debug!("Port{} power role swap to {:?}", port.0, role);
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+        let command = match role {
+            policy::PowerRole::Provider => TpsCommand::Swsr,
+            policy::PowerRole::Consumer => TpsCommand::Swsk,
+        };
+        match tps6699x.execute_command(port, command, None, None).await? {
+            ReturnValue::Success => Ok(()),
+            _ => {
+                warn!("Port{} power role swap rejected", port.0);
+                Err(PdError::Rejected.into())
+            }
+        }
+    }
+
+    async fn get_alt_mode_state(&mut self, port: LocalPortId) -> Result<AltModeState, Error<Self::BusError>> {
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+
+        let alt_mode = tps6699x.get_alt_mode_status(port).await?;
+        let mode = if alt_mode.usb4() {
+            Some(AltModeKind::Usb4)
+        } else if alt_mode.tbt() {
+            Some(AltModeKind::Thunderbolt)
+        } else if alt_mode.dfp_d() || alt_mode.ufp_d() {
+            Some(AltModeKind::DisplayPort)
+        } else {
+            None
+        };
+
+        let dp = if mode == Some(AltModeKind::DisplayPort) {
+            let status = tps6699x.get_dp_status(port).await?;
+            trace!("Port{} DP status: {:#?}", port.0, status);
+            Some(DpStatus {
+                hpd: status.hpd_level(),
+                irq_hpd: status.irq_hpd(),
+                pin_assignment: dp_pin_assignment(status.pin_assignment()),
+            })
+        } else {
+            None
+        };
+
+        Ok(AltModeState { mode, dp })
+    }
+
+    /// The controller drives the mux from its own alt mode state, this only checks that it matches the requested
+    /// configuration
+    async fn set_mux(&mut self, port: LocalPortId, config: MuxConfig) -> Result<(), Error<Self::BusError>> {
+        let connected = self.get_port_status(port).await?.is_connected();
+        let current = MuxConfig::new(connected, &self.get_alt_mode_state(port).await?);
+        if current != config {
+            warn!("Port{} mux is {:?} instead of {:?}", port.0, current, config);
+            return Err(PdError::Rejected.into());
+        }
+        Ok(())
+    }
+
+    /// The controller configures the retimer along with the mux, [`Self::set_mux`] checks the result
+    async fn set_retimer(&mut self, port: LocalPortId, config: MuxConfig) -> Result<(), Error<Self::BusError>> {
+        trace!("Port{} retimer {:?} configured by controller", port.0, config);
+        Ok(())
+    }
+
+    /// The controller enters modes on its own, this exits the active mode if the policy doesn't allow it
+    async fn set_alt_mode_entry(
+        &mut self,
+        port: LocalPortId,
+        policy: EntryPolicy,
+    ) -> Result<(), Error<Self::BusError>> {
+        let Some(mode) = self.get_alt_mode_state(port).await?.mode else {
+            return Ok(());
+        };
+
+        if policy.allows(mode) {
+            return Ok(());
+        }
+
+        debug!("Port{} exiting {:?}", port.0, mode);
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+        let result = match mode {
+            AltModeKind::DisplayPort => {
+                tps6699x
+                    .execute_command(port, TpsCommand::Amex, Some(&DP_SVID.to_le_bytes()), None)
+                    .await?
+            }
+            AltModeKind::Thunderbolt => {
+                tps6699x
+                    .execute_command(port, TpsCommand::Amex, Some(&TBT_SVID.to_le_bytes()), None)
+                    .await?
+            }
+            // USB4 isn't an alternate mode, leaving it takes a data reset
+            AltModeKind::Usb4 => tps6699x.execute_command(port, TpsCommand::Drst, None, None).await?,
+        };
+
+        match result {
+            ReturnValue::Success => Ok(()),
+            _ => {
+                warn!("Port{} {:?} exit rejected", port.0, mode);
+                Err(PdError::Rejected.into())
+            }
+        }
+    }
+
+    async fn get_partner_source_caps(
+        &mut self,
+        port: LocalPortId,
+    ) -> Result<controller::SourceCapabilities, Error<Self::BusError>> {
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+
+        let caps = tps6699x.get_rx_src_caps(port).await?;
+        controller::SourceCapabilities::from_slice(caps.spr()).map_err(Error::Pd)
+    }
+
+    async fn get_partner_sink_caps(
+        &mut self,
+        port: LocalPortId,
+    ) -> Result<controller::SinkCapabilities, Error<Self::BusError>> {
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+
+        let caps = tps6699x.get_rx_snk_caps(port).await?;
+        controller::SinkCapabilities::from_slice(caps.spr()).map_err(Error::Pd)
+    }
+
+    /// The controller negotiates on its own, requesting the highest power PDO within its autonegotiate sink settings.
+    /// Limiting the maximum sink voltage to the voltage of a fixed PDO makes the controller request it, PPS APDOs are
+    /// requested through the autonegotiate PPS settings. The request completes once the active RDO matches it.
+    async fn request_pdo(
+        &mut self,
+        port: LocalPortId,
+        request: controller::PdoRequest,
+    ) -> Result<(), Error<Self::BusError>> {
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+
+        let caps = tps6699x.get_rx_src_caps(port).await?;
+        let caps = controller::SourceCapabilities::from_slice(caps.spr()).map_err(Error::Pd)?;
+        let pdo = caps.get(request.position()).ok_or(Error::Pd(PdError::InvalidParams))?;
+
+        match request {
+            controller::PdoRequest::Pdo(position) => {
+                let voltage_mv = PowerCapability::from(*pdo).voltage_mv;
+                debug!("Port{}: Requesting PDO {} at {}mV", port.0, position, voltage_mv);
+                tps6699x.set_autonegotiate_sink_pps(port, None).await?;
+                tps6699x
+                    .set_autonegotiate_sink_max_voltage(port, Some(voltage_mv))
+                    .await?;
+            }
+            controller::PdoRequest::Apdo {
+                position,
+                voltage_mv,
+                current_ma,
+            } => {
+                if !matches!(pdo, source::Pdo::Augmented(source::Apdo::SprPps(_))) {
+                    // The controller only negotiates PPS on its own
+                    warn!("Port{}: Only PPS APDOs are supported", port.0);
+                    return Err(PdError::UnrecognizedCommand.into());
+                }
+
+                debug!(
+                    "Port{}: Requesting APDO {} at {}mV {}mA",
+                    port.0, position, voltage_mv, current_ma
+                );
+                tps6699x
+                    .set_autonegotiate_sink_pps(port, Some((voltage_mv, current_ma)))
+                    .await?;
+            }
+        }
+
+        // Renegotiate with the new settings
+        match tps6699x.execute_command(port, TpsCommand::Aneg, None, None).await? {
+            ReturnValue::Success => {}
+            _ => {
+                warn!("Port{}: Renegotiation rejected", port.0);
+                return Err(PdError::Rejected.into());
+            }
+        }
+
+        self.wait_active_rdo(&mut tps6699x, port, request).await
+    }
+
+    async fn get_active_rdo(&mut self, port: LocalPortId) -> Result<Option<Rdo>, Error<Self::BusError>> {
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+
+        let pdo_raw = tps6699x.get_active_pdo_contract(port).await?.active_pdo();
+        let rdo_raw = tps6699x.get_active_rdo_contract(port).await?.active_rdo();
+        if pdo_raw == 0 || rdo_raw == 0 {
+            // No explicit contract
+            return Ok(None);
+        }
+
+        let rdo = if tps6699x.get_pd_status(port).await?.is_source() {
+            Rdo::for_pdo(rdo_raw, source::Pdo::try_from(pdo_raw).map_err(Error::Pd)?)
+        } else {
+            Rdo::for_pdo(rdo_raw, sink::Pdo::try_from(pdo_raw).map_err(Error::Pd)?)
+        };
+        Ok(Some(rdo))
+    }
+
+    /// The controller runs discovery on its own after every contract and only keeps the Discover Identity responses
+    async fn discover(
+        &mut self,
+        port: LocalPortId,
+        sop: Sop,
+        command: DiscoverCommand,
+    ) -> Result<Vdos, Error<Self::BusError>> {
+        if command != DiscoverCommand::Identity {
+            return Err(PdError::UnrecognizedCommand.into());
+        }
+
+        let mut tps6699x = self
+            .tps6699x
+            .try_lock()
+            .expect("Driver should not have been locked before this, thus infallible");
+        let vdos = match sop {
+            Sop::Sop => tps6699x.get_rx_identity_sop(port).await?.vdos(),
+            Sop::SopPrime => tps6699x.get_rx_identity_sop_prime(port).await?.vdos(),
+        };
+        trace!("Port{} {:?} identity: {:?}", port.0, sop, vdos);
+
+        if vdos.is_empty() {
+            // Not discovered yet or NAKed
+            return Err(PdError::Rejected.into());
+        }
+        Vdos::from_slice(&vdos).map_err(Error::Pd)
+    }
+
     async fn get_controller_status(&mut self) -> Result<ControllerStatus<'static>, Error<Self::BusError>> {
         let mut tps6699x = self
             .tps6699x
@@ -547,6 +840,30 @@ pub fn tps66993<'a, M: RawMutex, B: I2c, V: FwOfferValidator>(
     ))
 }
 
+/// Returns true if a raw RDO selects the PDO and, for PPS, the output voltage of `request`
+fn rdo_matches(raw: u32, request: controller::PdoRequest) -> bool {
+    let position = ((raw >> 28) & 0xF) as u8;
+    match request {
+        controller::PdoRequest::Pdo(requested) => position == requested,
+        // Output voltage in 20mV units
+        controller::PdoRequest::Apdo {
+            position: requested,
+            voltage_mv,
+            ..
+        } => position == requested && (raw >> 9) & 0xFFF == voltage_mv as u32 / 20,
+    }
+}
+
+/// Pin assignment from the pin assignment bits of a DisplayPort Configure VDO, `None` for assignments the mux can't use
+fn dp_pin_assignment(raw: u8) -> Option<DpPinAssignment> {
+    match raw {
+        0x04 => Some(DpPinAssignment::C),
+        0x08 => Some(DpPinAssignment::D),
+        0x10 => Some(DpPinAssignment::E),
+        _ => None,
+    }
+}
+
 bitfield! {
     /// Custom customer use format
     //#[derive(Clone, Copy)]
@@ -1,14 +1,17 @@
 use core::future::Future;
-use embassy_futures::select::{select, Either};
-use embassy_sync::{mutex::Mutex, once_lock::OnceLock};
+use embassy_futures::select::{select4, Either4};
+use embassy_sync::{mutex::Mutex, once_lock::OnceLock, signal::Signal};
 use embedded_services::{
     comms::{self, EndpointID, Internal},
-    debug, error, info, intrusive_list,
+    debug,
+    ec_type::message::UcsiMessage,
+    error, info, intrusive_list,
     ipc::deferred,
     type_c::{
         self,
-        controller::PortStatus,
-        event::{PortEventFlagsIter, PortEventKind},
+        alt_mode::{EntryPolicy, EntryPolicyMessage},
+        controller::{PdoRequest, PortStatus},
+        event::{PortEventFlagsIter, PortEventKind, MAX_SUPPORTED_PORTS},
         external::{self, ControllerCommandData},
         ControllerId,
     },
@@ -16,16 +19,24 @@ use embedded_services::{
 };
 use embedded_usb_pd::GlobalPortId;
 use embedded_usb_pd::PdError as Error;
+use heapless::LinearMap;
 
-const MAX_SUPPORTED_PORTS: usize = 4;
+mod alt_mode;
+mod ucsi;
 
 /// Type-C service state
 #[derive(Default)]
 struct State {
-    /// Current port status
-    port_status: [PortStatus; MAX_SUPPORTED_PORTS],
+    /// Current port status of registered ports, populated as ports report events
+    port_status: LinearMap<GlobalPortId, PortStatus, MAX_SUPPORTED_PORTS>,
     /// Next port to check, this is used to round-robin through ports
     event_iter: Option<PortEventFlagsIter>,
+    /// Alternate mode state of registered ports
+    alt_modes: LinearMap<GlobalPortId, alt_mode::PortState, MAX_SUPPORTED_PORTS>,
+    /// Alternate modes allowed by the host
+    entry_policy: EntryPolicy,
+    /// UCSI PPM state
+    ucsi: ucsi::Ppm,
 }
 
 /// Type-C service
@@ -36,6 +47,10 @@ pub struct Service {
     context: type_c::controller::ContextToken,
     /// Current state
     state: Mutex<GlobalRawMutex, State>,
+    /// Entry policy received from the host
+    entry_policy: Signal<GlobalRawMutex, EntryPolicy>,
+    /// UCSI CONTROL written by the host
+    ucsi_control: Signal<GlobalRawMutex, u64>,
 }
 
 pub enum Event<'a> {
@@ -43,6 +58,10 @@ pub enum Event<'a> {
     PortEvent(GlobalPortId, PortEventKind, PortStatus),
     /// External command
     ExternalCommand(deferred::Request<'a, GlobalRawMutex, external::Command, external::Response<'static>>),
+    /// New alternate mode entry policy from the host
+    EntryPolicy(EntryPolicy),
+    /// UCSI command from the host
+    UcsiCommand(u64),
 }
 
 impl Service {
@@ -52,27 +71,32 @@ impl Service {
             tp: comms::Endpoint::uninit(EndpointID::Internal(Internal::Usbc)),
             context: type_c::controller::ContextToken::create()?,
             state: Mutex::new(State::default()),
+            entry_policy: Signal::new(),
+            ucsi_control: Signal::new(),
         })
     }
 
     /// Get the cached port status
     pub async fn get_cached_port_status(&self, port_id: GlobalPortId) -> Result<PortStatus, Error> {
-        if port_id.0 as usize >= MAX_SUPPORTED_PORTS {
-            return Err(Error::InvalidPort);
+        if let Some(status) = self.state.lock().await.port_status.get(&port_id) {
+            return Ok(*status);
         }
 
-        let state = self.state.lock().await;
-        Ok(state.port_status[port_id.0 as usize])
+        // Registered ports that haven't reported anything yet
+        if self.context.has_port(port_id).await {
+            Ok(PortStatus::new())
+        } else {
+            Err(Error::InvalidPort)
+        }
     }
 
     /// Set the cached port status
     async fn set_cached_port_status(&self, port_id: GlobalPortId, status: PortStatus) -> Result<(), Error> {
-        if port_id.0 as usize >= MAX_SUPPORTED_PORTS {
+        let mut state = self.state.lock().await;
+        if state.port_status.insert(port_id, status).is_err() {
+            error!("Port{}: Exceeds the supported number of ports", port_id.0);
             return Err(Error::InvalidPort);
         }
-
-        let mut state = self.state.lock().await;
-        state.port_status[port_id.0 as usize] = status;
         Ok(())
     }
 
@@ -109,8 +133,8 @@ impl Service {
         }
 
         self.set_cached_port_status(port_id, status).await?;
-
-        Ok(())
+        self.process_ucsi_port_event(port_id, event).await;
+        self.process_alt_mode_event(port_id, event, &status).await
     }
 
     /// Process external controller status command
@@ -192,6 +216,56 @@ impl Service {
         external::Response::Port(status.map(|_| external::PortResponseData::Complete))
     }
 
+    /// Process get partner source caps commands
+    async fn process_get_partner_source_caps(&self, port_id: GlobalPortId) -> external::Response<'static> {
+        let caps = self.context.get_partner_source_caps(port_id).await;
+        if let Err(e) = caps {
+            error!("Error getting partner source caps: {:#?}", e);
+        }
+
+        external::Response::Port(caps.map(external::PortResponseData::PartnerSourceCaps))
+    }
+
+    /// Process get partner sink caps commands
+    async fn process_get_partner_sink_caps(&self, port_id: GlobalPortId) -> external::Response<'static> {
+        let caps = self.context.get_partner_sink_caps(port_id).await;
+        if let Err(e) = caps {
+            error!("Error getting partner sink caps: {:#?}", e);
+        }
+
+        external::Response::Port(caps.map(external::PortResponseData::PartnerSinkCaps))
+    }
+
+    /// Process request PDO commands
+    async fn process_request_pdo(&self, port_id: GlobalPortId, request: PdoRequest) -> external::Response<'static> {
+        let status = self.context.request_pdo(port_id, request).await;
+        if let Err(e) = status {
+            error!("Error requesting PDO: {:#?}", e);
+        }
+
+        external::Response::Port(status.map(|_| external::PortResponseData::Complete))
+    }
+
+    /// Process get active RDO commands
+    async fn process_get_active_rdo(&self, port_id: GlobalPortId) -> external::Response<'static> {
+        let rdo = self.context.get_active_rdo(port_id).await;
+        if let Err(e) = rdo {
+            error!("Error getting active RDO: {:#?}", e);
+        }
+
+        external::Response::Port(rdo.map(external::PortResponseData::ActiveRdo))
+    }
+
+    /// Process get discovery commands
+    async fn process_get_discovery(&self, port_id: GlobalPortId) -> external::Response<'static> {
+        let discovery = self.context.get_discovery(port_id).await;
+        if let Err(e) = discovery {
+            error!("Error getting discovery results: {:#?}", e);
+        }
+
+        external::Response::Port(discovery.map(external::PortResponseData::Discovery))
+    }
+
     /// Process external port commands
     async fn process_external_port_command(&self, command: &external::PortCommand) -> external::Response<'static> {
         debug!("Processing external port command: {:#?}", command);
@@ -207,6 +281,11 @@ impl Service {
                 self.process_clear_rt_fw_update_state(command.port).await
             }
             external::PortCommandData::SetRetimerCompliance => self.process_set_rt_compliance(command.port).await,
+            external::PortCommandData::GetPartnerSourceCaps => self.process_get_partner_source_caps(command.port).await,
+            external::PortCommandData::GetPartnerSinkCaps => self.process_get_partner_sink_caps(command.port).await,
+            external::PortCommandData::RequestPdo(request) => self.process_request_pdo(command.port, request).await,
+            external::PortCommandData::GetActiveRdo => self.process_get_active_rdo(command.port).await,
+            external::PortCommandData::GetDiscovery => self.process_get_discovery(command.port).await,
         }
     }
 
@@ -234,8 +313,15 @@ impl Service {
     /// Wait for the next event
     pub async fn wait_next(&self) -> Result<Event<'_>, Error> {
         loop {
-            match select(self.wait_port_flags(), self.context.wait_external_command()).await {
-                Either::First(mut pending) => {
+            match select4(
+                self.wait_port_flags(),
+                self.context.wait_external_command(),
+                self.entry_policy.wait(),
+                self.ucsi_control.wait(),
+            )
+            .await
+            {
+                Either4::First(mut pending) => {
                     let mut state = self.state.lock().await;
                     if let Some(port_id) = pending.next() {
                         debug!("Port{}: Event", port_id.0);
@@ -250,9 +336,15 @@ impl Service {
                         continue;
                     }
                 }
-                Either::Second(request) => {
+                Either4::Second(request) => {
                     return Ok(Event::ExternalCommand(request));
                 }
+                Either4::Third(policy) => {
+                    return Ok(Event::EntryPolicy(policy));
+                }
+                Either4::Fourth(control) => {
+                    return Ok(Event::UcsiCommand(control));
+                }
             }
         }
     }
@@ -266,6 +358,8 @@ impl Service {
                 request.respond(response);
                 Ok(())
             }
+            Event::EntryPolicy(policy) => self.process_entry_policy(policy).await,
+            Event::UcsiCommand(control) => self.process_ucsi_command(control).await,
         }
     }
 
@@ -282,8 +376,12 @@ impl Service {
 }
 
 impl comms::MailboxDelegate for Service {
-    fn receive(&self, _message: &comms::Message) -> Result<(), comms::MailboxDelegateError> {
-        // Currently only need to send messages
+    fn receive(&self, message: &comms::Message) -> Result<(), comms::MailboxDelegateError> {
+        if let Some(EntryPolicyMessage(policy)) = message.data.get::<EntryPolicyMessage>() {
+            self.entry_policy.signal(*policy);
+        } else if let Some(UcsiMessage::Control(control)) = message.data.get::<UcsiMessage>() {
+            self.ucsi_control.signal(*control);
+        }
         Ok(())
     }
 }
@@ -309,6 +407,8 @@ pub async fn task_closure<'a, Fut: Future<Output = ()>, F: Fn(&'a Service) -> Fu
         return;
     }
 
+    service.init_ucsi().await;
+
     loop {
         f(service).await;
     }
@@ -0,0 +1,376 @@
+//! Alternate mode management
+//!
+//! Tracks alternate mode entry and exit on every port, configures the mux and retimer to match and publishes
+//! [`AltModeMessage`]s to the host. Ports may only enter the modes allowed by the host's [`EntryPolicy`], nothing is
+//! allowed until the host sends its first [`EntryPolicyMessage`](type_c::alt_mode::EntryPolicyMessage).
+use embedded_services::type_c::alt_mode::{AltModeKind, AltModeMessage, AltModeState, EntryPolicy, MuxConfig};
+use embedded_services::{comms::External, warn};
+
+use super::*;
+
+/// Alternate mode state of a port
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct PortState {
+    /// Last state reported by the controller
+    alt_mode: AltModeState,
+    /// Current mux and retimer configuration
+    mux: MuxConfig,
+}
+
+impl PortState {
+    /// Active alternate mode
+    pub(super) fn mode(&self) -> Option<AltModeKind> {
+        self.alt_mode.mode
+    }
+}
+
+impl Service {
+    /// Process alternate mode related port events
+    pub(crate) async fn process_alt_mode_event(
+        &self,
+        port_id: GlobalPortId,
+        event: PortEventKind,
+        status: &PortStatus,
+    ) -> Result<(), Error> {
+        if !(event.plug_inserted_or_removed()
+            || event.alt_mode_entered()
+            || event.alt_mode_exited()
+            || event.dp_status_update())
+        {
+            return Ok(());
+        }
+
+        self.update_alt_mode(port_id, status.is_connected()).await
+    }
+
+    /// Apply a new entry policy to every port
+    pub(crate) async fn process_entry_policy(&self, policy: EntryPolicy) -> Result<(), Error> {
+        info!("Alt mode entry policy: {:#?}", policy);
+        self.state.lock().await.entry_policy = policy;
+
+        for port_id in (0..MAX_SUPPORTED_PORTS).map(|port| GlobalPortId(port as u8)) {
+            if !self.context.has_port(port_id).await {
+                continue;
+            }
+
+            self.apply_entry_policy(port_id, policy).await;
+            let connected = self.get_cached_port_status(port_id).await?.is_connected();
+            if let Err(e) = self.update_alt_mode(port_id, connected).await {
+                error!("Port{}: Error updating alt mode: {:#?}", port_id.0, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restrict the modes the controller may enter on the given port
+    async fn apply_entry_policy(&self, port_id: GlobalPortId, policy: EntryPolicy) {
+        match self.context.set_alt_mode_entry(port_id, policy).await {
+            Ok(()) => {}
+            Err(Error::UnrecognizedCommand) => {
+                debug!("Port{}: Controller can't restrict alt mode entry", port_id.0)
+            }
+            Err(e) => error!("Port{}: Error setting alt mode entry policy: {:#?}", port_id.0, e),
+        }
+    }
+
+    /// Reconcile the mux, retimer and host with the controller's alt mode state
+    async fn update_alt_mode(&self, port_id: GlobalPortId, connected: bool) -> Result<(), Error> {
+        let mut alt_mode = if connected {
+            match self.context.get_alt_mode_state(port_id).await {
+                Ok(alt_mode) => alt_mode,
+                // Controllers without alt mode support stay in USB mode
+                Err(Error::UnrecognizedCommand) => AltModeState::default(),
+                Err(e) => return Err(e),
+            }
+        } else {
+            AltModeState::default()
+        };
+
+        let (old, policy) = {
+            let state = self.state.lock().await;
+            (
+                state.alt_modes.get(&port_id).copied().unwrap_or_default(),
+                state.entry_policy,
+            )
+        };
+
+        if let Some(mode) = alt_mode.mode {
+            if !policy.allows(mode) {
+                // The controller entered a mode on its own, make it exit and keep the port in USB mode meanwhile
+                warn!("Port{}: {:?} not allowed by host", port_id.0, mode);
+                self.apply_entry_policy(port_id, policy).await;
+                alt_mode = AltModeState::default();
+            }
+        }
+
+        let mux = MuxConfig::new(connected, &alt_mode);
+        if mux != old.mux {
+            debug!("Port{}: Mux {:?} -> {:?}", port_id.0, old.mux, mux);
+            self.configure_mux(port_id, mux).await?;
+        }
+
+        {
+            let mut state = self.state.lock().await;
+            let port = PortState { alt_mode, mux };
+            if state.alt_modes.insert(port_id, port).is_err() {
+                error!("Port{}: Exceeds the supported number of ports", port_id.0);
+                return Err(Error::InvalidPort);
+            }
+        }
+
+        for event in type_c::alt_mode::events(&old.alt_mode, &alt_mode) {
+            debug!("Port{}: Alt mode event: {:?}", port_id.0, event);
+            let message = AltModeMessage { port: port_id, event };
+            if self
+                .tp
+                .send(EndpointID::External(External::Host), &message)
+                .await
+                .is_err()
+            {
+                error!("Failed to send alt mode message");
+            }
+        }
+        Ok(())
+    }
+
+    /// Configure the retimer and mux, the retimer goes first so the mux never drives an unconfigured retimer
+    ///
+    /// Controllers without a retimer or that configure the mux on their own don't recognize the respective command.
+    async fn configure_mux(&self, port_id: GlobalPortId, mux: MuxConfig) -> Result<(), Error> {
+        match self.context.set_retimer(port_id, mux).await {
+            Ok(()) => {}
+            Err(Error::UnrecognizedCommand) => debug!("Port{}: No retimer to configure", port_id.0),
+            Err(e) => {
+                error!("Port{}: Error configuring retimer: {:#?}", port_id.0, e);
+                return Err(e);
+            }
+        }
+
+        match self.context.set_mux(port_id, mux).await {
+            Ok(()) => Ok(()),
+            Err(Error::UnrecognizedCommand) => {
+                debug!("Port{}: Mux not configurable", port_id.0);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Port{}: Error configuring mux: {:#?}", port_id.0, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::channel::Channel;
+    use embedded_services::comms::{Endpoint, MailboxDelegate, MailboxDelegateError, Message};
+    use embedded_services::type_c::alt_mode::{AltModeEvent, DpPinAssignment, DpStatus};
+    use embedded_services::type_c::controller::{
+        Command, Device, PortCommand, PortCommandData, PortResponseData, Response,
+    };
+    use embedded_usb_pd::type_c::ConnectionState;
+
+    use super::*;
+
+    const PORT: GlobalPortId = GlobalPortId(0);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Call {
+        Retimer(MuxConfig),
+        Mux(MuxConfig),
+        Entry(EntryPolicy),
+    }
+
+    /// Collects the alt mode events published to the host
+    struct HostListener {
+        tp: Endpoint,
+        events: Channel<GlobalRawMutex, AltModeEvent, 8>,
+    }
+
+    impl MailboxDelegate for HostListener {
+        fn receive(&self, message: &Message) -> Result<(), MailboxDelegateError> {
+            if let Some(message) = message.data.get::<AltModeMessage>() {
+                self.events
+                    .try_send(message.event)
+                    .map_err(|_| MailboxDelegateError::BufferFull)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl HostListener {
+        fn take_events(&self) -> Vec<AltModeEvent> {
+            core::iter::from_fn(|| self.events.try_receive().ok()).collect()
+        }
+    }
+
+    /// Mock controller that enters the alt mode set by the test and records the mux, retimer and policy commands
+    struct MockController {
+        device: &'static Device<'static>,
+        alt_mode: Cell<AltModeState>,
+        /// Whether the controller lets the service configure its mux
+        mux_supported: Cell<bool>,
+        calls: RefCell<Vec<Call>>,
+    }
+
+    impl MockController {
+        fn calls(&self) -> Vec<Call> {
+            self.calls.take()
+        }
+
+        fn process_port_command(&self, data: PortCommandData) -> Result<PortResponseData, Error> {
+            match data {
+                PortCommandData::GetAltModeState => return Ok(PortResponseData::AltModeState(self.alt_mode.get())),
+                PortCommandData::SetRetimer(config) => self.calls.borrow_mut().push(Call::Retimer(config)),
+                PortCommandData::SetMux(config) => {
+                    self.calls.borrow_mut().push(Call::Mux(config));
+                    if !self.mux_supported.get() {
+                        return Err(Error::UnrecognizedCommand);
+                    }
+                }
+                PortCommandData::SetAltModeEntry(policy) => {
+                    self.calls.borrow_mut().push(Call::Entry(policy));
+                    // Exit the active mode like a real controller would
+                    if self.alt_mode.get().mode.is_some_and(|mode| !policy.allows(mode)) {
+                        self.alt_mode.set(AltModeState::default());
+                    }
+                }
+                _ => return Err(Error::UnrecognizedCommand),
+            }
+            Ok(PortResponseData::Complete)
+        }
+
+        async fn process(&self) {
+            loop {
+                let request = self.device.receive().await;
+                let response = match request.command {
+                    Command::Port(PortCommand { data, .. }) => Response::Port(self.process_port_command(data)),
+                    _ => Response::Port(Err(Error::UnrecognizedCommand)),
+                };
+                request.respond(response);
+            }
+        }
+    }
+
+    fn display_port(hpd: bool) -> AltModeState {
+        AltModeState {
+            mode: Some(AltModeKind::DisplayPort),
+            dp: Some(DpStatus {
+                hpd,
+                irq_hpd: false,
+                pin_assignment: Some(DpPinAssignment::D),
+            }),
+        }
+    }
+
+    fn port_event(set: impl FnOnce(&mut PortEventKind)) -> PortEventKind {
+        let mut event = PortEventKind::none();
+        set(&mut event);
+        event
+    }
+
+    #[test]
+    fn test_alt_mode_manager() {
+        static PORTS: [GlobalPortId; 1] = [PORT];
+        static DEVICE: OnceLock<Device<'static>> = OnceLock::new();
+        static LISTENER: OnceLock<HostListener> = OnceLock::new();
+
+        let device = DEVICE.get_or_init(|| Device::new(ControllerId(0), &PORTS));
+        let listener = LISTENER.get_or_init(|| HostListener {
+            tp: Endpoint::uninit(EndpointID::External(External::Host)),
+            events: Channel::new(),
+        });
+        let controller = MockController {
+            device,
+            alt_mode: Cell::new(AltModeState::default()),
+            mux_supported: Cell::new(true),
+            calls: RefCell::new(Vec::new()),
+        };
+        let service = Service::create().unwrap();
+
+        let connected = PortStatus {
+            connection_state: Some(ConnectionState::Attached),
+            ..PortStatus::new()
+        };
+        let disconnected = PortStatus::new();
+
+        let test = async {
+            embedded_services::init().await;
+            comms::register_endpoint(listener, &listener.tp).await.unwrap();
+            type_c::controller::register_controller(device).await.unwrap();
+            service.set_cached_port_status(PORT, connected).await.unwrap();
+
+            // Plain USB connection
+            let plug = port_event(|event| event.set_plug_inserted_or_removed(true));
+            service.process_alt_mode_event(PORT, plug, &connected).await.unwrap();
+            assert_eq!(
+                controller.calls(),
+                [Call::Retimer(MuxConfig::Usb), Call::Mux(MuxConfig::Usb)]
+            );
+
+            // Nothing is allowed before the host sends a policy, the controller is made to exit the mode
+            controller.alt_mode.set(display_port(true));
+            let entered = port_event(|event| event.set_alt_mode_entered(true));
+            service.process_alt_mode_event(PORT, entered, &connected).await.unwrap();
+            assert_eq!(controller.calls(), [Call::Entry(EntryPolicy::default())]);
+            assert_eq!(controller.alt_mode.get(), AltModeState::default());
+            assert!(listener.take_events().is_empty());
+
+            // Once allowed the mux follows the mode and the host is notified
+            let policy = EntryPolicy {
+                display_port: true,
+                ..Default::default()
+            };
+            service.process_entry_policy(policy).await.unwrap();
+            assert_eq!(controller.calls(), [Call::Entry(policy)]);
+
+            controller.alt_mode.set(display_port(true));
+            service.process_alt_mode_event(PORT, entered, &connected).await.unwrap();
+            let dp = MuxConfig::DisplayPort(DpPinAssignment::D);
+            assert_eq!(controller.calls(), [Call::Retimer(dp), Call::Mux(dp)]);
+            assert_eq!(
+                listener.take_events(),
+                [
+                    AltModeEvent::Entered(AltModeKind::DisplayPort),
+                    AltModeEvent::PinAssignment(DpPinAssignment::D),
+                    AltModeEvent::Hpd(true),
+                ]
+            );
+
+            // HPD changes don't touch the mux
+            controller.alt_mode.set(display_port(false));
+            let dp_status = port_event(|event| event.set_dp_status_update(true));
+            service
+                .process_alt_mode_event(PORT, dp_status, &connected)
+                .await
+                .unwrap();
+            assert!(controller.calls().is_empty());
+            assert_eq!(listener.take_events(), [AltModeEvent::Hpd(false)]);
+
+            // A controller that configures its own mux doesn't fail the update
+            controller.mux_supported.set(false);
+            controller.alt_mode.set(AltModeState::default());
+            service.process_alt_mode_event(PORT, plug, &disconnected).await.unwrap();
+            assert_eq!(
+                controller.calls(),
+                [
+                    Call::Retimer(MuxConfig::Disconnected),
+                    Call::Mux(MuxConfig::Disconnected)
+                ]
+            );
+            assert_eq!(listener.take_events(), [AltModeEvent::Exited(AltModeKind::DisplayPort)]);
+        };
+
+        match block_on(select(controller.process(), test)) {
+            Either::First(()) => unreachable!(),
+            Either::Second(()) => (),
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! UCSI Platform Policy Manager (PPM)
+//!
+//! Presents the ports of all registered controllers to the host as a single UCSI interface, connector `n` is global
+//! port `n - 1`. The host writes CONTROL to the UCSI section of the EC memory map, the PPM runs the command and
+//! publishes MESSAGE_IN followed by CCI.
+//!
+//! Connector changes are collected per connector. Only one connector is indicated in CCI at a time, the next one is
+//! indicated once the host acknowledges the previous one with ACK_CC_CI.
+use bitfield::bitfield;
+use embedded_services::comms::External;
+use embedded_services::ec_type::message::UcsiMessage;
+use embedded_usb_pd::type_c::ConnectionState;
+use embedded_usb_pd::ucsi::lpm;
+
+use super::*;
+
+/// UCSI version implemented by the PPM, BCD
+pub const UCSI_VERSION: u16 = 0x0120;
+/// USB PD version reported in the capabilities, BCD
+const PD_VERSION: u16 = 0x0300;
+/// USB Type-C version reported in the capabilities, BCD
+const TYPE_C_VERSION: u16 = 0x0200;
+
+/// Size of MESSAGE_IN
+const MESSAGE_IN_SIZE: usize = 16;
+/// Size of the GET_CONNECTOR_CAPABILITY data
+const CONNECTOR_CAPABILITY_SIZE: usize = 4;
+/// Size of the GET_CONNECTOR_STATUS data
+const CONNECTOR_STATUS_SIZE: usize = 9;
+
+/// UCSI command codes
+mod command {
+    pub const PPM_RESET: u8 = 0x01;
+    pub const CONNECTOR_RESET: u8 = 0x03;
+    pub const ACK_CC_CI: u8 = 0x04;
+    pub const SET_NOTIFICATION_ENABLE: u8 = 0x05;
+    pub const GET_CAPABILITY: u8 = 0x06;
+    pub const GET_CONNECTOR_CAPABILITY: u8 = 0x07;
+    pub const GET_CONNECTOR_STATUS: u8 = 0x12;
+}
+
+/// Connector status change bits, the notification enable bits use the same positions
+mod change {
+    pub const POWER_OPERATION_MODE: u16 = 1 << 2;
+    pub const SUPPORTED_PROVIDER_CAPS: u16 = 1 << 5;
+    pub const NEGOTIATED_POWER_LEVEL: u16 = 1 << 6;
+    pub const SUPPORTED_CAM: u16 = 1 << 8;
+    pub const POWER_DIRECTION: u16 = 1 << 12;
+    pub const CONNECT: u16 = 1 << 14;
+}
+
+/// Notification enable bit for command completion
+const NOTIFY_COMMAND_COMPLETE: u16 = 1 << 0;
+
+bitfield! {
+    /// UCSI CONTROL
+    #[derive(Copy, Clone, Default, PartialEq, Eq)]
+    pub struct Control(u64);
+    impl Debug;
+    /// Command code
+    pub u8, command, set_command: 7, 0;
+    /// Connector number, for connector commands
+    pub u8, connector, set_connector: 22, 16;
+    /// Hard reset instead of data reset, for CONNECTOR_RESET
+    pub bool, hard_reset, set_hard_reset: 23;
+    /// Acknowledge the connector change, for ACK_CC_CI
+    pub bool, connector_change_ack, set_connector_change_ack: 16;
+    /// Acknowledge the command completion, for ACK_CC_CI
+    pub bool, command_complete_ack, set_command_complete_ack: 17;
+    /// Notifications to enable, for SET_NOTIFICATION_ENABLE
+    pub u16, notification_enable, set_notification_enable: 31, 16;
+}
+
+bitfield! {
+    /// UCSI Command Status and Connector Change Indication
+    #[derive(Copy, Clone, Default, PartialEq, Eq)]
+    pub struct Cci(u32);
+    impl Debug;
+    /// Connector with a change to report
+    pub u8, connector_change, set_connector_change: 7, 1;
+    /// Length of the data in MESSAGE_IN
+    pub u8, data_length, set_data_length: 15, 8;
+    /// Command not supported
+    pub bool, not_supported, set_not_supported: 25;
+    /// PPM reset completed
+    pub bool, reset_complete, set_reset_complete: 27;
+    /// ACK_CC_CI completed
+    pub bool, ack_command, set_ack_command: 29;
+    /// Command failed
+    pub bool, error, set_error: 30;
+    /// Command completed
+    pub bool, command_complete, set_command_complete: 31;
+}
+
+/// PPM notification state
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Ppm {
+    /// Notifications enabled by the host
+    notification_enable: u16,
+    /// Connector changes not acknowledged by the host, indexed by connector number - 1
+    changes: [u16; MAX_SUPPORTED_PORTS],
+    /// Connector indicated in CCI until the host acknowledges it
+    indicated: Option<u8>,
+    /// Last CCI published to the host
+    published: Cci,
+}
+
+impl Default for Ppm {
+    fn default() -> Self {
+        Self {
+            notification_enable: 0,
+            changes: [0; MAX_SUPPORTED_PORTS],
+            indicated: None,
+            published: Cci::default(),
+        }
+    }
+}
+
+impl Ppm {
+    /// Record changes on a connector, returns true if the connector is now indicated to the host
+    fn connector_changed(&mut self, connector: u8, changes: u16) -> bool {
+        let Some(pending) = (connector as usize)
+            .checked_sub(1)
+            .and_then(|index| self.changes.get_mut(index))
+        else {
+            return false;
+        };
+
+        *pending |= changes;
+        if self.indicated.is_none() {
+            self.indicated = self.next_indication();
+            return self.indicated.is_some();
+        }
+        false
+    }
+
+    /// Next connector with a change the host wants to be notified about
+    fn next_indication(&self) -> Option<u8> {
+        let enabled = self.notification_enable & !NOTIFY_COMMAND_COMPLETE;
+        self.changes
+            .iter()
+            .position(|changes| changes & enabled != 0)
+            .map(|index| index as u8 + 1)
+    }
+
+    /// Unacknowledged changes on a connector
+    fn changes(&self, connector: u8) -> u16 {
+        self.changes[connector as usize - 1]
+    }
+
+    /// CCI carrying the current connector change indication
+    fn cci(&self) -> Cci {
+        let mut cci = Cci::default();
+        cci.set_connector_change(self.indicated.unwrap_or(0));
+        cci
+    }
+
+    /// Last published CCI with the current connector change indication, the command status is kept for the host
+    fn connector_change_cci(&self) -> Cci {
+        let mut cci = self.published;
+        cci.set_connector_change(self.indicated.unwrap_or(0));
+        cci
+    }
+
+    /// Handle SET_NOTIFICATION_ENABLE
+    fn set_notification_enable(&mut self, notification_enable: u16) {
+        self.notification_enable = notification_enable;
+        if self.indicated.is_none() {
+            self.indicated = self.next_indication();
+        }
+    }
+
+    /// Handle ACK_CC_CI
+    fn acknowledge(&mut self, control: Control) -> Cci {
+        if control.connector_change_ack() {
+            if let Some(connector) = self.indicated.take() {
+                self.changes[connector as usize - 1] = 0;
+            }
+            self.indicated = self.next_indication();
+        }
+
+        let mut cci = self.cci();
+        cci.set_ack_command(true);
+        cci
+    }
+}
+
+/// Connector changes caused by a port event
+fn event_changes(event: PortEventKind) -> u16 {
+    let mut changes = 0;
+    if event.plug_inserted_or_removed() {
+        changes |= change::CONNECT;
+    }
+    if event.new_power_contract_as_consumer() || event.new_power_contract_as_provider() {
+        changes |= change::NEGOTIATED_POWER_LEVEL | change::POWER_OPERATION_MODE;
+    }
+    if event.source_caps_received() {
+        changes |= change::SUPPORTED_PROVIDER_CAPS;
+    }
+    if event.fast_role_swap() {
+        changes |= change::POWER_DIRECTION;
+    }
+    if event.alt_mode_entered() || event.alt_mode_exited() {
+        changes |= change::SUPPORTED_CAM;
+    }
+    changes
+}
+
+/// GET_CAPABILITY data
+fn capability(num_connectors: u8) -> [u8; MESSAGE_IN_SIZE] {
+    /// USB PD and Type-C current supported, powered from VBUS
+    const ATTRIBUTES: u32 = (1 << 2) | (1 << 6) | (1 << 14);
+
+    let mut data = [0; MESSAGE_IN_SIZE];
+    data[0..4].copy_from_slice(&ATTRIBUTES.to_le_bytes());
+    data[4] = num_connectors;
+    data[12..14].copy_from_slice(&PD_VERSION.to_le_bytes());
+    data[14..16].copy_from_slice(&TYPE_C_VERSION.to_le_bytes());
+    data
+}
+
+/// GET_CONNECTOR_CAPABILITY data
+fn connector_capability() -> [u8; CONNECTOR_CAPABILITY_SIZE] {
+    /// DRP with USB2, debug accessory support, provider and consumer
+    const CAPABILITY: u32 = (1 << 2) | (1 << 4) | (1 << 5) | (1 << 8) | (1 << 9);
+    CAPABILITY.to_le_bytes()
+}
+
+/// GET_CONNECTOR_STATUS data
+///
+/// The RDO isn't reported and PD contracts at 5V and up to 3A are reported as Type-C current.
+fn connector_status(changes: u16, status: &PortStatus, alt_mode: bool) -> [u8; CONNECTOR_STATUS_SIZE] {
+    let mut bits = changes as u128;
+    if status.is_connected() {
+        let provider = status.available_source_contract.is_some();
+        let power_operation_mode: u128 = match status.available_sink_contract.or(status.available_source_contract) {
+            Some(contract) if contract.voltage_mv > 5000 || contract.current_ma > 3000 => 3,
+            Some(contract) if contract.current_ma >= 3000 => 5,
+            Some(contract) if contract.current_ma >= 1500 => 4,
+            _ => 1,
+        };
+        let partner_flags: u128 = if alt_mode { 1 << 1 } else { 1 << 0 };
+        let partner_type: u128 = match status.connection_state {
+            Some(ConnectionState::DebugAccessory) => 6,
+            Some(ConnectionState::AudioAccessory) => 7,
+            // Partner is a UFP when we provide power, a DFP otherwise
+            _ if provider => 2,
+            _ => 1,
+        };
+
+        bits |= power_operation_mode << 16;
+        bits |= 1 << 19;
+        bits |= (provider as u128) << 20;
+        bits |= partner_flags << 21;
+        bits |= partner_type << 29;
+    }
+
+    let mut data = [0; CONNECTOR_STATUS_SIZE];
+    data.copy_from_slice(&bits.to_le_bytes()[..CONNECTOR_STATUS_SIZE]);
+    data
+}
+
+impl Service {
+    /// Publish the UCSI version to the host
+    pub(crate) async fn init_ucsi(&self) {
+        self.send_ucsi(UcsiMessage::Version(UCSI_VERSION)).await;
+    }
+
+    /// Send a message to the UCSI section of the memory map
+    async fn send_ucsi(&self, message: UcsiMessage) {
+        if self
+            .tp
+            .send(EndpointID::External(External::Host), &message)
+            .await
+            .is_err()
+        {
+            error!("Failed to send UCSI message");
+        }
+    }
+
+    /// Publish MESSAGE_IN and then CCI to the host
+    async fn publish_ucsi(&self, mut cci: Cci, message_in: &[u8]) {
+        let mut data = [0; MESSAGE_IN_SIZE];
+        data[..message_in.len()].copy_from_slice(message_in);
+
+        let words = message_in.len().div_ceil(4);
+        for (index, word) in data.chunks_exact(4).take(words).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let message = match index {
+                0 => UcsiMessage::MessageIn0(word),
+                1 => UcsiMessage::MessageIn1(word),
+                2 => UcsiMessage::MessageIn2(word),
+                _ => UcsiMessage::MessageIn3(word),
+            };
+            self.send_ucsi(message).await;
+        }
+
+        cci.set_data_length(message_in.len() as u8);
+        self.publish_cci(cci).await;
+    }
+
+    /// Publish CCI to the host
+    async fn publish_cci(&self, cci: Cci) {
+        self.state.lock().await.ucsi.published = cci;
+        debug!("UCSI CCI: {:#x}", cci.0);
+        self.send_ucsi(UcsiMessage::Cci(cci.0)).await;
+    }
+
+    /// Number of connectors presented to the host
+    async fn num_connectors(&self) -> u8 {
+        let mut count = 0;
+        for port in 0..MAX_SUPPORTED_PORTS {
+            if self.context.has_port(GlobalPortId(port as u8)).await {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Global port of a connector
+    async fn connector_port(&self, connector: u8) -> Result<GlobalPortId, Error> {
+        let port_id = GlobalPortId(connector.checked_sub(1).ok_or(Error::InvalidPort)?);
+        if self.context.has_port(port_id).await {
+            Ok(port_id)
+        } else {
+            Err(Error::InvalidPort)
+        }
+    }
+
+    /// Record connector changes for the host
+    pub(crate) async fn process_ucsi_port_event(&self, port_id: GlobalPortId, event: PortEventKind) {
+        let changes = event_changes(event);
+        if changes == 0 {
+            return;
+        }
+
+        let cci = {
+            let mut state = self.state.lock().await;
+            state
+                .ucsi
+                .connector_changed(port_id.0 + 1, changes)
+                .then(|| state.ucsi.connector_change_cci())
+        };
+
+        if let Some(cci) = cci {
+            debug!("UCSI: Connector {} changed", port_id.0 + 1);
+            // MESSAGE_IN may still hold the response to the last command, leave it alone
+            self.publish_cci(cci).await;
+        }
+    }
+
+    /// Run a command written to CONTROL by the host
+    pub(crate) async fn process_ucsi_command(&self, control: u64) -> Result<(), Error> {
+        let control = Control(control);
+        debug!("UCSI command: {:#x}", control.command());
+
+        match control.command() {
+            command::PPM_RESET => {
+                self.state.lock().await.ucsi = Ppm::default();
+                let mut cci = Cci::default();
+                cci.set_reset_complete(true);
+                self.publish_ucsi(cci, &[]).await;
+            }
+            command::ACK_CC_CI => {
+                let cci = self.state.lock().await.ucsi.acknowledge(control);
+                self.publish_ucsi(cci, &[]).await;
+            }
+            command::SET_NOTIFICATION_ENABLE => {
+                let cci = {
+                    let mut state = self.state.lock().await;
+                    state.ucsi.set_notification_enable(control.notification_enable());
+                    state.ucsi.cci()
+                };
+                self.complete_ucsi_command(cci, Ok(&[])).await;
+            }
+            command::GET_CAPABILITY => {
+                let cci = self.state.lock().await.ucsi.cci();
+                let data = capability(self.num_connectors().await);
+                self.complete_ucsi_command(cci, Ok(&data)).await;
+            }
+            command::GET_CONNECTOR_CAPABILITY => {
+                let cci = self.state.lock().await.ucsi.cci();
+                let result = self.connector_port(control.connector()).await;
+                let data = connector_capability();
+                self.complete_ucsi_command(cci, result.map(|_| data.as_slice())).await;
+            }
+            command::GET_CONNECTOR_STATUS => {
+                let result = self.get_connector_status(control.connector()).await;
+                let cci = self.state.lock().await.ucsi.cci();
+                match result {
+                    Ok(data) => self.complete_ucsi_command(cci, Ok(&data)).await,
+                    Err(e) => self.complete_ucsi_command(cci, Err(e)).await,
+                }
+            }
+            command::CONNECTOR_RESET => {
+                let result = self.connector_reset(control).await;
+                let cci = self.state.lock().await.ucsi.cci();
+                self.complete_ucsi_command(cci, result.map(|_| [].as_slice())).await;
+            }
+            _ => {
+                debug!("UCSI command {:#x} not supported", control.command());
+                let mut cci = self.state.lock().await.ucsi.cci();
+                cci.set_not_supported(true);
+                cci.set_command_complete(true);
+                self.publish_ucsi(cci, &[]).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish the result of a command
+    async fn complete_ucsi_command(&self, mut cci: Cci, result: Result<&[u8], Error>) {
+        cci.set_command_complete(true);
+        match result {
+            Ok(data) => self.publish_ucsi(cci, data).await,
+            Err(e) => {
+                error!("UCSI command failed: {:#?}", e);
+                cci.set_error(true);
+                self.publish_ucsi(cci, &[]).await;
+            }
+        }
+    }
+
+    /// Handle GET_CONNECTOR_STATUS
+    async fn get_connector_status(&self, connector: u8) -> Result<[u8; CONNECTOR_STATUS_SIZE], Error> {
+        let port_id = self.connector_port(connector).await?;
+        let status = self.get_cached_port_status(port_id).await?;
+        let state = self.state.lock().await;
+        let alt_mode = state.alt_modes.get(&port_id).is_some_and(|port| port.mode().is_some());
+        Ok(connector_status(state.ucsi.changes(connector), &status, alt_mode))
+    }
+
+    /// Handle CONNECTOR_RESET
+    async fn connector_reset(&self, control: Control) -> Result<(), Error> {
+        let port_id = self.connector_port(control.connector()).await?;
+        let reset_type = if control.hard_reset() {
+            lpm::ResetType::Hard
+        } else {
+            lpm::ResetType::Data
+        };
+        self.context.reset_port(port_id, reset_type).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_services::power::policy::PowerCapability;
+
+    fn ack_connector_change() -> Control {
+        let mut control = Control::default();
+        control.set_command(command::ACK_CC_CI);
+        control.set_connector_change_ack(true);
+        control
+    }
+
+    #[test]
+    fn test_notifications() {
+        let mut ppm = Ppm::default();
+
+        // Nothing is indicated until the host enables notifications
+        assert!(!ppm.connector_changed(2, change::CONNECT));
+        ppm.set_notification_enable(change::CONNECT);
+        assert_eq!(ppm.cci().connector_change(), 2);
+
+        // Changes on other connectors wait for the acknowledgement
+        assert!(!ppm.connector_changed(1, change::CONNECT));
+        assert_eq!(ppm.changes(2), change::CONNECT);
+        let cci = ppm.acknowledge(ack_connector_change());
+        assert!(cci.ack_command());
+        assert_eq!(cci.connector_change(), 1);
+        assert_eq!(ppm.changes(2), 0);
+
+        ppm.acknowledge(ack_connector_change());
+        assert_eq!(ppm.cci().connector_change(), 0);
+
+        // Changes the host isn't interested in are kept for GET_CONNECTOR_STATUS but not indicated
+        assert!(!ppm.connector_changed(1, change::SUPPORTED_CAM));
+        assert_eq!(ppm.changes(1), change::SUPPORTED_CAM);
+        assert!(ppm.connector_changed(1, change::CONNECT));
+
+        // Invalid connectors are ignored
+        let mut ppm = Ppm::default();
+        ppm.set_notification_enable(change::CONNECT);
+        assert!(!ppm.connector_changed(0, change::CONNECT));
+        assert!(!ppm.connector_changed(MAX_SUPPORTED_PORTS as u8 + 1, change::CONNECT));
+    }
+
+    #[test]
+    fn test_connector_change_keeps_command_status() {
+        let mut ppm = Ppm::default();
+        ppm.set_notification_enable(change::CONNECT);
+
+        let mut cci = ppm.cci();
+        cci.set_command_complete(true);
+        cci.set_data_length(9);
+        ppm.published = cci;
+
+        assert!(ppm.connector_changed(3, change::CONNECT));
+        let cci = ppm.connector_change_cci();
+        assert_eq!(cci.connector_change(), 3);
+        assert!(cci.command_complete());
+        assert_eq!(cci.data_length(), 9);
+    }
+
+    #[test]
+    fn test_connector_status() {
+        assert_eq!(
+            connector_status(0, &PortStatus::new(), false),
+            [0; CONNECTOR_STATUS_SIZE]
+        );
+
+        let status = PortStatus {
+            connection_state: Some(ConnectionState::Attached),
+            available_sink_contract: Some(PowerCapability {
+                voltage_mv: 20000,
+                current_ma: 3000,
+            }),
+            ..PortStatus::new()
+        };
+        let data = connector_status(change::CONNECT, &status, false);
+        let bits = u64::from_le_bytes(data[..8].try_into().unwrap());
+        assert_eq!(bits & 0xffff, change::CONNECT as u64);
+        // PD, connected, consumer, USB partner, DFP attached
+        assert_eq!((bits >> 16) & 0x7, 3);
+        assert_eq!((bits >> 19) & 0x1, 1);
+        assert_eq!((bits >> 20) & 0x1, 0);
+        assert_eq!((bits >> 21) & 0xff, 1);
+        assert_eq!((bits >> 29) & 0x7, 1);
+    }
+
+    #[test]
+    fn test_capability() {
+        let data = capability(8);
+        assert_eq!(data[4], 8);
+        assert_eq!(u16::from_le_bytes([data[12], data[13]]), PD_VERSION);
+    }
+}
@@ -29,27 +29,17 @@ impl FwUpdateState {
 }
 
 impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N, C, V> {
-    /// Create a new invalid FW version response
-    fn create_invalid_fw_version_response(&self) -> InternalResponseData {
-        let dev_inf = FwVerComponentInfo::new(FwVersion::new(0xffffffff), self.cfu_device.component_id());
-        let comp_info: [FwVerComponentInfo; MAX_CMPT_COUNT] = [dev_inf; MAX_CMPT_COUNT];
-        InternalResponseData::FwVersionResponse(GetFwVersionResponse {
-            header: GetFwVersionResponseHeader::new(1, GetFwVerRespHeaderByte3::NoSpecialFlags),
-            component_info: comp_info,
-        })
-    }
-
     /// Process a GetFwVersion command
     async fn process_get_fw_version(&self, target: &mut C) -> InternalResponseData {
         let version = match target.get_active_fw_version().await {
             Ok(v) => v,
             Err(Error::Pd(e)) => {
                 error!("Failed to get active firmware version: {:?}", e);
-                return self.create_invalid_fw_version_response();
+                return InternalResponseData::invalid_fw_version(self.cfu_device.component_id());
             }
             Err(Error::Bus(_)) => {
                 error!("Failed to get active firmware version, bus error");
-                return self.create_invalid_fw_version_response();
+                return InternalResponseData::invalid_fw_version(self.cfu_device.component_id());
             }
         };
 
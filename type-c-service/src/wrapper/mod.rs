@@ -10,6 +10,7 @@ use embedded_services::power::policy::device::StateKind;
 use embedded_services::power::policy::{self, action};
 use embedded_services::type_c::controller::{self, Controller, PortStatus};
 use embedded_services::type_c::event::{PortEventFlags, PortEventKind};
+use embedded_services::type_c::vdm::Discovery;
 use embedded_services::GlobalRawMutex;
 use embedded_services::SyncCell;
 use embedded_services::{debug, error, info, trace, warn};
@@ -18,6 +19,7 @@ use embedded_usb_pd::{Error, PdError, PortId as LocalPortId};
 mod cfu;
 mod pd;
 mod power;
+mod vdm;
 
 /// Base interval for checking for FW update timeouts and recovery attempts
 pub const DEFAULT_FW_UPDATE_TICK_INTERVAL_MS: u64 = 5000;
@@ -65,6 +67,8 @@ pub struct ControllerWrapper<'a, const N: usize, C: Controller, V: FwOfferValida
     state: Mutex<GlobalRawMutex, InternalState>,
     controller: Mutex<GlobalRawMutex, C>,
     active_events: [SyncCell<PortEventKind>; N],
+    /// Partner and cable discovery results
+    discovery: [SyncCell<Discovery>; N],
     /// Trait object for validating firmware versions
     fw_version_validator: V,
 }
@@ -85,6 +89,7 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
             state: Mutex::new(Default::default()),
             controller: Mutex::new(controller),
             active_events: [const { SyncCell::new(PortEventKind::none()) }; N],
+            discovery: from_fn(|_| SyncCell::new(Discovery::default())),
             fw_version_validator,
         }
     }
@@ -92,7 +97,7 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
     /// Handle a plug event
     async fn process_plug_event(
         &self,
-        _controller: &mut C,
+        controller: &mut C,
         power: &policy::device::Device,
         port: LocalPortId,
         status: &PortStatus,
@@ -106,6 +111,9 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
         if status.is_connected() {
             info!("Plug inserted");
 
+            // Discover the cable before any contract is reported to the power policy
+            self.process_discovery(controller, port).await;
+
             // Recover if we're not in the correct state
             if power.state().await.kind() != StateKind::Detached {
                 warn!("Power device not in detached state, recovering");
@@ -127,6 +135,7 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
             }
         } else {
             info!("Plug removed");
+            self.clear_discovery(port);
             if let Err(e) = power.detach().await {
                 error!("Error detaching power device: {:?}", e);
                 return PdError::Failed.into();
@@ -200,6 +209,9 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
                 continue;
             }
 
+            // Detaching clears the dual-role flag, so update it after processing plug events
+            power.notify_dual_role(status.dual_power).await;
+
             // Only notify power policy of a contract after Sink Ready event (always after explicit or implicit contract)
             if event.sink_ready()
                 && self
@@ -211,6 +223,16 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
                 continue;
             }
 
+            if event.fast_role_swap()
+                && self
+                    .process_fast_role_swap(power, local_port_id, &status)
+                    .await
+                    .is_err()
+            {
+                error!("Port{}: Error processing fast role swap", global_port_id.0);
+                continue;
+            }
+
             if event.new_power_contract_as_provider()
                 && self
                     .process_new_provider_contract(global_port_id, power, &status)
@@ -284,12 +306,12 @@ impl<'a, const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'
 
         controller::register_controller(&self.pd_controller)
             .await
-            .map_err(|_| {
+            .map_err(|e| {
                 error!(
                     "Controller{}: Failed to register PD controller",
                     self.pd_controller.id().0
                 );
-                Error::Pd(PdError::Failed)
+                Error::Pd(e)
             })?;
 
         //TODO: Remove when we have a more general framework in place
@@ -1,5 +1,5 @@
 use embedded_services::debug;
-use embedded_services::type_c::controller::{InternalResponseData, Response};
+use embedded_services::type_c::controller::{InternalResponseData, PdoRequest, Response};
 use embedded_usb_pd::ucsi::lpm;
 
 use super::*;
@@ -70,9 +70,102 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
                     Error::Pd(e) => Err(e),
                 },
             },
+            controller::PortCommandData::GetAltModeState => match controller.get_alt_mode_state(local_port).await {
+                Ok(state) => Ok(controller::PortResponseData::AltModeState(state)),
+                Err(e) => match e {
+                    Error::Bus(_) => Err(PdError::Failed),
+                    Error::Pd(e) => Err(e),
+                },
+            },
+            controller::PortCommandData::SetMux(config) => match controller.set_mux(local_port, config).await {
+                Ok(()) => Ok(controller::PortResponseData::Complete),
+                Err(e) => match e {
+                    Error::Bus(_) => Err(PdError::Failed),
+                    Error::Pd(e) => Err(e),
+                },
+            },
+            controller::PortCommandData::SetRetimer(config) => match controller.set_retimer(local_port, config).await {
+                Ok(()) => Ok(controller::PortResponseData::Complete),
+                Err(e) => match e {
+                    Error::Bus(_) => Err(PdError::Failed),
+                    Error::Pd(e) => Err(e),
+                },
+            },
+            controller::PortCommandData::GetPartnerSourceCaps => {
+                match controller.get_partner_source_caps(local_port).await {
+                    Ok(caps) => Ok(controller::PortResponseData::PartnerSourceCaps(caps)),
+                    Err(e) => match e {
+                        Error::Bus(_) => Err(PdError::Failed),
+                        Error::Pd(e) => Err(e),
+                    },
+                }
+            }
+            controller::PortCommandData::GetPartnerSinkCaps => match controller.get_partner_sink_caps(local_port).await
+            {
+                Ok(caps) => Ok(controller::PortResponseData::PartnerSinkCaps(caps)),
+                Err(e) => match e {
+                    Error::Bus(_) => Err(PdError::Failed),
+                    Error::Pd(e) => Err(e),
+                },
+            },
+            controller::PortCommandData::RequestPdo(request) => {
+                match self.process_request_pdo(controller, local_port, request).await {
+                    Ok(()) => Ok(controller::PortResponseData::Complete),
+                    Err(e) => match e {
+                        Error::Bus(_) => Err(PdError::Failed),
+                        Error::Pd(e) => Err(e),
+                    },
+                }
+            }
+            controller::PortCommandData::GetDiscovery => Ok(controller::PortResponseData::Discovery(
+                self.discovery[local_port.0 as usize].get(),
+            )),
+            controller::PortCommandData::GetActiveRdo => match controller.get_active_rdo(local_port).await {
+                Ok(rdo) => Ok(controller::PortResponseData::ActiveRdo(rdo)),
+                Err(e) => match e {
+                    Error::Bus(_) => Err(PdError::Failed),
+                    Error::Pd(e) => Err(e),
+                },
+            },
+            controller::PortCommandData::SetAltModeEntry(policy) => {
+                match controller.set_alt_mode_entry(local_port, policy).await {
+                    Ok(()) => Ok(controller::PortResponseData::Complete),
+                    Err(e) => match e {
+                        Error::Bus(_) => Err(PdError::Failed),
+                        Error::Pd(e) => Err(e),
+                    },
+                }
+            }
         })
     }
 
+    /// Check a power request against the partner's source capabilities before sending it
+    async fn process_request_pdo(
+        &self,
+        controller: &mut C,
+        port: LocalPortId,
+        request: PdoRequest,
+    ) -> Result<(), Error<<C as Controller>::BusError>> {
+        match controller.get_partner_source_caps(port).await {
+            Ok(caps) => {
+                if let Err(e) = request.validate(&caps) {
+                    error!("Port{}: Invalid power request {:#?}", port.0, request);
+                    return Err(Error::Pd(e));
+                }
+            }
+            // Leave it up to the controller
+            Err(Error::Pd(PdError::UnrecognizedCommand)) => {
+                debug!(
+                    "Port{}: Source caps not available, not validating power request",
+                    port.0
+                )
+            }
+            Err(e) => return Err(e),
+        }
+
+        controller.request_pdo(port, request).await
+    }
+
     async fn process_controller_command(
         &self,
         controller: &mut C,
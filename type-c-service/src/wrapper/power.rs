@@ -4,13 +4,20 @@ use embedded_services::{
     ipc::deferred,
     power::policy::{
         device::{CommandData, InternalResponseData},
-        PowerCapability,
+        PowerCapability, PowerRole,
     },
 };
 use embedded_usb_pd::GlobalPortId;
 
 use super::*;
 
+/// Capability assumed after a fast role swap if the controller doesn't report a usable source contract, Type-C default
+/// USB 3 current
+const FRS_DEFAULT_CAPABILITY: PowerCapability = PowerCapability {
+    voltage_mv: 5000,
+    current_ma: 900,
+};
+
 impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N, C, V> {
     /// Return the power device for the given port
     pub(super) fn get_power_device(
@@ -26,12 +33,26 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
     /// Handle a new contract as consumer
     pub(super) async fn process_new_consumer_contract(
         &self,
-        _controller: &mut C,
+        controller: &mut C,
         power: &policy::device::Device,
-        _port: LocalPortId,
+        port: LocalPortId,
         status: &PortStatus,
     ) -> Result<(), Error<<C as Controller>::BusError>> {
         info!("Process new consumer contract");
+        let contract = match status.available_sink_contract {
+            Some(contract) => {
+                let limited = self.cable_limit(port, contract);
+                // Never consume above the cable's voltage rating, the renegotiated contract is reported separately
+                if limited.is_none() && self.renegotiate_for_cable(controller, port).await.is_err() {
+                    error!(
+                        "Port{}: Unable to renegotiate a contract within the cable rating",
+                        port.0
+                    );
+                }
+                limited
+            }
+            None => None,
+        };
 
         let current_state = power.state().await.kind();
         info!("current power state: {:?}", current_state);
@@ -47,27 +68,22 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
             }
         }
 
-        if let Ok(state) = power.try_device_action::<action::Idle>().await {
-            if let Err(e) = state
-                .notify_consumer_power_capability(status.available_sink_contract)
-                .await
-            {
-                error!("Error setting power contract: {:?}", e);
+        // A sink contract while providing means the port partner swapped power roles
+        if let Ok(state) = power.try_device_action::<action::ConnectedProvider>().await {
+            info!("Port partner swapped power roles, now consuming");
+            if let Err(e) = state.notify_power_role_swap().await {
+                error!("Error processing power role swap: {:?}", e);
                 return PdError::Failed.into();
             }
-        } else if let Ok(state) = power.try_device_action::<action::ConnectedConsumer>().await {
-            if let Err(e) = state
-                .notify_consumer_power_capability(status.available_sink_contract)
-                .await
-            {
+        }
+
+        if let Ok(state) = power.try_device_action::<action::Idle>().await {
+            if let Err(e) = state.notify_consumer_power_capability(contract).await {
                 error!("Error setting power contract: {:?}", e);
                 return PdError::Failed.into();
             }
-        } else if let Ok(state) = power.try_device_action::<action::ConnectedProvider>().await {
-            if let Err(e) = state
-                .notify_consumer_power_capability(status.available_sink_contract)
-                .await
-            {
+        } else if let Ok(state) = power.try_device_action::<action::ConnectedConsumer>().await {
+            if let Err(e) = state.notify_consumer_power_capability(contract).await {
                 error!("Error setting power contract: {:?}", e);
                 return PdError::Failed.into();
             }
@@ -92,13 +108,19 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
             return PdError::InvalidPort.into();
         }
 
+        let local_port = self.pd_controller.lookup_local_port(port).map_err(Error::Pd)?;
+        let contract = status
+            .available_source_contract
+            .and_then(|contract| self.cable_limit(local_port, contract));
+
         let current_state = power.state().await.kind();
         info!("current power state: {:?}", current_state);
 
+        // A source contract while consuming means the port partner swapped power roles
         if let action::device::AnyState::ConnectedConsumer(state) = power.device_action().await {
-            info!("ConnectedConsumer");
-            if let Err(e) = state.detach().await {
-                info!("Error detaching power device: {:?}", e);
+            info!("Port partner swapped power roles, now providing");
+            if let Err(e) = state.notify_power_role_swap().await {
+                error!("Error processing power role swap: {:?}", e);
                 return PdError::Failed.into();
             }
         }
@@ -115,14 +137,14 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
         }
 
         if let Ok(state) = power.try_device_action::<action::Idle>().await {
-            if let Some(contract) = status.available_source_contract {
+            if let Some(contract) = contract {
                 if let Err(e) = state.request_provider_power_capability(contract).await {
                     error!("Error setting power contract: {:?}", e);
                     return PdError::Failed.into();
                 }
             }
         } else if let Ok(state) = power.try_device_action::<action::ConnectedProvider>().await {
-            if let Some(contract) = status.available_source_contract {
+            if let Some(contract) = contract {
                 if let Err(e) = state.request_provider_power_capability(contract).await {
                     error!("Error setting power contract: {:?}", e);
                     return PdError::Failed.into();
@@ -142,6 +164,59 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
         Ok(())
     }
 
+    /// Handle a fast role swap, the port is already providing power
+    pub(super) async fn process_fast_role_swap(
+        &self,
+        power: &policy::device::Device,
+        port: LocalPortId,
+        status: &PortStatus,
+    ) -> Result<(), Error<<C as Controller>::BusError>> {
+        info!("Process fast role swap");
+
+        if !status.dual_power {
+            warn!("Fast role swap with a port partner that isn't dual-role");
+        }
+
+        let state = match power.try_device_action::<action::ConnectedConsumer>().await {
+            Ok(state) => state,
+            Err(_) => {
+                // Power policy wasn't consuming from this port, handle like a normal source contract
+                debug!("Fast role swap while not consuming");
+                return Ok(());
+            }
+        };
+
+        let capability = status
+            .available_source_contract
+            .and_then(|contract| self.cable_limit(port, contract))
+            .unwrap_or(FRS_DEFAULT_CAPABILITY);
+        if let Err(e) = state.notify_fast_role_swap(capability).await {
+            error!("Error processing fast role swap: {:?}", e);
+            return PdError::Failed.into();
+        }
+
+        Ok(())
+    }
+
+    /// Handle a power role swap command
+    async fn process_power_role_swap(
+        &self,
+        port: LocalPortId,
+        role: PowerRole,
+        controller: &mut C,
+        power: &policy::device::Device,
+    ) -> Result<(), Error<<C as Controller>::BusError>> {
+        info!("Port{}: Power role swap to {:?}", port.0, role);
+        if power.state().await.kind() == StateKind::ConnectedConsumer
+            && controller.enable_sink_path(port, false).await.is_err()
+        {
+            error!("Error disabling sink path");
+            return PdError::Failed.into();
+        }
+
+        controller.power_role_swap(port, role).await
+    }
+
     /// Handle a disconnect command
     async fn process_disconnect(
         &self,
@@ -219,6 +294,7 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
                     error!("Error enabling sink path");
                     return Err(policy::Error::Failed);
                 }
+                power.notify_sink_ready().await;
             }
             policy::device::CommandData::ConnectAsProvider(capability) => {
                 if self
@@ -236,6 +312,16 @@ impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N
                     return Err(policy::Error::Failed);
                 }
             }
+            policy::device::CommandData::PowerRoleSwap(role) => {
+                if self
+                    .process_power_role_swap(port, *role, controller, power)
+                    .await
+                    .is_err()
+                {
+                    error!("Error processing power role swap");
+                    return Err(policy::Error::Failed);
+                }
+            }
         }
 
         Ok(policy::device::ResponseData::Complete)
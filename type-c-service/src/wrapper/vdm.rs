@@ -0,0 +1,126 @@
+//! Structured VDM discovery of the port partner and cable
+use embedded_services::power::policy::PowerCapability;
+use embedded_services::type_c::controller::PdoRequest;
+use embedded_services::type_c::vdm::{DiscoverCommand, Discovery, Identity, Sop, SvidModes};
+use embedded_usb_pd::pdo::source;
+
+use super::*;
+
+impl<const N: usize, C: Controller, V: FwOfferValidator> ControllerWrapper<'_, N, C, V> {
+    /// Discover the identity of the cable and partner and the partner's modes
+    ///
+    /// Discovery is best effort, anything the controller or partner doesn't support is left out of the results.
+    pub(super) async fn process_discovery(&self, controller: &mut C, port: LocalPortId) {
+        let mut discovery = Discovery::default();
+
+        discovery.cable = self.discover_identity(controller, port, Sop::SopPrime).await;
+        if let Some(cable) = discovery.cable_properties() {
+            info!("Port{}: Cable: {:#?}", port.0, cable);
+        }
+
+        discovery.partner = self.discover_identity(controller, port, Sop::Sop).await;
+        if discovery.partner.is_some_and(|partner| partner.modal) {
+            self.discover_modes(controller, port, &mut discovery).await;
+        }
+
+        self.discovery[port.0 as usize].set(discovery);
+    }
+
+    /// Run Discover Identity
+    async fn discover_identity(&self, controller: &mut C, port: LocalPortId, sop: Sop) -> Option<Identity> {
+        let vdos = match controller.discover(port, sop, DiscoverCommand::Identity).await {
+            Ok(vdos) => vdos,
+            Err(Error::Pd(PdError::UnrecognizedCommand)) => return None,
+            Err(_) => {
+                debug!("Port{}: Discover identity {:?} failed", port.0, sop);
+                return None;
+            }
+        };
+
+        match Identity::parse(sop, &vdos) {
+            Ok(identity) => {
+                debug!("Port{}: {:?} identity: {:#?}", port.0, sop, identity);
+                Some(identity)
+            }
+            Err(_) => {
+                warn!("Port{}: Invalid {:?} identity {:?}", port.0, sop, vdos.as_slice());
+                None
+            }
+        }
+    }
+
+    /// Run Discover SVIDs and Discover Modes for each SVID against the partner
+    async fn discover_modes(&self, controller: &mut C, port: LocalPortId, discovery: &mut Discovery) {
+        let svids = match controller.discover(port, Sop::Sop, DiscoverCommand::Svids).await {
+            Ok(svids) => svids,
+            Err(_) => {
+                debug!("Port{}: Discover SVIDs failed", port.0);
+                return;
+            }
+        };
+
+        for (slot, svid) in discovery.modes.iter_mut().zip(svids.svids()) {
+            match controller.discover(port, Sop::Sop, DiscoverCommand::Modes(svid)).await {
+                Ok(modes) => *slot = Some(SvidModes { svid, modes }),
+                Err(_) => debug!("Port{}: Discover modes for SVID {:#x} failed", port.0, svid),
+            }
+        }
+    }
+
+    /// Clear discovery results on disconnect
+    pub(super) fn clear_discovery(&self, port: LocalPortId) {
+        self.discovery[port.0 as usize].set(Discovery::default());
+    }
+
+    /// Limit a contract to the current rating of the cable, if the cable has an e-marker
+    ///
+    /// Returns `None` for a contract above the cable's voltage rating, it must not be used. Cables without an e-marker
+    /// are already limited to 3A by the controller.
+    pub(super) fn cable_limit(&self, port: LocalPortId, capability: PowerCapability) -> Option<PowerCapability> {
+        let Some(cable) = self.discovery[port.0 as usize].get().cable_properties() else {
+            return Some(capability);
+        };
+
+        if !cable.supports_voltage(capability) {
+            warn!(
+                "Port{}: Contract at {}mV exceeds the cable rating of {}mV",
+                port.0, capability.voltage_mv, cable.max_voltage_mv
+            );
+            return None;
+        }
+
+        let limited = cable.limit(capability);
+        if limited != capability {
+            info!("Port{}: Contract limited to {}mA by cable", port.0, limited.current_ma);
+        }
+        Some(limited)
+    }
+
+    /// Request the highest voltage fixed PDO from the partner that the cable is rated for
+    pub(super) async fn renegotiate_for_cable(
+        &self,
+        controller: &mut C,
+        port: LocalPortId,
+    ) -> Result<(), Error<<C as Controller>::BusError>> {
+        let Some(cable) = self.discovery[port.0 as usize].get().cable_properties() else {
+            return Ok(());
+        };
+
+        let capabilities = controller.get_partner_source_caps(port).await?;
+        let position = capabilities
+            .iter()
+            .zip(1..)
+            .filter_map(|(pdo, position)| match pdo {
+                source::Pdo::Fixed(data) if data.voltage_mv <= cable.max_voltage_mv => {
+                    Some((data.voltage_mv, position))
+                }
+                _ => None,
+            })
+            .max()
+            .map(|(_, position)| position)
+            .ok_or(Error::Pd(PdError::InvalidParams))?;
+
+        info!("Port{}: Renegotiating PDO {} within the cable rating", port.0, position);
+        controller.request_pdo(port, PdoRequest::Pdo(position)).await
+    }
+}